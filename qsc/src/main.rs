@@ -28,12 +28,26 @@
 //!        --batch <BATCH>
 //!            Parallel scan [default: 5000]
 //!
+//!        --csv <CSV>
+//!            Path to file where to save results in csv format
+//!
+//!        --dry-run
+//!            Print the ip:port pairs that would be scanned (TCP connect scan) and exit
+//!
+//!        --exclude <EXCLUDE>
+//!            Comma separated list of addresses never scanned, even if they are in the targets.
+//!            Same format as --targets. E.g., '192.168.1.1,10.0.0.0/28'
+//!
 //!    -h, --help
 //!            Print help information
 //!
 //!        --json <JSON>
 //!            Path to file whre to save results in json format
 //!
+//!        --max-targets <MAX_TARGETS>
+//!            Refuse to scan more than this number of ip:port pairs (IPs for the ping scan), 0 for no
+//!            limit [default: 100000000]
+//!
 //!        --mode <MODE>
 //!            Scan mode:
 //!              - 0: TCP connect;
@@ -42,35 +56,57 @@
 //!                     [default: 0]
 //!
 //!        --ping-interval <PING_INTERVAL>
-//!            Inteval in ms between pings for a single target. [default: 1000]
+//!            Inteval between pings for a single target (e.g., '500ms', '1s', bare numbers are
+//!            ms). [default: 1000]
 //!
 //!        --ping-tries <PING_TRIES>
 //!            Number of maximum retries for each target (ping scan) [default: 1]
 //!
 //!        --ports <PORTS>
-//!            Comma separate list of ports (or port ranges) to scan for each target. E.g., '80',
-//!            '22,443', '1-1024,8080'
+//!            Comma separate list of ports (or port ranges, or service names) to scan for each
+//!            target. E.g., '80', '22,443', '1-1024,8080', 'ssh,http', '1024-', 'all'
 //!
 //!        --printlevel <PRINTLEVEL>
 //!            Console output mode:
 //!              - 0: suppress console output;
 //!              - 1: print ip:port for open ports at the end of the scan;
-//!              - 2: print ip:port:<OPEN|CLOSE> at the end of the scan;
+//!              - 2: print ip:port:<OPEN|CLOSE|FILTERED> at the end of the scan;
 //!              - 3: print ip:port for open ports as soon as they are found;
-//!              - 4: print ip:port:<OPEN:CLOSE> as soon as the scan for a
+//!              - 4: print ip:port:<OPEN|CLOSE|FILTERED> as soon as the scan for a
 //!                   target ends;
 //!                     [default: 3]
 //!
+//!        --progress
+//!            Print a progress bar with the estimated time to the end of the scan (ETA) on stderr
+//!
+//!        --show-closed
+//!            Print ip:port for closed ports at the end of the scan (TCP connect scan)
+//!
+//!        --show-filtered
+//!            Print ip:port for filtered ports (no answer or unreachable target) at the end of the
+//!            scan (TCP connect scan)
+//!
+//!        --strict-resolution
+//!            Fail if a target cannot be resolved or parsed (e.g., an unknown domain name), instead
+//!            of skipping it
+//!
+//!        --strict-targets
+//!            Fail if a target that looks like a path (it contains a '/' or ends with '.txt') is not
+//!            a file, instead of skipping it
+//!
 //!        --targets <TARGETS>
 //!            Comma separated list of targets to scan. A target can be an IP, a set of IPs in CIDR
-//!            notation, a domain name or a path to a file containing one of the previous for each
-//!            line. E.g., '8.8.8.8', '192.168.1.0/24', 'www.google.com,/tmp/ips.txt'
+//!            notation, an IPv4 range, a domain name or a path to a file containing one of the
+//!            previous for each line. E.g., '8.8.8.8', '192.168.1.0/24', '192.168.1.1-50',
+//!            'www.google.com,/tmp/ips.txt'. Existing files named by a target that looks like a
+//!            path are read first; paths cannot contain commas
 //!
 //!        --tcp-tries <TCP_TRIES>
 //!            Number of maximum retries for each target:port pair (TCP Connect scan) [default: 1]
 //!
 //!        --timeout <TIMEOUT>
-//!            Timeout in ms. If the timeout expires the port is considered close [default: 1500]
+//!            Timeout (e.g., '1500ms', '2s', '1m', bare numbers are ms). If the timeout expires
+//!            the port is considered close [default: 1500]
 //!
 //!    -V, --version
 //!            Print version information
@@ -81,10 +117,15 @@ use std::fs::File;
 use std::io::Write;
 use std::net::IpAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use qscan::{QSPrintMode, QScanPingState, QScanResult, QScanTcpConnectState, QScanType, QScanner};
+use qscan::{
+    QSPrintMode, QScanPingState, QScanProgress, QScanResult, QScanTcpConnectState, QScanType,
+    QScanner,
+};
 
 use clap::Parser;
+use log::{Level, LevelFilter, Log, Metadata, Record};
 use tokio::runtime::Runtime;
 
 #[cfg(target_os = "linux")]
@@ -99,16 +140,25 @@ struct Args {
     #[clap(
         long,
         help = "Comma separated list of targets to scan. \
-        A target can be an IP, a set of IPs in CIDR notation, a domain name \
-        or a path to a file containing one of the previous for each line. \
-        E.g., '8.8.8.8', '192.168.1.0/24', 'www.google.com,/tmp/ips.txt'"
+        A target can be an IP, a set of IPs in CIDR notation, an IPv4 range, \
+        a domain name or a path to a file containing one of the previous for \
+        each line. E.g., '8.8.8.8', '192.168.1.0/24', '192.168.1.1-50', \
+        'www.google.com,/tmp/ips.txt'. Existing files named by a target \
+        that looks like a path are read first; paths cannot contain commas"
     )]
     targets: String,
 
     #[clap(
         long,
-        help = "Comma separate list of ports (or port ranges) to scan for each target. \
-           E.g., '80', '22,443', '1-1024,8080'"
+        help = "Comma separated list of addresses never scanned, even if they are in the \
+        targets. Same format as --targets. E.g., '192.168.1.1,10.0.0.0/28'"
+    )]
+    exclude: Option<String>,
+
+    #[clap(
+        long,
+        help = "Comma separate list of ports (or port ranges, or service names) to scan for \
+           each target. E.g., '80', '22,443', '1-1024,8080', 'ssh,http', '1024-', 'all'"
     )]
     ports: String,
 
@@ -117,15 +167,19 @@ struct Args {
 
     #[clap(
         long,
-        default_value_t = 1500,
-        help = "Timeout in ms. If the timeout expires the port is considered close"
+        default_value = "1500",
+        parse(try_from_str = parse_duration_ms),
+        help = "Timeout (e.g., '1500ms', '2s', '1m', bare numbers are ms). \
+        If the timeout expires the port is considered close"
     )]
     timeout: u64,
 
     #[clap(
         long,
-        default_value_t = 1000,
-        help = "Inteval in ms between pings for a single target."
+        default_value = "1000",
+        parse(try_from_str = parse_duration_ms),
+        help = "Inteval between pings for a single target (e.g., '500ms', '1s', \
+        bare numbers are ms)."
     )]
     ping_interval: u64,
 
@@ -149,9 +203,9 @@ struct Args {
         help = "Console output mode:
   - 0: suppress console output;
   - 1: print ip:port for open ports at the end of the scan;
-  - 2: print ip:port:<OPEN|CLOSE> at the end of the scan;
+  - 2: print ip:port:<OPEN|CLOSE|FILTERED> at the end of the scan;
   - 3: print ip:port for open ports as soon as they are found;
-  - 4: print ip:port:<OPEN:CLOSE> as soon as the scan for a
+  - 4: print ip:port:<OPEN|CLOSE|FILTERED> as soon as the scan for a
        target ends;
         "
     )]
@@ -168,8 +222,120 @@ struct Args {
     )]
     mode: u8,
 
+    #[clap(
+        long,
+        default_value_t = 100_000_000,
+        help = "Refuse to scan more than this number of ip:port pairs (IPs for the ping \
+        scan), 0 for no limit"
+    )]
+    max_targets: usize,
+
     #[clap(long, help = "Path to file whre to save results in json format")]
     json: Option<PathBuf>,
+
+    #[clap(long, help = "Path to file where to save results in csv format")]
+    csv: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "Print ip:port for closed ports at the end of the scan (TCP connect scan)"
+    )]
+    show_closed: bool,
+
+    #[clap(
+        long,
+        help = "Print ip:port for filtered ports (no answer or unreachable target) at the end \
+        of the scan (TCP connect scan)"
+    )]
+    show_filtered: bool,
+
+    #[clap(
+        long,
+        help = "Fail if a target cannot be resolved or parsed (e.g., an unknown \
+        domain name), instead of skipping it"
+    )]
+    strict_resolution: bool,
+
+    #[clap(
+        long,
+        help = "Fail if a target that looks like a path (it contains a '/' or ends \
+        with '.txt') is not a file, instead of skipping it"
+    )]
+    strict_targets: bool,
+
+    #[clap(
+        long,
+        help = "Print the ip:port pairs that would be scanned (TCP connect scan) and exit"
+    )]
+    dry_run: bool,
+
+    #[clap(
+        long,
+        help = "Print a progress bar with the estimated time to the end of the scan (ETA) on stderr"
+    )]
+    progress: bool,
+}
+
+/// Parse a duration with an optional unit suffix (`ms`, `s`, `m`, `h`) and
+/// return it in milliseconds. Bare numbers are milliseconds.
+#[doc(hidden)]
+fn parse_duration_ms(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}'", s))?;
+    let factor: u64 = match unit.trim() {
+        "" | "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        u => {
+            return Err(format!(
+                "Unknown duration unit '{}' (allowed ms, s, m, h)",
+                u
+            ))
+        }
+    };
+
+    value
+        .checked_mul(factor)
+        .ok_or_else(|| format!("Duration '{}' is too large", s))
+}
+
+/// Width of the --progress bar, in characters
+#[doc(hidden)]
+const PROGRESS_BAR_WIDTH: usize = 20;
+
+/// Format a duration as, e.g., `45s`, `1m12s` or `2h03m10s`
+#[doc(hidden)]
+fn format_eta(eta: Duration) -> String {
+    let secs = eta.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+/// Format the --progress line, e.g., `[========            ] 42% ETA 1m12s`
+#[doc(hidden)]
+fn format_progress(progress: &QScanProgress) -> String {
+    let percent = (progress.completed * 100)
+        .checked_div(progress.total)
+        .unwrap_or(100)
+        .min(100);
+    let filled = percent * PROGRESS_BAR_WIDTH / 100;
+    let bar = format!(
+        "{}{}",
+        "=".repeat(filled),
+        " ".repeat(PROGRESS_BAR_WIDTH - filled)
+    );
+    match progress.eta {
+        Some(eta) => format!("[{}] {}% ETA {}", bar, percent, format_eta(eta)),
+        None => format!("[{}] {}%", bar, percent),
+    }
 }
 
 #[doc(hidden)]
@@ -177,7 +343,16 @@ fn do_tcp_connect_scan_and_print(scanner: &mut QScanner, args: &Args) {
     scanner.set_scan_type(QScanType::TcpConnect);
     scanner.set_ntries(args.tcp_tries);
     set_print_level(scanner, args);
-    let res: &Vec<QScanResult> = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    let res: &Vec<QScanResult> = match Runtime::new()
+        .unwrap()
+        .block_on(scanner.try_scan_tcp_connect())
+    {
+        Ok(res) => res,
+        Err(e) => {
+            println!("Error: {} (see --max-targets)", e);
+            std::process::exit(1);
+        }
+    };
 
     if (args.printlevel == 0) && (args.printlevel == 1 || args.printlevel == 2) {
         for r in res {
@@ -194,6 +369,18 @@ fn do_tcp_connect_scan_and_print(scanner: &mut QScanner, args: &Args) {
             }
         }
     }
+
+    if args.show_closed {
+        for l in scanner.get_closed_ports_as_lines() {
+            println!("{}", l);
+        }
+    }
+
+    if args.show_filtered {
+        for l in scanner.get_filtered_ports_as_lines() {
+            println!("{}", l);
+        }
+    }
 }
 
 #[doc(hidden)]
@@ -238,6 +425,30 @@ fn set_print_level(scanner: &mut QScanner, args: &Args) {
     }
 }
 
+/// Print the warnings and errors of the scanner to stdout
+#[doc(hidden)]
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            match record.level() {
+                Level::Error => println!("Error: {}", record.args()),
+                _ => println!("Warning: {}", record.args()),
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[doc(hidden)]
+static LOGGER: StdoutLogger = StdoutLogger;
+
 /// Simple async tcp connect scanner
 #[doc(hidden)]
 fn main() {
@@ -246,32 +457,88 @@ fn main() {
     #[cfg(feature="debugoff")]
     debugoff::multi_ptraceme_or_die();
 
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Warn);
+    }
+
     let args = Args::parse();
     let batch = args.batch;
     let timeout = args.timeout;
     let mut jf: Option<File> = None;
+    let mut cf: Option<File> = None;
 
-    if args.json.is_some() {
-        jf = if let Ok(f) = File::create(&args.json.as_ref().unwrap().as_path()) {
+    if let Some(json) = &args.json {
+        jf = if let Ok(f) = File::create(json.as_path()) {
             Some(f)
         } else {
-            panic!(
-                "Cannot create file {}",
-                args.json.unwrap().to_str().unwrap()
-            );
+            panic!("Cannot create file {}", json.to_str().unwrap());
+        }
+    }
+
+    if let Some(csv) = &args.csv {
+        cf = if let Ok(f) = File::create(csv.as_path()) {
+            Some(f)
+        } else {
+            panic!("Cannot create file {}", csv.to_str().unwrap());
         }
     }
 
-    let mut scanner = QScanner::new(&args.targets, &args.ports);
+    let mut scanner = match QScanner::try_new("", &args.ports) {
+        Ok(scanner) => scanner,
+        Err(e) => {
+            println!("Error: invalid --ports: {}", e);
+            std::process::exit(1);
+        }
+    };
+    scanner.set_strict_resolution(args.strict_resolution);
+    if !args.strict_targets && !args.strict_resolution {
+        scanner.set_targets_addr(&args.targets);
+    } else if let Err(e) = scanner.try_set_targets_addr(&args.targets) {
+        println!("Error: invalid --targets: {}", e);
+        std::process::exit(1);
+    }
 
     scanner.set_batch(batch);
     scanner.set_timeout_ms(timeout);
+    if args.max_targets > 0 {
+        scanner.set_max_targets(args.max_targets);
+    }
+
+    if let Some(exclude) = &args.exclude {
+        scanner.set_exclude(exclude);
+    }
+
+    if args.progress {
+        scanner.set_progress_eta_callback(|progress| {
+            eprint!("\r{}", format_progress(progress));
+            if progress.completed == progress.total {
+                eprintln!();
+            }
+        });
+    }
+
+    if args.dry_run {
+        for socket in scanner.enumerate_targets() {
+            println!("{}", socket);
+        }
+        return;
+    }
 
     #[cfg(target_os = "linux")]
     #[cfg(not(debug_assertions))]
     #[cfg(feature="debugoff")]
     debugoff::multi_ptraceme_or_die();
 
+    // The TCP connect scan of --mode 2 is checked again after the ping
+    scanner.set_scan_type(match args.mode {
+        0 => QScanType::TcpConnect,
+        _ => QScanType::Ping,
+    });
+    if let Err(e) = scanner.check_max_targets() {
+        println!("Error: {} (see --max-targets)", e);
+        std::process::exit(1);
+    }
+
     match args.mode {
         0 => do_tcp_connect_scan_and_print(&mut scanner, &args),
         1 => do_ping_scan_and_print(&mut scanner, &args),
@@ -300,9 +567,91 @@ fn main() {
         if let Err(e) = f.write_all(j.as_bytes()) {
             eprintln!(
                 "Error writing json results in {}: {}",
-                args.json.unwrap().to_str().unwrap(),
+                args.json.as_ref().unwrap().to_str().unwrap(),
+                e
+            );
+        }
+    }
+
+    if let Some(mut f) = cf {
+        let c = scanner.get_last_results_as_csv_string().unwrap();
+        if let Err(e) = f.write_all(c.as_bytes()) {
+            eprintln!(
+                "Error writing csv results in {}: {}",
+                args.csv.unwrap().to_str().unwrap(),
                 e
             );
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{format_eta, format_progress, parse_duration_ms};
+    use qscan::QScanProgress;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration_ms("250ms"), Ok(250));
+        assert_eq!(parse_duration_ms("3s"), Ok(3000));
+        assert_eq!(parse_duration_ms("2m"), Ok(120_000));
+        assert_eq!(parse_duration_ms("1h"), Ok(3_600_000));
+        assert_eq!(parse_duration_ms(" 10 s "), Ok(10_000));
+    }
+
+    #[test]
+    fn parse_duration_no_unit() {
+        assert_eq!(parse_duration_ms("500"), Ok(500));
+        assert_eq!(parse_duration_ms("0"), Ok(0));
+    }
+
+    #[test]
+    fn parse_duration_overflow() {
+        assert!(parse_duration_ms(&format!("{}h", u64::MAX)).is_err());
+        assert!(parse_duration_ms(&format!("{}", u64::MAX)).is_ok());
+        assert!(parse_duration_ms("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn parse_duration_garbage() {
+        for s in ["", "s", "ms10", "1.5s", "-1s", "10d", "10 sec", "abc"] {
+            assert!(parse_duration_ms(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn eta_format() {
+        assert_eq!(format_eta(Duration::ZERO), "0s");
+        assert_eq!(format_eta(Duration::from_millis(45_900)), "45s");
+        assert_eq!(format_eta(Duration::from_secs(72)), "1m12s");
+        assert_eq!(
+            format_eta(Duration::from_secs(2 * 3600 + 3 * 60 + 10)),
+            "2h03m10s"
+        );
+    }
+
+    #[test]
+    fn progress_format() {
+        let mut progress = QScanProgress {
+            completed: 42,
+            total: 100,
+            elapsed: Duration::from_secs(52),
+            eta: Some(Duration::from_secs(72)),
+        };
+        assert_eq!(
+            format_progress(&progress),
+            "[========            ] 42% ETA 1m12s"
+        );
+
+        progress.eta = None;
+        assert_eq!(format_progress(&progress), "[========            ] 42%");
+
+        progress.completed = 100;
+        progress.eta = Some(Duration::ZERO);
+        assert_eq!(
+            format_progress(&progress),
+            "[====================] 100% ETA 0s"
+        );
+    }
+}
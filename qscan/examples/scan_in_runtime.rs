@@ -0,0 +1,45 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//
+use qscan::{QScanResult, QScanTcpConnectState, QScanner};
+use tokio::runtime::Runtime;
+
+// Equivalent to `#[tokio::main] async fn main()`: the scanner runs on the
+// application runtime and its scans are simply awaited
+pub fn main() {
+    Runtime::new().unwrap().block_on(app());
+}
+
+async fn app() {
+    let mut scanner = QScanner::new("localhost,127.0.0.1", "22,80,443");
+    scanner.set_timeout_ms(1000);
+
+    // Other tasks keep running on the runtime while the scan is in progress
+    let ticker = tokio::spawn(async {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        println!("The runtime is not blocked by the scan");
+    });
+
+    for r in scanner.scan_tcp_connect().await {
+        if let QScanResult::TcpConnect(sa) = r {
+            if sa.state == QScanTcpConnectState::Open {
+                println!("{}", sa.target);
+            }
+        }
+    }
+
+    ticker.await.unwrap();
+}
@@ -0,0 +1,107 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Append-only log of the probes sent by the scanner, independent of the
+//! results.
+//!
+//! One line per probe: `<timestamp> <protocol> <source> <destination> <payload>`,
+//! where the timestamp is the time the probe was sent (seconds since the UNIX
+//! epoch, with microseconds), the source is `-` when unknown and the payload
+//! is hex encoded (`-` if empty).
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub(crate) struct ProbeAuditLog {
+    file: File,
+}
+
+impl ProbeAuditLog {
+    /// Open (or create) the log at `path`, new records are appended
+    pub(crate) fn new(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self { file })
+    }
+
+    /// Append a record. The file is unbuffered and each record is written
+    /// with a single write, so records are never interleaved and reach the
+    /// OS as soon as the probe is recorded.
+    pub(crate) fn record(
+        &self,
+        sent: SystemTime,
+        protocol: &str,
+        source: Option<SocketAddr>,
+        destination: &dyn fmt::Display,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let line = format_record(sent, protocol, source, destination, payload);
+        (&self.file).write_all(line.as_bytes())
+    }
+}
+
+fn format_record(
+    sent: SystemTime,
+    protocol: &str,
+    source: Option<SocketAddr>,
+    destination: &dyn fmt::Display,
+    payload: &[u8],
+) -> String {
+    let ts = sent.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let source = source.map_or_else(|| "-".to_string(), |s| s.to_string());
+    let payload = if payload.is_empty() {
+        "-".to_string()
+    } else {
+        payload.iter().map(|b| format!("{:02x}", b)).collect()
+    };
+
+    format!(
+        "{}.{:06} {} {} {} {}\n",
+        ts.as_secs(),
+        ts.subsec_micros(),
+        protocol,
+        source,
+        destination,
+        payload
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn format_record() {
+        let sent = UNIX_EPOCH + Duration::from_micros(1_650_000_000_000_042);
+        let src: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let dst: SocketAddr = "127.0.0.1:80".parse().unwrap();
+
+        assert_eq!(
+            super::format_record(sent, "tcp", Some(src), &dst, b"GET"),
+            "1650000000.000042 tcp 127.0.0.1:40000 127.0.0.1:80 474554\n"
+        );
+        assert_eq!(
+            super::format_record(sent, "icmp", None, &dst.ip(), &[]),
+            "1650000000.000042 icmp - 127.0.0.1 -\n"
+        );
+    }
+}
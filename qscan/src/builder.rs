@@ -0,0 +1,177 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Chainable construction of a [QScanner]
+//!
+//! Settings that are not given keep the [QScanner::new] defaults, missing
+//! targets or ports are empty.
+
+use crate::qscanner::{QSPrintMode, QScanParseError, QScanType, QScanner};
+
+/// Builder of a [QScanner]
+///
+/// # Examples
+///
+/// ```
+/// use qscan::builder::QScannerBuilder;
+/// use qscan::qscanner::QScanType;
+/// let scanner = QScannerBuilder::new()
+///     .targets("127.0.0.1")
+///     .ports("53,80")
+///     .batch(1000)
+///     .timeout_ms(500)
+///     .ntries(2)
+///     .scan_type(QScanType::TcpConnect)
+///     .build();
+/// assert_eq!(scanner.get_tagets_ports(), &vec![53, 80]);
+/// ```
+#[derive(Debug, Default)]
+pub struct QScannerBuilder {
+    targets: String,
+    ports: String,
+    batch: Option<u16>,
+    timeout_ms: Option<u64>,
+    ntries: Option<u8>,
+    scan_type: Option<QScanType>,
+    print_mode: Option<QSPrintMode>,
+}
+
+impl QScannerBuilder {
+    /// Create a new builder, without targets
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the targets addresses (see [QScanner::set_targets_addr])
+    pub fn targets(mut self, addresses: &str) -> Self {
+        self.targets = addresses.to_string();
+        self
+    }
+
+    /// Set the targets ports (see [QScanner::set_targets_port])
+    pub fn ports(mut self, ports: &str) -> Self {
+        self.ports = ports.to_string();
+        self
+    }
+
+    /// See [QScanner::set_batch]
+    pub fn batch(mut self, batch: u16) -> Self {
+        self.batch = Some(batch);
+        self
+    }
+
+    /// See [QScanner::set_timeout_ms]
+    pub fn timeout_ms(mut self, to_ms: u64) -> Self {
+        self.timeout_ms = Some(to_ms);
+        self
+    }
+
+    /// See [QScanner::set_ntries]
+    pub fn ntries(mut self, ntries: u8) -> Self {
+        self.ntries = Some(ntries);
+        self
+    }
+
+    /// See [QScanner::set_scan_type]
+    pub fn scan_type(mut self, scan_type: QScanType) -> Self {
+        self.scan_type = Some(scan_type);
+        self
+    }
+
+    /// See [QScanner::set_print_mode]
+    pub fn print_mode(mut self, print_mode: QSPrintMode) -> Self {
+        self.print_mode = Some(print_mode);
+        self
+    }
+
+    /// Create the scanner. Malformed ports are reported and skipped, as in
+    /// [QScanner::new]
+    pub fn build(self) -> QScanner {
+        let scanner = QScanner::new(&self.targets, &self.ports);
+        self.configure(scanner)
+    }
+
+    /// Same as [QScannerBuilder::build], but fail on malformed ports (see
+    /// [QScanner::try_new])
+    pub fn try_build(self) -> Result<QScanner, QScanParseError> {
+        let scanner = QScanner::try_new(&self.targets, &self.ports)?;
+        Ok(self.configure(scanner))
+    }
+
+    fn configure(self, mut scanner: QScanner) -> QScanner {
+        if let Some(batch) = self.batch {
+            scanner.set_batch(batch);
+        }
+        if let Some(to_ms) = self.timeout_ms {
+            scanner.set_timeout_ms(to_ms);
+        }
+        if let Some(ntries) = self.ntries {
+            scanner.set_ntries(ntries);
+        }
+        if let Some(scan_type) = self.scan_type {
+            scanner.set_scan_type(scan_type);
+        }
+        if let Some(print_mode) = self.print_mode {
+            scanner.set_print_mode(print_mode);
+        }
+
+        scanner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QScannerBuilder;
+    use crate::qscanner::{QScanType, QScanner};
+
+    #[test]
+    fn build() {
+        let plan = QScannerBuilder::new()
+            .targets("127.0.0.1,127.0.0.2")
+            .ports("22,80-81")
+            .batch(10)
+            .timeout_ms(250)
+            .ntries(3)
+            .scan_type(QScanType::Ping)
+            .build()
+            .export_scan_plan();
+
+        assert_eq!(plan.ips.len(), 2);
+        assert_eq!(plan.ports, vec![22, 80, 81]);
+        assert_eq!(plan.scan_type, QScanType::Ping);
+        assert_eq!((plan.batch, plan.timeout_ms, plan.tries), (10, 250, 3));
+    }
+
+    #[test]
+    fn build_defaults() {
+        let plan = QScannerBuilder::new().build().export_scan_plan();
+        let def = QScanner::new("", "").export_scan_plan();
+
+        assert!(plan.ips.is_empty());
+        assert!(plan.ports.is_empty());
+        assert_eq!(plan.scan_type, def.scan_type);
+        assert_eq!(
+            (plan.batch, plan.timeout_ms, plan.tries),
+            (def.batch, def.timeout_ms, def.tries)
+        );
+    }
+
+    #[test]
+    fn try_build() {
+        assert!(QScannerBuilder::new().ports("80,x").try_build().is_err());
+        assert!(QScannerBuilder::new().ports("80").try_build().is_ok());
+    }
+}
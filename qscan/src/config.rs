@@ -0,0 +1,422 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Scanner configuration file (TOML)
+//!
+//! Each key maps onto the [QScanner] setter with the same name (without the
+//! `set_` prefix, durations in milliseconds take a `_ms` suffix). All the
+//! keys are optional. `resolver_config` takes the list of the name servers
+//! (plain DNS, port 53) instead of a
+//! [ResolverConfig](crate::qscanner::ResolverConfig). `top_ports` replaces
+//! `ports` and `exclude_file` replaces `exclude`, so they cannot be combined.
+//!
+//! ```toml
+//! targets = "127.0.0.1,192.168.1.0/24"
+//! ports = "22,80,443,1024-2048"
+//! exclude = "192.168.1.1,10.0.0.0/28"
+//! top_ports = 100                    # instead of ports
+//! exclude_file = "/etc/qscan/exclude" # instead of exclude
+//! strict_resolution = false
+//! scan_type = "tcp_connect"          # "ping", "udp_connect" or "syn" (raw feature)
+//! print_mode = "real_time"           # "non_real_time", "real_time_all", "live_table"
+//! batch = 5000
+//! timeout_ms = 1000
+//! timeout_tiers_ms = [200, 2000]
+//! ntries = 1
+//! retry_on_refused = false
+//! timeout_backoff = 1.0
+//! adaptive_timeout = false
+//! adaptive_timeout_max_ms = 10000
+//! total_retry_budget = 100
+//! ping_interval_ms = 1000
+//! service_detection = false
+//! resolve_ptr = false
+//! grab_banner = false
+//! banner_len = 1024
+//! require_banner_for_open = false
+//! max_total_banner_bytes = 1048576
+//! http_probe = false
+//! tcp_mss = 1200
+//! reuse_addr = false
+//! source_addr = "192.168.1.10"
+//! close_policy = "graceful_fin"      # "abortive_rst" or "drop"
+//! linger_ms = 0
+//! rate = 0                           # probes per second, 0 is unlimited
+//! scan_spread_ms = 0
+//! allowed_time_window = ["22:00-06:00"]
+//! host_probe_coalescing = 1
+//! max_memory_mb = 512
+//! max_fds = 1000
+//! max_targets = 100000000
+//! host_start_jitter_ms = 0
+//! stratified_sample = 100
+//! ipv6_sample_per_prefix = 16
+//! dns_cache_file = "/tmp/qscan_dns_cache"
+//! resolver_config = ["1.1.1.1", "8.8.8.8"]
+//! probe_audit_log = "/tmp/qscan_audit.log"
+//! seed = 42
+//! randomize = false
+//! sort_results = false
+//! local_only = false
+//!
+//! [host_port_map]
+//! "192.168.1.1" = [22, 8080]
+//! ```
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use toml::value::{Table, Value};
+
+use trust_dns_resolver::config::NameServerConfigGroup;
+
+use crate::qscanner::{
+    ClosePolicy, QSPrintMode, QScanType, QScanner, ResolverConfig, ResolverOpts, TimeWindow,
+};
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn get_str<'a>(key: &str, v: &'a Value) -> io::Result<&'a str> {
+    v.as_str()
+        .ok_or_else(|| invalid(format!("{}: expected a string", key)))
+}
+
+fn get_bool(key: &str, v: &Value) -> io::Result<bool> {
+    v.as_bool()
+        .ok_or_else(|| invalid(format!("{}: expected a boolean", key)))
+}
+
+fn get_int<T: TryFrom<i64>>(key: &str, v: &Value) -> io::Result<T> {
+    v.as_integer()
+        .and_then(|i| T::try_from(i).ok())
+        .ok_or_else(|| invalid(format!("{}: expected an integer in range", key)))
+}
+
+fn get_float(key: &str, v: &Value) -> io::Result<f64> {
+    v.as_float()
+        .or_else(|| v.as_integer().map(|i| i as f64))
+        .ok_or_else(|| invalid(format!("{}: expected a number", key)))
+}
+
+fn get_ports(key: &str, v: &Value) -> io::Result<Vec<u16>> {
+    v.as_array()
+        .ok_or_else(|| invalid(format!("{}: expected an array", key)))?
+        .iter()
+        .map(|p| get_int(key, p))
+        .collect()
+}
+
+impl QScanner {
+    /// Create a new QScanner configured from a TOML file (see the [module
+    /// documentation](crate::config) for the format)
+    ///
+    /// # Return
+    ///
+    /// `Err` with kind `InvalidData` if the file is not valid TOML, has
+    /// unknown keys or values of the wrong type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let path = std::env::temp_dir().join("qscan_doc_config.toml");
+    /// std::fs::write(&path, "targets = \"127.0.0.1\"\nports = \"80,443\"\nbatch = 10\n").unwrap();
+    /// let scanner = QScanner::from_config_file(&path).unwrap();
+    /// assert_eq!(scanner.get_tagets_ports(), &vec![80, 443]);
+    /// ```
+    ///
+    pub fn from_config_file(path: &Path) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let table: Table = toml::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+
+        let mut scanner = Self::new("", "");
+        let mut targets = "";
+        let mut ports = "";
+        let mut local_only = false;
+        let mut strict_resolution = false;
+        let mut top_ports = None;
+        let (mut grab_banner, mut banner_len) = (None, None);
+
+        for (key, v) in &table {
+            let key = key.as_str();
+            match key {
+                "targets" => targets = get_str(key, v)?,
+                "ports" => ports = get_str(key, v)?,
+                "top_ports" => top_ports = Some(get_int::<usize>(key, v)?),
+                "exclude" => scanner.set_exclude(get_str(key, v)?),
+                "exclude_file" => scanner.set_exclude_file(get_str(key, v)?.into())?,
+                "strict_resolution" => strict_resolution = get_bool(key, v)?,
+                "scan_type" => scanner.set_scan_type(match get_str(key, v)? {
+                    "tcp_connect" => QScanType::TcpConnect,
+                    "ping" => QScanType::Ping,
+                    "udp_connect" => QScanType::UdpConnect,
+                    #[cfg(feature = "raw")]
+                    "syn" => QScanType::Syn,
+                    x => return Err(invalid(format!("{}: unknown scan type {}", key, x))),
+                }),
+                "print_mode" => scanner.set_print_mode(match get_str(key, v)? {
+                    "non_real_time" => QSPrintMode::NonRealTime,
+                    "real_time" => QSPrintMode::RealTime,
+                    "real_time_all" => QSPrintMode::RealTimeAll,
+                    #[cfg(feature = "tui")]
+                    "live_table" => QSPrintMode::LiveTable,
+                    x => return Err(invalid(format!("{}: unknown print mode {}", key, x))),
+                }),
+                "batch" => scanner.set_batch(get_int(key, v)?),
+                "timeout_ms" => scanner.set_timeout_ms(get_int(key, v)?),
+                "timeout_tiers_ms" => {
+                    let tiers = v
+                        .as_array()
+                        .ok_or_else(|| invalid(format!("{}: expected an array", key)))?
+                        .iter()
+                        .map(|t| get_int(key, t).map(Duration::from_millis))
+                        .collect::<io::Result<Vec<Duration>>>()?;
+                    scanner.set_timeout_tiers(tiers);
+                }
+                "ntries" => scanner.set_ntries(get_int(key, v)?),
+                "retry_on_refused" => scanner.set_retry_on_refused(get_bool(key, v)?),
+                "timeout_backoff" => scanner.set_timeout_backoff(get_float(key, v)? as f32),
+                "adaptive_timeout" => scanner.set_adaptive_timeout(get_bool(key, v)?),
+                "adaptive_timeout_max_ms" => scanner.set_adaptive_timeout_max_ms(get_int(key, v)?),
+                "total_retry_budget" => scanner.set_total_retry_budget(get_int(key, v)?),
+                "ping_interval_ms" => scanner.set_ping_interval_ms(get_int(key, v)?),
+                "service_detection" => scanner.set_service_detection(get_bool(key, v)?),
+                "resolve_ptr" => scanner.set_resolve_ptr(get_bool(key, v)?),
+                "http_probe" => scanner.set_http_probe(get_bool(key, v)?),
+                "grab_banner" => grab_banner = Some(get_bool(key, v)?),
+                "banner_len" => banner_len = Some(get_int(key, v)?),
+                "require_banner_for_open" => scanner.set_require_banner_for_open(get_bool(key, v)?),
+                "max_total_banner_bytes" => scanner.set_max_total_banner_bytes(get_int(key, v)?),
+                "tcp_mss" => scanner.set_tcp_mss(get_int(key, v)?),
+                "reuse_addr" => scanner.set_reuse_addr(get_bool(key, v)?),
+                "source_addr" => {
+                    let addr = get_str(key, v)?;
+                    let addr = addr
+                        .parse()
+                        .map_err(|_| invalid(format!("{}: invalid address {}", key, addr)))?;
+                    scanner.set_source_addr(addr);
+                }
+                "close_policy" => scanner.set_close_policy(match get_str(key, v)? {
+                    "graceful_fin" => ClosePolicy::GracefulFin,
+                    "abortive_rst" => ClosePolicy::AbortiveRst,
+                    "drop" => ClosePolicy::Drop,
+                    x => return Err(invalid(format!("{}: unknown close policy {}", key, x))),
+                }),
+                "linger_ms" => scanner.set_linger(Some(Duration::from_millis(get_int(key, v)?))),
+                "rate" => scanner.set_rate(get_int(key, v)?),
+                "scan_spread_ms" => {
+                    scanner.set_scan_spread(Duration::from_millis(get_int(key, v)?))
+                }
+                "allowed_time_window" => {
+                    let windows = v
+                        .as_array()
+                        .ok_or_else(|| invalid(format!("{}: expected an array", key)))?
+                        .iter()
+                        .map(|w| {
+                            let w = get_str(key, w)?;
+                            TimeWindow::parse(w)
+                                .ok_or_else(|| invalid(format!("{}: invalid window {}", key, w)))
+                        })
+                        .collect::<io::Result<Vec<TimeWindow>>>()?;
+                    scanner.set_allowed_time_window(windows);
+                }
+                "host_probe_coalescing" => scanner.set_host_probe_coalescing(get_int(key, v)?),
+                "max_memory_mb" => scanner.set_max_memory_mb(get_int(key, v)?),
+                "max_fds" => scanner.set_max_fds(get_int(key, v)?),
+                "max_targets" => scanner.set_max_targets(get_int(key, v)?),
+                "host_start_jitter_ms" => {
+                    scanner.set_host_start_jitter(Duration::from_millis(get_int(key, v)?))
+                }
+                "stratified_sample" => scanner.set_stratified_sample(get_int(key, v)?),
+                "ipv6_sample_per_prefix" => scanner.set_ipv6_sample_per_prefix(get_int(key, v)?),
+                "seed" => scanner.set_seed(get_int(key, v)?),
+                "randomize" => scanner.set_randomize(get_bool(key, v)?),
+                "sort_results" => scanner.set_sort_results(get_bool(key, v)?),
+                "dns_cache_file" => scanner.set_dns_cache_file(get_str(key, v)?.into()),
+                "resolver_config" => {
+                    let ips = v
+                        .as_array()
+                        .ok_or_else(|| invalid(format!("{}: expected an array", key)))?
+                        .iter()
+                        .map(|ip| {
+                            let ip = get_str(key, ip)?;
+                            ip.parse::<IpAddr>()
+                                .map_err(|_| invalid(format!("{}: invalid IP {}", key, ip)))
+                        })
+                        .collect::<io::Result<Vec<IpAddr>>>()?;
+                    let servers = NameServerConfigGroup::from_ips_clear(&ips, 53, true);
+                    scanner.set_resolver_config(
+                        ResolverConfig::from_parts(None, vec![], servers),
+                        ResolverOpts::default(),
+                    );
+                }
+                "probe_audit_log" => scanner.set_probe_audit_log(get_str(key, v)?.into())?,
+                "local_only" => local_only = get_bool(key, v)?,
+                "host_port_map" => {
+                    let map = v
+                        .as_table()
+                        .ok_or_else(|| invalid(format!("{}: expected a table", key)))?
+                        .iter()
+                        .map(|(ip, p)| {
+                            let ip = ip
+                                .parse::<IpAddr>()
+                                .map_err(|_| invalid(format!("{}: invalid IP {}", key, ip)))?;
+                            Ok((ip, get_ports(key, p)?))
+                        })
+                        .collect::<io::Result<HashMap<IpAddr, Vec<u16>>>>()?;
+                    scanner.set_host_port_map(map);
+                }
+                _ => return Err(invalid(format!("unknown key {}", key))),
+            }
+        }
+
+        if let Some(len) = banner_len {
+            scanner.set_banner_len(len);
+        }
+        if let Some(grab) = grab_banner {
+            scanner.set_grab_banner(grab);
+        }
+
+        if table.contains_key("exclude") && table.contains_key("exclude_file") {
+            return Err(invalid(
+                "exclude and exclude_file cannot be combined".into(),
+            ));
+        }
+        if top_ports.is_some() && !ports.is_empty() {
+            return Err(invalid("ports and top_ports cannot be combined".into()));
+        }
+
+        // Targets are parsed last, with the sampling settings already applied
+        if strict_resolution {
+            scanner.set_strict_resolution(true);
+            scanner
+                .try_set_targets(targets, ports)
+                .map_err(|e| invalid(format!("targets: {}", e)))?;
+        } else {
+            scanner.set_targets(targets, ports);
+        }
+        if let Some(n) = top_ports {
+            scanner.set_top_ports(n);
+        }
+        // Off-host targets are reported (and skipped) when scanning
+        let _ = scanner.set_local_only(local_only);
+
+        Ok(scanner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::qscanner::QScanner;
+    use std::io::ErrorKind;
+    use std::net::IpAddr;
+
+    fn write_config(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("qscan_test_{}.toml", name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn from_config_file() {
+        let path = write_config(
+            "config_ok",
+            r#"
+targets = "127.0.0.1,127.0.0.2"
+ports = "22,80-81"
+scan_type = "tcp_connect"
+batch = 10
+timeout_ms = 500
+ntries = 2
+allowed_time_window = ["00:00-23:59"]
+
+[host_port_map]
+"127.0.0.2" = [443]
+"#,
+        );
+        let scanner = QScanner::from_config_file(&path).unwrap();
+        let plan = scanner.export_scan_plan();
+
+        assert_eq!(plan.ips.len(), 2);
+        assert_eq!(plan.ports, vec![22, 80, 81]);
+        assert_eq!(plan.batch, 10);
+        assert_eq!(plan.timeout_ms, 500);
+        assert_eq!(plan.tries, 2);
+        assert_eq!(
+            scanner.validate_ports_against_policy(&[22, 80, 81]),
+            Err(vec![443])
+        );
+        assert!(scanner
+            .get_tagets_ips()
+            .contains(&"127.0.0.2".parse::<IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn from_config_file_later_keys() {
+        let exclude = std::env::temp_dir().join("qscan_test_config_exclude");
+        std::fs::write(&exclude, "127.0.0.2\n").unwrap();
+        let audit = std::env::temp_dir().join("qscan_test_config_audit.log");
+        let path = write_config(
+            "config_later_keys",
+            &format!(
+                r#"
+targets = "127.0.0.1-127.0.0.3"
+top_ports = 3
+exclude_file = {:?}
+probe_audit_log = {:?}
+resolver_config = ["127.0.0.1"]
+retry_on_refused = true
+strict_resolution = true
+scan_spread_ms = 1000
+scan_type = "tcp_connect"
+"#,
+                exclude, audit
+            ),
+        );
+        let scanner = QScanner::from_config_file(&path).unwrap();
+
+        assert_eq!(scanner.get_tagets_ports(), &vec![80, 23, 443]);
+        assert_eq!(scanner.get_excluded_count(), 1);
+        assert!(audit.exists());
+    }
+
+    #[test]
+    fn from_config_file_invalid() {
+        for (name, content) in [
+            ("config_unknown_key", "prots = \"80\""),
+            ("config_wrong_type", "batch = \"10\""),
+            ("config_out_of_range", "batch = 100000"),
+            ("config_bad_mode", "scan_type = \"xmas\""),
+            ("config_bad_source", "source_addr = \"eth0\""),
+            ("config_not_toml", "targets = "),
+            ("config_top_ports", "ports = \"80\"\ntop_ports = 10"),
+            ("config_bad_resolver", "resolver_config = [\"dns.google\"]"),
+            (
+                "config_strict",
+                "targets = \"127.0.0.1,10.0.0.5-1\"\nstrict_resolution = true",
+            ),
+        ] {
+            let path = write_config(name, content);
+            let err = QScanner::from_config_file(&path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! On-disk cache of hostname resolutions.
+//!
+//! One entry per line: `<hostname> <expiry> <ip>[,<ip>...]`, where the expiry
+//! is in seconds since the UNIX epoch. Expired entries are ignored and
+//! dropped when the cache is written back.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use itertools::Itertools;
+use log::warn;
+
+pub(crate) struct DnsCache {
+    path: PathBuf,
+    entries: HashMap<String, (u64, Vec<IpAddr>)>,
+    dirty: bool,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn parse_entry(line: &str) -> Option<(String, (u64, Vec<IpAddr>))> {
+    let mut fields = line.rsplitn(3, ' ');
+    let ips = fields
+        .next()?
+        .split(',')
+        .map(|ip| ip.parse::<IpAddr>().ok())
+        .collect::<Option<Vec<IpAddr>>>()?;
+    let expiry = fields.next()?.parse::<u64>().ok()?;
+    let name = fields.next()?;
+
+    Some((name.to_string(), (expiry, ips)))
+}
+
+impl DnsCache {
+    /// Load the cache from `path`, a missing file is an empty cache
+    pub(crate) fn load(path: &Path) -> Self {
+        let mut entries = HashMap::new();
+
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                for line in content.lines().filter(|l| !l.is_empty()) {
+                    match parse_entry(line) {
+                        Some((name, entry)) => {
+                            entries.insert(name, entry);
+                        }
+                        None => warn!("invalid DNS cache entry {:?}", line),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("cannot read DNS cache {:?}: {}", path, e),
+        }
+
+        Self {
+            path: path.to_path_buf(),
+            entries,
+            dirty: false,
+        }
+    }
+
+    /// Addresses of `name`, if cached and not expired
+    pub(crate) fn get(&self, name: &str) -> Option<Vec<IpAddr>> {
+        self.entries
+            .get(name)
+            .filter(|(expiry, _)| *expiry > now_secs())
+            .map(|(_, ips)| ips.clone())
+    }
+
+    /// Cache the addresses of `name` for `ttl`
+    pub(crate) fn insert(&mut self, name: &str, ips: Vec<IpAddr>, ttl: Duration) {
+        self.entries
+            .insert(name.to_string(), (now_secs() + ttl.as_secs(), ips));
+        self.dirty = true;
+    }
+
+    /// Write the cache back to disk (only if new entries have been added)
+    pub(crate) fn save(&self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let now = now_secs();
+        let content: String = self
+            .entries
+            .iter()
+            .filter(|(_, (expiry, _))| *expiry > now)
+            .sorted_by(|a, b| a.0.cmp(b.0))
+            .map(|(name, (expiry, ips))| format!("{} {} {}\n", name, expiry, ips.iter().join(",")))
+            .collect();
+
+        fs::write(&self.path, content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DnsCache;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join("qscan_test_dns_cache_save");
+        let _ = std::fs::remove_file(&path);
+        let ips: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+
+        let mut cache = DnsCache::load(&path);
+        assert_eq!(cache.get("example.com"), None);
+        cache.insert("example.com", ips.clone(), Duration::from_secs(3600));
+        cache.insert("expired.com", ips.clone(), Duration::from_secs(0));
+        cache.save().unwrap();
+
+        let cache = DnsCache::load(&path);
+        assert_eq!(cache.get("example.com"), Some(ips));
+        assert_eq!(cache.get("expired.com"), None);
+        assert!(!std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("expired.com"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn expired_and_invalid_entries() {
+        let path = std::env::temp_dir().join("qscan_test_dns_cache_invalid");
+        std::fs::write(
+            &path,
+            "old.com 1 127.0.0.1\nbad.com x 127.0.0.1\nbad2.com 99999999999 nope\n",
+        )
+        .unwrap();
+
+        let cache = DnsCache::load(&path);
+        assert_eq!(cache.get("old.com"), None);
+        assert_eq!(cache.get("bad.com"), None);
+        assert_eq!(cache.get("bad2.com"), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -24,15 +24,66 @@
 //! [dependencies]
 //! qscan = { path = "../qscan", version = "0.5.0" , features = ["serialize"] }
 //! ```
+//!
+//! Warnings and errors (e.g., unknown targets) are emitted with the `log`
+//! crate macros, per-socket connect errors at the `debug` level and retries
+//! at the `trace` level: install a logger to see them.
 
+pub use crate::builder::QScannerBuilder;
+pub use crate::qscanner::CancelHandle;
+pub use crate::qscanner::ClosePolicy;
+pub use crate::qscanner::ConnectPath;
 pub use crate::qscanner::QSPrintMode;
+pub use crate::qscanner::QScanHttpInfo;
+pub use crate::qscanner::QScanParseError;
 pub use crate::qscanner::QScanPingResult;
 pub use crate::qscanner::QScanPingState;
+pub use crate::qscanner::QScanProgress;
 pub use crate::qscanner::QScanResult;
+pub use crate::qscanner::QScanStats;
 pub use crate::qscanner::QScanTcpConnectResult;
 pub use crate::qscanner::QScanTcpConnectState;
+pub use crate::qscanner::QScanTierStats;
 pub use crate::qscanner::QScanType;
+pub use crate::qscanner::QScanUdpResult;
+pub use crate::qscanner::QScanUdpState;
 pub use crate::qscanner::QScanner;
+pub use crate::qscanner::RttPercentiles;
+pub use crate::qscanner::ScanHandle;
+pub use crate::qscanner::ScanPlan;
+pub use crate::qscanner::TargetOrigin;
+pub use crate::qscanner::TimeWindow;
+#[cfg(feature = "syslog")]
+pub use crate::syslog::QSSyslogFacility;
 
 /// Module for asynchronous network ports scanning
 pub mod qscanner;
+
+mod service_probes;
+
+mod ports_db;
+
+mod dns_cache;
+
+mod audit_log;
+
+#[cfg(feature = "tui")]
+mod live_table;
+
+#[cfg(feature = "raw")]
+mod syn;
+
+/// Module for building the scanner with chainable settings
+pub mod builder;
+
+/// Module for configuring the scanner from a file
+#[cfg(feature = "config")]
+pub mod config;
+
+/// Protobuf messages of the scan results
+#[cfg(feature = "protobuf")]
+pub mod proto;
+
+/// Module for emitting scan findings to syslog
+#[cfg(feature = "syslog")]
+pub mod syslog;
@@ -0,0 +1,162 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crossterm::{cursor, queue, terminal};
+use log::error;
+
+use crate::qscanner::{QScanPingState, QScanResult, QScanTcpConnectState, QScanUdpState};
+
+/// Minimum time between two redraws of the table
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Live table printed by [crate::QSPrintMode::LiveTable]: number of open
+/// ports (or UP state) of each host and overall progress. When the output
+/// writer is not a terminal, results are printed one per line instead.
+pub(crate) struct LiveTable<'a> {
+    out: &'a Mutex<Box<dyn Write + Send>>,
+    total: usize,
+    done: usize,
+    hosts: BTreeMap<IpAddr, usize>,
+    tty: bool,
+    last_draw: Option<Instant>,
+    drawn_lines: u16,
+}
+
+impl<'a> LiveTable<'a> {
+    pub fn new(total: usize, out: &'a Mutex<Box<dyn Write + Send>>, tty: bool) -> Self {
+        Self {
+            out,
+            total,
+            done: 0,
+            hosts: BTreeMap::new(),
+            tty,
+            last_draw: None,
+            drawn_lines: 0,
+        }
+    }
+
+    pub fn update(&mut self, res: &QScanResult) {
+        self.done += 1;
+
+        let found = match res {
+            QScanResult::TcpConnect(r) if r.state == QScanTcpConnectState::Open => {
+                Some(r.target.to_string())
+            }
+            QScanResult::Ping(r) if r.state == QScanPingState::Up => Some(r.target.to_string()),
+            QScanResult::Udp(r) if r.state == QScanUdpState::Open => Some(r.target.to_string()),
+            _ => None,
+        };
+
+        if let Some(target) = &found {
+            let ip = match res {
+                QScanResult::TcpConnect(r) => r.target.ip(),
+                QScanResult::Ping(r) => r.target,
+                QScanResult::Udp(r) => r.target.ip(),
+            };
+            *self.hosts.entry(ip).or_insert(0) += 1;
+
+            if !self.tty {
+                if let Err(e) = writeln!(self.out.lock().unwrap(), "{}", target) {
+                    error!("cannot write the output: {}", e);
+                }
+            }
+        }
+
+        if self.tty
+            && self
+                .last_draw
+                .is_none_or(|t| t.elapsed() >= REDRAW_INTERVAL)
+        {
+            let _ = self.draw();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if self.tty {
+            let _ = self.draw();
+        }
+    }
+
+    fn lines(&self, max_rows: usize) -> Vec<String> {
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100);
+        let mut lines = vec![
+            format!("Progress: {}/{} ({}%)", self.done, self.total, percent),
+            format!("{:<40} {:>8}", "HOST", "OPEN/UP"),
+        ];
+
+        for (ip, count) in self.hosts.iter().take(max_rows) {
+            lines.push(format!("{:<40} {:>8}", ip, count));
+        }
+        if self.hosts.len() > max_rows {
+            lines.push(format!("... {} more hosts", self.hosts.len() - max_rows));
+        }
+
+        lines
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let rows = terminal::size().map(|(_, r)| r as usize).unwrap_or(24);
+        let lines = self.lines(rows.saturating_sub(4));
+        let mut out = self.out.lock().unwrap();
+
+        if self.drawn_lines > 0 {
+            queue!(out, cursor::MoveToPreviousLine(self.drawn_lines))?;
+        }
+        queue!(out, terminal::Clear(terminal::ClearType::FromCursorDown))?;
+        for line in &lines {
+            writeln!(out, "{}", line)?;
+        }
+        out.flush()?;
+
+        self.drawn_lines = lines.len() as u16;
+        self.last_draw = Some(Instant::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::qscanner::{QScanPingResult, QScanPingState, QScanResult};
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    #[test]
+    fn live_table_lines() {
+        let out: Mutex<Box<dyn Write + Send>> = Mutex::new(Box::new(std::io::sink()));
+        let mut table = super::LiveTable::new(4, &out, false);
+        for (ip, state) in [
+            ("127.0.0.2", QScanPingState::Up),
+            ("127.0.0.1", QScanPingState::Up),
+            ("127.0.0.3", QScanPingState::Down),
+        ] {
+            table.update(&QScanResult::Ping(QScanPingResult {
+                target: ip.parse().unwrap(),
+                state,
+            }));
+        }
+
+        let lines = table.lines(1);
+        assert_eq!(lines[0], "Progress: 3/4 (75%)");
+        assert!(lines[2].starts_with("127.0.0.1 "));
+        assert_eq!(lines[3], "... 1 more hosts");
+    }
+}
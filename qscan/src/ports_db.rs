@@ -0,0 +1,145 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Ports database: TCP service names, resolved against `/etc/services` with
+//! a small built-in fallback table, and the most frequently open TCP ports.
+
+use std::collections::HashMap;
+
+const SERVICES_FILE: &str = "/etc/services";
+
+/// Built-in TCP services, used for the names not found in `/etc/services`
+const SERVICES: &[(&str, u16)] = &[
+    ("ftp", 21),
+    ("ssh", 22),
+    ("telnet", 23),
+    ("smtp", 25),
+    ("domain", 53),
+    ("http", 80),
+    ("pop3", 110),
+    ("imap", 143),
+    ("ldap", 389),
+    ("https", 443),
+    ("microsoft-ds", 445),
+    ("submission", 587),
+    ("imaps", 993),
+    ("pop3s", 995),
+    ("ms-sql-s", 1433),
+    ("mysql", 3306),
+    ("ms-wbt-server", 3389),
+    ("postgresql", 5432),
+    ("vnc", 5900),
+    ("redis", 6379),
+    ("http-alt", 8080),
+];
+
+/// Most frequently open TCP ports, most frequent first (same order as nmap's
+/// `--top-ports`)
+pub(crate) const TOP_PORTS: &[u16] = &[
+    80, 23, 443, 21, 22, 25, 3389, 110, 445, 139, 143, 53, 135, 3306, 8080, 1723, 111, 995, 993,
+    5900, 1025, 587, 8888, 199, 1720, 465, 548, 113, 81, 6001, 10000, 514, 5060, 179, 1026, 2000,
+    8443, 8000, 32768, 554, 26, 1433, 49152, 2001, 515, 8008, 49154, 1027, 5666, 646, 5000, 5631,
+    631, 49153, 8081, 2049, 88, 79, 5800, 106, 2121, 1110, 49155, 6000, 513, 990, 5357, 427, 49156,
+    543, 544, 5101, 144, 7, 389, 8009, 3128, 444, 9999, 5009, 7070, 5190, 3000, 5432, 1900, 3986,
+    13, 1029, 9, 5051, 6646, 49157, 1028, 873, 1755, 2717, 4899, 9100, 119, 37,
+];
+
+/// TCP services (names and aliases) of a services(5) file
+fn parse_services(content: &str) -> HashMap<String, u16> {
+    let mut services = HashMap::new();
+
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+
+        let (name, port) = match (fields.next(), fields.next().and_then(|p| p.split_once('/'))) {
+            (Some(name), Some((port, "tcp"))) => (name, port),
+            _ => continue,
+        };
+
+        if let Ok(port) = port.parse::<u16>() {
+            for name in std::iter::once(name).chain(fields) {
+                services.entry(name.to_ascii_lowercase()).or_insert(port);
+            }
+        }
+    }
+
+    services
+}
+
+/// Resolve service names to TCP ports. `/etc/services` is read once, the
+/// first time a name is resolved.
+pub(crate) struct ServiceNames {
+    system: Option<HashMap<String, u16>>,
+}
+
+impl ServiceNames {
+    pub(crate) fn new() -> Self {
+        Self { system: None }
+    }
+
+    /// TCP port of the service `name` (case insensitive)
+    pub(crate) fn port(&mut self, name: &str) -> Option<u16> {
+        let name = name.to_ascii_lowercase();
+        let system = self.system.get_or_insert_with(|| {
+            std::fs::read_to_string(SERVICES_FILE)
+                .map(|content| parse_services(&content))
+                .unwrap_or_default()
+        });
+
+        system.get(&name).copied().or_else(|| {
+            SERVICES
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, port)| *port)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse_services() {
+        let services = super::parse_services(
+            "# comment\n\
+             ssh\t\t22/tcp\t\t\t# SSH Remote Login Protocol\n\
+             domain\t\t53/udp\n\
+             http\t\t80/tcp\t\twww\t\t# WorldWideWeb HTTP\n\
+             bad\t\tx/tcp\n",
+        );
+
+        assert_eq!(services.len(), 3);
+        assert_eq!(services["ssh"], 22);
+        assert_eq!(services["www"], 80);
+        assert!(!services.contains_key("domain"));
+    }
+
+    #[test]
+    fn top_ports_unique() {
+        let mut ports = super::TOP_PORTS.to_vec();
+        ports.sort_unstable();
+        ports.dedup();
+        assert_eq!(ports.len(), super::TOP_PORTS.len());
+    }
+
+    #[test]
+    fn service_port() {
+        let mut names = super::ServiceNames::new();
+        assert_eq!(names.port("HTTPS"), Some(443));
+        assert_eq!(names.port("http-alt"), Some(8080));
+        assert_eq!(names.port("nosuchservice"), None);
+    }
+}
@@ -0,0 +1,191 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Protobuf messages of the scan results (feature `protobuf`)
+//!
+//! The messages are defined in `proto/qscan.proto` (package `qscan`) and can
+//! be decoded by any protobuf implementation, e.g. by a gRPC service
+//! ingesting the results of several scanners. See
+//! [crate::qscanner::QScanner::get_last_results_as_protobuf].
+
+use crate::qscanner::{
+    QScanPingResult, QScanPingState, QScanResult, QScanTcpConnectResult, QScanTcpConnectState,
+    QScanUdpResult, QScanUdpState,
+};
+
+use std::net::IpAddr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum TcpConnectState {
+    Close = 0,
+    Open = 1,
+    Filtered = 2,
+    OpenNoResponse = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum PingState {
+    Down = 0,
+    Up = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum UdpState {
+    Closed = 0,
+    Open = 1,
+    Filtered = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TcpConnectResult {
+    /// 4 bytes for IPv4, 16 bytes for IPv6
+    #[prost(bytes = "vec", tag = "1")]
+    pub ip: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub port: u32,
+    #[prost(enumeration = "TcpConnectState", tag = "3")]
+    pub state: i32,
+    #[prost(string, optional, tag = "4")]
+    pub service: Option<String>,
+    #[prost(float, tag = "5")]
+    pub confidence: f32,
+    #[prost(string, tag = "6")]
+    pub via: String,
+    #[prost(uint32, optional, tag = "7")]
+    pub peer_mss: Option<u32>,
+    #[prost(uint64, optional, tag = "8")]
+    pub rtt_us: Option<u64>,
+    #[prost(string, optional, tag = "9")]
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PingResult {
+    #[prost(bytes = "vec", tag = "1")]
+    pub ip: Vec<u8>,
+    #[prost(enumeration = "PingState", tag = "2")]
+    pub state: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UdpResult {
+    #[prost(bytes = "vec", tag = "1")]
+    pub ip: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub port: u32,
+    #[prost(enumeration = "UdpState", tag = "3")]
+    pub state: i32,
+}
+
+/// Result of a single probe, one of the scan specific results
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum ScanResult {
+    #[prost(message, tag = "1")]
+    TcpConnect(TcpConnectResult),
+    #[prost(message, tag = "2")]
+    Ping(PingResult),
+    #[prost(message, tag = "3")]
+    Udp(UdpResult),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Result {
+    #[prost(oneof = "ScanResult", tags = "1, 2, 3")]
+    pub result: Option<ScanResult>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Results {
+    #[prost(message, repeated, tag = "1")]
+    pub results: Vec<Result>,
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+impl From<&QScanTcpConnectResult> for TcpConnectResult {
+    fn from(r: &QScanTcpConnectResult) -> Self {
+        let state = match r.state {
+            QScanTcpConnectState::Open => TcpConnectState::Open,
+            QScanTcpConnectState::Close => TcpConnectState::Close,
+            QScanTcpConnectState::Filtered => TcpConnectState::Filtered,
+            QScanTcpConnectState::OpenNoResponse => TcpConnectState::OpenNoResponse,
+        };
+
+        Self {
+            ip: ip_bytes(r.target.ip()),
+            port: r.target.port() as u32,
+            state: state as i32,
+            service: r.detected_service.clone(),
+            confidence: r.confidence,
+            via: r.via.to_string(),
+            peer_mss: r.peer_mss,
+            rtt_us: r.rtt.map(|rtt| rtt.as_micros() as u64),
+            reason: r.reason.clone(),
+        }
+    }
+}
+
+impl From<&QScanPingResult> for PingResult {
+    fn from(r: &QScanPingResult) -> Self {
+        let state = match r.state {
+            QScanPingState::Up => PingState::Up,
+            QScanPingState::Down => PingState::Down,
+        };
+
+        Self {
+            ip: ip_bytes(r.target),
+            state: state as i32,
+        }
+    }
+}
+
+impl From<&QScanUdpResult> for UdpResult {
+    fn from(r: &QScanUdpResult) -> Self {
+        let state = match r.state {
+            QScanUdpState::Closed => UdpState::Closed,
+            QScanUdpState::Open => UdpState::Open,
+            QScanUdpState::Filtered => UdpState::Filtered,
+        };
+
+        Self {
+            ip: ip_bytes(r.target.ip()),
+            port: r.target.port() as u32,
+            state: state as i32,
+        }
+    }
+}
+
+impl From<&QScanResult> for Result {
+    fn from(r: &QScanResult) -> Self {
+        let result = match r {
+            QScanResult::TcpConnect(x) => ScanResult::TcpConnect(x.into()),
+            QScanResult::Ping(x) => ScanResult::Ping(x.into()),
+            QScanResult::Udp(x) => ScanResult::Udp(x.into()),
+        };
+
+        Self {
+            result: Some(result),
+        }
+    }
+}
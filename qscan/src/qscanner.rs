@@ -14,76 +14,321 @@
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
 
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::fmt;
 
+#[cfg(feature = "serialize")]
+use serde::de::{Deserialize, Deserializer};
 #[cfg(feature = "serialize")]
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 #[cfg(feature = "serialize")]
 use serde_json;
 
 use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
 
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Write;
+#[cfg(feature = "raw")]
+use std::net::SocketAddrV4;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::OnceLock;
 
-use std::num::NonZeroU8;
+use std::num::{NonZeroU32, NonZeroU8, NonZeroUsize};
+use std::ops::RangeInclusive;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
 use tokio::io;
+use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
+use tokio::net::TcpSocket;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio::sync::Semaphore;
 use tokio::time;
 use tokio::time::error::Elapsed;
 use tokio::time::timeout;
+use tokio::time::MissedTickBehavior;
+
+use itertools::{Either, Itertools};
+
+use log::{debug, error, trace, warn};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-use itertools::Itertools;
+use cidr_utils::cidr::{IpCidr, Ipv6Cidr};
 
-use cidr_utils::cidr::IpCidr;
+use futures::future::{self, Future};
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt};
 
-use futures::stream::{FuturesUnordered, StreamExt};
+use crate::audit_log::ProbeAuditLog;
+use crate::dns_cache::DnsCache;
+#[cfg(feature = "tui")]
+use crate::live_table::LiveTable;
+use crate::ports_db::{ServiceNames, TOP_PORTS};
+use crate::service_probes;
+#[cfg(feature = "raw")]
+use crate::syn::{self, SynSocket};
+#[cfg(feature = "syslog")]
+use crate::syslog::{QSSyslog, QSSyslogFacility};
 
-use trust_dns_resolver::{
-    config::{ResolverConfig, ResolverOpts},
-    Resolver,
-};
+pub use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveResult;
+use trust_dns_resolver::lookup_ip::LookupIp;
+use trust_dns_resolver::{Resolver, TokioAsyncResolver};
 
 /// Scanning mode:
 ///
 /// * `TcpConnect`: TCP connect scan;
-#[derive(Debug)]
+/// * `UdpConnect`: UDP scan (see [QScanner::scan_udp]);
+/// * `Syn`: TCP SYN scan over a raw socket (see [QScanner::scan_syn], `raw`
+///   feature);
+///
+/// The variants depend on the enabled features, so matches outside of this
+/// crate need a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
 pub enum QScanType {
     TcpConnect,
     Ping,
+    UdpConnect,
+    #[cfg(feature = "raw")]
+    Syn,
 }
 
 /// Printing mode while scanning
 ///
 /// * `NonRealTime`: do not print during async scan
 /// * `RealTime`: print as soon as the result is available
+/// * `LiveTable`: print a live updating table with the open ports (or UP
+///   state) of each host and the scan progress (`tui` feature). Fall back to
+///   printing one result per line when stdout is not a terminal
 #[derive(Debug)]
 pub enum QSPrintMode {
     NonRealTime,
     RealTime,
     RealTimeAll,
+    #[cfg(feature = "tui")]
+    LiveTable,
 }
 
 /// Asynchronous network scanner
 #[derive(Debug)]
 pub struct QScanner {
-    ips: Vec<IpAddr>,
+    ips: TargetIps,
+    origins: HashMap<IpAddr, TargetOrigin>,
+    cidrs: Vec<(IpCidr, TargetOrigin)>,
+    excluded_ips: HashSet<IpAddr>,
+    excludes: Vec<IpCidr>,
     ports: Vec<u16>,
+    host_ports: HashMap<IpAddr, Vec<u16>>,
     scan_type: QScanType,
     print_mode: QSPrintMode,
     batch: u16,
     to: Duration,
+    timeout_tiers: Vec<Duration>,
+    timeout_backoff: f32,
+    adaptive_timeout: bool,
+    adaptive_timeout_max: Duration,
+    rtt_estimate: std::sync::Mutex<Option<RttEstimate>>,
     tries: NonZeroU8,
+    retry_on_refused: bool,
     ping_payload: Vec<u8>,
+    udp_payload: Vec<u8>,
     ping_interval: Duration,
+    target_rewriter: Option<QSCallback<dyn Fn(IpAddr) -> IpAddr + Send + Sync>>,
+    progress_callback: Option<QSCallback<dyn Fn(usize, usize) + Send + Sync>>,
+    progress_eta_callback: Option<QSCallback<ProgressEtaCallback>>,
+    progress_rate: std::sync::Mutex<Option<ProgressRate>>,
+    on_open: Option<QSCallback<dyn Fn(SocketAddr) + Send + Sync>>,
+    output: QSOutput,
+    blocking_connector: Option<QSCallback<BlockingConnector>>,
+    stratified_sample: Option<usize>,
+    seed: Option<u64>,
+    randomize: bool,
+    sort_results: bool,
+    local_only: bool,
+    strict_resolution: bool,
+    retry_budget: Option<usize>,
+    retries_left: AtomicUsize,
+    service_detection: bool,
+    resolve_ptr: bool,
+    banner_len: Option<usize>,
+    banner_budget: Option<usize>,
+    banner_bytes_left: AtomicUsize,
+    require_banner: bool,
+    http_probe: bool,
+    tcp_mss: Option<u32>,
+    reuse_addr: bool,
+    source_addr: Option<IpAddr>,
+    close_policy: ClosePolicy,
+    linger: Option<Duration>,
+    probe_coalescing: NonZeroUsize,
+    max_memory: Option<usize>,
+    max_fds: Option<usize>,
+    max_targets: Option<usize>,
+    host_start_jitter: Option<Duration>,
+    host_start: std::sync::Mutex<Option<(Instant, u64)>>,
+    scan_spread: Option<Duration>,
+    spread_slots: std::sync::Mutex<Option<(Instant, Duration)>>,
+    spread_next: AtomicUsize,
+    rate: Option<NonZeroU32>,
+    rate_next: std::sync::Mutex<Option<Instant>>,
+    ipv6_sample: Option<usize>,
+    dns_cache_file: Option<PathBuf>,
+    resolver: LazyResolver,
+    time_windows: Vec<TimeWindow>,
+    #[cfg(feature = "syslog")]
+    syslog: Option<QSSyslog>,
+    probe_audit_log: Option<ProbeAuditLog>,
+    cancel: Arc<watch::Sender<bool>>,
+    /// The cancel flag has been reset by [QScanner::spawn_scan], so that a
+    /// cancel coming before the start of the scan is not lost
+    cancel_armed: AtomicBool,
     last_results: Option<Vec<QScanResult>>,
+    last_errors: Vec<(SocketAddr, String)>,
+    last_stats: Option<QScanStats>,
+    last_tier_stats: Vec<QScanTierStats>,
+}
+
+/// Blocking connector: try to connect to the socket within the timeout
+type BlockingConnector = dyn Fn(SocketAddr, Duration) -> std::io::Result<()> + Send + Sync;
+
+/// Progress callback with the ETA (see [QScanner::set_progress_eta_callback])
+type ProgressEtaCallback = dyn Fn(&QScanProgress) + Send + Sync;
+
+/// Input that produced a target address
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TargetOrigin {
+    /// Token (IP, CIDR or domain name) of the targets string
+    Token(String),
+    /// Line (starting from 1) of a targets file
+    FileLine {
+        path: PathBuf,
+        line: usize,
+        token: String,
+    },
+}
+
+/// User provided callback
+struct QSCallback<T: ?Sized>(Arc<T>);
+
+impl<T: ?Sized> fmt::Debug for QSCallback<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QSCallback")
+    }
+}
+
+/// Destination of the real-time output (see [QScanner::set_output_writer])
+struct QSOutput {
+    writer: std::sync::Mutex<Box<dyn Write + Send>>,
+    /// The writer is a terminal, where the live table is redrawn in place
+    #[cfg(feature = "tui")]
+    tty: bool,
+}
+
+impl Default for QSOutput {
+    fn default() -> Self {
+        Self {
+            writer: std::sync::Mutex::new(Box::new(std::io::stdout())),
+            #[cfg(feature = "tui")]
+            tty: std::io::IsTerminal::is_terminal(&std::io::stdout()),
+        }
+    }
+}
+
+impl fmt::Debug for QSOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QSOutput")
+    }
+}
+
+/// Target addresses without duplicates, in insertion order
+#[derive(Debug, Default)]
+struct TargetIps {
+    ips: Vec<IpAddr>,
+    seen: HashSet<IpAddr>,
+}
+
+impl TargetIps {
+    fn into_vec(self) -> Vec<IpAddr> {
+        self.ips
+    }
+}
+
+impl std::ops::Deref for TargetIps {
+    type Target = Vec<IpAddr>;
+
+    fn deref(&self) -> &Vec<IpAddr> {
+        &self.ips
+    }
+}
+
+impl Extend<IpAddr> for TargetIps {
+    fn extend<I: IntoIterator<Item = IpAddr>>(&mut self, iter: I) {
+        for ip in iter {
+            if self.seen.insert(ip) {
+                self.ips.push(ip);
+            }
+        }
+    }
+}
+
+impl From<Vec<IpAddr>> for TargetIps {
+    fn from(ips: Vec<IpAddr>) -> Self {
+        let mut target_ips = Self::default();
+        target_ips.extend(ips);
+        target_ips
+    }
+}
+
+/// Ports of a single host, by state (see
+/// [QScanner::get_last_results_as_greppable])
+#[derive(Debug, Default)]
+struct HostPorts {
+    open: Vec<u16>,
+    closed: Vec<u16>,
+    filtered: Vec<u16>,
+    open_no_response: Vec<u16>,
+    /// Time to establish the connections to the open ports
+    open_rtts: Vec<Duration>,
+    /// Time to get the connections to the closed ports refused
+    closed_rtts: Vec<Duration>,
+}
+
+impl HostPorts {
+    /// See [suspected_rst_injection]
+    fn suspected_rst_injection(&self) -> bool {
+        suspected_rst_injection(&self.open_rtts, &self.closed_rtts)
+    }
+}
+
+/// Host of the nmap-like XML output (see
+/// [QScanner::get_last_results_as_xml_string])
+#[cfg(feature = "xml")]
+#[derive(Debug, Default)]
+struct XmlHost {
+    /// Whether the host replied to a probe, `None` if nothing is known
+    up: Option<bool>,
+    hostname: Option<String>,
+    /// (protocol, port, state, service)
+    ports: Vec<(&'static str, u16, &'static str, Option<String>)>,
 }
 
 /// Possible states of a TCP connect target
@@ -91,13 +336,47 @@ pub struct QScanner {
 pub enum QScanResult {
     TcpConnect(QScanTcpConnectResult),
     Ping(QScanPingResult),
+    Udp(QScanUdpResult),
+}
+
+impl QScanResult {
+    /// (IP, port) of the target, port 0 for ping results. IPv4 addresses
+    /// sort before IPv6 ones.
+    fn sort_key(&self) -> (IpAddr, u16) {
+        match self {
+            QScanResult::TcpConnect(r) => (r.target.ip(), r.target.port()),
+            QScanResult::Ping(r) => (r.target, 0),
+            QScanResult::Udp(r) => (r.target.ip(), r.target.port()),
+        }
+    }
 }
 
 /// Possible states of a TCP connect target
-#[derive(Debug, PartialEq)]
+///
+/// * `Open`: the connection was established
+/// * `Close`: the connection was refused (or reset), or failed with an
+///   unexpected error
+/// * `Filtered`: no answer (timeout) or the target is unreachable, e.g.
+///   dropped by a firewall
+/// * `OpenNoResponse`: the connection was established but the service sent
+///   nothing (see [QScanner::set_require_banner_for_open])
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum QScanTcpConnectState {
     Open,
     Close,
+    Filtered,
+    OpenNoResponse,
+}
+
+impl QScanTcpConnectState {
+    fn label(&self) -> &'static str {
+        match self {
+            QScanTcpConnectState::Open => "OPEN",
+            QScanTcpConnectState::Close => "CLOSE",
+            QScanTcpConnectState::Filtered => "FILTERED",
+            QScanTcpConnectState::OpenNoResponse => "OPEN_NO_RESPONSE",
+        }
+    }
 }
 
 /// Result of a TCP Connect Scan for a single target
@@ -105,6 +384,190 @@ pub enum QScanTcpConnectState {
 pub struct QScanTcpConnectResult {
     pub target: SocketAddr,
     pub state: QScanTcpConnectState,
+    /// Service detected on an open port (see [QScanner::set_service_detection])
+    pub detected_service: Option<String>,
+    /// How sure the scanner is about `state`, from 0.0 to 1.0. An
+    /// established connection is sure (0.9, 1.0 if the service was also
+    /// detected), as a refused one (0.95). A timeout is a weak hint that the
+    /// port is filtered (0.3) that grows with the number of attempts.
+    pub confidence: f32,
+    /// How the target has been reached
+    pub via: ConnectPath,
+    /// MSS used to send to an open port, bounded by the MSS advertised by
+    /// the target (unix only). See [QScanner::set_tcp_mss]
+    pub peer_mss: Option<u32>,
+    /// Time needed to establish the connection to an open port, or to get it
+    /// refused on a closed port
+    pub rtt: Option<Duration>,
+    /// Name of the address of an open port (see [QScanner::set_resolve_ptr])
+    pub hostname: Option<String>,
+    /// First bytes sent by the service on an open port (see
+    /// [QScanner::set_grab_banner])
+    pub banner: Option<Vec<u8>>,
+    /// Response of an open port to an HTTP request (see
+    /// [QScanner::set_http_probe])
+    pub http: Option<QScanHttpInfo>,
+    /// Error that made the connection fail on a closed or filtered port
+    /// (e.g., "Connection refused (os error 111)", "deadline has elapsed")
+    pub reason: Option<String>,
+}
+
+/// Response to the HTTP probe of an open port (see [QScanner::set_http_probe])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QScanHttpInfo {
+    /// Status code (e.g., 200, 301)
+    pub status: u16,
+    /// Value of the `Server` header, if any
+    pub server: Option<String>,
+}
+
+impl QScanTcpConnectResult {
+    fn new(target: SocketAddr, state: QScanTcpConnectState) -> Self {
+        Self {
+            target,
+            state,
+            detected_service: None,
+            confidence: 1.0,
+            via: ConnectPath::Direct,
+            peer_mss: None,
+            rtt: None,
+            hostname: None,
+            banner: None,
+            http: None,
+            reason: None,
+        }
+    }
+}
+
+/// Path used to reach a TCP connect target
+///
+/// * `Direct`: direct connection from the scanning host
+/// * `Socks5`: through the SOCKS5 proxy at the given address
+/// * `Http`: through the HTTP (CONNECT) proxy at the given address
+/// * `Custom`: through a custom connector (see [QScanner::set_blocking_connector])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectPath {
+    Direct,
+    Socks5(SocketAddr),
+    Http(SocketAddr),
+    Custom(String),
+}
+
+impl fmt::Display for ConnectPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectPath::Direct => write!(f, "direct"),
+            ConnectPath::Socks5(proxy) => write!(f, "socks5://{}", proxy),
+            ConnectPath::Http(proxy) => write!(f, "http://{}", proxy),
+            ConnectPath::Custom(name) => write!(f, "custom:{}", name),
+        }
+    }
+}
+
+/// A pending socket error (e.g., ICMP port unreachable) does not wake up a
+/// UDP `recv`, so it is checked with this interval while waiting for a reply
+const UDP_ERROR_POLL: Duration = Duration::from_millis(50);
+
+/// Interval used by the SYN scan receiver to check whether the scan is over
+#[cfg(feature = "raw")]
+const SYN_RECV_POLL: Duration = Duration::from_millis(50);
+
+/// How the connections to open ports are closed (see
+/// [QScanner::set_close_policy])
+///
+/// * `GracefulFin`: shutdown the connection (FIN). The target sees, and may
+///   log, a normal connection close and its connection table entry follows
+///   the normal teardown. The scanner side keeps the connection in
+///   TIME_WAIT for a while.
+/// * `AbortiveRst`: reset the connection (RST, linger 0). The connection is
+///   immediately removed from the connection tables of both sides (no
+///   TIME_WAIT), but the target may log a "connection reset by peer" and an
+///   IDS is more likely to flag it.
+/// * `Drop`: close the socket without shutting it down first. The kernel
+///   sends a FIN, or a RST if the target sent data that has not been read
+///   (e.g., a greeting banner).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClosePolicy {
+    GracefulFin,
+    AbortiveRst,
+    Drop,
+}
+
+/// Name of the [ConnectPath::Custom] path of the blocking connector
+const BLOCKING_CONNECTOR_PATH: &str = "blocking_connector";
+
+/// Connect RTT percentiles of a port across all the hosts (see
+/// [QScanner::get_rtt_percentiles])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttPercentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+/// Progress of a running scan (see [QScanner::set_progress_eta_callback])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QScanProgress {
+    /// Completed probes
+    pub completed: usize,
+    /// Probes to run
+    pub total: usize,
+    /// Time since the scan started
+    pub elapsed: Duration,
+    /// Estimated time to the end of the scan, `None` until the first probes
+    /// complete
+    pub eta: Option<Duration>,
+}
+
+/// Statistics of the latest TCP connect scan (see [QScanner::get_last_stats])
+#[derive(Debug, Clone, PartialEq)]
+pub struct QScanStats {
+    /// Wall clock time the scan started
+    pub start: SystemTime,
+    /// Wall clock time the scan ended
+    pub end: SystemTime,
+    /// Duration of the scan
+    pub elapsed: Duration,
+    /// Sockets to scan (before any sampling or skipping)
+    pub total: usize,
+    /// Open ports
+    pub open: usize,
+    /// Closed or filtered ports: connection refused, reset or timed out
+    pub closed: usize,
+    /// Probes failed with any other error (e.g. network unreachable). These
+    /// ports are reported as filtered (unreachable targets) or closed in the
+    /// results.
+    pub errors: usize,
+    /// The scan ran out of file descriptors ("too many open files"). The
+    /// batch size was reduced to the probes in flight and, if no probe was
+    /// in flight, the scan stopped early: the results are partial and the
+    /// last failed socket is reported among the errors.
+    pub fd_exhausted: bool,
+}
+
+/// Statistics of a timeout tier of the latest TCP connect scan (see
+/// [QScanner::set_timeout_tiers])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QScanTierStats {
+    /// Connect timeout of the tier
+    pub timeout: Duration,
+    /// Sockets probed in the tier
+    pub probed: usize,
+    /// Open ports found in the tier
+    pub open: usize,
+    /// Ports that refused (or reset) the connection
+    pub closed: usize,
+    /// Probes failed with any other error (see [QScanStats::errors])
+    pub errors: usize,
+    /// Probes timed out, re-probed by the next tier (if any)
+    pub timed_out: usize,
+}
+
+impl QScanStats {
+    /// Probes completed per second
+    pub fn sockets_per_sec(&self) -> f64 {
+        (self.open + self.closed + self.errors) as f64 / self.elapsed.as_secs_f64()
+    }
 }
 
 /// Possible states of a Ping scan taret
@@ -121,15 +584,377 @@ pub struct QScanPingResult {
     pub state: QScanPingState,
 }
 
+/// Possible states of a UDP scan target
+///
+/// * `Open`: the target replied to the probe
+/// * `Closed`: the target replied with an ICMP port unreachable
+/// * `Filtered`: no reply (the port may also be open and silently discard
+///   the probe)
+#[derive(Debug, PartialEq)]
+pub enum QScanUdpState {
+    Open,
+    Closed,
+    Filtered,
+}
+
+impl QScanUdpState {
+    fn label(&self) -> &'static str {
+        match self {
+            QScanUdpState::Open => "OPEN",
+            QScanUdpState::Closed => "CLOSED",
+            QScanUdpState::Filtered => "FILTERED",
+        }
+    }
+}
+
+/// Result of a UDP Scan for a single target
+#[derive(Debug)]
+pub struct QScanUdpResult {
+    pub target: SocketAddr,
+    pub state: QScanUdpState,
+}
+
+/// Handle of a scan running in the background (see [QScanner::spawn_scan]).
+/// The scan is cancelled when the handle is dropped: dropping the handle is
+/// equivalent to calling [CancelHandle::cancel] on the cancel handle of the
+/// scanner (see [QScanner::cancel_handle]).
+#[derive(Debug)]
+pub struct ScanHandle {
+    task: Option<tokio::task::JoinHandle<QScanner>>,
+    cancel: CancelHandle,
+}
+
+impl ScanHandle {
+    /// Cancel the scan (see [CancelHandle::cancel]). Pending probes are
+    /// dropped and their sockets closed, the results collected so far are
+    /// kept.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Wait for the end of the scan
+    ///
+    /// # Return
+    ///
+    /// The scanner, with the results of the scan (see
+    /// [QScanner::get_last_results]), partial if the scan has been
+    /// cancelled. `None` if the scan task panicked.
+    pub async fn wait(mut self) -> Option<QScanner> {
+        self.task.take()?.await.ok()
+    }
+}
+
+impl Drop for ScanHandle {
+    fn drop(&mut self) {
+        if self.task.is_some() {
+            self.cancel();
+        }
+    }
+}
+
+/// Handle to cancel, from another task, the scan that a [QScanner] is
+/// running (see [QScanner::cancel_handle])
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    cancel: Arc<watch::Sender<bool>>,
+}
+
+impl CancelHandle {
+    /// Cancel the running scan: in-flight probes are dropped and the scan
+    /// returns the results collected so far. No-op if no scan is running.
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+}
+
+/// Daily time window (UTC) in which the scan is allowed to send traffic.
+/// A window whose end precedes its start spans midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: u32,
+    end: u32,
+}
+
+/// Scan plan: the targets, exclusions and timing parameters of a scan,
+/// exported without sending any packet so that it can be reviewed before
+/// being executed with [QScanner::from_scan_plan] (or, once serialized, with
+/// [QScanner::from_scan_plan_json]).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanPlan {
+    /// Target addresses, expanded
+    pub ips: Vec<IpAddr>,
+    /// Large target CIDRs (e.g., "10.0.0.0/8"), not expanded (see
+    /// [QScanner::get_tagets_ips_iter])
+    pub cidrs: Vec<String>,
+    pub ports: Vec<u16>,
+    /// Ports of specific hosts (see [QScanner::set_host_port_map])
+    pub host_port_map: HashMap<IpAddr, Vec<u16>>,
+    /// Addresses and CIDRs never scanned, even if they are targets (see
+    /// [QScanner::set_exclude])
+    pub excludes: Vec<String>,
+    pub scan_type: QScanType,
+    pub batch: u16,
+    pub timeout_ms: u64,
+    pub tries: u8,
+    pub ping_interval_ms: u64,
+    /// See [QScanner::set_timeout_tiers]
+    pub timeout_tiers_ms: Vec<u64>,
+    /// See [QScanner::set_timeout_backoff]
+    pub timeout_backoff: f32,
+    /// Probes per second, 0 for unlimited (see [QScanner::set_rate])
+    pub rate: u32,
+    /// See [QScanner::set_scan_spread]
+    pub scan_spread_ms: Option<u64>,
+    /// See [QScanner::set_host_start_jitter]
+    pub host_start_jitter_ms: Option<u64>,
+    /// See [QScanner::set_allowed_time_window]
+    pub time_windows: Vec<TimeWindow>,
+}
+
+impl TimeWindow {
+    /// Create a new daily time window from `start` (included) to `end`
+    /// (excluded), both given as `(hour, minute)` UTC
+    ///
+    /// # Panics
+    ///
+    /// Panics if a time is not valid (the hour is greater than 23 or the
+    /// minute greater than 59), see [TimeWindow::try_new].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::TimeWindow;
+    /// // From 22:00 to 06:00 UTC
+    /// let window = TimeWindow::new((22, 0), (6, 0));
+    /// ```
+    ///
+    pub fn new(start: (u8, u8), end: (u8, u8)) -> Self {
+        match Self::try_new(start, end) {
+            Some(window) => window,
+            None => panic!(
+                "Invalid time window {:02}:{:02}-{:02}:{:02}",
+                start.0, start.1, end.0, end.1
+            ),
+        }
+    }
+
+    /// Same as [TimeWindow::new], but return `None` if a time is not valid
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::TimeWindow;
+    /// assert!(TimeWindow::try_new((22, 0), (6, 0)).is_some());
+    /// assert!(TimeWindow::try_new((24, 0), (6, 0)).is_none());
+    /// ```
+    ///
+    pub fn try_new(start: (u8, u8), end: (u8, u8)) -> Option<Self> {
+        let secs = |(h, m): (u8, u8)| -> Option<u32> {
+            (h < 24 && m < 60).then_some(h as u32 * 3600 + m as u32 * 60)
+        };
+
+        Some(Self {
+            start: secs(start)?,
+            end: secs(end)?,
+        })
+    }
+
+    fn contains(&self, secs: u32) -> bool {
+        if self.start <= self.end {
+            secs >= self.start && secs < self.end
+        } else {
+            secs >= self.start || secs < self.end
+        }
+    }
+
+    /// Seconds from `secs` (seconds of the day) to the start of the window
+    fn secs_until_start(&self, secs: u32) -> u32 {
+        (self.start + SECS_PER_DAY - secs) % SECS_PER_DAY
+    }
+
+    /// Parse a time window in the form "HH:MM-HH:MM"
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        let hm = |t: &str| -> Option<(u8, u8)> {
+            let (h, m) = t.split_once(':')?;
+            Some((h.trim().parse().ok()?, m.trim().parse().ok()?))
+        };
+        let (start, end) = s.split_once('-')?;
+
+        Self::try_new(hm(start)?, hm(end)?)
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hm = |secs: u32| format!("{:02}:{:02}", secs / 3600, secs / 60 % 60);
+        write!(f, "{}-{}", hm(self.start), hm(self.end))
+    }
+}
+
+const SECS_PER_DAY: u32 = 24 * 3600;
+
 #[derive(Debug, Clone)]
 struct QScanError {
     msg: String,
     sock: SocketAddr,
+    confidence: f32,
+    via: ConnectPath,
+    /// The port refused the connection or did not answer, as opposed to
+    /// any other error
+    closed: bool,
+    /// The port did not answer
+    timed_out: bool,
+    /// The probe was not sent: no file descriptor available (see
+    /// [QScanStats::fd_exhausted])
+    fd_exhausted: bool,
+    /// State reported for the socket
+    state: QScanTcpConnectState,
+    /// Time the port took to refuse the connection
+    rtt: Option<Duration>,
+}
+
+impl QScanError {
+    /// Probe of `sock` not sent because of `e` (see [fd_exhausted_error])
+    fn fd_exhausted(e: &io::Error, sock: SocketAddr, via: ConnectPath) -> Self {
+        QScanError {
+            msg: e.to_string(),
+            sock,
+            confidence: 0.0,
+            via,
+            closed: false,
+            timed_out: false,
+            fd_exhausted: true,
+            state: QScanTcpConnectState::Close,
+            rtt: None,
+        }
+    }
+
+    /// Closed (or filtered) result for the failed socket
+    fn to_result(&self) -> QScanTcpConnectResult {
+        QScanTcpConnectResult {
+            confidence: self.confidence,
+            via: self.via.clone(),
+            reason: Some(self.msg.clone()),
+            rtt: self.rtt,
+            ..QScanTcpConnectResult::new(self.sock, self.state)
+        }
+    }
+}
+
+/// Confidence of an established connection (see
+/// [QScanTcpConnectResult::confidence])
+const OPEN_CONFIDENCE: f32 = 0.9;
+/// Confidence of an established connection with a detected service
+const OPEN_SERVICE_CONFIDENCE: f32 = 1.0;
+/// Confidence of an open port whose connection could not be shut down
+const SHUTDOWN_ERROR_CONFIDENCE: f32 = 0.5;
+
+/// Escape `s` to be used as an XML attribute value
+#[cfg(feature = "xml")]
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Whether `e` means that the process (or the system) ran out of file
+/// descriptors, i.e. "too many open files"
+fn fd_exhausted_error(e: &io::Error) -> bool {
+    e.to_string().to_lowercase().contains("too many open files")
+}
+
+/// Whether `error` (`None` for a timeout) means that the port is closed
+fn closed_error(error: Option<io::ErrorKind>) -> bool {
+    matches!(
+        error,
+        Some(io::ErrorKind::ConnectionRefused)
+            | Some(io::ErrorKind::ConnectionReset)
+            | Some(io::ErrorKind::TimedOut)
+            | None
+    )
+}
+
+/// State of a port whose connection failed with `error` (`None` for a
+/// timeout)
+fn error_state(error: Option<io::ErrorKind>) -> QScanTcpConnectState {
+    match error {
+        Some(io::ErrorKind::TimedOut)
+        | Some(io::ErrorKind::HostUnreachable)
+        | Some(io::ErrorKind::NetworkUnreachable)
+        | None => QScanTcpConnectState::Filtered,
+        _ => QScanTcpConnectState::Close,
+    }
+}
+
+/// Confidence of a closed port after `attempts` failed attempts, the last
+/// one with `error` (`None` for a timeout)
+fn closed_confidence(error: Option<io::ErrorKind>, attempts: u8) -> f32 {
+    match error {
+        Some(io::ErrorKind::ConnectionRefused) | Some(io::ErrorKind::ConnectionReset) => 0.95,
+        Some(io::ErrorKind::TimedOut) | None => 1.0 - 0.7f32.powi(attempts as i32),
+        Some(_) => 0.6,
+    }
 }
 
 impl fmt::Display for QScanError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "QScanError: {}", self.msg)
+        write!(f, "QScanError: {} {}", self.msg, self.sock)
+    }
+}
+
+/// Malformed target ports (see [QScanner::try_new]), missing targets file
+/// or unresolved targets (see [QScanner::try_set_targets_addr]). Each variant
+/// holds the token(s) that failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QScanParseError {
+    /// Neither a port number nor a known service name (e.g., "abc")
+    InvalidPort(String),
+    /// Port number larger than 65535 (e.g., "70000")
+    PortOutOfRange(String),
+    /// Range without exactly two bounds (e.g., "1-2-3", "80-")
+    InvalidRange(String),
+    /// Range whose start is after its end (e.g., "100-80")
+    ReversedRange(String),
+    /// Target that looks like a path but is not a file (e.g., "/tmp/nope.txt")
+    MissingFile(String),
+    /// Targets that cannot be resolved or parsed (see
+    /// [QScanner::set_strict_resolution]), lines of targets files are
+    /// reported as "path:line: token"
+    UnresolvedTargets(Vec<String>),
+}
+
+impl fmt::Display for QScanParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QScanParseError::InvalidPort(t) => write!(f, "unknown port or service {:?}", t),
+            QScanParseError::PortOutOfRange(t) => write!(f, "port out of range {:?}", t),
+            QScanParseError::InvalidRange(t) => write!(f, "invalid range {:?}", t),
+            QScanParseError::ReversedRange(t) => write!(f, "reversed range {:?}", t),
+            QScanParseError::MissingFile(t) => write!(f, "no such file {:?}", t),
+            QScanParseError::UnresolvedTargets(t) => write!(
+                f,
+                "cannot resolve or parse {}",
+                t.iter().map(|t| format!("{:?}", t)).join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QScanParseError {}
+
+#[cfg(feature = "serialize")]
+impl Serialize for QScanHttpInfo {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("QScanHttpInfo", 2)?;
+        s.serialize_field("status", &self.status)?;
+        if let Some(server) = &self.server {
+            s.serialize_field("server", server)?;
+        }
+        s.end()
     }
 }
 
@@ -139,16 +964,56 @@ impl Serialize for QScanTcpConnectResult {
     where
         S: Serializer,
     {
-        let mut s = serializer.serialize_struct("QScanTcpConnectResult", 3)?;
+        let mut s = serializer.serialize_struct("QScanTcpConnectResult", 12)?;
         s.serialize_field("IP", &self.target.ip())?;
         s.serialize_field("port", &self.target.port())?;
-        match self.state {
-            QScanTcpConnectState::Open => {
-                s.serialize_field("state", "OPEN")?;
-            }
-            QScanTcpConnectState::Close => {
-                s.serialize_field("state", "CLOSE")?;
-            }
+        s.serialize_field("state", self.state.label())?;
+        if let Some(service) = &self.detected_service {
+            s.serialize_field("service", service)?;
+        }
+        if let Some(hostname) = &self.hostname {
+            s.serialize_field("hostname", hostname)?;
+        }
+        if let Some(banner) = &self.banner {
+            s.serialize_field("banner", &String::from_utf8_lossy(banner))?;
+        }
+        if let Some(http) = &self.http {
+            s.serialize_field("http", http)?;
+        }
+        if let Some(reason) = &self.reason {
+            s.serialize_field("reason", reason)?;
+        }
+        s.serialize_field("confidence", &self.confidence)?;
+        s.serialize_field("via", &self.via.to_string())?;
+        if let Some(mss) = self.peer_mss {
+            s.serialize_field("peer_mss", &mss)?;
+        }
+        if let Some(rtt) = self.rtt {
+            s.serialize_field("rtt_us", &(rtt.as_micros() as u64))?;
+        }
+        s.end()
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl Serialize for HostPorts {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("HostPorts", 5)?;
+        s.serialize_field("open", &self.open)?;
+        s.serialize_field("closed", &self.closed)?;
+        s.serialize_field("filtered", &self.filtered)?;
+        if self.open_no_response.is_empty() {
+            s.skip_field("open_no_response")?;
+        } else {
+            s.serialize_field("open_no_response", &self.open_no_response)?;
+        }
+        if self.suspected_rst_injection() {
+            s.serialize_field("suspected_rst_injection", &true)?;
+        } else {
+            s.skip_field("suspected_rst_injection")?;
         }
         s.end()
     }
@@ -174,6 +1039,20 @@ impl Serialize for QScanPingResult {
     }
 }
 
+#[cfg(feature = "serialize")]
+impl Serialize for QScanUdpResult {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("QScanUdpResult", 3)?;
+        s.serialize_field("IP", &self.target.ip())?;
+        s.serialize_field("port", &self.target.port())?;
+        s.serialize_field("state", self.state.label())?;
+        s.end()
+    }
+}
+
 #[cfg(feature = "serialize")]
 impl Serialize for QScanResult {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -183,25 +1062,263 @@ impl Serialize for QScanResult {
         match self {
             QScanResult::TcpConnect(x) => x.serialize(serializer),
             QScanResult::Ping(x) => x.serialize(serializer),
+            QScanResult::Udp(x) => x.serialize(serializer),
         }
     }
 }
 
-/// Defaults
-const SCAN_TYPE: QScanType = QScanType::TcpConnect;
-const PRINT_MODE: QSPrintMode = QSPrintMode::NonRealTime;
-const BATCH_DEF: u16 = 2500;
-const TIMEOUT_DEF: u64 = 1000;
-const TRIES_DEF: u8 = 1;
-const PING_INTERVAL_DEF: u64 = 1000;
-
-impl QScanner {
-    /// Create a new QScanner
-    ///
-    /// # Arguments
-    ///
-    /// * `addresses` - IPs string, comma separated and CIDR notation
-    /// * `ports` - ports string, comma separated and ranges
+#[cfg(feature = "serialize")]
+impl Serialize for QScanStats {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let unix_us = |t: SystemTime| {
+            t.duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_micros() as u64)
+        };
+
+        let mut s = serializer.serialize_struct("QScanStats", 9)?;
+        s.serialize_field("start_us", &unix_us(self.start))?;
+        s.serialize_field("end_us", &unix_us(self.end))?;
+        s.serialize_field("elapsed_us", &(self.elapsed.as_micros() as u64))?;
+        s.serialize_field("total", &self.total)?;
+        s.serialize_field("open", &self.open)?;
+        s.serialize_field("closed", &self.closed)?;
+        s.serialize_field("errors", &self.errors)?;
+        s.serialize_field("fd_exhausted", &self.fd_exhausted)?;
+        s.serialize_field("sockets_per_sec", &self.sockets_per_sec())?;
+        s.end()
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl Serialize for ScanPlan {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("ScanPlan", 16)?;
+        s.serialize_field("IPs", &self.ips)?;
+        s.serialize_field("CIDRs", &self.cidrs)?;
+        s.serialize_field("ports", &self.ports)?;
+        // Sorted by IP, so that the same plan is always serialized the same way
+        let host_port_map: BTreeMap<_, _> = self.host_port_map.iter().collect();
+        s.serialize_field("host_port_map", &host_port_map)?;
+        s.serialize_field("excludes", &self.excludes)?;
+        s.serialize_field("scan_type", scan_type_label(self.scan_type))?;
+        s.serialize_field("batch", &self.batch)?;
+        s.serialize_field("timeout_ms", &self.timeout_ms)?;
+        s.serialize_field("tries", &self.tries)?;
+        s.serialize_field("ping_interval_ms", &self.ping_interval_ms)?;
+        s.serialize_field("timeout_tiers_ms", &self.timeout_tiers_ms)?;
+        s.serialize_field("timeout_backoff", &self.timeout_backoff)?;
+        s.serialize_field("rate", &self.rate)?;
+        s.serialize_field("scan_spread_ms", &self.scan_spread_ms)?;
+        s.serialize_field("host_start_jitter_ms", &self.host_start_jitter_ms)?;
+        let time_windows: Vec<String> = self.time_windows.iter().map(|w| w.to_string()).collect();
+        s.serialize_field("time_windows", &time_windows)?;
+        s.end()
+    }
+}
+
+/// Label of `scan_type` in the serialized scan plan
+#[cfg(feature = "serialize")]
+fn scan_type_label(scan_type: QScanType) -> &'static str {
+    match scan_type {
+        QScanType::TcpConnect => "TCP_CONNECT",
+        QScanType::Ping => "PING",
+        QScanType::UdpConnect => "UDP_CONNECT",
+        #[cfg(feature = "raw")]
+        QScanType::Syn => "SYN",
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl<'de> Deserialize<'de> for ScanPlan {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        use serde_json::{from_value, Value};
+
+        let mut v = Value::deserialize(deserializer)?;
+        let mut field = |name: &str| -> Result<Value, D::Error> {
+            v.get_mut(name)
+                .map(Value::take)
+                .ok_or_else(|| D::Error::custom(format!("missing field `{}`", name)))
+        };
+        macro_rules! get {
+            ($name:expr) => {
+                from_value(field($name)?).map_err(|e| D::Error::custom(format!("{}: {}", $name, e)))
+            };
+        }
+
+        let scan_type: String = get!("scan_type")?;
+        let scan_type = [
+            QScanType::TcpConnect,
+            QScanType::Ping,
+            QScanType::UdpConnect,
+            #[cfg(feature = "raw")]
+            QScanType::Syn,
+        ]
+        .into_iter()
+        .find(|t| scan_type_label(*t) == scan_type)
+        .ok_or_else(|| D::Error::custom(format!("unknown scan_type {:?}", scan_type)))?;
+
+        let time_windows: Vec<String> = get!("time_windows")?;
+        let time_windows = time_windows
+            .iter()
+            .map(|w| {
+                TimeWindow::parse(w)
+                    .ok_or_else(|| D::Error::custom(format!("invalid time window {:?}", w)))
+            })
+            .collect::<Result<Vec<TimeWindow>, D::Error>>()?;
+
+        Ok(ScanPlan {
+            ips: get!("IPs")?,
+            cidrs: get!("CIDRs")?,
+            ports: get!("ports")?,
+            host_port_map: get!("host_port_map")?,
+            excludes: get!("excludes")?,
+            scan_type,
+            batch: get!("batch")?,
+            timeout_ms: get!("timeout_ms")?,
+            tries: get!("tries")?,
+            ping_interval_ms: get!("ping_interval_ms")?,
+            timeout_tiers_ms: get!("timeout_tiers_ms")?,
+            timeout_backoff: get!("timeout_backoff")?,
+            rate: get!("rate")?,
+            scan_spread_ms: get!("scan_spread_ms")?,
+            host_start_jitter_ms: get!("host_start_jitter_ms")?,
+            time_windows,
+        })
+    }
+}
+
+/// Binary results format (see [QScanner::write_last_results_binary])
+#[cfg(feature = "binary")]
+const BINARY_MAGIC: &[u8; 4] = b"QSR1";
+#[cfg(feature = "binary")]
+const BINARY_RECORD_LEN: usize = 25;
+/// Binary record RTT of the results without one
+#[cfg(feature = "binary")]
+const BINARY_NO_RTT: u32 = u32::MAX;
+
+/// Defaults
+const SCAN_TYPE: QScanType = QScanType::TcpConnect;
+const PRINT_MODE: QSPrintMode = QSPrintMode::NonRealTime;
+const CLOSE_POLICY: ClosePolicy = ClosePolicy::GracefulFin;
+const BATCH_DEF: u16 = 2500;
+const TIMEOUT_DEF: u64 = 1000;
+const TRIES_DEF: u8 = 1;
+const PING_INTERVAL_DEF: u64 = 1000;
+const PROGRESS_STEPS: usize = 100;
+/// Weight of the latest progress step in the rolling completion rate (see
+/// [QScanner::set_progress_eta_callback])
+const PROGRESS_RATE_WEIGHT: f64 = 0.3;
+const HTTPS_PORTS_DEF: &[u16] = &[443, 8443];
+/// Longest connect timeout reached by the backoff (see
+/// [QScanner::set_timeout_backoff])
+const TIMEOUT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Bounds of the adaptive timeout (see [QScanner::set_adaptive_timeout])
+const ADAPTIVE_TIMEOUT_MIN: Duration = Duration::from_millis(100);
+const ADAPTIVE_TIMEOUT_MAX_DEF: Duration = Duration::from_secs(10);
+/// Concurrent PTR lookups (see [QScanner::set_resolve_ptr])
+const PTR_LOOKUPS: usize = 32;
+/// Banner grabbing (see [QScanner::set_grab_banner])
+const BANNER_LEN_DEF: usize = 1024;
+const BANNER_TIMEOUT_DEF: Duration = Duration::from_millis(500);
+/// File descriptors left to the rest of the process (e.g., stdio, output
+/// files and the DNS resolver) when the cap is computed from the limits
+const FD_RESERVE: usize = 64;
+
+/// Smoothed round trip time and its variation, in seconds (RFC 6298, see
+/// [QScanner::set_adaptive_timeout])
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RttEstimate {
+    srtt: f64,
+    rttvar: f64,
+}
+
+impl RttEstimate {
+    fn new(rtt: Duration) -> Self {
+        Self {
+            srtt: rtt.as_secs_f64(),
+            rttvar: rtt.as_secs_f64() / 2.0,
+        }
+    }
+
+    fn update(&mut self, rtt: Duration) {
+        let rtt = rtt.as_secs_f64();
+        self.rttvar = 0.75 * self.rttvar + 0.25 * (self.srtt - rtt).abs();
+        self.srtt = 0.875 * self.srtt + 0.125 * rtt;
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.srtt + 4.0 * self.rttvar)
+    }
+}
+
+/// Rolling completion rate of a running scan, in probes per second (see
+/// [QScanner::set_progress_eta_callback])
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ProgressRate {
+    start: Instant,
+    last: Instant,
+    last_completed: usize,
+    rate: Option<f64>,
+}
+
+impl ProgressRate {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+            last_completed: 0,
+            rate: None,
+        }
+    }
+
+    fn update(&mut self, completed: usize, total: usize) -> QScanProgress {
+        let now = Instant::now();
+        let dt = now.duration_since(self.last).as_secs_f64();
+        if completed > self.last_completed && dt > 0.0 {
+            let rate = (completed - self.last_completed) as f64 / dt;
+            self.rate = Some(match self.rate {
+                Some(r) => r + PROGRESS_RATE_WEIGHT * (rate - r),
+                None => rate,
+            });
+            self.last = now;
+            self.last_completed = completed;
+        }
+
+        let remaining = total.saturating_sub(completed);
+        let eta = match self.rate {
+            _ if remaining == 0 => Some(Duration::ZERO),
+            Some(r) if r > 0.0 => Duration::try_from_secs_f64(remaining as f64 / r).ok(),
+            _ => None,
+        };
+
+        QScanProgress {
+            completed,
+            total,
+            elapsed: now.duration_since(self.start),
+            eta,
+        }
+    }
+}
+
+impl QScanner {
+    /// Create a new QScanner. Malformed ports are reported and skipped (see
+    /// [QScanner::try_new])
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - IPs string, comma separated and CIDR notation
+    /// * `ports` - ports string, comma separated and ranges
     ///
     /// # Examples
     ///
@@ -212,18 +1329,296 @@ impl QScanner {
     /// ```
     ///
     pub fn new(addresses: &str, ports: &str) -> Self {
+        Self::with_ports(addresses, ports_parse_lenient(ports))
+    }
+
+    /// Same as [QScanner::new], but fail on malformed ports
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - IPs string, comma separated and CIDR notation
+    /// * `ports` - ports string, comma separated and ranges
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{QScanParseError, QScanner};
+    /// assert!(QScanner::try_new("127.0.0.1", "80,443").is_ok());
+    /// assert_eq!(
+    ///     QScanner::try_new("127.0.0.1", "80,100-80").err(),
+    ///     Some(QScanParseError::ReversedRange("100-80".to_string()))
+    /// );
+    /// ```
+    ///
+    pub fn try_new(addresses: &str, ports: &str) -> Result<Self, QScanParseError> {
+        Ok(Self::with_ports(addresses, ports_parse(ports)?))
+    }
+
+    fn with_ports(addresses: &str, ports: Vec<u16>) -> Self {
+        let (addresses, cidrs) = split_lazy_cidrs(addresses, None);
+        let resolver = LazyResolver::default();
+        let (ips, origins) = addresses_parse(&addresses, &resolver);
+
+        Self {
+            cidrs,
+            resolver,
+            ..Self::with_targets(ips, origins, ports)
+        }
+    }
+
+    /// Create a new QScanner with the given targets and the default
+    /// configuration
+    fn with_targets(
+        ips: Vec<IpAddr>,
+        origins: HashMap<IpAddr, TargetOrigin>,
+        ports: Vec<u16>,
+    ) -> Self {
         Self {
-            ips: addresses_parse(addresses),
-            ports: ports_parse(ports),
+            ips: ips.into(),
+            origins,
+            cidrs: Vec::new(),
+            excluded_ips: HashSet::new(),
+            excludes: Vec::new(),
+            ports,
+            host_ports: HashMap::new(),
             scan_type: SCAN_TYPE,
             print_mode: PRINT_MODE,
             batch: BATCH_DEF,
             to: Duration::from_millis(TIMEOUT_DEF),
+            timeout_tiers: Vec::new(),
+            timeout_backoff: 1.0,
+            adaptive_timeout: false,
+            adaptive_timeout_max: ADAPTIVE_TIMEOUT_MAX_DEF,
+            rtt_estimate: std::sync::Mutex::new(None),
             tries: NonZeroU8::new(std::cmp::max(TRIES_DEF, 1)).unwrap(),
+            retry_on_refused: false,
             ping_payload: vec![0; 56],
+            udp_payload: Vec::new(),
             ping_interval: Duration::from_millis(PING_INTERVAL_DEF),
+            target_rewriter: None,
+            progress_callback: None,
+            progress_eta_callback: None,
+            progress_rate: std::sync::Mutex::new(None),
+            on_open: None,
+            output: QSOutput::default(),
+            blocking_connector: None,
+            stratified_sample: None,
+            randomize: false,
+            sort_results: false,
+            seed: None,
+            local_only: false,
+            strict_resolution: false,
+            retry_budget: None,
+            retries_left: AtomicUsize::new(0),
+            service_detection: false,
+            resolve_ptr: false,
+            banner_len: None,
+            banner_budget: None,
+            banner_bytes_left: AtomicUsize::new(0),
+            require_banner: false,
+            http_probe: false,
+            tcp_mss: None,
+            reuse_addr: false,
+            source_addr: None,
+            close_policy: CLOSE_POLICY,
+            linger: None,
+            probe_coalescing: NonZeroUsize::new(1).unwrap(),
+            max_memory: None,
+            max_fds: None,
+            max_targets: None,
+            host_start_jitter: None,
+            host_start: std::sync::Mutex::new(None),
+            scan_spread: None,
+            spread_slots: std::sync::Mutex::new(None),
+            spread_next: AtomicUsize::new(0),
+            rate: None,
+            rate_next: std::sync::Mutex::new(None),
+            ipv6_sample: None,
+            dns_cache_file: None,
+            resolver: LazyResolver::default(),
+            time_windows: Vec::new(),
+            #[cfg(feature = "syslog")]
+            syslog: None,
+            probe_audit_log: None,
+            cancel: Arc::new(watch::channel(false).0),
+            cancel_armed: AtomicBool::new(false),
             last_results: None,
+            last_errors: Vec::new(),
+            last_stats: None,
+            last_tier_stats: Vec::new(),
+        }
+    }
+
+    /// Create a new QScanner from a (previously exported and approved) scan plan
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let planner = QScanner::new("127.0.0.1", "80,443");
+    /// let plan = planner.export_scan_plan();
+    /// let scanner = QScanner::from_scan_plan(plan);
+    /// ```
+    ///
+    pub fn from_scan_plan(plan: ScanPlan) -> Self {
+        let mut scanner = Self::new("", "");
+        scanner.set_vec_targets(plan.ips, plan.ports);
+        scanner.add_parsed_targets_addr(&plan.cidrs.join(","));
+        scanner.set_host_port_map(plan.host_port_map);
+        scanner.set_exclude(&plan.excludes.join(","));
+        scanner.set_scan_type(plan.scan_type);
+        scanner.set_batch(plan.batch);
+        scanner.set_timeout_ms(plan.timeout_ms);
+        scanner.set_ntries(plan.tries);
+        scanner.set_ping_interval_ms(plan.ping_interval_ms);
+        scanner.set_timeout_tiers(
+            plan.timeout_tiers_ms
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect(),
+        );
+        scanner.set_timeout_backoff(plan.timeout_backoff);
+        scanner.set_rate(plan.rate);
+        scanner.scan_spread = plan.scan_spread_ms.map(Duration::from_millis);
+        scanner.host_start_jitter = plan.host_start_jitter_ms.map(Duration::from_millis);
+        scanner.set_allowed_time_window(plan.time_windows);
+        scanner
+    }
+
+    /// Create a new QScanner from a scan plan serialized in JSON (see
+    /// [QScanner::get_scan_plan_as_json_string])
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let planner = QScanner::new("127.0.0.1,10.0.0.0/8", "80,443");
+    /// let json = planner.get_scan_plan_as_json_string().unwrap();
+    /// let scanner = QScanner::from_scan_plan_json(&json).unwrap();
+    /// assert_eq!(scanner.export_scan_plan(), planner.export_scan_plan());
+    /// ```
+    ///
+    #[cfg(feature = "serialize")]
+    pub fn from_scan_plan_json(json: &str) -> serde_json::Result<Self> {
+        Ok(Self::from_scan_plan(serde_json::from_str(json)?))
+    }
+
+    /// Export the scan plan (targets, exclusions and timing parameters)
+    /// without scanning. Large CIDRs are kept as they are, not expanded.
+    pub fn export_scan_plan(&self) -> ScanPlan {
+        ScanPlan {
+            ips: self.ips.iter().copied().collect(),
+            cidrs: self
+                .cidrs
+                .iter()
+                .map(|(cidr, _)| cidr.to_string())
+                .collect(),
+            ports: self.ports.clone(),
+            host_port_map: self.host_ports.clone(),
+            excludes: collapse_to_cidrs(&self.excluded_ips)
+                .into_iter()
+                .chain(self.excludes.iter().map(IpCidr::to_string))
+                .collect(),
+            scan_type: self.scan_type,
+            batch: self.batch,
+            timeout_ms: self.to.as_millis() as u64,
+            tries: self.tries.get(),
+            ping_interval_ms: self.ping_interval.as_millis() as u64,
+            timeout_tiers_ms: self
+                .timeout_tiers
+                .iter()
+                .map(|to| to.as_millis() as u64)
+                .collect(),
+            timeout_backoff: self.timeout_backoff,
+            rate: self.rate.map_or(0, NonZeroU32::get),
+            scan_spread_ms: self.scan_spread.map(|d| d.as_millis() as u64),
+            host_start_jitter_ms: self.host_start_jitter.map(|d| d.as_millis() as u64),
+            time_windows: self.time_windows.clone(),
+        }
+    }
+
+    /// Restore the default configuration (e.g., scan type, print mode, batch,
+    /// timeout, tries and excludes, see [QScanner::set_exclude]). Targets
+    /// (including the per-host ports, see [QScanner::set_host_port_map]), the
+    /// DNS resolver (with its configuration, see
+    /// [QScanner::set_resolver_config]) and the cached results of the latest
+    /// scan are preserved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{QScanType, QScanner};
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// scanner.set_scan_type(QScanType::Ping);
+    /// scanner.set_batch(10);
+    /// scanner.reset_config();
+    /// ```
+    ///
+    pub fn reset_config(&mut self) {
+        let defaults = Self::with_targets(
+            std::mem::take(&mut self.ips).into_vec(),
+            std::mem::take(&mut self.origins),
+            std::mem::take(&mut self.ports),
+        );
+
+        *self = Self {
+            cancel: self.cancel.clone(),
+            cidrs: std::mem::take(&mut self.cidrs),
+            host_ports: std::mem::take(&mut self.host_ports),
+            resolver: std::mem::take(&mut self.resolver),
+            last_results: self.last_results.take(),
+            last_errors: std::mem::take(&mut self.last_errors),
+            last_stats: self.last_stats.take(),
+            last_tier_stats: std::mem::take(&mut self.last_tier_stats),
+            ..defaults
+        };
+    }
+
+    /// Return a handle to cancel the running TCP connect, ping, UDP or SYN
+    /// scan from another task (a SYN scan stops at the end of the current
+    /// batch). When cancelled, the scan returns promptly with the
+    /// results collected so far (also available with
+    /// [QScanner::get_last_results]).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::time::Duration;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "1-1024");
+    /// let cancel = scanner.cancel_handle();
+    /// Runtime::new().unwrap().block_on(async {
+    ///     tokio::spawn(async move {
+    ///         tokio::time::sleep(Duration::from_millis(10)).await;
+    ///         cancel.cancel();
+    ///     });
+    ///     let res = scanner.scan_tcp_connect().await;
+    ///     println!("{} results before cancel", res.len());
+    /// });
+    /// ```
+    ///
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    /// Start a new scan that can be cancelled (see [QScanner::cancel_handle]).
+    /// The returned future completes when the scan is cancelled.
+    fn cancellable(&self) -> impl Future<Output = ()> + Unpin {
+        if !self.cancel_armed.swap(false, Ordering::Relaxed) {
+            self.cancel.send_replace(false);
         }
+        let mut rx = self.cancel.subscribe();
+
+        Box::pin(async move {
+            while !*rx.borrow_and_update() {
+                if rx.changed().await.is_err() {
+                    future::pending::<()>().await;
+                }
+            }
+        })
     }
 
     /// Set the scanner type
@@ -236,6 +1631,40 @@ impl QScanner {
         self.print_mode = print_mode;
     }
 
+    /// Set where the results are printed in the real-time and live table
+    /// print modes (see [QScanner::set_print_mode]), stdout by default. The
+    /// live table is redrawn in place only on stdout, other writers get one
+    /// line per result. Warnings and errors go to the `log` crate instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - destination of the printed lines
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{QSPrintMode, QScanner};
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// scanner.set_print_mode(QSPrintMode::RealTime);
+    /// scanner.set_output_writer(Box::new(std::io::stderr()));
+    /// ```
+    pub fn set_output_writer(&mut self, writer: Box<dyn Write + Send>) {
+        self.output = QSOutput {
+            writer: std::sync::Mutex::new(writer),
+            #[cfg(feature = "tui")]
+            tty: false,
+        };
+    }
+
+    /// Print a line of the real-time output (see
+    /// [QScanner::set_output_writer])
+    fn print_line(&self, line: impl fmt::Display) {
+        let mut out = self.output.writer.lock().unwrap();
+        if let Err(e) = writeln!(out, "{}", line) {
+            error!("cannot write the output: {}", e);
+        }
+    }
+
     /// Set the number of parallel scans
     pub fn set_batch(&mut self, batch: u16) {
         self.batch = batch;
@@ -246,996 +1675,9166 @@ impl QScanner {
         self.to = Duration::from_millis(to_ms);
     }
 
+    /// Set the connect timeouts of a multi-pass TCP connect scan
+    ///
+    /// All the sockets are probed with the first timeout, then the sockets
+    /// that timed out are probed again with the second timeout, and so on.
+    /// Probes of the next tier start as soon as the new sockets of the
+    /// previous tiers have all been dispatched. Only the final outcome of each
+    /// socket is reported (and printed), statistics of each tier are
+    /// available with [QScanner::get_last_tier_stats]. Each probe is still
+    /// tried `ntries` times (see [QScanner::set_ntries]).
+    ///
+    /// An empty vector disables the tiers, [QScanner::set_timeout_ms] is
+    /// used for all the probes (default).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::time::Duration;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// scanner.set_timeout_tiers(vec![Duration::from_millis(200), Duration::from_secs(2)]);
+    /// ```
+    pub fn set_timeout_tiers(&mut self, tiers: Vec<Duration>) {
+        self.timeout_tiers = tiers;
+    }
+
+    /// Connect timeout of the `tier`-th pass of the TCP connect scan
+    fn tier_timeout(&self, tier: usize) -> Duration {
+        self.timeout_tiers.get(tier).copied().unwrap_or(self.to)
+    }
+
+    /// Grow the connect timeout of each try of a TCP connect scan: try `n`
+    /// (starting from 0) waits `timeout * factor^n`, capped to 60s (or to the
+    /// base timeout, if longer). Default: 1.0, all the tries wait the same
+    /// timeout. Factors smaller than 1.0 are converted to 1.0.
+    ///
+    /// # Arguments
+    ///
+    /// * `factor` - multiplier of the timeout between two consecutive tries
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// scanner.set_timeout_ms(500);
+    /// scanner.set_ntries(3);
+    /// // 500ms, 1s, 2s
+    /// scanner.set_timeout_backoff(2.0);
+    /// ```
+    pub fn set_timeout_backoff(&mut self, factor: f32) {
+        self.timeout_backoff = match factor.is_finite() && factor >= 1.0 {
+            true => factor,
+            false => 1.0,
+        };
+    }
+
+    /// Connect timeout of the `ntry`-th try of a probe whose base timeout is
+    /// `to` (see [QScanner::set_timeout_backoff])
+    fn try_timeout(&self, to: Duration, ntry: u8) -> Duration {
+        let cap = std::cmp::max(to, TIMEOUT_BACKOFF_MAX);
+        let to = to.as_secs_f64() * (self.timeout_backoff as f64).powi(ntry as i32);
+
+        Duration::from_secs_f64(to.min(cap.as_secs_f64()))
+    }
+
+    /// Adapt the connect timeout of a TCP connect scan to the round trip
+    /// times observed during the scan
+    ///
+    /// The time needed to open a connection, or to get it refused, is
+    /// averaged over the probes of the scan and the timeout of the next
+    /// probes becomes the average plus four times its mean deviation (as TCP
+    /// retransmission timers do, RFC 6298). The scan starts with the timeout
+    /// set with [QScanner::set_timeout_ms], the adaptive timeout is never
+    /// shorter than 100ms nor longer than the maximum (see
+    /// [QScanner::set_adaptive_timeout_max_ms]). Probes that time out are not
+    /// measured. Ignored if timeout tiers are set (see
+    /// [QScanner::set_timeout_tiers]). Default: `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("192.168.1.0/24", "1-1024");
+    /// scanner.set_timeout_ms(2000);
+    /// scanner.set_adaptive_timeout(true);
+    /// ```
+    pub fn set_adaptive_timeout(&mut self, adaptive: bool) {
+        self.adaptive_timeout = adaptive;
+    }
+
+    /// Set the longest timeout reached by the adaptive timeout (see
+    /// [QScanner::set_adaptive_timeout]). Default: 10s.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_ms` - maximum timeout in milliseconds, at least 100
+    pub fn set_adaptive_timeout_max_ms(&mut self, max_ms: u64) {
+        self.adaptive_timeout_max =
+            std::cmp::max(Duration::from_millis(max_ms), ADAPTIVE_TIMEOUT_MIN);
+    }
+
+    /// Forget the round trip times of the previous scan
+    fn reset_adaptive_timeout(&self) {
+        *self.rtt_estimate.lock().unwrap() = None;
+    }
+
+    /// Account the round trip time of a probe that got an answer
+    fn observe_rtt(&self, rtt: Duration) {
+        if !self.adaptive_timeout {
+            return;
+        }
+        let mut estimate = self.rtt_estimate.lock().unwrap();
+        match estimate.as_mut() {
+            Some(e) => e.update(rtt),
+            None => *estimate = Some(RttEstimate::new(rtt)),
+        }
+    }
+
+    /// Connect timeout of the next probe whose base timeout is `to` (see
+    /// [QScanner::set_adaptive_timeout])
+    fn adaptive_timeout(&self, to: Duration) -> Duration {
+        if !self.adaptive_timeout || !self.timeout_tiers.is_empty() {
+            return to;
+        }
+        match *self.rtt_estimate.lock().unwrap() {
+            Some(e) => e
+                .timeout()
+                .clamp(ADAPTIVE_TIMEOUT_MIN, self.adaptive_timeout_max),
+            None => std::cmp::min(to, self.adaptive_timeout_max),
+        }
+    }
+
     /// Set how many retries for each target
     /// If `ntries` is 0, it is converted to 1
     pub fn set_ntries(&mut self, ntries: u8) {
         self.tries = NonZeroU8::new(std::cmp::max(ntries, 1)).unwrap();
     }
 
-    /// Set ping payload
-    pub fn set_ping_payload(&mut self, payload: &[u8]) {
-        self.ping_payload = Vec::from(payload);
+    /// Set whether a TCP connection refused or reset by the target is
+    /// retried (see [QScanner::set_ntries]). Default: `false`, the target
+    /// answered so the port is closed and only timeouts and the other
+    /// errors are retried.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1", "1-1024");
+    /// scanner.set_ntries(3);
+    /// scanner.set_retry_on_refused(true);
+    /// ```
+    pub fn set_retry_on_refused(&mut self, retry: bool) {
+        self.retry_on_refused = retry;
     }
 
-    /// Set ping interval in ms
-    pub fn set_ping_interval_ms(&mut self, ping_int_ms: u64) {
-        self.ping_interval = Duration::from_millis(ping_int_ms);
+    /// Whether a TCP connection failed with `kind` can be retried (see
+    /// [QScanner::set_retry_on_refused])
+    fn retryable(&self, kind: io::ErrorKind) -> bool {
+        self.retry_on_refused
+            || !matches!(
+                kind,
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+            )
     }
 
-    pub fn get_last_results(&self) -> Option<&Vec<QScanResult>> {
-        match &self.last_results {
-            Some(res) => Some(res),
-            None => None,
-        }
+    /// Set a retry budget shared by all the targets of a TCP connect scan
+    ///
+    /// Each target is still tried at most `ntries` times (see
+    /// [QScanner::set_ntries]), but every retry after the first attempt
+    /// consumes the shared budget. Once the budget is exhausted, the
+    /// remaining targets get a single attempt. The budget is restored at the
+    /// beginning of each scan.
+    pub fn set_total_retry_budget(&mut self, n: usize) {
+        self.retry_budget = Some(n);
     }
 
-    /// QScanner caches the results of the latest scan. This function clear the cache.
-    pub fn reset_last_results(&mut self) {
-        if let Some(last_res) = &mut self.last_results {
-            last_res.clear();
-            self.last_results = None;
+    /// Restore the retry budget at the beginning of a scan
+    fn reset_retry_budget(&self) {
+        if let Some(budget) = self.retry_budget {
+            self.retries_left.store(budget, Ordering::Relaxed);
         }
     }
 
-    /// Return the vector of target IP addresses
-    pub fn get_tagets_ips(&self) -> &Vec<IpAddr> {
-        &self.ips
+    /// Take a retry from the shared budget, return `false` if the budget is
+    /// exhausted
+    fn take_retry(&self) -> bool {
+        self.retry_budget.is_none()
+            || self
+                .retries_left
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
     }
 
-    /// Return the vector of target ports
-    pub fn get_tagets_ports(&self) -> &Vec<u16> {
-        &self.ports
+    /// Enable service detection on open ports (TCP connect scan)
+    ///
+    /// The greeting sent by the service right after the connection is
+    /// matched first. If nothing is received, a few known protocol probes
+    /// (HTTP, TLS) are sent, each one on a new connection. Detection takes at
+    /// most the scan timeout for each open port. The result is stored in
+    /// [QScanTcpConnectResult::detected_service].
+    pub fn set_service_detection(&mut self, service_detection: bool) {
+        self.service_detection = service_detection;
     }
 
-    /// Set targets addresses. Old targets are discarded
+    /// Resolve the names (PTR records) of the addresses with open ports
+    /// (TCP connect scan) and store them in
+    /// [QScanTcpConnectResult::hostname]
     ///
-    /// # Arguments
+    /// The lookups start when the scan ends, at most 32 at a time, and each
+    /// one takes at most the scan timeout. The hostname of the addresses
+    /// that cannot be resolved is left to `None`.
     ///
-    /// * `addresses` - IPs string, comma separated and CIDR notation
+    /// # Examples
     ///
-    pub fn set_targets_addr(&mut self, addresses: &str) {
-        self.ips = addresses_parse(addresses);
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("8.8.8.8", "53");
+    /// scanner.set_resolve_ptr(true);
+    /// ```
+    pub fn set_resolve_ptr(&mut self, resolve_ptr: bool) {
+        self.resolve_ptr = resolve_ptr;
     }
 
-    /// Set targets port. Old targets are discarded
+    /// Grab the banner of open ports (TCP connect scan): the first bytes
+    /// (at most 1024, see [QScanner::set_banner_len]) sent by the service
+    /// right after the connection, stored in [QScanTcpConnectResult::banner]
     ///
-    /// # Arguments
+    /// The banner is awaited for at most 500ms (or the scan timeout, if
+    /// shorter). Services that do not talk first (e.g. HTTP) have no banner.
     ///
-    /// * `ports` - ports string, comma separated and ranges
+    /// # Examples
     ///
-    pub fn set_targets_port(&mut self, ports: &str) {
-        self.ports = ports_parse(ports);
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1", "21,22,25");
+    /// scanner.set_grab_banner(true);
+    /// ```
+    pub fn set_grab_banner(&mut self, grab_banner: bool) {
+        self.banner_len = match grab_banner {
+            true => Some(self.banner_len.unwrap_or(BANNER_LEN_DEF)),
+            false => None,
+        };
     }
 
-    /// Set targets. Old targets are discarded
+    /// Set the maximum length of the grabbed banners, and enable banner
+    /// grabbing (see [QScanner::set_grab_banner])
     ///
     /// # Arguments
     ///
-    /// * `addresses` - IPs string, comma separated and CIDR notation
-    /// * `ports` - ports string, comma separated and ranges
+    /// * `len` - maximum number of bytes read
     ///
-    pub fn set_targets(&mut self, addresses: &str, ports: &str) {
-        self.ips = addresses_parse(addresses);
-        self.ports = ports_parse(ports);
+    pub fn set_banner_len(&mut self, len: usize) {
+        self.banner_len = Some(len);
     }
 
-    /// Add targets addresses to existing targets
+    /// Cap the total number of bytes read by banner grabbing (see
+    /// [QScanner::set_grab_banner]) across all the sockets of a scan
+    ///
+    /// Once `n` bytes have been read, the remaining open ports get no
+    /// banner. Each banner is still bounded by [QScanner::set_banner_len].
+    /// The budget is restored at the beginning of each scan.
     ///
     /// # Arguments
     ///
-    /// * `addresses` - IPs string, comma separated and CIDR notation
+    /// * `n` - maximum number of banner bytes read by a scan
     ///
-    pub fn add_targets_addr(&mut self, addresses: &str) {
-        self.ips.extend(addresses_parse(addresses));
-        self.ips = self
-            .ips
-            .clone()
-            .into_iter()
-            .unique()
-            .collect::<Vec<IpAddr>>();
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1", "1-1024");
+    /// scanner.set_grab_banner(true);
+    /// scanner.set_max_total_banner_bytes(64 * 1024);
+    /// ```
+    pub fn set_max_total_banner_bytes(&mut self, n: usize) {
+        self.banner_budget = Some(n);
     }
 
-    /// Add targets (ports) to existing targets
+    /// Restore the banner bytes budget at the beginning of a scan
+    fn reset_banner_budget(&self) {
+        if let Some(budget) = self.banner_budget {
+            self.banner_bytes_left.store(budget, Ordering::Relaxed);
+        }
+    }
+
+    /// Send an HTTP request (`GET / HTTP/1.0`) to the open ports (TCP connect
+    /// scan) and store the status code and the `Server` header of the
+    /// response in [QScanTcpConnectResult::http]
     ///
-    /// # Arguments
+    /// The request is sent on the connection opened by the scan, after the
+    /// banner (see [QScanner::set_grab_banner]), and the response is awaited
+    /// for at most the scan timeout. Services that do not answer with an HTTP
+    /// status line get `None`. The request is sent in clear text: HTTPS ports
+    /// (e.g., 443) get `None` too.
     ///
-    /// * `ports` - ports string, comma separated and ranges
+    /// # Examples
     ///
-    pub fn add_targets_port(&mut self, ports: &str) {
-        self.ports.extend(ports_parse(ports));
-        self.ports = self
-            .ports
-            .clone()
-            .into_iter()
-            .unique()
-            .collect::<Vec<u16>>();
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80,8080");
+    /// scanner.set_http_probe(true);
+    /// ```
+    pub fn set_http_probe(&mut self, http_probe: bool) {
+        self.http_probe = http_probe;
     }
 
-    /// Add targets to existing targets
+    /// Classify as [QScanTcpConnectState::Open] only the ports that send a
+    /// banner (see [QScanner::set_grab_banner]) within the read timeout. The
+    /// ports that accept the connection but stay silent (e.g., a backlog
+    /// that is never served) are reported as
+    /// [QScanTcpConnectState::OpenNoResponse] instead, and not printed in the
+    /// [QSPrintMode::RealTime] mode. No effect without banner grabbing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1", "21,22,25");
+    /// scanner.set_grab_banner(true);
+    /// scanner.set_require_banner_for_open(true);
+    /// ```
+    pub fn set_require_banner_for_open(&mut self, require: bool) {
+        self.require_banner = require;
+    }
+
+    /// Set the MSS advertised in the SYN of the TCP connections (TCP connect
+    /// scan), e.g., to observe how the target stacks react to small segments
+    ///
+    /// The MSS used to send to open ports, which is bounded by the MSS
+    /// advertised by the target, is stored in
+    /// [QScanTcpConnectResult::peer_mss].
+    ///
+    /// Platform limitations: the MSS can only be set, and read back, on unix
+    /// systems (on other systems the connections fail). The value read back
+    /// is the effective sending MSS, not the raw option sent by the target.
+    /// Other TCP options and the window advertised by the target are not
+    /// observable from a connect scan (raw sockets are needed).
     ///
     /// # Arguments
     ///
-    /// * `addresses` - IPs string, comma separated and CIDR notation
-    /// * `ports` - ports string, comma separated and ranges
+    /// * `mss` - maximum segment size in bytes
     ///
-    pub fn add_targets(&mut self, addresses: &str, ports: &str) {
-        self.ips.extend(addresses_parse(addresses));
-        self.ips = self
-            .ips
-            .clone()
-            .into_iter()
-            .unique()
-            .collect::<Vec<IpAddr>>();
-        self.ports.extend(ports_parse(ports));
-        self.ports = self
-            .ports
-            .clone()
-            .into_iter()
-            .unique()
-            .collect::<Vec<u16>>();
+    pub fn set_tcp_mss(&mut self, mss: u32) {
+        self.tcp_mss = Some(mss);
     }
 
-    /// Set targets addresses. Old targets are discarded
+    /// Set `SO_REUSEADDR` (and `SO_REUSEPORT` on unix) on the sockets of the
+    /// TCP connections, so that rapid rescans can bind local ports still in
+    /// `TIME_WAIT` (disabled by default).
+    ///
+    /// The options only matter when the local address is bound before
+    /// connecting (see [QScanner::set_source_addr]): otherwise the system
+    /// picks an ephemeral port that is not in use anyway. Platform semantics:
+    ///
+    /// * Linux: `SO_REUSEADDR` allows binding a local port in `TIME_WAIT`,
+    ///   `SO_REUSEPORT` allows sockets of the same user to bind the very same
+    ///   address and port
+    /// * BSD and macOS: as on Linux, but `SO_REUSEADDR` alone also allows
+    ///   binding a specific address on a port bound to the wildcard address
+    /// * Windows: only `SO_REUSEADDR` is set, and it allows binding a port in
+    ///   use by any other socket (not only in `TIME_WAIT`)
+    pub fn set_reuse_addr(&mut self, reuse_addr: bool) {
+        self.reuse_addr = reuse_addr;
+    }
+
+    /// Bind the TCP connections to the local address `addr` (TCP connect
+    /// scan), e.g., to choose the interface of a multi-homed host. By default
+    /// the system picks the source address from the routing table.
+    ///
+    /// The targets of the other address family cannot be reached from `addr`:
+    /// their probes fail (see [QScanner::get_last_errors]).
     ///
     /// # Arguments
     ///
-    /// * `ips` - Target IPs
+    /// * `addr` - local address, it must belong to one of the interfaces
     ///
     /// # Examples
     ///
-    ///```
+    /// ```
     /// use qscan::qscanner::QScanner;
-    /// use std::net::{IpAddr, Ipv4Addr};
-    /// let mut qs = QScanner::new("", "");
-    /// let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))];
-    /// qs.set_vec_targets_addr(target_ips);
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// scanner.set_source_addr("127.0.0.1".parse().unwrap());
     /// ```
-    pub fn set_vec_targets_addr(&mut self, ips: Vec<IpAddr>) {
-        self.ips = ips;
+    pub fn set_source_addr(&mut self, addr: IpAddr) {
+        self.source_addr = Some(addr);
     }
-    /// Set targets port. Old targets are discarded
+
+    /// Set how the connections to open ports are closed, after the service
+    /// detection (TCP connect scan). The scanner never keeps connections
+    /// open. Default: [ClosePolicy::GracefulFin].
     ///
-    /// # Arguments
+    /// Use [ClosePolicy::Drop] to skip the explicit shutdown. A failed
+    /// shutdown does not change the state of the port: the connection was
+    /// established, so it is open.
+    pub fn set_close_policy(&mut self, policy: ClosePolicy) {
+        self.close_policy = policy;
+    }
+
+    /// Set the linger time (SO_LINGER) of the connections to open ports,
+    /// applied just before closing them with the [ClosePolicy::GracefulFin]
+    /// and [ClosePolicy::Drop] policies ([ClosePolicy::AbortiveRst] always
+    /// uses 0). `None` (default) keeps the system default.
     ///
-    /// * `ports` - Target ports
+    /// A non-zero linger time can block the scan, while the socket is
+    /// closed, until the unsent data is acknowledged or the time elapses.
     ///
     /// # Examples
     ///
-    ///```
+    /// ```
     /// use qscan::qscanner::QScanner;
-    /// use std::net::{IpAddr, Ipv4Addr};
-    /// let mut qs = QScanner::new("", "");
-    /// let target_ports = vec![80];
-    /// qs.set_vec_targets_port(target_ports);
+    /// use std::time::Duration;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// scanner.set_linger(Some(Duration::from_secs(1)));
     /// ```
-    pub fn set_vec_targets_port(&mut self, ports: Vec<u16>) {
-        self.ports = ports;
+    pub fn set_linger(&mut self, linger: Option<Duration>) {
+        self.linger = linger;
     }
 
-    /// Set targets. Old targets are discarded
-    ///
-    /// # Arguments
+    /// Close `stream` according to the close policy
+    async fn close_stream(&self, mut stream: TcpStream) -> io::Result<()> {
+        if self.close_policy != ClosePolicy::AbortiveRst && self.linger.is_some() {
+            stream.set_linger(self.linger)?;
+        }
+
+        match self.close_policy {
+            ClosePolicy::GracefulFin => stream.shutdown().await,
+            // Dropping the stream with linger 0 sends a RST
+            ClosePolicy::AbortiveRst => stream.set_linger(Some(Duration::from_secs(0))),
+            ClosePolicy::Drop => Ok(()),
+        }
+    }
+
+    /// Restrict the scan to the given daily time windows (UTC)
     ///
-    /// * `ips` - Target IPs
-    /// * `ports` - Target ports
+    /// Outside of the allowed windows the scan is paused (no new probe is
+    /// sent) and resumed as soon as one of the windows opens again. An empty
+    /// vector (default) allows the scan at any time.
     ///
     /// # Examples
     ///
-    ///```
-    /// use qscan::qscanner::QScanner;
-    /// use std::net::{IpAddr, Ipv4Addr};
-    /// let mut qs = QScanner::new("", "");
-    /// let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))];
-    /// let target_ports = vec![80];
-    /// qs.set_vec_targets(target_ips, target_ports);
     /// ```
-    pub fn set_vec_targets(&mut self, ips: Vec<IpAddr>, ports: Vec<u16>) {
-        self.ips = ips;
-        self.ports = ports;
+    /// use qscan::qscanner::{QScanner, TimeWindow};
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// scanner.set_allowed_time_window(vec![TimeWindow::new((22, 0), (6, 0))]);
+    /// ```
+    ///
+    pub fn set_allowed_time_window(&mut self, windows: Vec<TimeWindow>) {
+        self.time_windows = windows;
     }
 
-    /// Add new targets (addresses)
+    /// Wait until the current time is inside one of the allowed time windows
+    async fn wait_time_window(&self) {
+        loop {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            let secs = (now.as_secs() % SECS_PER_DAY as u64) as u32;
+
+            match time_window_wait(&self.time_windows, secs) {
+                None => return,
+                Some(wait) => {
+                    // Round up to the start of the window
+                    let wait = Duration::from_secs(wait as u64)
+                        .saturating_sub(Duration::from_nanos(now.subsec_nanos() as u64));
+                    time::sleep(wait).await;
+                }
+            }
+        }
+    }
+
+    /// Group up to `group_size` consecutive ports of the same host in a
+    /// single future (TCP connect scan), to reduce the scheduling overhead on
+    /// dense port ranges. The batch size still bounds the number of
+    /// concurrent connections: `batch / group_size` groups are in flight.
     ///
-    /// # Arguments
+    /// Hosts are iterated in the inner loop (for each port), so groups
+    /// are only formed when a single host is scanned or with
+    /// [QScanner::set_host_port_map]. Default: 1 (no coalescing).
+    pub fn set_host_probe_coalescing(&mut self, group_size: usize) {
+        self.probe_coalescing = NonZeroUsize::new(group_size).unwrap_or(NonZeroUsize::MIN);
+    }
+
+    /// Cap the (estimated) memory used by a TCP connect scan to `mb` megabytes
     ///
-    /// * `ips` - Target IPs
+    /// The memory is estimated from the number of results collected so far
+    /// and the number of connections in flight. When the estimate approaches
+    /// the cap, the number of concurrent connections is reduced (down to one)
+    /// below the batch size. When a single connection is left, the results
+    /// collected so far are spilled: they are written to the output writer
+    /// (see [QScanner::set_output_writer]) as `ip:port:STATE` lines, unless
+    /// already printed there ([QSPrintMode::RealTimeAll]), and dropped.
+    ///
+    /// After a spill, [QScanner::get_last_results] and
+    /// [QScanner::get_last_errors] only hold the results collected since, while
+    /// [QScanner::get_last_stats] still counts all of them. Use
+    /// [QScanner::scan_tcp_connect_stream] to consume all the results as they
+    /// arrive instead.
+    pub fn set_max_memory_mb(&mut self, mb: usize) {
+        self.max_memory = Some(mb.saturating_mul(1024 * 1024));
+    }
+
+    /// Cap the number of sockets in flight to `n`, whatever the batch size
+    /// (see [QScanner::set_batch]). At most `min(batch, n)` connections are
+    /// concurrent.
+    ///
+    /// If not set, the cap is computed from the open files limit of the
+    /// process (`RLIMIT_NOFILE` on unix), leaving some descriptors to the
+    /// rest of the process.
     ///
     /// # Examples
     ///
     /// ```
     /// use qscan::qscanner::QScanner;
-    /// use std::net::{IpAddr, Ipv4Addr};
-    /// let mut qs = QScanner::new("127.0.0.1", "80");
-    /// let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))];
-    /// qs.add_vec_targets_addr(target_ips);
+    /// let mut scanner = QScanner::new("10.0.0.0/16", "80,443");
+    /// scanner.set_batch(10000);
+    /// scanner.set_max_fds(1000);
     /// ```
-    pub fn add_vec_targets_addr(&mut self, ips: Vec<IpAddr>) {
-        self.ips.extend(ips);
-        self.ips = self
-            .ips
-            .clone()
-            .into_iter()
-            .unique()
-            .collect::<Vec<IpAddr>>();
+    pub fn set_max_fds(&mut self, n: usize) {
+        self.max_fds = Some(std::cmp::max(n, 1));
     }
 
-    /// Add new targets (port)
+    /// Refuse to run a TCP connect, UDP or SYN scan of more than `n`
+    /// sockets (see [QScanner::target_count]), or a ping scan of more than
+    /// `n` addresses, e.g., to catch a mistyped CIDR or port range. An
+    /// oversized scan logs an error and returns no results (see
+    /// [QScanner::try_scan_tcp_connect] to get the error instead), see
+    /// [QScanner::check_max_targets].
     ///
-    /// # Arguments
+    /// # Examples
     ///
-    /// * `ports` - Target ports
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("10.0.0.0/8", "1-65535");
+    /// scanner.set_max_targets(1_000_000);
+    /// let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// assert!(res.is_empty());
+    /// ```
+    pub fn set_max_targets(&mut self, n: usize) {
+        self.max_targets = Some(n);
+    }
+
+    /// Check the number of probes of the configured scan type (see
+    /// [QScanner::set_scan_type]) against the cap set with
+    /// [QScanner::set_max_targets]: addresses for the ping scan, sockets
+    /// (see [QScanner::target_count]) for the other scans.
+    ///
+    /// Return the number of probes, or an `InvalidInput` error if they are
+    /// more than the cap.
     ///
     /// # Examples
     ///
     /// ```
     /// use qscan::qscanner::QScanner;
-    /// use std::net::{IpAddr, Ipv4Addr};
-    /// let mut qs = QScanner::new("127.0.0.1", "80");
-    /// let target_ports = vec![443];
-    /// qs.add_vec_targets_port(target_ports);
+    /// let mut scanner = QScanner::new("10.0.0.0/16", "1-1000");
+    /// scanner.set_max_targets(100_000);
+    /// assert!(scanner.check_max_targets().is_err());
     /// ```
-    pub fn add_vec_targets_port(&mut self, ports: Vec<u16>) {
-        self.ports.extend(ports);
-        self.ports = self
-            .ports
-            .clone()
-            .into_iter()
-            .unique()
-            .collect::<Vec<u16>>();
+    pub fn check_max_targets(&self) -> std::io::Result<u64> {
+        match self.scan_type {
+            QScanType::Ping => self.check_probes(self.addresses_count(), "addresses"),
+            _ => self.check_probes(self.target_count(), "sockets"),
+        }
     }
 
-    /// Add new targets
-    ///
-    /// # Arguments
+    /// Check `count` probes (`what`) against the targets cap (see
+    /// [QScanner::set_max_targets])
+    fn check_probes(&self, count: u64, what: &str) -> std::io::Result<u64> {
+        match self.max_targets {
+            Some(max) if count > max as u64 => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} {} to scan, more than the maximum of {}",
+                    count, what, max
+                ),
+            )),
+            _ => Ok(count),
+        }
+    }
+
+    /// Whether the scan must be refused because its `count` probes (`what`)
+    /// exceed the targets cap (see [QScanner::set_max_targets]). If so, the
+    /// error is logged and the results are cleared.
+    fn refuse_oversized(&mut self, count: u64, what: &str) -> bool {
+        match self.check_probes(count, what) {
+            Ok(_) => false,
+            Err(e) => {
+                error!("{}", e);
+                self.set_last_results(Vec::new());
+                true
+            }
+        }
+    }
+
+    /// Number of concurrent probes holding a socket: the batch size, capped
+    /// to the available file descriptors (see [QScanner::set_max_fds])
+    fn concurrency(&self) -> usize {
+        let max_fds = self.max_fds.unwrap_or_else(fds_limit);
+        std::cmp::max(std::cmp::min(self.batch as usize, max_fds), 1)
+    }
+
+    /// Delay the first probe of each target address by a random amount of
+    /// time up to `max`, so that the probes of the different hosts do not
+    /// all start together. See also [QScanner::set_seed].
+    pub fn set_host_start_jitter(&mut self, max: Duration) {
+        self.host_start_jitter = Some(max);
+    }
+
+    /// Set the start time and draw the jitter key of a new scan
+    fn reset_host_start(&self) {
+        *self.host_start.lock().unwrap() = self
+            .host_start_jitter
+            .map(|_| (Instant::now(), self.rng().gen::<u64>()));
+    }
+
+    /// Start time of `ip`: the jitter is derived from the address and the
+    /// key of the scan, so that no per-host state is kept
+    fn host_start_of(&self, ip: IpAddr) -> Option<Instant> {
+        use std::hash::{Hash, Hasher};
+
+        let (start, key) = (*self.host_start.lock().unwrap())?;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (key, ip).hash(&mut hasher);
+        let frac = hasher.finish() as f64 / u64::MAX as f64;
+
+        Some(start + self.host_start_jitter?.mul_f64(frac))
+    }
+
+    /// Wait until the start time of `ip` (see [QScanner::set_host_start_jitter])
+    async fn wait_host_start(&self, ip: IpAddr) {
+        if let Some(start) = self.host_start_of(ip) {
+            time::sleep_until(start.into()).await;
+        }
+    }
+
+    /// Stretch the whole scan over `total_duration`, sending the probes at
+    /// evenly spaced times (one every `total_duration` / number of probes)
+    /// however fast the network could go, e.g., to stay under the rate
+    /// threshold of an IDS. Retries are sent right after a failed attempt and
+    /// are not spaced.
     ///
-    /// * `ips` - Target IPs
-    /// * `ports` - Target ports
+    /// The number of probes is computed from the targets before sampling and
+    /// skipping, so a sampled scan ends earlier.
     ///
     /// # Examples
     ///
     /// ```
     /// use qscan::qscanner::QScanner;
-    /// use std::net::{IpAddr, Ipv4Addr};
-    /// let mut qs = QScanner::new("127.0.0.1", "80");
-    /// let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))];
-    /// let target_ports = vec![443];
-    /// qs.add_vec_targets(target_ips, target_ports);
+    /// use std::time::Duration;
+    /// let mut scanner = QScanner::new("127.0.0.1", "1-1024");
+    /// // About one probe every 3.5 seconds
+    /// scanner.set_scan_spread(Duration::from_secs(3600));
     /// ```
-    pub fn add_vec_targets(&mut self, ips: Vec<IpAddr>, ports: Vec<u16>) {
-        self.ips.extend(ips);
-        self.ips = self
-            .ips
-            .clone()
-            .into_iter()
-            .unique()
-            .collect::<Vec<IpAddr>>();
-        self.ports.extend(ports);
-        self.ports = self
-            .ports
-            .clone()
-            .into_iter()
-            .unique()
-            .collect::<Vec<u16>>();
+    ///
+    pub fn set_scan_spread(&mut self, total_duration: Duration) {
+        self.scan_spread = Some(total_duration);
     }
 
-    #[cfg(feature = "serialize")]
-    pub fn get_last_results_as_json_string(&self) -> serde_json::Result<String> {
-        serde_json::to_string(&self.last_results)
+    /// Compute the probes slots of a new scan of `probes` probes
+    fn reset_scan_spread(&self, probes: usize) {
+        self.spread_next.store(0, Ordering::Relaxed);
+        *self.spread_slots.lock().unwrap() = self.scan_spread.map(|total| {
+            let probes = u32::try_from(std::cmp::max(probes, 1)).unwrap_or(u32::MAX);
+            (Instant::now(), total / probes)
+        });
     }
 
-    /// Async TCP connect scan
-    ///
-    /// # Return
+    /// Wait for the next probe slot (see [QScanner::set_scan_spread])
+    async fn wait_scan_spread(&self) {
+        let slots = *self.spread_slots.lock().unwrap();
+
+        if let Some((start, interval)) = slots {
+            let slot = self.spread_next.fetch_add(1, Ordering::Relaxed);
+            let slot = u32::try_from(slot).unwrap_or(u32::MAX);
+            time::sleep_until((start + interval * slot).into()).await;
+        }
+    }
+
+    /// Limit the rate at which new probes are started, in probes per second
     ///
-    /// A vector of [SocketAddr] for each open port found.
+    /// Each target (socket, or IP address for the ping scan) takes a single
+    /// slot when its first attempt starts: retries and timeouts are not
+    /// counted against the rate. At most `batch` probes are still in flight
+    /// at the same time (see [QScanner::set_batch]). A rate of 0 means
+    /// unlimited (default).
     ///
     /// # Examples
     ///
     /// ```
     /// use qscan::qscanner::QScanner;
-    /// use tokio::runtime::Runtime;
-    /// let mut scanner = QScanner::new("127.0.0.1", "80");
-    /// let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// let mut scanner = QScanner::new("127.0.0.1", "1-1000");
+    /// // Scanning the 1000 sockets takes about 10 seconds
+    /// scanner.set_rate(100);
     /// ```
     ///
-    pub async fn scan_tcp_connect(&mut self) -> &Vec<QScanResult> {
-        let mut sock_res: Vec<QScanResult> = Vec::new();
-        let mut sock_it: sockiter::SockIter = sockiter::SockIter::new(&self.ips, &self.ports);
-        let mut ftrs = FuturesUnordered::new();
+    pub fn set_rate(&mut self, pps: u32) {
+        self.rate = NonZeroU32::new(pps);
+    }
 
-        for _ in 0..self.batch {
-            if let Some(socket) = sock_it.next() {
-                ftrs.push(self.scan_socket_tcp_connect(socket));
-            } else {
-                break;
-            }
+    /// Wait for the next probe slot (see [QScanner::set_rate])
+    async fn wait_rate(&self) {
+        if let Some(pps) = self.rate {
+            let interval = Duration::from_secs(1) / pps.get();
+            let slot = {
+                let mut next = self.rate_next.lock().unwrap();
+                let now = Instant::now();
+                let slot = match *next {
+                    Some(t) if t > now => t,
+                    _ => now,
+                };
+                *next = Some(slot + interval);
+                slot
+            };
+            time::sleep_until(slot.into()).await;
         }
+    }
 
-        while let Some(result) = ftrs.next().await {
-            if let Some(socket) = sock_it.next() {
-                ftrs.push(self.scan_socket_tcp_connect(socket));
-            }
+    /// Set ping payload
+    pub fn set_ping_payload(&mut self, payload: &[u8]) {
+        self.ping_payload = Vec::from(payload);
+    }
 
-            match result {
-                Ok(socket) => {
-                    match self.print_mode {
-                        QSPrintMode::RealTime => {
-                            println!("{}:{}", socket.ip(), socket.port());
-                        }
-                        QSPrintMode::RealTimeAll => {
-                            println!("{}:{}:OPEN", socket.ip(), socket.port());
-                        }
-                        _ => {}
-                    }
+    /// Set the payload of the UDP probe datagram (empty by default)
+    pub fn set_udp_payload(&mut self, payload: &[u8]) {
+        self.udp_payload = Vec::from(payload);
+    }
 
-                    sock_res.push(QScanResult::TcpConnect(QScanTcpConnectResult {
-                        target: socket,
-                        state: QScanTcpConnectState::Open,
-                    }));
-                }
-                Err(error) => {
-                    if let QSPrintMode::RealTimeAll = self.print_mode {
-                        println!("{}:{}:CLOSE", error.sock.ip(), error.sock.port());
-                    }
+    /// Set ping interval in ms
+    pub fn set_ping_interval_ms(&mut self, ping_int_ms: u64) {
+        self.ping_interval = Duration::from_millis(ping_int_ms);
+    }
 
-                    sock_res.push(QScanResult::TcpConnect(QScanTcpConnectResult {
-                        target: error.sock,
-                        state: QScanTcpConnectState::Close,
-                    }));
-                }
-            }
+    pub fn get_last_results(&self) -> Option<&Vec<QScanResult>> {
+        match &self.last_results {
+            Some(res) => Some(res),
+            None => None,
         }
+    }
+
+    /// Return the closed ports found by the latest TCP connect scan, one
+    /// `ip:port` line for each port
+    pub fn get_closed_ports_as_lines(&self) -> Vec<String> {
+        self.tcp_sockets_in(QScanTcpConnectState::Close)
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    /// Return the filtered ports (no answer or unreachable target) found by
+    /// the latest TCP connect scan, one `ip:port` line for each port
+    pub fn get_filtered_ports_as_lines(&self) -> Vec<String> {
+        self.tcp_sockets_in(QScanTcpConnectState::Filtered)
+            .map(|socket| socket.to_string())
+            .collect()
+    }
+
+    /// Sockets found in `state` by the latest TCP connect scan
+    fn tcp_sockets_in(&self, state: QScanTcpConnectState) -> impl Iterator<Item = &SocketAddr> {
+        self.last_results
+            .iter()
+            .flatten()
+            .filter_map(move |r| match r {
+                QScanResult::TcpConnect(sa) if sa.state == state => Some(&sa.target),
+                _ => None,
+            })
+    }
+
+    /// Iterate over the open ports found by the latest TCP connect scan (see
+    /// [QScanner::get_last_results]). Empty if no scan has been run.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "1-1024");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// for socket in scanner.open_ports() {
+    ///     println!("{}", socket);
+    /// }
+    /// ```
+    ///
+    pub fn open_ports(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.tcp_sockets_in(QScanTcpConnectState::Open)
+    }
+
+    /// Iterate over the closed ports found by the latest TCP connect scan,
+    /// filtered ports are not included (see [QScanner::open_ports])
+    pub fn closed_ports(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.tcp_sockets_in(QScanTcpConnectState::Close)
+    }
+
+    /// Number of open ports found by the latest TCP connect scan (see
+    /// [QScanner::open_ports])
+    pub fn open_count(&self) -> usize {
+        self.open_ports().count()
+    }
+
+    /// Number of closed ports found by the latest TCP connect scan (see
+    /// [QScanner::closed_ports])
+    pub fn closed_count(&self) -> usize {
+        self.closed_ports().count()
+    }
+
+    /// Return the open ports found by the latest TCP connect scan as URLs
+    /// (`<scheme>://<ip>:<port>/`), e.g., to be fed to web scanners
+    ///
+    /// # Arguments
+    ///
+    /// * `https_ports` - ports using the `https` scheme, all the other ports
+    ///   use `http`. If empty, 443 and 8443 are used.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80,443,8443");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// for url in scanner.get_last_results_as_urls(&[]) {
+    ///     println!("{}", url);
+    /// }
+    /// ```
+    ///
+    pub fn get_last_results_as_urls(&self, https_ports: &[u16]) -> Vec<String> {
+        let https_ports = match https_ports {
+            [] => HTTPS_PORTS_DEF,
+            ports => ports,
+        };
+
+        self.last_results
+            .iter()
+            .flatten()
+            .filter_map(|r| match r {
+                QScanResult::TcpConnect(sa) if sa.state == QScanTcpConnectState::Open => {
+                    let scheme = match https_ports.contains(&sa.target.port()) {
+                        true => "https",
+                        false => "http",
+                    };
+                    // IPv6 addresses are enclosed in brackets
+                    Some(format!("{}://{}/", scheme, sa.target))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Return the results of the latest TCP connect scan in a greppable
+    /// format: one line per host, sorted by IP, listing its open ports (e.g.,
+    /// `Host: 127.0.0.1 Ports: 22/open,80/open`). Hosts without open ports
+    /// are listed with no ports.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "22,80");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// print!("{}", scanner.get_last_results_as_greppable());
+    /// ```
+    ///
+    pub fn get_last_results_as_greppable(&self) -> String {
+        self.last_results_by_host()
+            .into_iter()
+            .map(|(ip, ports)| {
+                let ports = ports.open.iter().map(|p| format!("{}/open", p)).join(",");
+                format!("Host: {} Ports: {}\n", ip, ports)
+            })
+            .collect()
+    }
+
+    /// Ports of each host in the latest TCP connect results, by state,
+    /// sorted by IP and port
+    fn last_results_by_host(&self) -> BTreeMap<IpAddr, HostPorts> {
+        let mut hosts: BTreeMap<IpAddr, HostPorts> = BTreeMap::new();
+        for r in self.last_results.iter().flatten() {
+            if let QScanResult::TcpConnect(sa) = r {
+                let ports = hosts.entry(sa.target.ip()).or_default();
+                match sa.state {
+                    QScanTcpConnectState::Open => ports.open.push(sa.target.port()),
+                    QScanTcpConnectState::Close => ports.closed.push(sa.target.port()),
+                    QScanTcpConnectState::Filtered => ports.filtered.push(sa.target.port()),
+                    QScanTcpConnectState::OpenNoResponse => {
+                        ports.open_no_response.push(sa.target.port())
+                    }
+                }
+                match (sa.state, sa.rtt) {
+                    (QScanTcpConnectState::Open, Some(rtt)) => ports.open_rtts.push(rtt),
+                    (QScanTcpConnectState::Close, Some(rtt)) => ports.closed_rtts.push(rtt),
+                    _ => {}
+                }
+            }
+        }
+
+        for ports in hosts.values_mut() {
+            for list in [
+                &mut ports.open,
+                &mut ports.closed,
+                &mut ports.filtered,
+                &mut ports.open_no_response,
+            ] {
+                list.sort_unstable();
+                list.dedup();
+            }
+            ports.open_rtts.sort_unstable();
+        }
+        hosts
+    }
+
+    /// Return the hosts of the latest TCP connect scan whose closed ports are
+    /// likely to be reset by a filtering device (e.g., a firewall injecting
+    /// RSTs) rather than genuinely closed: the host refused many connections
+    /// implausibly fast and uniformly compared to the time it took to accept
+    /// the connections to its open ports. The hosts are also flagged with
+    /// `suspected_rst_injection` in
+    /// [QScanner::get_last_results_as_grouped_json_string].
+    ///
+    /// This is a heuristic: at least `RST_INJECTION_MIN_PORTS` (8) closed
+    /// ports and an open port are needed to compare them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "1-1024");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// for ip in scanner.get_suspected_rst_injection() {
+    ///     println!("{}: closed ports may be filtered", ip);
+    /// }
+    /// ```
+    ///
+    pub fn get_suspected_rst_injection(&self) -> Vec<IpAddr> {
+        self.last_results_by_host()
+            .into_iter()
+            .filter(|(_, ports)| ports.suspected_rst_injection())
+            .map(|(ip, _)| ip)
+            .collect()
+    }
+
+    /// Group the results of the latest TCP connect scan by host: the map is
+    /// ordered by IP and the ports of each host are sorted. Empty if no scan
+    /// has been run.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{QScanTcpConnectState, QScanner};
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "1-1024");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// for (ip, ports) in scanner.results_by_host() {
+    ///     let open: Vec<u16> = ports
+    ///         .iter()
+    ///         .filter(|(_, state)| *state == QScanTcpConnectState::Open)
+    ///         .map(|(port, _)| *port)
+    ///         .collect();
+    ///     println!("{}: {:?}", ip, open);
+    /// }
+    /// ```
+    ///
+    pub fn results_by_host(&self) -> BTreeMap<IpAddr, Vec<(u16, QScanTcpConnectState)>> {
+        let mut hosts: BTreeMap<IpAddr, Vec<(u16, QScanTcpConnectState)>> = BTreeMap::new();
+        for r in self.last_results.iter().flatten() {
+            if let QScanResult::TcpConnect(sa) = r {
+                hosts
+                    .entry(sa.target.ip())
+                    .or_default()
+                    .push((sa.target.port(), sa.state));
+            }
+        }
+
+        for ports in hosts.values_mut() {
+            ports.sort_by_key(|(port, _)| *port);
+        }
+        hosts
+    }
+
+    /// Return the probes of the latest TCP connect scan that failed, with the
+    /// error that made them fail (e.g. "Connection refused (os error 111)",
+    /// "deadline has elapsed")
+    pub fn get_last_errors(&self) -> Vec<(SocketAddr, String)> {
+        self.last_errors.clone()
+    }
+
+    /// Return the statistics of the latest TCP connect scan
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80,443");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// let stats = scanner.get_last_stats().unwrap();
+    /// println!("{} sockets in {:?}", stats.total, stats.elapsed);
+    /// println!("{:.1} sockets/s", stats.sockets_per_sec());
+    /// ```
+    ///
+    pub fn get_last_stats(&self) -> Option<&QScanStats> {
+        self.last_stats.as_ref()
+    }
+
+    /// Return the statistics of each timeout tier of the latest TCP connect
+    /// scan (see [QScanner::set_timeout_tiers]), empty if the tiers are not
+    /// set
+    pub fn get_last_tier_stats(&self) -> &[QScanTierStats] {
+        &self.last_tier_stats
+    }
+
+    /// Return the p50, p90 and p99 of the connect RTT of each port, across all
+    /// the hosts of the latest TCP connect scan. Only the RTT of open ports
+    /// is used (see [QScanTcpConnectResult::rtt]), ports never found open are
+    /// not included.
+    ///
+    /// Percentiles are computed with the nearest-rank method.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let port = listener.local_addr().unwrap().port();
+    /// let mut scanner = QScanner::new("127.0.0.1", &port.to_string());
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// let percentiles = scanner.get_rtt_percentiles();
+    /// assert!(percentiles[&port].p50 <= percentiles[&port].p99);
+    /// ```
+    ///
+    pub fn get_rtt_percentiles(&self) -> HashMap<u16, RttPercentiles> {
+        let mut rtts: HashMap<u16, Vec<Duration>> = HashMap::new();
+
+        for r in self.last_results.iter().flatten() {
+            if let QScanResult::TcpConnect(QScanTcpConnectResult {
+                target,
+                state: QScanTcpConnectState::Open,
+                rtt: Some(rtt),
+                ..
+            }) = r
+            {
+                rtts.entry(target.port()).or_default().push(*rtt);
+            }
+        }
+
+        rtts.into_iter()
+            .map(|(port, mut rtts)| {
+                rtts.sort_unstable();
+                let percentiles = RttPercentiles {
+                    p50: percentile(&rtts, 50),
+                    p90: percentile(&rtts, 90),
+                    p99: percentile(&rtts, 99),
+                };
+                (port, percentiles)
+            })
+            .collect()
+    }
+
+    /// Merge TCP connect results coming from an external source (e.g., another
+    /// tool) into the cached results of the latest scan, so that they are
+    /// included in the results accessors and serializers.
+    ///
+    /// Results are deduplicated by `ip:port`: an open result replaces a
+    /// closed (or filtered) one, otherwise the result already in the cache
+    /// is kept.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{ConnectPath, QScanTcpConnectResult, QScanTcpConnectState, QScanner};
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// scanner.ingest_external_results(vec![QScanTcpConnectResult {
+    ///     target: "127.0.0.1:22".parse().unwrap(),
+    ///     state: QScanTcpConnectState::Open,
+    ///     detected_service: None,
+    ///     confidence: 1.0,
+    ///     via: ConnectPath::Direct,
+    ///     peer_mss: None,
+    ///     rtt: None,
+    ///     hostname: None,
+    ///     banner: None,
+    ///     http: None,
+    ///     reason: None,
+    /// }]);
+    /// assert_eq!(scanner.get_last_results().unwrap().len(), 1);
+    /// ```
+    ///
+    pub fn ingest_external_results(&mut self, results: Vec<QScanTcpConnectResult>) {
+        let cache = self.last_results.get_or_insert_with(Vec::new);
+        let mut index: HashMap<SocketAddr, usize> = cache
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| match r {
+                QScanResult::TcpConnect(r) => Some((r.target, i)),
+                _ => None,
+            })
+            .collect();
+
+        for res in results {
+            match index.get(&res.target) {
+                Some(&i) => {
+                    if let QScanResult::TcpConnect(cached) = &mut cache[i] {
+                        if cached.state != QScanTcpConnectState::Open
+                            && res.state == QScanTcpConnectState::Open
+                        {
+                            *cached = res;
+                        }
+                    }
+                }
+                None => {
+                    index.insert(res.target, cache.len());
+                    cache.push(QScanResult::TcpConnect(res));
+                }
+            }
+        }
+    }
+
+    /// QScanner caches the results of the latest scan. This function clear the cache.
+    pub fn reset_last_results(&mut self) {
+        if let Some(last_res) = &mut self.last_results {
+            last_res.clear();
+            self.last_results = None;
+        }
+        self.last_errors.clear();
+        self.last_stats = None;
+        self.last_tier_stats.clear();
+    }
+
+    /// Return the vector of target IP addresses. CIDRs larger than a /16
+    /// (IPv4) or a /112 (IPv6) are not expanded in memory and so not included
+    /// (see [QScanner::get_tagets_ips_iter])
+    pub fn get_tagets_ips(&self) -> &Vec<IpAddr> {
+        &self.ips
+    }
+
+    /// Return an iterator over all the target IP addresses, including the
+    /// addresses of the large CIDRs, which are generated on the fly. Excluded
+    /// addresses are skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let scanner = QScanner::new("127.0.0.1,10.0.0.0/8", "80");
+    /// assert_eq!(scanner.get_tagets_ips().len(), 1);
+    /// assert_eq!(scanner.get_tagets_ips_iter().count(), 1 + (1 << 24));
+    /// ```
+    ///
+    pub fn get_tagets_ips_iter(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.targets_iter()
+    }
+
+    /// Return the vector of target ports
+    pub fn get_tagets_ports(&self) -> &Vec<u16> {
+        &self.ports
+    }
+
+    /// Set targets addresses. Old targets are discarded
+    ///
+    /// Each comma separated token is parsed as, in order of precedence:
+    ///
+    /// * a file of addresses, one target per line, if the token looks like a
+    ///   path (it contains a `/` or ends with `.txt`) and the file exists;
+    /// * an IP, a CIDR or an IPv4 range;
+    /// * a missing file, reported and skipped, if the token looks like a path
+    ///   (see [QScanner::try_set_targets_addr] to fail instead);
+    /// * a hostname;
+    /// * a file of addresses, if the name cannot be resolved.
+    ///
+    /// Whitespaces are ignored and commas always separate tokens: paths
+    /// cannot contain them.
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - IPs string, comma separated and CIDR notation
+    ///
+    pub fn set_targets_addr(&mut self, addresses: &str) {
+        self.set_parsed_targets_addr(addresses);
+    }
+
+    /// Same as [QScanner::set_targets_addr], but fail if a token that looks
+    /// like a path is not a file or, with [QScanner::set_strict_resolution],
+    /// if any target cannot be resolved or parsed. The old targets are kept
+    /// on failure.
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - IPs string, comma separated and CIDR notation
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{QScanParseError, QScanner};
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// assert_eq!(
+    ///     scanner.try_set_targets_addr("127.0.0.2,/tmp/qscan_no_such_file.txt"),
+    ///     Err(QScanParseError::MissingFile("/tmp/qscan_no_such_file.txt".to_string()))
+    /// );
+    /// assert_eq!(scanner.get_tagets_ips().len(), 1);
+    /// ```
+    ///
+    pub fn try_set_targets_addr(&mut self, addresses: &str) -> Result<(), QScanParseError> {
+        let addrs: String = addresses.chars().filter(|c| !c.is_whitespace()).collect();
+        for addr in addrs.split(',') {
+            if let Some(TargetFile::Missing(path)) = target_file(addr) {
+                return Err(QScanParseError::MissingFile(path.display().to_string()));
+            }
+        }
+
+        let mut skipped = Vec::new();
+        let parsed = self.parse_targets_addr(addresses, &mut skipped);
+        if self.strict_resolution && !skipped.is_empty() {
+            return Err(QScanParseError::UnresolvedTargets(skipped));
+        }
+
+        self.replace_targets_addr(parsed);
+        Ok(())
+    }
+
+    /// Set targets port. Old targets are discarded
+    ///
+    /// # Arguments
+    ///
+    /// * `ports` - ports string, comma separated and ranges
+    ///
+    pub fn set_targets_port(&mut self, ports: &str) {
+        self.ports = ports_parse_lenient(ports);
+    }
+
+    /// Same as [QScanner::set_targets_port], but fail on malformed ports.
+    /// The old target ports are kept on failure.
+    ///
+    /// # Arguments
+    ///
+    /// * `ports` - ports string, comma separated and ranges
+    ///
+    pub fn try_set_targets_port(&mut self, ports: &str) -> Result<(), QScanParseError> {
+        self.ports = ports_parse(ports)?;
+        Ok(())
+    }
+
+    /// Set the target ports to the `n` most frequently open TCP ports, most
+    /// frequent first. Old target ports are discarded.
+    ///
+    /// The embedded table holds the 100 most frequent ports, larger values of
+    /// `n` are capped to the table size.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - number of ports
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1", "");
+    /// scanner.set_top_ports(3);
+    /// assert_eq!(scanner.get_tagets_ports(), &vec![80, 23, 443]);
+    /// ```
+    ///
+    pub fn set_top_ports(&mut self, n: usize) {
+        self.ports = TOP_PORTS[..std::cmp::min(n, TOP_PORTS.len())].to_vec();
+    }
+
+    /// Set targets. Old targets are discarded
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - IPs string, comma separated and CIDR notation
+    /// * `ports` - ports string, comma separated and ranges
+    ///
+    pub fn set_targets(&mut self, addresses: &str, ports: &str) {
+        self.set_parsed_targets_addr(addresses);
+        self.ports = ports_parse_lenient(ports);
+    }
+
+    /// Same as [QScanner::set_targets], but fail on malformed ports and on
+    /// the targets refused by [QScanner::try_set_targets_addr]. The old
+    /// targets are kept on failure.
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - IPs string, comma separated and CIDR notation
+    /// * `ports` - ports string, comma separated and ranges
+    ///
+    pub fn try_set_targets(&mut self, addresses: &str, ports: &str) -> Result<(), QScanParseError> {
+        let ports = ports_parse(ports)?;
+        self.try_set_targets_addr(addresses)?;
+        self.ports = ports;
+        Ok(())
+    }
+
+    /// Add targets addresses to existing targets
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - IPs string, comma separated and CIDR notation
+    ///
+    pub fn add_targets_addr(&mut self, addresses: &str) {
+        self.add_parsed_targets_addr(addresses);
+    }
+
+    /// Add targets (ports) to existing targets
+    ///
+    /// # Arguments
+    ///
+    /// * `ports` - ports string, comma separated and ranges
+    ///
+    pub fn add_targets_port(&mut self, ports: &str) {
+        self.ports.extend(ports_parse_lenient(ports));
+        self.ports = self
+            .ports
+            .clone()
+            .into_iter()
+            .unique()
+            .collect::<Vec<u16>>();
+    }
+
+    /// Add targets to existing targets
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - IPs string, comma separated and CIDR notation
+    /// * `ports` - ports string, comma separated and ranges
+    ///
+    pub fn add_targets(&mut self, addresses: &str, ports: &str) {
+        self.add_parsed_targets_addr(addresses);
+        self.ports.extend(ports_parse_lenient(ports));
+        self.ports = self
+            .ports
+            .clone()
+            .into_iter()
+            .unique()
+            .collect::<Vec<u16>>();
+    }
+
+    /// Set the addresses excluded from the scan. Old excludes are discarded
+    ///
+    /// Excludes always win: an address is not scanned if it is excluded,
+    /// even when it is also explicitly listed in the targets. Excludes apply
+    /// to the target addresses as set (i.e., before any rewriting, see
+    /// [QScanner::set_target_rewriter]) and are kept when the targets change.
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - same format as the targets: IPs, CIDRs, domain names
+    ///   or paths to files containing one of the previous for each line,
+    ///   comma separated
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut qs = QScanner::new("192.168.1.0/24", "80");
+    /// qs.set_exclude("192.168.1.0/28,192.168.1.254");
+    /// assert_eq!(qs.get_excluded_count(), 17);
+    /// ```
+    pub fn set_exclude(&mut self, addresses: &str) {
+        // Excluded IPv6 ranges are never sampled
+        let (addresses, cidrs) = split_lazy_cidrs(addresses, None);
+        let (ips, _) = addresses_parse_sampled(
+            &addresses,
+            None,
+            self.dns_cache_file.as_deref(),
+            &self.resolver,
+            &mut self.rng(),
+            &mut Vec::new(),
+        );
+
+        self.excluded_ips = ips.into_iter().collect();
+        self.excludes = cidrs.into_iter().map(|(cidr, _)| cidr).collect();
+    }
+
+    /// Set the addresses excluded from the scan reading them from a file
+    /// (e.g., a do-not-scan list). Old excludes are discarded, see
+    /// [QScanner::set_exclude]
+    ///
+    /// Each line holds an IP, a CIDR or a domain name, as in a targets file.
+    /// Empty lines and lines starting with `#` are skipped. Unlike the
+    /// files passed to [QScanner::set_exclude], large CIDRs are not expanded
+    /// in memory. Excluded addresses that are not targets are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - path of the excludes file
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let path = std::env::temp_dir().join("qscan_doc_exclude_file");
+    /// std::fs::write(&path, "# do not scan\n192.168.1.0/28\n192.168.1.254\n").unwrap();
+    /// let mut qs = QScanner::new("192.168.1.0/24", "80");
+    /// qs.set_exclude_file(path).unwrap();
+    /// assert_eq!(qs.get_excluded_count(), 17);
+    /// ```
+    pub fn set_exclude_file(&mut self, path: PathBuf) -> std::io::Result<()> {
+        let content = std::fs::read_to_string(&path)?;
+        let addresses = content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .join(",");
+
+        self.set_exclude(&addresses);
+        Ok(())
+    }
+
+    /// Return the number of target addresses removed by the excludes (see
+    /// [QScanner::set_exclude])
+    pub fn get_excluded_count(&self) -> usize {
+        let in_ips = self.ips.iter().filter(|ip| self.is_excluded(**ip)).count();
+
+        self.cidrs.iter().fold(in_ips, |n, (cidr, _)| {
+            // CIDRs are either nested or disjoint: the excluded part of `cidr`
+            // is the union of the outermost intersections
+            let parts: Vec<IpCidr> = self
+                .excludes
+                .iter()
+                .filter_map(|e| cidr_intersection(cidr, e))
+                .collect();
+            let excluded = parts
+                .iter()
+                .enumerate()
+                .filter(|(i, p)| {
+                    !parts
+                        .iter()
+                        .enumerate()
+                        .any(|(j, o)| cidr_contains(o, p) && (!cidr_contains(p, o) || j < *i))
+                })
+                .fold(0usize, |m, (_, p)| m.saturating_add(cidr_size(p)));
+            let single = self
+                .excluded_ips
+                .iter()
+                .filter(|ip| cidr.contains(**ip) && !self.excludes.iter().any(|e| e.contains(**ip)))
+                .count();
+            n.saturating_add(excluded).saturating_add(single)
+        })
+    }
+
+    /// Whether `ip` is excluded from the scan (see [QScanner::set_exclude])
+    fn is_excluded(&self, ip: IpAddr) -> bool {
+        self.excluded_ips.contains(&ip) || self.excludes.iter().any(|e| e.contains(ip))
+    }
+
+    /// Set targets addresses. Old targets are discarded
+    ///
+    /// # Arguments
+    ///
+    /// * `ips` - Target IPs
+    ///
+    /// # Examples
+    ///
+    ///```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let mut qs = QScanner::new("", "");
+    /// let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))];
+    /// qs.set_vec_targets_addr(target_ips);
+    /// ```
+    pub fn set_vec_targets_addr(&mut self, ips: Vec<IpAddr>) {
+        self.ips = ips.into();
+        self.origins.clear();
+        self.cidrs.clear();
+    }
+    /// Set targets port. Old targets are discarded
+    ///
+    /// # Arguments
+    ///
+    /// * `ports` - Target ports
+    ///
+    /// # Examples
+    ///
+    ///```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let mut qs = QScanner::new("", "");
+    /// let target_ports = vec![80];
+    /// qs.set_vec_targets_port(target_ports);
+    /// ```
+    pub fn set_vec_targets_port(&mut self, ports: Vec<u16>) {
+        self.ports = ports;
+    }
+
+    /// Set targets. Old targets are discarded
+    ///
+    /// # Arguments
+    ///
+    /// * `ips` - Target IPs
+    /// * `ports` - Target ports
+    ///
+    /// # Examples
+    ///
+    ///```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let mut qs = QScanner::new("", "");
+    /// let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))];
+    /// let target_ports = vec![80];
+    /// qs.set_vec_targets(target_ips, target_ports);
+    /// ```
+    pub fn set_vec_targets(&mut self, ips: Vec<IpAddr>, ports: Vec<u16>) {
+        self.ips = ips.into();
+        self.origins.clear();
+        self.cidrs.clear();
+        self.ports = ports;
+    }
+
+    /// Add new targets (addresses)
+    ///
+    /// # Arguments
+    ///
+    /// * `ips` - Target IPs
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let mut qs = QScanner::new("127.0.0.1", "80");
+    /// let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))];
+    /// qs.add_vec_targets_addr(target_ips);
+    /// ```
+    pub fn add_vec_targets_addr(&mut self, ips: Vec<IpAddr>) {
+        self.ips.extend(ips);
+    }
+
+    /// Add new targets (port)
+    ///
+    /// # Arguments
+    ///
+    /// * `ports` - Target ports
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let mut qs = QScanner::new("127.0.0.1", "80");
+    /// let target_ports = vec![443];
+    /// qs.add_vec_targets_port(target_ports);
+    /// ```
+    pub fn add_vec_targets_port(&mut self, ports: Vec<u16>) {
+        self.ports.extend(ports);
+        self.ports = self
+            .ports
+            .clone()
+            .into_iter()
+            .unique()
+            .collect::<Vec<u16>>();
+    }
+
+    /// Add new targets
+    ///
+    /// # Arguments
+    ///
+    /// * `ips` - Target IPs
+    /// * `ports` - Target ports
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let mut qs = QScanner::new("127.0.0.1", "80");
+    /// let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))];
+    /// let target_ports = vec![443];
+    /// qs.add_vec_targets(target_ips, target_ports);
+    /// ```
+    pub fn add_vec_targets(&mut self, ips: Vec<IpAddr>, ports: Vec<u16>) {
+        self.ips.extend(ips);
+        self.ports.extend(ports);
+        self.ports = self
+            .ports
+            .clone()
+            .into_iter()
+            .unique()
+            .collect::<Vec<u16>>();
+    }
+
+    /// Set the allowed ports for specific hosts. Old mapping is discarded
+    ///
+    /// Mapped hosts are scanned only on their own ports, while hosts that are
+    /// not in the map are scanned on the global targets ports.
+    ///
+    /// # Arguments
+    ///
+    /// * `map` - Target ports for each host
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::collections::HashMap;
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let mut qs = QScanner::new("127.0.0.1,127.0.0.2", "80,443");
+    /// let mut map = HashMap::new();
+    /// map.insert(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), vec![5432]);
+    /// qs.set_host_port_map(map);
+    /// ```
+    pub fn set_host_port_map(&mut self, map: HashMap<IpAddr, Vec<u16>>) {
+        self.host_ports = map;
+    }
+
+    /// Set the exact sockets to scan, instead of every port of every target
+    /// address. The target addresses, ports and host ports map are replaced
+    /// (exclusions, sampling and the other filters still apply).
+    ///
+    /// # Arguments
+    ///
+    /// * `sockets` - `ip:port` pairs to scan (duplicates are scanned once)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::SocketAddr;
+    /// let mut scanner = QScanner::new("", "");
+    /// scanner.set_socket_targets(vec![
+    ///     "8.8.8.8:53".parse::<SocketAddr>().unwrap(),
+    ///     "1.1.1.1:80".parse::<SocketAddr>().unwrap(),
+    /// ]);
+    /// ```
+    pub fn set_socket_targets(&mut self, sockets: Vec<SocketAddr>) {
+        let mut host_ports: HashMap<IpAddr, Vec<u16>> = HashMap::new();
+        for sock in sockets.iter().unique() {
+            host_ports.entry(sock.ip()).or_default().push(sock.port());
+        }
+
+        self.ips = sockets
+            .iter()
+            .map(|sock| sock.ip())
+            .collect::<Vec<IpAddr>>()
+            .into();
+        self.origins.retain(|ip, _| host_ports.contains_key(ip));
+        self.cidrs.clear();
+        self.ports.clear();
+        self.host_ports = host_ports;
+    }
+
+    /// Restrict the targets of the next scan to the sockets found open by
+    /// the latest TCP connect scan (see [QScanner::get_last_results]), e.g.,
+    /// to quickly check if they changed. The targets ports and the host
+    /// ports map are replaced.
+    ///
+    /// Return the number of sockets to scan or, if there are no cached
+    /// results, `None` and the targets are not changed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80,443");
+    /// // No previous scan
+    /// assert_eq!(scanner.rescan_open_only(), None);
+    /// ```
+    pub fn rescan_open_only(&mut self) -> Option<usize> {
+        let results = match &self.last_results {
+            Some(results) => results,
+            None => {
+                warn!("no previous results, all the targets are scanned again");
+                return None;
+            }
+        };
+
+        let open: Vec<SocketAddr> = results
+            .iter()
+            .filter_map(|r| match r {
+                QScanResult::TcpConnect(r) if r.state == QScanTcpConnectState::Open => {
+                    Some(r.target)
+                }
+                _ => None,
+            })
+            .unique()
+            .collect();
+
+        let nopen = open.len();
+        self.set_socket_targets(open);
+
+        Some(nopen)
+    }
+
+    /// Set a function used to rewrite each target address just before it is
+    /// scanned (e.g., to translate addresses behind a NAT). Results report the
+    /// rewritten addresses.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let mut qs = QScanner::new("192.0.2.1", "80");
+    /// qs.set_target_rewriter(|ip| {
+    ///     if ip == IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)) {
+    ///         IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))
+    ///     } else {
+    ///         ip
+    ///     }
+    /// });
+    /// ```
+    pub fn set_target_rewriter(&mut self, f: impl Fn(IpAddr) -> IpAddr + Send + Sync + 'static) {
+        self.target_rewriter = Some(QSCallback(Arc::new(f)));
+    }
+
+    /// Set a function called with the number of completed and total probes
+    /// (sockets for the TCP connect and UDP scans, IP addresses for the ping
+    /// scan) while a scan is running.
+    ///
+    /// To keep the overhead low, the function is called at most
+    /// `PROGRESS_STEPS` (100) times for each scan, i.e. about every 1% of the
+    /// probes, and always when the last probe completes.
+    ///
+    /// The total is computed before the filters (excludes, sampling, local
+    /// only mode, unroutable IPv6 targets, ...) drop some of the probes,
+    /// which are only known once the scan is over: a scan that is not
+    /// cancelled always ends with a call where `completed == total`, with the
+    /// number of probes actually run.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut qs = QScanner::new("127.0.0.1", "1-1000");
+    /// qs.set_progress_callback(|completed, total| {
+    ///     println!("{}%", completed * 100 / total);
+    /// });
+    /// ```
+    pub fn set_progress_callback(&mut self, f: impl Fn(usize, usize) + Send + Sync + 'static) {
+        self.progress_callback = Some(QSCallback(Arc::new(f)));
+    }
+
+    /// Set a function called with the progress of a running scan, including
+    /// the elapsed time and the estimated time to its end (ETA), at the same
+    /// points as the function set by [QScanner::set_progress_callback].
+    ///
+    /// The ETA is the number of remaining probes divided by a rolling
+    /// completion rate, which weights the latest probes the most: it adapts
+    /// when the scan slows down or speeds up (e.g., when it reaches a
+    /// filtered network).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut qs = QScanner::new("127.0.0.1", "1-1000");
+    /// qs.set_progress_eta_callback(|progress| {
+    ///     if let Some(eta) = progress.eta {
+    ///         println!("{}/{} ETA {:?}", progress.completed, progress.total, eta);
+    ///     }
+    /// });
+    /// ```
+    pub fn set_progress_eta_callback(
+        &mut self,
+        f: impl Fn(&QScanProgress) + Send + Sync + 'static,
+    ) {
+        self.progress_eta_callback = Some(QSCallback(Arc::new(f)));
+    }
+
+    /// Start measuring the completion rate of a new scan (see
+    /// [QScanner::set_progress_eta_callback])
+    fn start_progress(&self) {
+        *self.progress_rate.lock().unwrap() = Some(ProgressRate::new());
+    }
+
+    /// Set a function called with each socket found open by a TCP connect
+    /// (or SYN) scan, as soon as it is found and in addition to the printing
+    /// (see [QScanner::set_print_mode]), e.g., to store it in a database.
+    ///
+    /// The function is called from the scan loop, which waits for it to
+    /// return: heavy or blocking work (e.g., a network request) should be
+    /// offloaded, for example by sending the socket on a channel.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::sync::mpsc;
+    /// use std::sync::Mutex;
+    /// let mut qs = QScanner::new("127.0.0.1", "1-1000");
+    /// let (tx, rx) = mpsc::channel();
+    /// let tx = Mutex::new(tx);
+    /// qs.set_on_open(move |socket| {
+    ///     let _ = tx.lock().unwrap().send(socket);
+    /// });
+    /// ```
+    pub fn set_on_open(&mut self, f: impl Fn(SocketAddr) + Send + Sync + 'static) {
+        self.on_open = Some(QSCallback(Arc::new(f)));
+    }
+
+    /// Report an open socket (see [QScanner::set_on_open])
+    fn report_open(&self, socket: SocketAddr) {
+        if let Some(f) = &self.on_open {
+            f.0(socket);
+        }
+    }
+
+    /// Report the scan progress (see [QScanner::set_progress_callback] and
+    /// [QScanner::set_progress_eta_callback])
+    fn report_progress(&self, completed: usize, total: usize) {
+        let step = std::cmp::max(total / PROGRESS_STEPS, 1);
+        if completed.is_multiple_of(step) || completed == total {
+            self.emit_progress(completed, total);
+        }
+    }
+
+    /// Report the end of a scan of `completed` probes out of the `total`
+    /// expected ones, when the filters dropped some of them (see
+    /// [QScanner::set_progress_callback]). Cancelled scans are not reported
+    /// as complete.
+    fn finish_progress(&self, completed: usize, total: usize) {
+        if completed < total && !*self.cancel.borrow() {
+            self.emit_progress(completed, completed);
+        }
+    }
+
+    /// Call the progress callbacks
+    fn emit_progress(&self, completed: usize, total: usize) {
+        if let Some(f) = &self.progress_callback {
+            f.0(completed, total);
+        }
+
+        if let Some(f) = &self.progress_eta_callback {
+            let progress = self
+                .progress_rate
+                .lock()
+                .unwrap()
+                .get_or_insert_with(ProgressRate::new)
+                .update(completed, total);
+            f.0(&progress);
+        }
+    }
+
+    /// Set a blocking (i.e., non async) connector used by the TCP connect scan
+    /// in place of the default async connect.
+    ///
+    /// The connector is called with the target socket and the scan timeout
+    /// and must return `Ok(())` if the connection succeeds. Each call runs
+    /// on the tokio blocking threads pool (see `tokio::task::spawn_blocking`).
+    ///
+    /// Pool sizing and backpressure: at most `batch` calls are in flight at
+    /// the same time, a new target is dispatched only when a previous call
+    /// returns. Calls beyond the maximum number of blocking threads of the
+    /// runtime (512 by default) wait for a free thread. A call that is
+    /// blocked cannot be cancelled, so the connector must honor the timeout.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::TcpStream;
+    /// let mut qs = QScanner::new("127.0.0.1", "80");
+    /// qs.set_blocking_connector(|sock, to| TcpStream::connect_timeout(&sock, to).map(|_| ()));
+    /// ```
+    pub fn set_blocking_connector(
+        &mut self,
+        f: impl Fn(SocketAddr, Duration) -> std::io::Result<()> + Send + Sync + 'static,
+    ) {
+        self.blocking_connector = Some(QSCallback(Arc::new(f)));
+    }
+
+    /// Scan only a sample of `total` target addresses. The sample is spread
+    /// across the targets inputs (see [TargetOrigin]) proportionally to the
+    /// number of addresses each input expands to, e.g., with targets
+    /// "10.0.0.0/24,10.1.0.0/26" and `total` 40, 32 addresses are sampled
+    /// from the first CIDR and 8 from the second.
+    ///
+    /// Addresses without an origin (set with the `*_vec_*` functions) are
+    /// sampled as a single group. See also [QScanner::set_seed].
+    pub fn set_stratified_sample(&mut self, total: usize) {
+        self.stratified_sample = Some(total);
+    }
+
+    /// Scan the (address, port) pairs in random order instead of, for each
+    /// port, all the addresses in order (disabled by default)
+    ///
+    /// The order is a random permutation of the sockets computed on the fly,
+    /// the sockets are not materialized in memory (large CIDRs included). See
+    /// also [QScanner::set_seed]. Consecutive sockets rarely belong to the
+    /// same host, so probes are seldom coalesced (see
+    /// [QScanner::set_host_probe_coalescing]).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("10.0.0.0/16", "22,80,443");
+    /// scanner.set_randomize(true);
+    /// scanner.set_seed(42);
+    /// ```
+    pub fn set_randomize(&mut self, randomize: bool) {
+        self.randomize = randomize;
+    }
+
+    /// Sort the results of the following scans by IP (IPv4 first) and port,
+    /// instead of keeping them in completion order (disabled by default).
+    /// Only the returned (and cached) results are sorted, real-time printing
+    /// is not affected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1,::1", "22,80,443");
+    /// scanner.set_sort_results(true);
+    /// ```
+    pub fn set_sort_results(&mut self, sort_results: bool) {
+        self.sort_results = sort_results;
+    }
+
+    /// Cache the results of the latest scan, sorted if required (see
+    /// [QScanner::set_sort_results])
+    fn set_last_results(&mut self, mut results: Vec<QScanResult>) {
+        if self.sort_results {
+            results.sort_by_key(QScanResult::sort_key);
+        }
+        self.last_results = Some(results);
+    }
+
+    /// Expand IPv6 CIDRs with more than `n` addresses to `n` random addresses
+    /// instead of enumerating them (e.g., a /64). Applies to the targets set
+    /// or added after this call. See also [QScanner::set_seed].
+    ///
+    /// IPv6 networks are too large to be enumerated, so host discovery on
+    /// them is inherently incomplete: this is a best-effort sample and most
+    /// of the live hosts will likely be missed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("", "80");
+    /// scanner.set_ipv6_sample_per_prefix(16);
+    /// scanner.set_targets_addr("2001:db8::/64");
+    /// assert_eq!(scanner.get_tagets_ips().len(), 16);
+    /// ```
+    ///
+    pub fn set_ipv6_sample_per_prefix(&mut self, n: usize) {
+        self.ipv6_sample = Some(n);
+    }
+
+    /// Cache hostname resolutions in the file at `path`, so that repeated
+    /// scans of the same targets do not resolve them again. Cached entries
+    /// are used until their DNS record TTL expires (names resolved with the
+    /// system resolver, whose TTL is unknown, are kept for 5 minutes). The
+    /// file is created if it does not exist.
+    ///
+    /// Applies to the targets set or added after this call.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - DNS cache file path
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let path = std::env::temp_dir().join("qscan_doc_dns_cache");
+    /// let mut scanner = QScanner::new("", "80");
+    /// scanner.set_dns_cache_file(path);
+    /// scanner.set_targets_addr("localhost");
+    /// ```
+    ///
+    pub fn set_dns_cache_file(&mut self, path: PathBuf) {
+        self.dns_cache_file = Some(path);
+    }
+
+    /// Resolve the hostnames (targets, excludes and PTR records, see
+    /// [QScanner::set_resolve_ptr]) with the given DNS configuration only
+    ///
+    /// By default, hostnames are resolved by the system resolver and, if it
+    /// fails, by Cloudflare (DNS over TLS). Only the targets set after this
+    /// call are affected. The resolver is created the first time a hostname
+    /// is resolved and then reused by the following target updates.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - name servers (and search domains) to query
+    /// * `opts` - resolver options (e.g., timeout and attempts)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{QScanner, ResolverConfig, ResolverOpts};
+    /// let mut scanner = QScanner::new("", "80");
+    /// scanner.set_resolver_config(ResolverConfig::google(), ResolverOpts::default());
+    /// scanner.set_targets_addr("127.0.0.1");
+    /// ```
+    pub fn set_resolver_config(&mut self, config: ResolverConfig, opts: ResolverOpts) {
+        self.resolver = LazyResolver::new(Some((config, opts)));
+    }
+
+    /// Make [QScanner::try_set_targets_addr] (and [QScanner::try_set_targets])
+    /// fail if any target cannot be resolved or parsed (e.g., an unknown
+    /// hostname or an invalid range), including the lines of the targets
+    /// files. The error lists all of them. Default: `false`, such targets are
+    /// reported with a warning and skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("", "80");
+    /// scanner.set_strict_resolution(true);
+    /// assert!(scanner.try_set_targets_addr("127.0.0.1,10.0.0.5-1").is_err());
+    /// ```
+    pub fn set_strict_resolution(&mut self, strict: bool) {
+        self.strict_resolution = strict;
+    }
+
+    /// Set the seed used for the random choices of the scanner (e.g.,
+    /// sampling), so that they can be reproduced
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    fn rng(&self) -> StdRng {
+        match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    /// Addresses selected by the stratified sample, `None` if sampling is not
+    /// enabled
+    fn sampled_ips(&self) -> Option<HashSet<IpAddr>> {
+        let total = self.stratified_sample?;
+        let mut strata: Vec<Vec<IpAddr>> = Vec::new();
+        let mut strata_idx: HashMap<Option<&TargetOrigin>, usize> = HashMap::new();
+
+        for ip in self.ips.iter() {
+            let idx = *strata_idx.entry(self.origins.get(ip)).or_insert_with(|| {
+                strata.push(Vec::new());
+                strata.len() - 1
+            });
+            strata[idx].push(*ip);
+        }
+
+        let mut sizes: Vec<usize> = strata.iter().map(|s| s.len()).collect();
+        sizes.extend(self.cidrs.iter().map(|(cidr, _)| cidr_size(cidr)));
+
+        let mut rng = self.rng();
+        let mut sampled = HashSet::new();
+        let alloc = stratified_allocation(&sizes, total);
+        for (stratum, n) in strata.iter().zip(&alloc) {
+            sampled.extend(
+                rand::seq::index::sample(&mut rng, stratum.len(), *n)
+                    .into_iter()
+                    .map(|idx| stratum[idx]),
+            );
+        }
+        for ((cidr, _), n) in self.cidrs.iter().zip(&alloc[strata.len()..]) {
+            sampled.extend(cidr_sample(cidr, *n, &mut rng));
+        }
+
+        Some(sampled)
+    }
+
+    /// Check the target ports (including the ones of [QScanner::set_host_port_map])
+    /// against a list of allowed ports, without scanning
+    ///
+    /// # Return
+    ///
+    /// `Err` with the sorted target ports not in `allowed`, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let qs = QScanner::new("127.0.0.1", "22,80,8080");
+    /// assert_eq!(qs.validate_ports_against_policy(&[22, 80]), Err(vec![8080]));
+    /// assert!(qs.validate_ports_against_policy(&[22, 80, 8080]).is_ok());
+    /// ```
+    ///
+    pub fn validate_ports_against_policy(&self, allowed: &[u16]) -> Result<(), Vec<u16>> {
+        let denied: Vec<u16> = self
+            .ports
+            .iter()
+            .chain(self.host_ports.values().flatten())
+            .filter(|port| !allowed.contains(port))
+            .copied()
+            .sorted()
+            .dedup()
+            .collect();
+
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(denied)
+        }
+    }
+
+    /// Restrict the scan to loopback and local interfaces addresses, so that
+    /// no packet leaves the host. Off-host targets are skipped (with a
+    /// warning) by the following scans.
+    ///
+    /// # Return
+    ///
+    /// `Err` with the current off-host targets, if any, when enabling it. The
+    /// addresses of large CIDRs (see [QScanner::get_tagets_ips]) are only
+    /// checked, and silently skipped, while scanning.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut qs = QScanner::new("127.0.0.1,192.0.2.1", "22");
+    /// let off_host = qs.set_local_only(true).unwrap_err();
+    /// assert_eq!(off_host, vec!["192.0.2.1".parse::<std::net::IpAddr>().unwrap()]);
+    /// ```
+    pub fn set_local_only(&mut self, local_only: bool) -> Result<(), Vec<IpAddr>> {
+        self.local_only = local_only;
+
+        let off_host = self.off_host_targets();
+        if off_host.is_empty() {
+            Ok(())
+        } else {
+            Err(off_host)
+        }
+    }
+
+    /// Targets (after rewriting) that are not local, if local only mode is set
+    fn off_host_targets(&self) -> Vec<IpAddr> {
+        if !self.local_only {
+            return Vec::new();
+        }
+
+        self.ips
+            .iter()
+            .map(|ip| self.rewrite(*ip))
+            .filter(|ip| !is_local(*ip))
+            .collect()
+    }
+
+    fn rewrite(&self, ip: IpAddr) -> IpAddr {
+        match &self.target_rewriter {
+            Some(rewriter) => (rewriter.0)(ip),
+            None => ip,
+        }
+    }
+
+    /// Emit each open port found by the TCP connect scan as a RFC 5424 syslog
+    /// message, either to the local syslog or, if `server` is set, to a
+    /// remote collector (UDP). The message structured data contain the
+    /// target IP, port, state and scan type.
+    #[cfg(feature = "syslog")]
+    pub fn set_syslog_output(
+        &mut self,
+        facility: QSSyslogFacility,
+        server: Option<SocketAddr>,
+    ) -> std::io::Result<()> {
+        self.syslog = Some(QSSyslog::new(facility, server)?);
+        Ok(())
+    }
+
+    /// Record every probe sent (TCP connection attempts, UDP datagrams, ICMP
+    /// echo requests and service detection payloads) in the append-only log
+    /// at `path`, independent of the results. Each record is a line
+    /// `<timestamp> <protocol> <source> <destination> <payload>`: the time
+    /// the probe was sent (seconds since the UNIX epoch, with microseconds),
+    /// `tcp`, `udp` or `icmp`, the source endpoint (`-` if unknown), the
+    /// destination and the hex encoded payload (`-` if empty). Records are
+    /// written, unbuffered, as soon as the probe is sent.
+    ///
+    /// The source endpoint of a TCP connection attempt is only known if the
+    /// connection is established (and never when using
+    /// [QScanner::set_blocking_connector]), ICMP probes have no source port.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - log file path, created if it does not exist
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let path = std::env::temp_dir().join("qscan_doc_probe_audit.log");
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// scanner.set_probe_audit_log(path.clone()).unwrap();
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    ///
+    pub fn set_probe_audit_log(&mut self, path: PathBuf) -> std::io::Result<()> {
+        self.probe_audit_log = Some(ProbeAuditLog::new(&path)?);
+        Ok(())
+    }
+
+    /// Record a probe in the audit log (see [QScanner::set_probe_audit_log])
+    fn audit_probe(
+        &self,
+        sent: SystemTime,
+        protocol: &str,
+        source: Option<SocketAddr>,
+        destination: &dyn fmt::Display,
+        payload: &[u8],
+    ) {
+        if let Some(log) = &self.probe_audit_log {
+            if let Err(e) = log.record(sent, protocol, source, destination, payload) {
+                error!("cannot write probe audit log: {}", e);
+            }
+        }
+    }
+
+    #[cfg(feature = "syslog")]
+    fn syslog_open(&self, socket: SocketAddr) {
+        if let Some(syslog) = &self.syslog {
+            let sd = [
+                ("ip", socket.ip().to_string()),
+                ("port", socket.port().to_string()),
+                ("state", "open".to_string()),
+                ("scan_type", "tcp_connect".to_string()),
+            ];
+            let msg = format!("{} open", socket);
+
+            if let Err(e) = syslog.emit("OPEN", &sd, &msg) {
+                error!("cannot write to syslog: {}", e);
+            }
+        }
+    }
+
+    /// Return the input (targets string token or file line) that produced the
+    /// target address `ip`. Addresses set with the `*_vec_*` functions have no
+    /// origin.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{QScanner, TargetOrigin};
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// let qs = QScanner::new("127.0.0.0/30", "80");
+    /// let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    /// assert_eq!(
+    ///     qs.origin_of(ip),
+    ///     Some(&TargetOrigin::Token("127.0.0.0/30".to_string()))
+    /// );
+    /// ```
+    pub fn origin_of(&self, ip: IpAddr) -> Option<&TargetOrigin> {
+        self.origins.get(&ip).or_else(|| {
+            self.cidrs
+                .iter()
+                .find(|(cidr, _)| cidr.contains(ip))
+                .map(|(_, origin)| origin)
+        })
+    }
+
+    /// Parse `addresses`, large CIDRs (see [split_lazy_cidrs]) are returned
+    /// separately without being expanded. The targets that cannot be
+    /// resolved or parsed are added to `skipped`.
+    fn parse_targets_addr(&self, addresses: &str, skipped: &mut Vec<String>) -> ParsedTargets {
+        let (addresses, cidrs) = split_lazy_cidrs(addresses, self.ipv6_sample);
+        let (ips, origins) = addresses_parse_sampled(
+            &addresses,
+            self.ipv6_sample,
+            self.dns_cache_file.as_deref(),
+            &self.resolver,
+            &mut self.rng(),
+            skipped,
+        );
+
+        (ips, origins, cidrs)
+    }
+
+    /// Replace the targets addresses with the parsed `addresses`
+    fn set_parsed_targets_addr(&mut self, addresses: &str) {
+        let parsed = self.parse_targets_addr(addresses, &mut Vec::new());
+        self.replace_targets_addr(parsed);
+    }
+
+    fn replace_targets_addr(&mut self, (ips, origins, cidrs): ParsedTargets) {
+        self.ips = ips.into();
+        self.origins = origins;
+        self.cidrs = cidrs;
+    }
+
+    fn add_parsed_targets_addr(&mut self, addresses: &str) {
+        let (ips, origins, cidrs) = self.parse_targets_addr(addresses, &mut Vec::new());
+        self.ips.extend(ips);
+        for (ip, origin) in origins {
+            self.origins.entry(ip).or_insert(origin);
+        }
+        for (cidr, origin) in cidrs {
+            if !self.cidrs.iter().any(|(c, _)| *c == cidr) {
+                self.cidrs.push((cidr, origin));
+            }
+        }
+    }
+
+    /// Iterator over all the target addresses, large CIDRs are expanded on
+    /// the fly
+    fn targets_iter(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.ips
+            .iter()
+            .copied()
+            .chain(self.cidrs.iter().flat_map(|(cidr, _)| cidr.iter()))
+            .filter(move |ip| !self.is_excluded(*ip))
+    }
+
+    #[cfg(feature = "serialize")]
+    pub fn get_scan_plan_as_json_string(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.export_scan_plan())
+    }
+
+    #[cfg(feature = "serialize")]
+    pub fn get_last_results_as_json_string(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.last_results)
+    }
+
+    /// Return the results of the latest TCP connect scan as a JSON object
+    /// keyed by IP, with the open, closed and filtered ports of each host
+    /// (e.g., `{"127.0.0.1":{"open":[22,80],"closed":[443],"filtered":[]}}`).
+    /// Hosts with silent open ports (see
+    /// [QScanner::set_require_banner_for_open]) also list them in
+    /// `open_no_response`, and hosts whose closed ports are likely reset by
+    /// a filtering device are flagged with `"suspected_rst_injection":true`
+    /// (see [QScanner::get_suspected_rst_injection]).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "22,80");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// let json = scanner.get_last_results_as_grouped_json_string().unwrap();
+    /// ```
+    ///
+    #[cfg(feature = "serialize")]
+    pub fn get_last_results_as_grouped_json_string(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.last_results_by_host())
+    }
+
+    #[cfg(feature = "serialize")]
+    pub fn get_last_stats_as_json_string(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.last_stats)
+    }
+
+    /// Return the results of the latest scan in CSV format: a `ip,port,state`
+    /// header followed by one row for each result. The state is `OPEN`,
+    /// `CLOSE` or `FILTERED` (TCP connect), `UP` or `DOWN` (ping, with an empty port) and
+    /// `OPEN`, `CLOSED` or `FILTERED` (UDP). No field contains commas or
+    /// quotes, so fields are never quoted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// let csv = scanner.get_last_results_as_csv_string().unwrap();
+    /// assert!(csv.starts_with("ip,port,state\n127.0.0.1,80,"));
+    /// ```
+    ///
+    #[cfg(feature = "csv")]
+    pub fn get_last_results_as_csv_string(&self) -> Result<String, fmt::Error> {
+        use std::fmt::Write;
+
+        let mut csv = String::from("ip,port,state\n");
+
+        for r in self.last_results.iter().flatten() {
+            match r {
+                QScanResult::TcpConnect(x) => {
+                    let state = x.state.label();
+                    writeln!(csv, "{},{},{}", x.target.ip(), x.target.port(), state)?;
+                }
+                QScanResult::Ping(x) => {
+                    let state = match x.state {
+                        QScanPingState::Up => "UP",
+                        QScanPingState::Down => "DOWN",
+                    };
+                    writeln!(csv, "{},,{}", x.target, state)?;
+                }
+                QScanResult::Udp(x) => {
+                    let (ip, port) = (x.target.ip(), x.target.port());
+                    writeln!(csv, "{},{},{}", ip, port, x.state.label())?;
+                }
+            }
+        }
+
+        Ok(csv)
+    }
+
+    /// Return the results of the latest scan as an XML document in a minimal
+    /// nmap-like format (`xml` feature), for the tools that ingest nmap XML
+    /// reports
+    ///
+    /// Each host has its `status`, `address`, PTR `hostnames` (see
+    /// [QScanner::set_resolve_ptr]) and a `port` element for each result
+    /// with its `state` (`open`, `closed` or `filtered`, `open|filtered` for
+    /// UDP ports that did not reply) and detected `service`. A host is `up`
+    /// if it replied to any probe.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// let xml = scanner.get_last_results_as_xml_string().unwrap();
+    /// assert!(xml.contains("<port protocol=\"tcp\" portid=\"80\">"));
+    /// ```
+    ///
+    #[cfg(feature = "xml")]
+    pub fn get_last_results_as_xml_string(&self) -> Result<String, fmt::Error> {
+        use std::fmt::Write;
+
+        let mut hosts: BTreeMap<IpAddr, XmlHost> = BTreeMap::new();
+        for r in self.last_results.iter().flatten() {
+            match r {
+                QScanResult::TcpConnect(x) => {
+                    let host = hosts.entry(x.target.ip()).or_default();
+                    let state = match x.state {
+                        QScanTcpConnectState::Open => "open",
+                        QScanTcpConnectState::Close => "closed",
+                        QScanTcpConnectState::Filtered => "filtered",
+                        // Established connection, as nmap reports it
+                        QScanTcpConnectState::OpenNoResponse => "open",
+                    };
+                    let replied = x.state != QScanTcpConnectState::Filtered;
+                    host.up = Some(host.up.unwrap_or(false) || replied);
+                    if host.hostname.is_none() {
+                        host.hostname = x.hostname.clone();
+                    }
+                    let service = x.detected_service.clone();
+                    host.ports.push(("tcp", x.target.port(), state, service));
+                }
+                QScanResult::Ping(x) => {
+                    let host = hosts.entry(x.target).or_default();
+                    let up = matches!(x.state, QScanPingState::Up);
+                    host.up = Some(host.up.unwrap_or(false) || up);
+                }
+                QScanResult::Udp(x) => {
+                    let host = hosts.entry(x.target.ip()).or_default();
+                    let state = match x.state {
+                        QScanUdpState::Open => "open",
+                        QScanUdpState::Closed => "closed",
+                        QScanUdpState::Filtered => "open|filtered",
+                    };
+                    let replied = x.state != QScanUdpState::Filtered;
+                    host.up = Some(host.up.unwrap_or(false) || replied);
+                    host.ports.push(("udp", x.target.port(), state, None));
+                }
+            }
+        }
+
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let nup = hosts.values().filter(|h| h.up == Some(true)).count();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        writeln!(
+            xml,
+            "<nmaprun scanner=\"qscan\" version=\"{}\" xmloutputversion=\"1.05\">",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        for (ip, host) in &hosts {
+            let status = match host.up {
+                Some(true) => "up",
+                Some(false) => "down",
+                None => "unknown",
+            };
+            let addrtype = if ip.is_ipv4() { "ipv4" } else { "ipv6" };
+            writeln!(xml, "<host>")?;
+            writeln!(xml, "<status state=\"{}\"/>", status)?;
+            writeln!(xml, "<address addr=\"{}\" addrtype=\"{}\"/>", ip, addrtype)?;
+            if let Some(name) = &host.hostname {
+                writeln!(
+                    xml,
+                    "<hostnames><hostname name=\"{}\" type=\"PTR\"/></hostnames>",
+                    xml_escape(name)
+                )?;
+            }
+            if !host.ports.is_empty() {
+                writeln!(xml, "<ports>")?;
+                for (proto, port, state, service) in &host.ports {
+                    write!(
+                        xml,
+                        "<port protocol=\"{}\" portid=\"{}\"><state state=\"{}\"/>",
+                        proto, port, state
+                    )?;
+                    if let Some(service) = service {
+                        write!(xml, "<service name=\"{}\"/>", xml_escape(service))?;
+                    }
+                    writeln!(xml, "</port>")?;
+                }
+                writeln!(xml, "</ports>")?;
+            }
+            writeln!(xml, "</host>")?;
+        }
+        writeln!(
+            xml,
+            "<runstats><finished time=\"{}\"/><hosts up=\"{}\" down=\"{}\" total=\"{}\"/></runstats>",
+            now,
+            nup,
+            hosts.len() - nup,
+            hosts.len()
+        )?;
+        writeln!(xml, "</nmaprun>")?;
+
+        Ok(xml)
+    }
+
+    /// Return the results of the latest scan encoded as a protobuf
+    /// [crate::proto::Results] message (see `proto/qscan.proto`), empty if
+    /// there are no results.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use prost::Message;
+    /// use qscan::proto::Results;
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// let buf = scanner.get_last_results_as_protobuf();
+    /// let res = Results::decode(buf.as_slice()).unwrap();
+    /// assert_eq!(res.results.len(), 1);
+    /// ```
+    ///
+    #[cfg(feature = "protobuf")]
+    pub fn get_last_results_as_protobuf(&self) -> Vec<u8> {
+        use prost::Message;
+
+        let results = crate::proto::Results {
+            results: self
+                .last_results
+                .iter()
+                .flatten()
+                .map(crate::proto::Result::from)
+                .collect(),
+        };
+
+        results.encode_to_vec()
+    }
+
+    /// Write the results of the latest scan in `path` using a compact binary
+    /// format.
+    ///
+    /// The file starts with the 4 bytes magic `QSR1` followed by one 25 bytes
+    /// record for each result:
+    ///
+    /// | offset | size | field                                             |
+    /// |--------|------|---------------------------------------------------|
+    /// | 0      | 1    | IP family: 4 or 6                                 |
+    /// | 1      | 16   | IP, IPv4 addresses use the first 4 bytes          |
+    /// | 17     | 2    | port (big endian), 0 for ping results             |
+    /// | 19     | 1    | scan type: 0 TCP connect, 1 ping, 2 UDP           |
+    /// | 20     | 1    | state: 0 CLOSE/DOWN, 1 OPEN/UP, 2 FILTERED,       |
+    /// |        |      | 3 OPEN_NO_RESPONSE                                |
+    /// | 21     | 4    | RTT in microseconds (big endian), `0xffffffff` if |
+    /// |        |      | not measured (see [QScanTcpConnectResult::rtt])   |
+    #[cfg(feature = "binary")]
+    pub fn write_last_results_binary(&self, path: &Path) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let empty = Vec::new();
+        let results = self.last_results.as_ref().unwrap_or(&empty);
+        let mut buf: Vec<u8> = Vec::with_capacity(4 + results.len() * BINARY_RECORD_LEN);
+        buf.extend_from_slice(BINARY_MAGIC);
+
+        for r in results {
+            let (ip, port, stype, state, rtt) = match r {
+                QScanResult::TcpConnect(x) => (
+                    x.target.ip(),
+                    x.target.port(),
+                    0,
+                    match x.state {
+                        QScanTcpConnectState::Close => 0,
+                        QScanTcpConnectState::Open => 1,
+                        QScanTcpConnectState::Filtered => 2,
+                        QScanTcpConnectState::OpenNoResponse => 3,
+                    },
+                    x.rtt.map_or(BINARY_NO_RTT, |rtt| {
+                        u32::try_from(rtt.as_micros()).unwrap_or(BINARY_NO_RTT - 1)
+                    }),
+                ),
+                QScanResult::Ping(x) => (
+                    x.target,
+                    0,
+                    1,
+                    (x.state == QScanPingState::Up) as u8,
+                    BINARY_NO_RTT,
+                ),
+                QScanResult::Udp(x) => (
+                    x.target.ip(),
+                    x.target.port(),
+                    2,
+                    match x.state {
+                        QScanUdpState::Closed => 0,
+                        QScanUdpState::Open => 1,
+                        QScanUdpState::Filtered => 2,
+                    },
+                    BINARY_NO_RTT,
+                ),
+            };
+
+            let mut ip_bytes = [0u8; 16];
+            match ip {
+                IpAddr::V4(ip) => {
+                    buf.push(4);
+                    ip_bytes[..4].copy_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    buf.push(6);
+                    ip_bytes.copy_from_slice(&ip.octets());
+                }
+            }
+            buf.extend_from_slice(&ip_bytes);
+            buf.extend_from_slice(&port.to_be_bytes());
+            buf.push(stype);
+            buf.push(state);
+            buf.extend_from_slice(&rtt.to_be_bytes());
+        }
+
+        let mut f = File::create(path)?;
+        f.write_all(&buf)?;
+        f.flush()
+    }
+
+    /// Read results written with [QScanner::write_last_results_binary]
+    #[cfg(feature = "binary")]
+    pub fn read_results_binary(path: &Path) -> std::io::Result<Vec<QScanResult>> {
+        let buf = std::fs::read(path)?;
+        let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+
+        if buf.len() < BINARY_MAGIC.len() || &buf[..BINARY_MAGIC.len()] != BINARY_MAGIC {
+            return Err(invalid("not a qscan binary results file"));
+        }
+
+        let records = &buf[BINARY_MAGIC.len()..];
+        if records.len() % BINARY_RECORD_LEN != 0 {
+            return Err(invalid("truncated qscan binary results file"));
+        }
+
+        let mut results = Vec::with_capacity(records.len() / BINARY_RECORD_LEN);
+        for rec in records.chunks_exact(BINARY_RECORD_LEN) {
+            let ip = match rec[0] {
+                4 => IpAddr::V4(Ipv4Addr::new(rec[1], rec[2], rec[3], rec[4])),
+                6 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&rec[1..17]);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                _ => return Err(invalid("invalid IP family")),
+            };
+            let port = u16::from_be_bytes([rec[17], rec[18]]);
+            let rtt = match u32::from_be_bytes([rec[21], rec[22], rec[23], rec[24]]) {
+                BINARY_NO_RTT => None,
+                us => Some(Duration::from_micros(us.into())),
+            };
+
+            results.push(match (rec[19], rec[20]) {
+                (0, state) => QScanResult::TcpConnect(QScanTcpConnectResult {
+                    rtt,
+                    ..QScanTcpConnectResult::new(
+                        SocketAddr::new(ip, port),
+                        match state {
+                            1 => QScanTcpConnectState::Open,
+                            2 => QScanTcpConnectState::Filtered,
+                            3 => QScanTcpConnectState::OpenNoResponse,
+                            _ => QScanTcpConnectState::Close,
+                        },
+                    )
+                }),
+                (1, state) => QScanResult::Ping(QScanPingResult {
+                    target: ip,
+                    state: if state == 1 {
+                        QScanPingState::Up
+                    } else {
+                        QScanPingState::Down
+                    },
+                }),
+                (2, state) => QScanResult::Udp(QScanUdpResult {
+                    target: SocketAddr::new(ip, port),
+                    state: match state {
+                        1 => QScanUdpState::Open,
+                        2 => QScanUdpState::Filtered,
+                        _ => QScanUdpState::Closed,
+                    },
+                }),
+                _ => return Err(invalid("invalid scan type")),
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Blocking TCP connect scan, for synchronous code: the scan runs on a
+    /// new runtime, created for this call only. If called from an async
+    /// context, the scan runs on a separate thread (the calling thread is
+    /// blocked until the scan completes), prefer
+    /// [QScanner::scan_tcp_connect] there.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// let res = scanner.scan_tcp_connect_blocking();
+    /// ```
+    ///
+    pub fn scan_tcp_connect_blocking(&mut self) -> &Vec<QScanResult> {
+        let scan = |scanner: &mut QScanner| {
+            tokio::runtime::Runtime::new()
+                .expect("Error creating the scan runtime")
+                .block_on(scanner.scan_tcp_connect());
+        };
+
+        // A runtime cannot be blocked on from a thread driving another one
+        match tokio::runtime::Handle::try_current() {
+            Ok(_) => std::thread::scope(|s| {
+                s.spawn(|| scan(self))
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            }),
+            Err(_) => scan(self),
+        }
+
+        self.last_results.as_ref().unwrap()
+    }
+
+    /// Async TCP connect scan
+    ///
+    /// The scan does not create any runtime, it can be awaited from the
+    /// application runtime (see also [QScanner::scan_tcp_connect_blocking]).
+    ///
+    /// # Return
+    ///
+    /// A vector of [SocketAddr] for each open port found.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+    /// ```
+    ///
+    /// From an async application:
+    ///
+    /// ```ignore
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut scanner = QScanner::new("127.0.0.1", "80");
+    ///     let res = scanner.scan_tcp_connect().await;
+    /// }
+    /// ```
+    ///
+    pub async fn scan_tcp_connect(&mut self) -> &Vec<QScanResult> {
+        if self.refuse_oversized(self.target_count(), "sockets") {
+            return self.last_results.as_ref().unwrap();
+        }
+        let start = (SystemTime::now(), Instant::now());
+        self.reset_retry_budget();
+        self.reset_banner_budget();
+        self.reset_adaptive_timeout();
+        self.reset_host_start();
+        self.reset_scan_spread(self.sockets_count());
+        let mut sock_res: Vec<QScanResult> = Vec::new();
+        let mut sock_err: Vec<(SocketAddr, String)> = Vec::new();
+        let mut sock_it = self.sock_iter().peekable();
+        let mut ftrs = FuturesUnordered::new();
+        let concurrency = self.concurrency();
+        let group_size = std::cmp::min(self.probe_coalescing.get(), concurrency);
+        let max_groups = std::cmp::max(concurrency / group_size, 1);
+        let mut capped = false;
+        let mut cancelled = self.cancellable();
+        let total = self.sockets_count();
+        self.start_progress();
+        let (mut nopen, mut closed, mut errors) = (0, 0, 0);
+        let ntiers = std::cmp::max(self.timeout_tiers.len(), 1);
+        let mut tier_stats: Vec<QScanTierStats> = (0..ntiers)
+            .map(|tier| QScanTierStats {
+                timeout: self.tier_timeout(tier),
+                probed: 0,
+                open: 0,
+                closed: 0,
+                errors: 0,
+                timed_out: 0,
+            })
+            .collect();
+        let mut timed_out: VecDeque<(SocketAddr, usize)> = VecDeque::new();
+        // Sockets not probed because of "too many open files", and the
+        // batch size (in groups) that fits the available file descriptors
+        let mut fd_retry: VecDeque<SocketAddr> = VecDeque::new();
+        let mut fd_window = max_groups;
+        let (mut fd_exhausted, mut fd_stopped) = (false, false);
+        #[cfg(feature = "tui")]
+        let mut live_table = self.live_table(total);
+
+        loop {
+            let mut window = self.dispatch_window(max_groups, group_size, sock_res.len());
+            if window < max_groups && !capped {
+                warn!("approaching the memory cap, reducing the batch size");
+                capped = true;
+            }
+            if window == 1 && self.max_memory.is_some() && !sock_res.is_empty() {
+                self.spill_results(&mut sock_res, &mut sock_err);
+                window = self.dispatch_window(max_groups, group_size, 0);
+            }
+            let window = std::cmp::min(window, fd_window);
+
+            while !fd_stopped && ftrs.len() < window {
+                if let Some(socket) = fd_retry.pop_front() {
+                    ftrs.push(self.scan_sockets_tcp_connect(vec![socket], 0));
+                    continue;
+                }
+
+                let group = next_host_group(&mut sock_it, group_size);
+                if !group.is_empty() {
+                    ftrs.push(self.scan_sockets_tcp_connect(group, 0));
+                } else if let Some((socket, tier)) = timed_out.pop_front() {
+                    ftrs.push(self.scan_sockets_tcp_connect(vec![socket], tier));
+                } else {
+                    break;
+                }
+            }
+
+            let (tier, results) = match next_or_cancelled(&mut ftrs, &mut cancelled).await {
+                Some(results) => results,
+                None => break,
+            };
+
+            for result in results {
+                if let Err(error) = &result {
+                    // The probes in flight hold file descriptors, that are
+                    // released as they complete
+                    if error.fd_exhausted && !ftrs.is_empty() {
+                        if !fd_exhausted {
+                            warn!(
+                                "too many open files, reducing the batch size to {}",
+                                ftrs.len() * group_size
+                            );
+                        }
+                        fd_window = std::cmp::min(fd_window, ftrs.len());
+                        fd_exhausted = true;
+                        fd_retry.push_back(error.sock);
+                        continue;
+                    } else if error.fd_exhausted && !fd_stopped {
+                        error!("too many open files, stopping the scan");
+                        fd_exhausted = true;
+                        fd_stopped = true;
+                    }
+                }
+
+                let stats = &mut tier_stats[tier];
+                stats.probed += 1;
+                match &result {
+                    Ok(_) => stats.open += 1,
+                    Err(e) if e.timed_out => stats.timed_out += 1,
+                    Err(e) if e.closed => stats.closed += 1,
+                    Err(_) => stats.errors += 1,
+                }
+
+                if let Err(error) = &result {
+                    if error.timed_out && tier + 1 < ntiers {
+                        timed_out.push_back((error.sock, tier + 1));
+                        continue;
+                    }
+                }
+
+                match result {
+                    Ok(open) => {
+                        let socket = open.target;
+                        let responsive = open.state == QScanTcpConnectState::Open;
+                        match self.print_mode {
+                            QSPrintMode::RealTime if responsive => {
+                                self.print_line(socket);
+                            }
+                            QSPrintMode::RealTimeAll => {
+                                self.print_line(format_args!("{}:{}", socket, open.state.label()));
+                            }
+                            _ => {}
+                        }
+                        if responsive {
+                            self.report_open(socket);
+
+                            #[cfg(feature = "syslog")]
+                            self.syslog_open(socket);
+                        }
+
+                        sock_res.push(QScanResult::TcpConnect(open));
+                        nopen += 1;
+                    }
+                    Err(error) => {
+                        if let QSPrintMode::RealTimeAll = self.print_mode {
+                            self.print_line(format_args!("{}:{}", error.sock, error.state.label()));
+                        }
+
+                        if error.closed {
+                            closed += 1;
+                        } else {
+                            errors += 1;
+                        }
+
+                        sock_res.push(QScanResult::TcpConnect(error.to_result()));
+                        sock_err.push((error.sock, error.msg));
+                    }
+                }
+
+                self.report_progress(nopen + closed + errors, total);
+
+                #[cfg(feature = "tui")]
+                if let Some(table) = &mut live_table {
+                    table.update(sock_res.last().unwrap());
+                }
+            }
+        }
+
+        #[cfg(feature = "tui")]
+        if let Some(table) = &mut live_table {
+            table.finish();
+        }
+        self.finish_progress(nopen + closed + errors, total);
+
+        drop(ftrs);
+        drop(sock_it);
+        // A scan cancelled during the probes has already completed `cancelled`
+        if self.resolve_ptr && !*self.cancel.borrow() {
+            let resolve = Box::pin(self.resolve_hostnames(&mut sock_res));
+            future::select(resolve, &mut cancelled).await;
+        }
+        self.set_last_results(sock_res);
+        self.last_errors = sock_err;
+        self.last_tier_stats = match self.timeout_tiers.is_empty() {
+            true => Vec::new(),
+            false => tier_stats,
+        };
+        self.last_stats = Some(QScanStats {
+            start: start.0,
+            end: SystemTime::now(),
+            elapsed: start.1.elapsed(),
+            total,
+            open: nopen,
+            closed,
+            errors,
+            fd_exhausted,
+        });
+        self.last_results.as_ref().unwrap()
+    }
+
+    /// Async TCP connect scan, failing if there are too many targets
+    ///
+    /// Same as [QScanner::scan_tcp_connect], but an oversized scan (see
+    /// [QScanner::set_max_targets]) returns an `InvalidInput` error instead
+    /// of only logging it. On error, the cached results are cleared.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("10.0.0.0/8", "1-65535");
+    /// scanner.set_max_targets(1_000_000);
+    /// let res = Runtime::new().unwrap().block_on(scanner.try_scan_tcp_connect());
+    /// assert!(res.is_err());
+    /// ```
+    ///
+    pub async fn try_scan_tcp_connect(&mut self) -> io::Result<&Vec<QScanResult>> {
+        if let Err(e) = self.check_probes(self.target_count(), "sockets") {
+            self.set_last_results(Vec::new());
+            return Err(e);
+        }
+        Ok(self.scan_tcp_connect().await)
+    }
+
+    /// Async TCP connect RTT scan
+    ///
+    /// Only the time needed to establish the connection is measured. As soon as
+    /// the connection is established it is abortively closed (RST, linger 0)
+    /// without any further shutdown.
+    ///
+    /// # Return
+    ///
+    /// A vector of [SocketAddr] with the measured connect RTT, `None` if the
+    /// connection could not be established. The vector is empty if the
+    /// sockets exceed the targets cap (see [QScanner::set_max_targets]) and
+    /// only holds the probed sockets if the scan is cancelled (see
+    /// [QScanner::cancel_handle]).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let scanner = QScanner::new("127.0.0.1", "80");
+    /// let res = Runtime::new().unwrap().block_on(scanner.scan_rtt());
+    /// ```
+    ///
+    pub async fn scan_rtt(&self) -> Vec<(SocketAddr, Option<Duration>)> {
+        if let Err(e) = self.check_probes(self.target_count(), "sockets") {
+            error!("{}", e);
+            return Vec::new();
+        }
+        self.reset_retry_budget();
+        self.reset_banner_budget();
+        self.reset_adaptive_timeout();
+        self.reset_host_start();
+        self.reset_scan_spread(self.sockets_count());
+        let mut rtt_res: Vec<(SocketAddr, Option<Duration>)> = Vec::new();
+        let mut sock_it = self.sock_iter();
+        let mut ftrs = FuturesUnordered::new();
+        let mut cancelled = self.cancellable();
+
+        for _ in 0..self.concurrency() {
+            if let Some(socket) = sock_it.next() {
+                ftrs.push(self.scan_socket_rtt(socket));
+            } else {
+                break;
+            }
+        }
+
+        while let Some(result) = next_or_cancelled(&mut ftrs, &mut cancelled).await {
+            if let Some(socket) = sock_it.next() {
+                ftrs.push(self.scan_socket_rtt(socket));
+            }
+
+            rtt_res.push(result);
+        }
+
+        rtt_res
+    }
+
+    /// Async UDP scan
+    ///
+    /// A probe datagram (see [QScanner::set_udp_payload]) is sent to each
+    /// target: any reply means the port is open, an ICMP port unreachable
+    /// that it is closed. Without a reply within the timeout (after all the
+    /// tries) the port is reported as filtered.
+    ///
+    /// # Return
+    ///
+    /// A vector of [QScanResult::Udp] for each target.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{QScanType, QScanner};
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "53,123");
+    /// scanner.set_scan_type(QScanType::UdpConnect);
+    /// let res = Runtime::new().unwrap().block_on(scanner.scan_udp());
+    /// ```
+    ///
+    pub async fn scan_udp(&mut self) -> &Vec<QScanResult> {
+        if self.refuse_oversized(self.target_count(), "sockets") {
+            return self.last_results.as_ref().unwrap();
+        }
+        self.reset_retry_budget();
+        self.reset_banner_budget();
+        self.reset_adaptive_timeout();
+        self.reset_host_start();
+        self.reset_scan_spread(self.sockets_count());
+        let mut udp_res: Vec<QScanResult> = Vec::new();
+        let mut sock_it = self.sock_iter();
+        let mut ftrs = FuturesUnordered::new();
+        let mut cancelled = self.cancellable();
+        let total = self.sockets_count();
+        self.start_progress();
+        #[cfg(feature = "tui")]
+        let mut live_table = self.live_table(total);
+
+        for _ in 0..self.concurrency() {
+            if let Some(socket) = sock_it.next() {
+                ftrs.push(self.scan_socket_udp(socket));
+            } else {
+                break;
+            }
+        }
+
+        while let Some(result) = next_or_cancelled(&mut ftrs, &mut cancelled).await {
+            if let Some(socket) = sock_it.next() {
+                ftrs.push(self.scan_socket_udp(socket));
+            }
+
+            let socket = result.target;
+            match self.print_mode {
+                QSPrintMode::RealTime if result.state == QScanUdpState::Open => {
+                    self.print_line(socket);
+                }
+                QSPrintMode::RealTimeAll => {
+                    self.print_line(format_args!("{}:{}", socket, result.state.label()));
+                }
+                _ => {}
+            }
+
+            udp_res.push(QScanResult::Udp(result));
+            self.report_progress(udp_res.len(), total);
+
+            #[cfg(feature = "tui")]
+            if let Some(table) = &mut live_table {
+                table.update(udp_res.last().unwrap());
+            }
+        }
+
+        #[cfg(feature = "tui")]
+        if let Some(table) = &mut live_table {
+            table.finish();
+        }
+        self.finish_progress(udp_res.len(), total);
+
+        drop(ftrs);
+        drop(sock_it);
+        self.set_last_results(udp_res);
+        self.last_results.as_ref().unwrap()
+    }
+
+    /// Async TCP SYN scan (`raw` feature)
+    ///
+    /// A SYN segment is sent to each target over a raw socket, without
+    /// completing the handshake: a SYN/ACK reply means the port is open, a
+    /// RST that it is closed. Without a reply within the timeout (after all
+    /// the tries) the port is reported as filtered.
+    ///
+    /// The targets are probed in batches of [QScanner::set_batch] sockets:
+    /// the results of a batch are printed (see [QScanner::set_print_mode])
+    /// once all its tries are over. Only IPv4 targets are supported, IPv6
+    /// ones are skipped. Each SYN segment is recorded in the probe audit log
+    /// (see [QScanner::set_probe_audit_log]).
+    ///
+    /// # Return
+    ///
+    /// A vector of [QScanResult::TcpConnect] for each IPv4 target, or an
+    /// error if the raw socket cannot be created (it requires root
+    /// privileges or the CAP_NET_RAW capability) or if there are too many
+    /// targets (see [QScanner::set_max_targets]). On error, the cached
+    /// results are cleared.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use qscan::qscanner::{QScanType, QScanner};
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "22,80,443");
+    /// scanner.set_scan_type(QScanType::Syn);
+    /// match Runtime::new().unwrap().block_on(scanner.scan_syn()) {
+    ///     Ok(res) => println!("{} results", res.len()),
+    ///     Err(e) => println!("SYN scan failed: {}", e),
+    /// }
+    /// ```
+    ///
+    #[cfg(feature = "raw")]
+    pub async fn scan_syn(&mut self) -> io::Result<&Vec<QScanResult>> {
+        if let Err(e) = self.check_probes(self.target_count(), "sockets") {
+            self.set_last_results(Vec::new());
+            return Err(e);
+        }
+        self.reset_host_start();
+        let socket = match SynSocket::new(SYN_RECV_POLL) {
+            Ok(socket) => Arc::new(socket),
+            Err(e) => {
+                self.set_last_results(Vec::new());
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("SYN scan needs root privileges or CAP_NET_RAW: {}", e),
+                ));
+            }
+        };
+
+        let mut rng = self.rng();
+        let src_port: u16 = rng.gen_range(32768..61000);
+        let seq: u32 = rng.gen();
+
+        let replies: Arc<std::sync::Mutex<HashMap<SocketAddrV4, bool>>> = Arc::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let receiver = {
+            let (socket, replies, stop) = (socket.clone(), replies.clone(), stop.clone());
+            tokio::task::spawn_blocking(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let reply = match socket.recv() {
+                        Some(r) if r.dst_port == src_port && r.ack == seq.wrapping_add(1) => r,
+                        _ => continue,
+                    };
+                    if let Some(open) = reply.is_open() {
+                        replies.lock().unwrap().entry(reply.from).or_insert(open);
+                    }
+                }
+            })
+        };
+
+        let total = self.sockets_count();
+        self.start_progress();
+        let mut sources: HashMap<Ipv4Addr, Ipv4Addr> = HashMap::new();
+        let mut syn_res: Vec<QScanResult> = Vec::new();
+        let mut cancelled = self.cancellable();
+        let mut sock_it = self.sock_iter();
+
+        // The targets are probed one batch at a time, so that large ranges
+        // are never collected in memory
+        loop {
+            if futures::FutureExt::now_or_never(&mut cancelled).is_some() {
+                break;
+            }
+            let batch: Vec<SocketAddr> = sock_it.by_ref().take(self.batch.into()).collect();
+            if batch.is_empty() {
+                break;
+            }
+            let targets = self.syn_sources(batch, &mut sources);
+
+            let mut pending = targets.clone();
+            for ntry in 0..self.tries.get() {
+                for (target, src) in &pending {
+                    self.wait_rate().await;
+                    let sent = SystemTime::now();
+                    match socket.send_syn(*src, src_port, *target, seq) {
+                        Ok(()) => {
+                            let source = SocketAddr::new(IpAddr::V4(*src), src_port);
+                            self.audit_probe(sent, "tcp", Some(source), target, &[]);
+                        }
+                        Err(e) => warn!("cannot send SYN to {}: {}", target, e),
+                    }
+                }
+                time::sleep(self.try_timeout(self.to, ntry)).await;
+
+                let replies = replies.lock().unwrap();
+                pending.retain(|(target, _)| !replies.contains_key(target));
+                if pending.is_empty() {
+                    break;
+                }
+            }
+
+            // Late replies to the previous batches are dropped with the
+            // replies of this one
+            let batch_replies = std::mem::take(&mut *replies.lock().unwrap());
+            for (target, _) in targets {
+                let socket = SocketAddr::V4(target);
+                let result = match batch_replies.get(&target) {
+                    Some(true) => QScanTcpConnectResult::new(socket, QScanTcpConnectState::Open),
+                    Some(false) => QScanTcpConnectResult {
+                        reason: Some("RST received".to_string()),
+                        ..QScanTcpConnectResult::new(socket, QScanTcpConnectState::Close)
+                    },
+                    None => QScanTcpConnectResult {
+                        reason: Some("no reply".to_string()),
+                        ..QScanTcpConnectResult::new(socket, QScanTcpConnectState::Filtered)
+                    },
+                };
+
+                match self.print_mode {
+                    QSPrintMode::RealTime if result.state == QScanTcpConnectState::Open => {
+                        self.print_line(socket);
+                    }
+                    QSPrintMode::RealTimeAll => {
+                        self.print_line(format_args!("{}:{}", socket, result.state.label()));
+                    }
+                    _ => {}
+                }
+                if result.state == QScanTcpConnectState::Open {
+                    self.report_open(socket);
+                }
+
+                syn_res.push(QScanResult::TcpConnect(result));
+                self.report_progress(syn_res.len(), total);
+            }
+        }
+        stop.store(true, Ordering::Relaxed);
+        let _ = receiver.await;
+        self.finish_progress(syn_res.len(), total);
+
+        drop(sock_it);
+        self.set_last_results(syn_res);
+        Ok(self.last_results.as_ref().unwrap())
+    }
+
+    /// IPv4 sockets of `batch` with the source address of their SYN
+    /// segments, cached in `sources`. IPv6 sockets and targets without a
+    /// route are skipped.
+    #[cfg(feature = "raw")]
+    fn syn_sources(
+        &self,
+        batch: Vec<SocketAddr>,
+        sources: &mut HashMap<Ipv4Addr, Ipv4Addr>,
+    ) -> Vec<(SocketAddrV4, Ipv4Addr)> {
+        let mut targets = Vec::with_capacity(batch.len());
+        for target in batch {
+            let target = match target {
+                SocketAddr::V4(target) => target,
+                SocketAddr::V6(target) => {
+                    warn!("{} skipped, SYN scan supports only IPv4", target);
+                    continue;
+                }
+            };
+            let src = match self.source_addr {
+                Some(IpAddr::V4(src)) => src,
+                _ => match sources.get(target.ip()) {
+                    Some(src) => *src,
+                    None => match syn::local_ipv4(*target.ip()) {
+                        Ok(src) => *sources.entry(*target.ip()).or_insert(src),
+                        Err(e) => {
+                            warn!("{} skipped: {}", target, e);
+                            continue;
+                        }
+                    },
+                },
+            };
+            targets.push((target, src));
+        }
+        targets
+    }
+
+    /// Async TCP connect scan sending each result to `tx` as soon as it is
+    /// available, before a new probe takes its place in the batch
+    ///
+    /// The channel is closed (`tx` is dropped) when the scan completes. If
+    /// the receiver is dropped, the scan stops. Results are not cached (see
+    /// [QScanner::get_last_results]) and not printed.
+    ///
+    /// # Arguments
+    ///
+    /// * `tx` - sender of the channel receiving the results
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::{QScanResult, QScanner};
+    /// use tokio::runtime::Runtime;
+    /// use tokio::sync::mpsc;
+    /// let scanner = QScanner::new("127.0.0.1", "22,80,443");
+    /// Runtime::new().unwrap().block_on(async {
+    ///     let (tx, mut rx) = mpsc::channel(16);
+    ///     let report = async {
+    ///         while let Some(QScanResult::TcpConnect(sa)) = rx.recv().await {
+    ///             println!("{}: {:?}", sa.target, sa.state);
+    ///         }
+    ///     };
+    ///     futures::join!(scanner.scan_tcp_connect_stream(tx), report);
+    /// });
+    /// ```
+    ///
+    pub async fn scan_tcp_connect_stream(&self, tx: mpsc::Sender<QScanResult>) {
+        self.reset_retry_budget();
+        self.reset_banner_budget();
+        self.reset_adaptive_timeout();
+        self.reset_host_start();
+        self.reset_scan_spread(self.sockets_count());
+        let mut sock_it = self.sock_iter();
+        let mut ftrs = FuturesUnordered::new();
+
+        for _ in 0..self.concurrency() {
+            if let Some(socket) = sock_it.next() {
+                ftrs.push(self.scan_socket_tcp_connect(socket, self.to));
+            } else {
+                break;
+            }
+        }
+
+        while let Some(result) = ftrs.next().await {
+            if tx.send(tcp_connect_result(result)).await.is_err() {
+                break;
+            }
+
+            if let Some(socket) = sock_it.next() {
+                ftrs.push(self.scan_socket_tcp_connect(socket, self.to));
+            }
+        }
+    }
+
+    /// Ping scan feeding a TCP connect scan: as soon as a target replies to
+    /// the ping, its ports are scanned with a TCP connect scan.
+    ///
+    /// Both ping and TCP connect results are returned by the same stream, as
+    /// soon as they are available. At most `batch` pings and `batch` TCP
+    /// connections are in flight at the same time. Results are not cached
+    /// (see [QScanner::get_last_results]) and not printed.
+    ///
+    /// It must be called from within a tokio runtime.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use qscan::qscanner::{QScanResult, QScanner};
+    /// use tokio::runtime::Runtime;
+    /// let scanner = QScanner::new("192.168.1.0/24", "22,80,443");
+    /// Runtime::new().unwrap().block_on(async {
+    ///     let mut results = Box::pin(scanner.scan_discover_and_connect_stream());
+    ///     while let Some(r) = results.next().await {
+    ///         if let QScanResult::TcpConnect(sa) = r {
+    ///             println!("{}: {:?}", sa.target, sa.state);
+    ///         }
+    ///     }
+    /// });
+    /// ```
+    ///
+    pub fn scan_discover_and_connect_stream(&self) -> impl Stream<Item = QScanResult> + '_ {
+        let client_v4 = surge_ping::Client::new(&surge_ping::Config::default())
+            .expect("Error creating ping IPv4 Client");
+        let client_v6 = surge_ping::Client::new(
+            &surge_ping::Config::builder()
+                .kind(surge_ping::ICMP::V6)
+                .build(),
+        )
+        .expect("Error creating ping IPv6 client");
+        let batch = self.concurrency();
+        let connect_permits = Arc::new(Semaphore::new(batch));
+        self.reset_retry_budget();
+        self.reset_banner_budget();
+        self.reset_adaptive_timeout();
+        self.reset_host_start();
+        self.reset_scan_spread(self.targets_count().saturating_add(self.sockets_count()));
+
+        stream::iter(
+            self.targets_iter()
+                .filter(|ip| !self.local_only || is_local(*ip)),
+        )
+        .map(move |ip| {
+            let (client_v4, client_v6) = (client_v4.clone(), client_v6.clone());
+            async move { self.scan_ip_ping(ip, &client_v4, &client_v6).await }
+        })
+        .buffer_unordered(batch)
+        .map(
+            move |result| -> std::pin::Pin<Box<dyn Stream<Item = QScanResult> + '_>> {
+                match result {
+                    Ok(ip) => {
+                        let ports = self.host_ports.get(&ip).unwrap_or(&self.ports);
+                        let permits = connect_permits.clone();
+                        let connects = stream::iter(ports.iter())
+                            .map(move |port| {
+                                let permits = permits.clone();
+                                async move {
+                                    let _permit = permits.acquire().await.unwrap();
+                                    self.scan_socket_tcp_connect(
+                                        SocketAddr::new(ip, *port),
+                                        self.to,
+                                    )
+                                    .await
+                                }
+                            })
+                            .buffer_unordered(batch)
+                            .map(tcp_connect_result);
+                        let up = QScanResult::Ping(QScanPingResult {
+                            target: ip,
+                            state: QScanPingState::Up,
+                        });
+
+                        Box::pin(stream::once(future::ready(up)).chain(connects))
+                    }
+                    Err(ip) => Box::pin(stream::once(future::ready(QScanResult::Ping(
+                        QScanPingResult {
+                            target: ip,
+                            state: QScanPingState::Down,
+                        },
+                    )))),
+                }
+            },
+        )
+        .flatten_unordered(None)
+    }
+
+    /// TODO: add comments
+    pub async fn scan_ping(&mut self) -> &Vec<QScanResult> {
+        if self.refuse_oversized(self.addresses_count(), "addresses") {
+            return self.last_results.as_ref().unwrap();
+        }
+        let client_v4 = surge_ping::Client::new(&surge_ping::Config::default())
+            .expect("Error creating ping IPv4 Client");
+        let client_v6 = surge_ping::Client::new(
+            &surge_ping::Config::builder()
+                .kind(surge_ping::ICMP::V6)
+                .build(),
+        )
+        .expect("Error creating ping IPv6 client");
+        self.reset_host_start();
+        self.reset_scan_spread(self.targets_count());
+        let mut ip_res: Vec<QScanResult> = Vec::new();
+        let mut ftrs = FuturesUnordered::new();
+        let mut cancelled = self.cancellable();
+        let off_host: HashSet<IpAddr> = self
+            .ips
+            .iter()
+            .filter(|ip| self.local_only && !is_local(**ip))
+            .copied()
+            .collect();
+        for ip in &off_host {
+            warn!("{} is not a local address, skipping", ip);
+        }
+        let mut ip_it = self
+            .targets_iter()
+            .filter(|ip| !off_host.contains(ip))
+            .filter(|ip| self.cidrs.is_empty() || !self.local_only || is_local(*ip));
+        let total = self.targets_count() - off_host.len();
+        self.start_progress();
+        #[cfg(feature = "tui")]
+        let mut live_table = self.live_table(total);
+
+        for _ in 0..self.batch {
+            if let Some(ip) = ip_it.next() {
+                ftrs.push(self.scan_ip_ping(ip, &client_v4, &client_v6));
+            } else {
+                break;
+            }
+        }
+
+        while let Some(result) = next_or_cancelled(&mut ftrs, &mut cancelled).await {
+            if let Some(ip) = ip_it.next() {
+                ftrs.push(self.scan_ip_ping(ip, &client_v4, &client_v6));
+            }
+
+            match result {
+                Ok(ip) => {
+                    match self.print_mode {
+                        QSPrintMode::RealTime => {
+                            self.print_line(ip);
+                        }
+                        QSPrintMode::RealTimeAll => {
+                            self.print_line(format_args!("{}:UP", ip));
+                        }
+                        _ => {}
+                    }
+
+                    ip_res.push(QScanResult::Ping(QScanPingResult {
+                        target: ip,
+                        state: QScanPingState::Up,
+                    }));
+                }
+                Err(ip) => {
+                    if let QSPrintMode::RealTimeAll = self.print_mode {
+                        self.print_line(format_args!("{}:DOWN", ip));
+                    }
+
+                    ip_res.push(QScanResult::Ping(QScanPingResult {
+                        target: ip,
+                        state: QScanPingState::Down,
+                    }));
+                }
+            }
+
+            self.report_progress(ip_res.len(), total);
+
+            #[cfg(feature = "tui")]
+            if let Some(table) = &mut live_table {
+                table.update(ip_res.last().unwrap());
+            }
+        }
+
+        #[cfg(feature = "tui")]
+        if let Some(table) = &mut live_table {
+            table.finish();
+        }
+        self.finish_progress(ip_res.len(), total);
 
         drop(ftrs);
-        self.last_results = Some(sock_res);
+        drop(ip_it);
+        self.set_last_results(ip_res);
         self.last_results.as_ref().unwrap()
     }
 
-    /// TODO: add comments
-    pub async fn scan_ping(&mut self) -> &Vec<QScanResult> {
-        let client_v4 = surge_ping::Client::new(&surge_ping::Config::default())
-            .expect("Error creating ping IPv4 Client");
-        let client_v6 = surge_ping::Client::new(
-            &surge_ping::Config::builder()
-                .kind(surge_ping::ICMP::V6)
-                .build(),
-        )
-        .expect("Error creating ping IPv6 client");
-        let mut ip_res: Vec<QScanResult> = Vec::new();
-        let mut ftrs = FuturesUnordered::new();
-        let mut ip_it = self.ips.iter();
+    /// Iterator over the sockets to scan. IPv6 targets are skipped (with a
+    /// warning) if the host has no route to them, off-host targets are
+    /// skipped in local only mode.
+    fn sock_iter(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        let mut skipped: HashSet<IpAddr> = self
+            .ips
+            .iter()
+            .map(|ip| self.rewrite(*ip))
+            .filter(|ip| ip.is_ipv6() && !is_routable(*ip))
+            .collect();
+
+        for ip in &skipped {
+            warn!("no IPv6 connectivity to {}, skipping", ip);
+        }
+
+        for ip in self.off_host_targets() {
+            warn!("{} is not a local address, skipping", ip);
+            skipped.insert(ip);
+        }
+
+        let sampled = self.sampled_ips();
+
+        let sockets = match self.randomize {
+            true => Either::Right(sockiter::ShuffledSockIter::new(
+                &self.ips,
+                &self.cidrs,
+                &self.ports,
+                &self.host_ports,
+                &mut self.rng(),
+            )),
+            false => Either::Left(sockiter::SockIter::new(
+                &self.ips,
+                &self.cidrs,
+                &self.ports,
+                &self.host_ports,
+            )),
+        };
+
+        sockets
+            .filter(move |socket| !self.is_excluded(socket.ip()))
+            .filter(move |socket| sampled.as_ref().is_none_or(|s| s.contains(&socket.ip())))
+            .map(move |socket| SocketAddr::new(self.rewrite(socket.ip()), socket.port()))
+            .filter(move |socket| !skipped.contains(&socket.ip()))
+            // Addresses of the large CIDRs are checked one by one
+            .filter(move |socket| {
+                self.cidrs.is_empty()
+                    || ((!self.local_only || is_local(socket.ip()))
+                        && (socket.is_ipv4() || is_routable(socket.ip())))
+            })
+    }
+
+    /// Write the TCP connect results collected so far to the output writer
+    /// and drop them, with their errors (see [QScanner::set_max_memory_mb])
+    fn spill_results(
+        &self,
+        results: &mut Vec<QScanResult>,
+        errors: &mut Vec<(SocketAddr, String)>,
+    ) {
+        debug!("memory cap reached, spilling {} results", results.len());
+        if !matches!(self.print_mode, QSPrintMode::RealTimeAll) {
+            for r in results.iter() {
+                if let QScanResult::TcpConnect(r) = r {
+                    self.print_line(format_args!("{}:{}", r.target, r.state.label()));
+                }
+            }
+        }
+        results.clear();
+        errors.clear();
+    }
+
+    /// Number of groups of sockets that can be in flight without exceeding
+    /// the memory cap (see [QScanner::set_max_memory_mb]), at least one
+    fn dispatch_window(&self, max_groups: usize, group_size: usize, nresults: usize) -> usize {
+        match self.max_memory {
+            Some(max_memory) => {
+                let available = max_memory.saturating_sub(nresults.saturating_mul(RESULT_SIZE_EST));
+                (available / IN_FLIGHT_SIZE_EST.saturating_mul(group_size)).clamp(1, max_groups)
+            }
+            None => max_groups,
+        }
+    }
+
+    /// Sockets that the next TCP connect scan would probe, without probing
+    /// them (dry run): all the filters (excludes, sampling, local only mode,
+    /// ...) are applied.
+    ///
+    /// The sockets are generated lazily, so that even huge ranges can be
+    /// inspected: see [QScanner::get_sockets_count] for their number.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::net::SocketAddr;
+    /// let scanner = QScanner::new("127.0.0.1,127.0.0.2", "22,80");
+    /// let sockets: Vec<SocketAddr> = scanner.enumerate_targets().collect();
+    /// assert_eq!(sockets.len(), 4);
+    /// ```
+    pub fn enumerate_targets(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.sock_iter()
+    }
+
+    /// Return the number of sockets to scan, before the excludes and the
+    /// other filters are applied (see [QScanner::enumerate_targets]). It is
+    /// computed without enumerating the sockets, and saturates to
+    /// `usize::MAX`: see [QScanner::target_count] for the exact count.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let scanner = QScanner::new("10.0.0.0/8", "1-1024");
+    /// assert_eq!(scanner.get_sockets_count(), (1 << 24) * 1024);
+    /// ```
+    pub fn get_sockets_count(&self) -> usize {
+        self.sockets_count()
+    }
+
+    /// Return the number of sockets to scan (target addresses × ports, plus
+    /// the ports of the hosts in the host port map, see
+    /// [QScanner::set_host_port_map]), before the excludes and the other
+    /// filters are applied. It is computed without enumerating the sockets
+    /// and saturates to `u64::MAX` only for huge IPv6 ranges.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// let scanner = QScanner::new("10.0.0.0/8", "1-65535");
+    /// assert_eq!(scanner.target_count(), (1 << 24) * 65535);
+    /// ```
+    pub fn target_count(&self) -> u64 {
+        let unmapped = self
+            .ips
+            .iter()
+            .filter(|ip| !self.host_ports.contains_key(ip))
+            .count() as u64;
+        let mapped: u64 = self
+            .ips
+            .iter()
+            .filter_map(|ip| self.host_ports.get(ip).map(|p| p.len() as u64))
+            .sum();
+
+        self.cidrs
+            .iter()
+            .fold(unmapped, |n, (cidr, _)| {
+                n.saturating_add(cidr_size_u64(cidr))
+            })
+            .saturating_mul(self.ports.len() as u64)
+            .saturating_add(mapped)
+    }
+
+    /// Number of target addresses (before any sampling or skipping)
+    fn addresses_count(&self) -> u64 {
+        self.cidrs
+            .iter()
+            .fold(self.ips.len() as u64, |n, (cidr, _)| {
+                n.saturating_add(cidr_size_u64(cidr))
+            })
+    }
+
+    /// Number of target addresses (before any sampling or skipping),
+    /// saturating to `usize::MAX`
+    fn targets_count(&self) -> usize {
+        usize::try_from(self.addresses_count()).unwrap_or(usize::MAX)
+    }
+
+    /// Number of sockets to scan (before any sampling or skipping),
+    /// saturating to `usize::MAX`
+    fn sockets_count(&self) -> usize {
+        usize::try_from(self.target_count()).unwrap_or(usize::MAX)
+    }
+
+    /// Live table for a scan of `total` targets, if enabled by the print mode
+    #[cfg(feature = "tui")]
+    fn live_table(&self, total: usize) -> Option<LiveTable<'_>> {
+        matches!(self.print_mode, QSPrintMode::LiveTable)
+            .then(|| LiveTable::new(total, &self.output.writer, self.output.tty))
+    }
+
+    /// Scan a group of sockets (of the same host) in a single future, see
+    /// [QScanner::set_host_probe_coalescing]
+    /// The sockets are probed with the timeout of the given `tier`
+    /// (see [QScanner::set_timeout_tiers]), which is returned along with the
+    /// results.
+    async fn scan_sockets_tcp_connect(
+        &self,
+        sockets: Vec<SocketAddr>,
+        tier: usize,
+    ) -> (usize, Vec<Result<QScanTcpConnectResult, QScanError>>) {
+        let to = self.tier_timeout(tier);
+        let results = future::join_all(
+            sockets
+                .into_iter()
+                .map(|socket| self.scan_socket_tcp_connect(socket, to)),
+        )
+        .await;
+
+        (tier, results)
+    }
+
+    async fn scan_socket_tcp_connect(
+        &self,
+        socket: SocketAddr,
+        to: Duration,
+    ) -> Result<QScanTcpConnectResult, QScanError> {
+        self.wait_time_window().await;
+        self.wait_host_start(socket.ip()).await;
+        self.wait_scan_spread().await;
+        self.wait_rate().await;
+
+        let to = self.adaptive_timeout(to);
+
+        if let Some(connector) = &self.blocking_connector {
+            return self.scan_socket_blocking(socket, &connector.0, to).await;
+        }
+
+        let tries = self.tries.get();
+
+        for ntry in 0..tries {
+            if ntry > 0 {
+                trace!("{}: try {} of {}", socket, ntry + 1, tries);
+            }
+            let start = Instant::now();
+            match self.tcp_connect(socket, self.try_timeout(to, ntry)).await {
+                Ok(Ok(mut x)) => {
+                    let mut res = QScanTcpConnectResult::new(socket, QScanTcpConnectState::Open);
+                    res.confidence = OPEN_CONFIDENCE;
+                    res.peer_mss = stream_mss(&x);
+                    res.rtt = Some(start.elapsed());
+                    self.observe_rtt(start.elapsed());
+
+                    if let Some(len) = self.banner_len {
+                        let to = std::cmp::min(BANNER_TIMEOUT_DEF, to);
+                        let budget = self.banner_budget.map(|_| &self.banner_bytes_left);
+                        let banner = read_response(&mut x, to, len, budget).await;
+                        res.banner = (!banner.is_empty()).then_some(banner);
+                        if self.require_banner && res.banner.is_none() {
+                            res.state = QScanTcpConnectState::OpenNoResponse;
+                        }
+                    }
+
+                    if self.service_detection {
+                        res.detected_service = self
+                            .detect_service(
+                                &mut x,
+                                socket,
+                                self.banner_len
+                                    .map(|_| res.banner.as_deref().unwrap_or(&[])),
+                            )
+                            .await;
+                        if res.detected_service.is_some() {
+                            res.confidence = OPEN_SERVICE_CONFIDENCE;
+                        }
+                    }
+
+                    if self.http_probe {
+                        res.http = self.http_probe(&mut x, socket, to).await;
+                    }
+
+                    // The connection was established: the port is open
+                    // whatever the outcome of the shutdown
+                    if let Err(e) = self.close_stream(x).await {
+                        debug!("{}: shutdown error: {}", socket, e);
+                        res.confidence = SHUTDOWN_ERROR_CONFIDENCE;
+                    }
+                    return Ok(res);
+                }
+                Ok(Err(e)) => {
+                    if fd_exhausted_error(&e) {
+                        return Err(QScanError::fd_exhausted(&e, socket, ConnectPath::Direct));
+                    }
+
+                    let refused_rtt =
+                        (e.kind() == io::ErrorKind::ConnectionRefused).then(|| start.elapsed());
+                    if let Some(rtt) = refused_rtt {
+                        self.observe_rtt(rtt);
+                    }
+
+                    let err_str = e.to_string();
+
+                    if ntry == tries - 1 || !self.retryable(e.kind()) || !self.take_retry() {
+                        debug!("{}: {}", socket, err_str);
+                        return Err(QScanError {
+                            msg: err_str,
+                            sock: socket,
+                            confidence: closed_confidence(Some(e.kind()), ntry + 1),
+                            via: ConnectPath::Direct,
+                            closed: closed_error(Some(e.kind())),
+                            timed_out: e.kind() == io::ErrorKind::TimedOut,
+                            fd_exhausted: false,
+                            state: error_state(Some(e.kind())),
+                            rtt: refused_rtt,
+                        });
+                    }
+                }
+                Err(e) => {
+                    let err_str = e.to_string();
+
+                    if ntry == tries - 1 || !self.take_retry() {
+                        debug!("{}: {}", socket, err_str);
+                        return Err(QScanError {
+                            msg: err_str,
+                            sock: socket,
+                            confidence: closed_confidence(None, ntry + 1),
+                            via: ConnectPath::Direct,
+                            closed: true,
+                            timed_out: true,
+                            fd_exhausted: false,
+                            state: QScanTcpConnectState::Filtered,
+                            rtt: None,
+                        });
+                    }
+                }
+            };
+        }
+        unreachable!();
+    }
+
+    /// Run the configured scan (see [QScanner::set_scan_type]) in a background
+    /// task. Must be called from within a tokio runtime.
+    ///
+    /// The scan is cancelled, and its sockets closed, when the returned
+    /// handle is dropped: dropping the handle is equivalent to calling
+    /// [CancelHandle::cancel] (see [QScanner::cancel_handle]). The cancelled
+    /// scan still ends in the background, so that the probes of the
+    /// blocking connector in flight are not followed by new ones.
+    ///
+    /// Only the work of the scan itself is cancelled: the probes and the
+    /// reverse lookups of the open ports (see [QScanner::set_resolve_ptr]).
+    /// Target names are resolved when the targets are set (e.g., with
+    /// [QScanner::set_targets_addr]), before the scanner is moved to the
+    /// background task, so their resolution cannot be cancelled by the
+    /// handle.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use tokio::runtime::Runtime;
+    /// let scanner = QScanner::new("127.0.0.1", "80");
+    /// let scanner = Runtime::new()
+    ///     .unwrap()
+    ///     .block_on(async { scanner.spawn_scan().wait().await })
+    ///     .unwrap();
+    /// assert_eq!(scanner.get_last_results().unwrap().len(), 1);
+    /// ```
+    ///
+    pub fn spawn_scan(mut self) -> ScanHandle {
+        self.cancel.send_replace(false);
+        self.cancel_armed.store(true, Ordering::Relaxed);
+        let cancel = self.cancel_handle();
+
+        let task = tokio::spawn(async move {
+            match self.scan_type {
+                QScanType::TcpConnect => {
+                    self.scan_tcp_connect().await;
+                }
+                QScanType::Ping => {
+                    self.scan_ping().await;
+                }
+                QScanType::UdpConnect => {
+                    self.scan_udp().await;
+                }
+                #[cfg(feature = "raw")]
+                QScanType::Syn => {
+                    if let Err(e) = self.scan_syn().await {
+                        error!("{}", e);
+                    }
+                }
+            }
+            // Scans failing before their start leave the flag armed
+            self.cancel_armed.store(false, Ordering::Relaxed);
+            self
+        });
+
+        ScanHandle {
+            task: Some(task),
+            cancel,
+        }
+    }
+
+    /// Repeatedly run the configured scan (see [QScanner::set_scan_type])
+    ///
+    /// Scans are started at a fixed rate: a new pass starts every `every`,
+    /// regardless of how long the previous one took. If a pass lasts longer
+    /// than `every`, the missed passes are skipped and the next one starts as
+    /// soon as the current one ends.
+    ///
+    /// # Arguments
+    ///
+    /// * `every` - interval between the start of two consecutive passes
+    /// * `iterations` - number of passes, `None` to run forever
+    /// * `on_result` - callback invoked with the results of each pass
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qscan::qscanner::QScanner;
+    /// use std::time::Duration;
+    /// use tokio::runtime::Runtime;
+    /// let mut scanner = QScanner::new("127.0.0.1", "80");
+    /// Runtime::new().unwrap().block_on(scanner.run_scheduled(
+    ///     Duration::from_millis(100),
+    ///     Some(2),
+    ///     |res| println!("{} results", res.len()),
+    /// ));
+    /// ```
+    ///
+    pub async fn run_scheduled(
+        &mut self,
+        every: Duration,
+        iterations: Option<usize>,
+        on_result: impl Fn(&[QScanResult]),
+    ) {
+        let mut interval = time::interval(every);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut pass: usize = 0;
+
+        while iterations.is_none_or(|n| pass < n) {
+            interval.tick().await;
+
+            let res = match self.scan_type {
+                QScanType::TcpConnect => self.scan_tcp_connect().await,
+                QScanType::Ping => self.scan_ping().await,
+                QScanType::UdpConnect => self.scan_udp().await,
+                #[cfg(feature = "raw")]
+                QScanType::Syn => match self.scan_syn().await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        error!("{}", e);
+                        self.last_results.as_ref().unwrap()
+                    }
+                },
+            };
+            on_result(res);
+
+            pass += 1;
+        }
+    }
+
+    /// Send an HTTP request on `stream` and parse the response (see
+    /// [QScanner::set_http_probe])
+    async fn http_probe(
+        &self,
+        stream: &mut TcpStream,
+        socket: SocketAddr,
+        to: Duration,
+    ) -> Option<QScanHttpInfo> {
+        let request = service_probes::http_request(socket.ip());
+        let probe = async {
+            let sent = SystemTime::now();
+            stream.write_all(&request).await.ok()?;
+            self.audit_probe(sent, "tcp", stream.local_addr().ok(), &socket, &request);
+
+            let mut resp = Vec::new();
+            let mut buf = [0u8; 1024];
+            // Headers only
+            while !resp.windows(4).any(|w| w == b"\r\n\r\n")
+                && resp.len() < service_probes::HTTP_HEAD_MAX_LEN
+            {
+                match stream.read(&mut buf).await {
+                    Ok(n) if n > 0 => resp.extend_from_slice(&buf[..n]),
+                    _ => break,
+                }
+            }
+
+            service_probes::http_parse(&resp)
+        };
+
+        let (status, server) = timeout(to, probe).await.ok().flatten()?;
+        Some(QScanHttpInfo { status, server })
+    }
+
+    /// Resolve the names of the addresses with open ports in `results` (see
+    /// [QScanner::set_resolve_ptr])
+    async fn resolve_hostnames(&self, results: &mut [QScanResult]) {
+        let ips: HashSet<IpAddr> = results
+            .iter()
+            .filter_map(|r| match r {
+                QScanResult::TcpConnect(r) if r.state == QScanTcpConnectState::Open => {
+                    Some(r.target.ip())
+                }
+                _ => None,
+            })
+            .collect();
+        if ips.is_empty() {
+            return;
+        }
+
+        let mut opts = ResolverOpts::default();
+        opts.timeout = self.to;
+        opts.attempts = 1;
+        let resolver = match &self.resolver.config {
+            Some((config, opts)) => TokioAsyncResolver::tokio(config.clone(), *opts),
+            None => TokioAsyncResolver::tokio_from_system_conf()
+                .or_else(|_| TokioAsyncResolver::tokio(ResolverConfig::cloudflare_tls(), opts)),
+        };
+        let resolver = match resolver {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                warn!("cannot create the PTR resolver: {}", e);
+                return;
+            }
+        };
+
+        let resolver = &resolver;
+        let names: HashMap<IpAddr, String> = stream::iter(ips)
+            .map(|ip| async move {
+                let lookup = time::timeout(self.to, resolver.reverse_lookup(ip)).await;
+                let name = lookup.ok()?.ok()?.iter().next()?.to_utf8();
+                Some((ip, name.trim_end_matches('.').to_string()))
+            })
+            .buffer_unordered(PTR_LOOKUPS)
+            .filter_map(future::ready)
+            .collect()
+            .await;
+
+        for r in results {
+            if let QScanResult::TcpConnect(r) = r {
+                if r.state == QScanTcpConnectState::Open {
+                    r.hostname = names.get(&r.target.ip()).cloned();
+                }
+            }
+        }
+    }
+
+    /// Detect the service listening on an open port (see `service_probes`).
+    /// The whole detection takes at most the scan timeout.
+    /// `banner` is the greeting already read from `stream` (empty if nothing
+    /// was received), if banners are grabbed (see [QScanner::set_grab_banner])
+    async fn detect_service(
+        &self,
+        stream: &mut TcpStream,
+        socket: SocketAddr,
+        banner: Option<&[u8]>,
+    ) -> Option<String> {
+        let read_to = self.to / (service_probes::PROBES.len() as u32 + 1);
+        let detection = async {
+            let greeting = match banner {
+                Some(banner) => banner.to_vec(),
+                None => {
+                    read_response(stream, read_to, service_probes::RESPONSE_MAX_LEN, None).await
+                }
+            };
+            if !greeting.is_empty() {
+                return service_probes::greeting_match(&greeting);
+            }
+
+            for probe in service_probes::PROBES {
+                if let Ok(Ok(mut x)) = self.tcp_connect(socket, self.to).await {
+                    let payload = (probe.payload)();
+                    let sent = SystemTime::now();
+                    if x.write_all(&payload).await.is_err() {
+                        continue;
+                    }
+                    self.audit_probe(sent, "tcp", x.local_addr().ok(), &socket, &payload);
+
+                    let resp =
+                        read_response(&mut x, read_to, service_probes::RESPONSE_MAX_LEN, None)
+                            .await;
+                    let _ = self.close_stream(x).await;
+                    if let Some(service) = (probe.matches)(&resp) {
+                        return Some(service);
+                    }
+                }
+            }
+
+            None
+        };
+
+        timeout(self.to, detection)
+            .await
+            .ok()
+            .flatten()
+            .map(|s| s.to_string())
+    }
+
+    async fn scan_socket_blocking(
+        &self,
+        socket: SocketAddr,
+        connector: &Arc<BlockingConnector>,
+        to: Duration,
+    ) -> Result<QScanTcpConnectResult, QScanError> {
+        let mut err_str = String::new();
+        let mut err_kind = None;
+        let mut refused_rtt = None;
+        let mut attempts = 0;
+
+        for ntry in 0..self.tries.get() {
+            if ntry > 0 && !self.take_retry() {
+                break;
+            }
+
+            let connector = connector.clone();
+            let to = self.try_timeout(to, ntry);
+            attempts += 1;
+
+            let start = Instant::now();
+            self.audit_probe(SystemTime::now(), "tcp", None, &socket, &[]);
+            match tokio::task::spawn_blocking(move || connector(socket, to)).await {
+                Ok(Ok(())) => {
+                    let mut res = QScanTcpConnectResult::new(socket, QScanTcpConnectState::Open);
+                    res.confidence = OPEN_CONFIDENCE;
+                    res.rtt = Some(start.elapsed());
+                    self.observe_rtt(start.elapsed());
+                    res.via = ConnectPath::Custom(BLOCKING_CONNECTOR_PATH.to_string());
+                    return Ok(res);
+                }
+                Ok(Err(e)) if fd_exhausted_error(&e) => {
+                    let via = ConnectPath::Custom(BLOCKING_CONNECTOR_PATH.to_string());
+                    return Err(QScanError::fd_exhausted(&e, socket, via));
+                }
+                Ok(Err(e)) => {
+                    refused_rtt =
+                        (e.kind() == io::ErrorKind::ConnectionRefused).then(|| start.elapsed());
+                    if let Some(rtt) = refused_rtt {
+                        self.observe_rtt(rtt);
+                    }
+                    err_str = e.to_string();
+                    err_kind = Some(e.kind());
+                    if !self.retryable(e.kind()) {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    err_str = e.to_string();
+                    err_kind = Some(io::ErrorKind::Other);
+                    refused_rtt = None;
+                }
+            }
+        }
+
+        Err(QScanError {
+            msg: err_str,
+            sock: socket,
+            confidence: closed_confidence(err_kind, attempts),
+            via: ConnectPath::Custom(BLOCKING_CONNECTOR_PATH.to_string()),
+            closed: closed_error(err_kind),
+            timed_out: err_kind == Some(io::ErrorKind::TimedOut),
+            fd_exhausted: false,
+            state: error_state(err_kind),
+            rtt: refused_rtt,
+        })
+    }
+
+    async fn scan_socket_rtt(&self, socket: SocketAddr) -> (SocketAddr, Option<Duration>) {
+        self.wait_time_window().await;
+        self.wait_host_start(socket.ip()).await;
+        self.wait_scan_spread().await;
+        self.wait_rate().await;
+
+        for ntry in 0..self.tries.get() {
+            if ntry > 0 && !self.take_retry() {
+                break;
+            }
+
+            let start = Instant::now();
+            if let Ok(Ok(x)) = self.tcp_connect(socket, self.to).await {
+                let rtt = start.elapsed();
+                // Dropping the stream with linger 0 sends a RST
+                let _ = x.set_linger(Some(Duration::from_secs(0)));
+                return (socket, Some(rtt));
+            }
+        }
+
+        (socket, None)
+    }
+
+    async fn scan_socket_udp(&self, socket: SocketAddr) -> QScanUdpResult {
+        self.wait_time_window().await;
+        self.wait_host_start(socket.ip()).await;
+        self.wait_scan_spread().await;
+        self.wait_rate().await;
+
+        let mut state = QScanUdpState::Filtered;
+
+        for ntry in 0..self.tries.get() {
+            if ntry > 0 && !self.take_retry() {
+                break;
+            }
+
+            state = self.udp_probe(socket).await;
+            if state != QScanUdpState::Filtered {
+                break;
+            }
+        }
+
+        QScanUdpResult {
+            target: socket,
+            state,
+        }
+    }
+
+    async fn scan_ip_ping(
+        &self,
+        ip: IpAddr,
+        client4: &surge_ping::Client,
+        client6: &surge_ping::Client,
+    ) -> Result<IpAddr, IpAddr> {
+        self.wait_time_window().await;
+        self.wait_host_start(ip).await;
+        self.wait_scan_spread().await;
+        self.wait_rate().await;
+
+        let mut client = client4;
+
+        if ip.is_ipv6() {
+            client = client6;
+        }
+
+        match self.ping(client, ip).await {
+            QScanPingState::Up => Ok(ip),
+            QScanPingState::Down => Err(ip),
+        }
+    }
+
+    async fn tcp_connect(
+        &self,
+        socket: SocketAddr,
+        to: Duration,
+    ) -> Result<io::Result<TcpStream>, Elapsed> {
+        let connect = async {
+            if self.tcp_mss.is_none() && !self.reuse_addr && self.source_addr.is_none() {
+                return TcpStream::connect(socket).await;
+            }
+
+            let tcp = match socket {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            if let Some(mss) = self.tcp_mss {
+                set_socket_mss(&tcp, mss)?;
+            }
+            if self.reuse_addr {
+                set_socket_reuse(&tcp)?;
+            }
+            if let Some(source) = self.source_addr {
+                if source.is_ipv4() != socket.is_ipv4() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("source address {} cannot reach {}", source, socket),
+                    ));
+                }
+                tcp.bind(SocketAddr::new(source, 0))?;
+            }
+            tcp.connect(socket).await
+        };
+
+        // See https://stackoverflow.com/questions/30022084/how-do-i-set-connect-timeout-on-tcpstream
+        let sent = SystemTime::now();
+        let res = timeout(to, connect).await;
+
+        let source = match &res {
+            Ok(Ok(stream)) => stream.local_addr().ok(),
+            _ => None,
+        };
+        self.audit_probe(sent, "tcp", source, &socket, &[]);
+
+        res
+    }
+
+    /// Send the UDP probe to `socket` and wait for a reply. The socket is
+    /// connected so that an ICMP port unreachable is reported as a
+    /// `ConnectionRefused` socket error.
+    async fn udp_probe(&self, socket: SocketAddr) -> QScanUdpState {
+        let local = match socket {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let udp = match tokio::net::UdpSocket::bind(local).await {
+            Ok(udp) => udp,
+            Err(_) => return QScanUdpState::Filtered,
+        };
+        let sent = SystemTime::now();
+        if udp.connect(socket).await.is_err() || udp.send(&self.udp_payload).await.is_err() {
+            return QScanUdpState::Filtered;
+        }
+        self.audit_probe(
+            sent,
+            "udp",
+            udp.local_addr().ok(),
+            &socket,
+            &self.udp_payload,
+        );
+
+        let deadline = Instant::now() + self.to;
+        let mut buf = [0u8; 1];
+        loop {
+            let wait = std::cmp::min(
+                UDP_ERROR_POLL,
+                deadline.saturating_duration_since(Instant::now()),
+            );
+            let err = match timeout(wait, udp.recv(&mut buf)).await {
+                Ok(Ok(_)) => return QScanUdpState::Open,
+                Ok(Err(e)) => Some(e),
+                Err(_) => udp.take_error().ok().flatten(),
+            };
+
+            match err {
+                Some(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    return QScanUdpState::Closed
+                }
+                Some(_) => return QScanUdpState::Filtered,
+                None if Instant::now() >= deadline => return QScanUdpState::Filtered,
+                None => {}
+            }
+        }
+    }
+
+    async fn ping(&self, client: &surge_ping::Client, addr: IpAddr) -> QScanPingState {
+        let mut pinger = client
+            .pinger(addr, surge_ping::PingIdentifier(rand::random()))
+            .await;
+        pinger.timeout(self.to);
+        let mut interval = time::interval(self.ping_interval);
+        for idx in 0..self.tries.get() {
+            self.audit_probe(SystemTime::now(), "icmp", None, &addr, &self.ping_payload);
+            match pinger
+                .ping(surge_ping::PingSequence(idx as u16), &self.ping_payload)
+                .await
+            {
+                Ok((surge_ping::IcmpPacket::V4(_), _)) => {
+                    return QScanPingState::Up;
+                }
+                Ok((surge_ping::IcmpPacket::V6(_), _)) => {
+                    return QScanPingState::Up;
+                }
+                _ => {}
+            }
+            interval.tick().await;
+        }
+        QScanPingState::Down
+    }
+}
+
+/// Set the MSS advertised in the SYN (see [QScanner::set_tcp_mss])
+#[cfg(unix)]
+fn set_socket_mss(socket: &TcpSocket, mss: u32) -> io::Result<()> {
+    socket2::SockRef::from(socket).set_mss(mss)
+}
+
+#[cfg(not(unix))]
+fn set_socket_mss(_socket: &TcpSocket, _mss: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP MSS can only be set on unix",
+    ))
+}
+
+/// Allow binding local addresses in use (see [QScanner::set_reuse_addr])
+#[cfg(unix)]
+fn set_socket_reuse(socket: &TcpSocket) -> io::Result<()> {
+    let sock = socket2::SockRef::from(socket);
+    sock.set_reuse_address(true)?;
+    sock.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_socket_reuse(socket: &TcpSocket) -> io::Result<()> {
+    socket.set_reuseaddr(true)
+}
+
+/// Default cap of the sockets in flight (see [QScanner::set_max_fds]): the
+/// soft open files limit minus [FD_RESERVE]
+#[cfg(unix)]
+fn fds_limit() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes the given struct
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return usize::MAX;
+    }
+
+    usize::try_from(limit.rlim_cur)
+        .unwrap_or(usize::MAX)
+        .saturating_sub(FD_RESERVE)
+}
+
+#[cfg(not(unix))]
+fn fds_limit() -> usize {
+    usize::MAX
+}
+
+/// Sending MSS of a connected stream (see [QScanTcpConnectResult::peer_mss])
+#[cfg(unix)]
+fn stream_mss(stream: &TcpStream) -> Option<u32> {
+    socket2::SockRef::from(stream).mss().ok()
+}
+
+#[cfg(not(unix))]
+fn stream_mss(_stream: &TcpStream) -> Option<u32> {
+    None
+}
+
+/// Fewest closed ports needed to suspect RST injection (see
+/// [QScanner::get_suspected_rst_injection])
+const RST_INJECTION_MIN_PORTS: usize = 8;
+/// Least gap between the connect and the refuse times of a host to suspect
+/// RST injection: smaller gaps are in the noise of the local stack
+const RST_INJECTION_MIN_GAP: Duration = Duration::from_millis(1);
+
+/// Whether the refuse times of a host (`refused`) suggest that the RSTs are
+/// injected by a middlebox, given the connect times of its open ports
+/// (`open`, sorted). A genuine RST comes from the host and takes about as
+/// long as a SYN-ACK: the RSTs are suspect when, on average, they come in
+/// less than half that time and they are uniform (standard deviation below
+/// a tenth of it).
+fn suspected_rst_injection(open: &[Duration], refused: &[Duration]) -> bool {
+    if open.is_empty() || refused.len() < RST_INJECTION_MIN_PORTS {
+        return false;
+    }
+
+    let connect = percentile(open, 50).as_secs_f64();
+    let n = refused.len() as f64;
+    let mean = refused.iter().map(Duration::as_secs_f64).sum::<f64>() / n;
+    let var = refused
+        .iter()
+        .map(|d| (d.as_secs_f64() - mean).powi(2))
+        .sum::<f64>()
+        / n;
+
+    connect - mean >= RST_INJECTION_MIN_GAP.as_secs_f64()
+        && mean * 2.0 < connect
+        && var.sqrt() * 10.0 < connect
+}
+
+/// Shortest list of addresses and CIDRs covering exactly `ips`, sorted, so
+/// that the expanded ranges stay short in the scan plan (see
+/// [QScanner::export_scan_plan])
+fn collapse_to_cidrs(ips: &HashSet<IpAddr>) -> Vec<String> {
+    let mut ips: Vec<IpAddr> = ips.iter().copied().collect();
+    ips.sort_unstable();
+    let as_bits = |ip: &IpAddr| match ip {
+        IpAddr::V4(ip) => (32, u32::from(*ip) as u128),
+        IpAddr::V6(ip) => (128, u128::from(*ip)),
+    };
+    let to_ip = |bits: u32, n: u128| match bits {
+        32 => IpAddr::V4(Ipv4Addr::from(n as u32)),
+        _ => IpAddr::V6(Ipv6Addr::from(n)),
+    };
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < ips.len() {
+        // Run of consecutive addresses of the same family
+        let (bits, lo) = as_bits(&ips[i]);
+        let mut hi = lo;
+        i += 1;
+        while i < ips.len() && as_bits(&ips[i]) == (bits, hi.wrapping_add(1)) {
+            hi += 1;
+            i += 1;
+        }
+
+        // Largest aligned blocks that fit in the run
+        let mut start = lo;
+        loop {
+            // floor(log2(addresses left)), the count overflows only for the
+            // whole IPv6 space
+            let fit = (hi - start)
+                .checked_add(1)
+                .map_or(128, |count| 127 - count.leading_zeros());
+            let size_bits = start.trailing_zeros().min(bits).min(fit);
+            let ip = to_ip(bits, start);
+            out.push(match size_bits {
+                0 => ip.to_string(),
+                n => format!("{}/{}", ip, bits - n),
+            });
+            match 1u128
+                .checked_shl(size_bits)
+                .and_then(|size| start.checked_add(size))
+            {
+                Some(next) if next <= hi => start = next,
+                _ => break,
+            }
+        }
+    }
+    out
+}
+
+/// Nearest-rank `p`-th percentile of `sorted` (not empty)
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (sorted.len() * p).div_ceil(100);
+    sorted[rank.max(1) - 1]
+}
+
+/// Next output of `ftrs`, `None` when they are all done or the scan has been
+/// cancelled (see [QScanner::cancel_handle])
+async fn next_or_cancelled<F: Future>(
+    ftrs: &mut FuturesUnordered<F>,
+    cancelled: &mut (impl Future<Output = ()> + Unpin),
+) -> Option<F::Output> {
+    match future::select(ftrs.next(), cancelled).await {
+        future::Either::Left((output, _)) => output,
+        future::Either::Right(_) => None,
+    }
+}
+
+fn tcp_connect_result(result: Result<QScanTcpConnectResult, QScanError>) -> QScanResult {
+    match result {
+        Ok(open) => QScanResult::TcpConnect(open),
+        Err(error) => QScanResult::TcpConnect(error.to_result()),
+    }
+}
+
+/// Read a single response (at most `max_len` bytes) from `stream`, empty if
+/// nothing is received within `to`. The bytes read are taken from `budget`,
+/// if any: the read is shortened to the bytes left, and skipped once the
+/// budget is exhausted.
+async fn read_response(
+    stream: &mut TcpStream,
+    to: Duration,
+    max_len: usize,
+    budget: Option<&AtomicUsize>,
+) -> Vec<u8> {
+    // Bytes are reserved before reading, so that concurrent reads cannot
+    // exceed the budget, and the unused ones are given back
+    let reserved = match budget {
+        Some(budget) => {
+            match budget.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                (left > 0).then(|| left - std::cmp::min(left, max_len))
+            }) {
+                Ok(left) => std::cmp::min(left, max_len),
+                Err(_) => return Vec::new(),
+            }
+        }
+        None => max_len,
+    };
+    let mut buf = vec![0u8; reserved];
+
+    let n = match timeout(to, stream.read(&mut buf)).await {
+        Ok(Ok(n)) => n,
+        _ => 0,
+    };
+    if let Some(budget) = budget {
+        budget.fetch_add(reserved - n, Ordering::Relaxed);
+    }
+    buf.truncate(n);
+    buf
+}
+
+/// Seconds to wait, from `secs` (seconds of the day), before one of `windows`
+/// opens. `None` if there are no windows or `secs` is inside one of them.
+fn time_window_wait(windows: &[TimeWindow], secs: u32) -> Option<u32> {
+    if windows.iter().any(|w| w.contains(secs)) {
+        return None;
+    }
+
+    windows.iter().map(|w| w.secs_until_start(secs)).min()
+}
+
+/// Estimated memory used by a result (and its error message)
+const RESULT_SIZE_EST: usize =
+    std::mem::size_of::<QScanResult>() + std::mem::size_of::<(SocketAddr, String)>() + 64;
+/// Estimated memory used by a connection in flight (future and socket)
+const IN_FLIGHT_SIZE_EST: usize = 4096;
+
+/// Next group of at most `size` consecutive sockets of the same host
+fn next_host_group(
+    sock_it: &mut std::iter::Peekable<impl Iterator<Item = SocketAddr>>,
+    size: usize,
+) -> Vec<SocketAddr> {
+    let mut group: Vec<SocketAddr> = Vec::new();
+
+    while group.len() < size {
+        match sock_it.next_if(|s| group.first().is_none_or(|first| first.ip() == s.ip())) {
+            Some(socket) => group.push(socket),
+            None => break,
+        }
+    }
+
+    group
+}
+
+/// Split `total` across the strata proportionally to their sizes (largest
+/// remainder method). Each stratum gets at most its own size.
+fn stratified_allocation(strata: &[usize], total: usize) -> Vec<usize> {
+    let size: u128 = strata.iter().map(|s| *s as u128).sum();
+
+    if total as u128 >= size {
+        return strata.to_vec();
+    }
+
+    let mut alloc: Vec<usize> = strata
+        .iter()
+        .map(|s| (*s as u128 * total as u128 / size) as usize)
+        .collect();
+    let mut remainders: Vec<(usize, u128)> = strata
+        .iter()
+        .enumerate()
+        .map(|(idx, s)| (idx, *s as u128 * total as u128 % size))
+        .collect();
+    remainders.sort_by_key(|r| std::cmp::Reverse(r.1));
+
+    let left = total - alloc.iter().sum::<usize>();
+    for (idx, _) in remainders.into_iter().take(left) {
+        alloc[idx] += 1;
+    }
+
+    alloc
+}
+
+/// Check if `ip` is a loopback address or the address of a local interface
+/// (i.e., a socket can be bound to it)
+fn is_local(ip: IpAddr) -> bool {
+    ip.is_loopback() || UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+/// Check if the host has a route to `ip` with a local address of the same
+/// family. Connecting a UDP socket does not send any packet.
+fn is_routable(ip: IpAddr) -> bool {
+    let local = match ip {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+
+    UdpSocket::bind(local)
+        .and_then(|sock| sock.connect(SocketAddr::new(ip, 9)))
+        .is_ok()
+}
+
+/// Parse ports strings, comma separated strings, service names and ranges.
+/// E.g., "80", "80,443", "80,100-200,443", "ssh,http,8080"
+///
+/// A missing range bound is the first (1) or last (65535) port: "1024-" is
+/// 1024-65535, "-1024" is 1-1024 and "-" is 1-65535.
+///
+/// Keywords (case insensitive) stand for the IANA port ranges: "all"
+/// (1-65535), "well-known" or "reserved" (1-1023), "registered"
+/// (1024-49151) and "dynamic" (49152-65535).
+///
+/// Service names are resolved against `/etc/services` (TCP), or a built-in
+/// table. Empty items are skipped, the first malformed one is returned as an
+/// error.
+fn ports_parse(ports: &str) -> Result<Vec<u16>, QScanParseError> {
+    let mut services = ServiceNames::new();
+    let ranges = ports_split(ports)
+        .map(|p| port_range_parse(&p, &mut services))
+        .collect::<Result<Vec<RangeInclusive<u16>>, QScanParseError>>()?;
+
+    Ok(ports_dedup(ranges))
+}
+
+/// Same as [ports_parse], but malformed items are reported and skipped
+fn ports_parse_lenient(ports: &str) -> Vec<u16> {
+    let mut services = ServiceNames::new();
+    let ranges = ports_split(ports)
+        .filter_map(|p| match port_range_parse(&p, &mut services) {
+            Ok(range) => Some(range),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        })
+        .collect();
+
+    ports_dedup(ranges)
+}
+
+/// Non empty comma separated items, without whitespaces
+fn ports_split(ports: &str) -> impl Iterator<Item = String> + '_ {
+    ports
+        .split(',')
+        .map(|p| p.chars().filter(|c| !c.is_whitespace()).collect::<String>())
+        .filter(|p| !p.is_empty())
+}
+
+/// Ports ranges keywords (see [ports_parse])
+const PORT_KEYWORDS: &[(&str, RangeInclusive<u16>)] = &[
+    ("all", 1..=65535),
+    ("well-known", 1..=1023),
+    ("reserved", 1..=1023),
+    ("registered", 1024..=49151),
+    ("dynamic", 49152..=65535),
+];
+
+/// Single port (as a range of one port), range of ports or keyword
+fn port_range_parse(
+    p: &str,
+    services: &mut ServiceNames,
+) -> Result<RangeInclusive<u16>, QScanParseError> {
+    if let Some((_, range)) = PORT_KEYWORDS
+        .iter()
+        .find(|(keyword, _)| keyword.eq_ignore_ascii_case(p))
+    {
+        return Ok(range.clone());
+    }
+
+    // Service names can contain '-' (e.g., "http-alt")
+    if let Some(port) = port_parse(p, services) {
+        return Ok(port..=port);
+    }
+
+    let bounds: Vec<&str> = p.split('-').collect();
+    if bounds.len() == 1 {
+        return Err(port_error(p));
+    }
+    if bounds.len() != 2 {
+        return Err(QScanParseError::InvalidRange(p.to_string()));
+    }
+
+    // Open-ended ranges (e.g., "1024-", "-1024")
+    let bound = |b: &str, default: u16, services: &mut ServiceNames| match b {
+        "" => Ok(default),
+        b => port_parse(b, services).ok_or_else(|| port_error(b)),
+    };
+    let start = bound(bounds[0], 1, services)?;
+    let end = bound(bounds[1], u16::MAX, services)?;
+    if start > end {
+        return Err(QScanParseError::ReversedRange(p.to_string()));
+    }
+
+    Ok(start..=end)
+}
+
+/// Error for a token that is neither a port nor a service name
+fn port_error(token: &str) -> QScanParseError {
+    match token.bytes().all(|b| b.is_ascii_digit()) {
+        true => QScanParseError::PortOutOfRange(token.to_string()),
+        false => QScanParseError::InvalidPort(token.to_string()),
+    }
+}
+
+/// Ports of `ranges`, first occurrence order
+fn ports_dedup(ranges: Vec<RangeInclusive<u16>>) -> Vec<u16> {
+    // One bit per port: cheaper than hashing when the list is large (e.g.,
+    // "0-65535")
+    let mut seen = vec![0u64; (u16::MAX as usize + 1) / 64];
+    ranges
+        .into_iter()
+        .flatten()
+        .filter(|p| {
+            let (word, bit) = (*p as usize / 64, 1u64 << (p % 64));
+            let new = seen[word] & bit == 0;
+            seen[word] |= bit;
+            new
+        })
+        .collect()
+}
+
+/// Port number or service name
+fn port_parse(port: &str, services: &mut ServiceNames) -> Option<u16> {
+    port.parse().ok().or_else(|| services.port(port))
+}
+
+/// Parse IP addresses strings.
+/// E.g., "1.2.3.4", "1.2.3.4,8.8.8.8", 192.168.1.0/24", "192.168.1.1-50",
+/// "10.0.0.250-10.0.1.5"
+///
+/// Return the addresses and, for each address, the input that produced it.
+///
+/// Hostnames are resolved with `resolver`, which is created only if needed.
+fn addresses_parse(
+    addresses: &str,
+    resolver: &LazyResolver,
+) -> (Vec<IpAddr>, HashMap<IpAddr, TargetOrigin>) {
+    addresses_parse_sampled(
+        addresses,
+        None,
+        None,
+        resolver,
+        &mut StdRng::from_entropy(),
+        &mut Vec::new(),
+    )
+}
+
+/// Same as [addresses_parse], but IPv6 CIDRs larger than `ipv6_sample`
+/// addresses only yield `ipv6_sample` random addresses. Hostnames resolutions
+/// are cached in `dns_cache_file` (see [QScanner::set_dns_cache_file]) and
+/// use `resolver` (see [QScanner::set_resolver_config]). The targets that
+/// cannot be resolved or parsed are added to `skipped`.
+fn addresses_parse_sampled(
+    addresses: &str,
+    ipv6_sample: Option<usize>,
+    dns_cache_file: Option<&Path>,
+    resolver: &LazyResolver,
+    rng: &mut StdRng,
+    skipped: &mut Vec<String>,
+) -> (Vec<IpAddr>, HashMap<IpAddr, TargetOrigin>) {
+    let parsed = addresses_parse_with_origin(
+        addresses,
+        ipv6_sample,
+        dns_cache_file,
+        resolver,
+        rng,
+        skipped,
+    );
+    let ips = parsed.iter().map(|(ip, _)| *ip).collect();
+
+    (ips, parsed.into_iter().collect())
+}
+
+fn addresses_parse_with_origin(
+    addresses: &str,
+    ipv6_sample: Option<usize>,
+    dns_cache_file: Option<&Path>,
+    resolver: &LazyResolver,
+    rng: &mut StdRng,
+    skipped: &mut Vec<String>,
+) -> Vec<(IpAddr, TargetOrigin)> {
+    let mut ips: Vec<(IpAddr, TargetOrigin)> = Vec::new();
+    let mut resolver = NameResolver::new(dns_cache_file, resolver);
+
+    let addrs: String = addresses.chars().filter(|c| !c.is_whitespace()).collect();
+
+    for addr in addrs.split(',') {
+        if addr.is_empty() {
+            continue;
+        }
+
+        match target_file(addr) {
+            Some(TargetFile::Existing(path)) => {
+                match read_addresses_from_file(path, &mut resolver, ipv6_sample, rng, skipped) {
+                    Ok(x) => ips.extend(x),
+                    Err(e) => {
+                        warn!("cannot read {:?}: {}", addr, e);
+                        skipped.push(addr.to_string());
+                    }
+                }
+                continue;
+            }
+            Some(TargetFile::Missing(_)) => {
+                warn!("not a file {:?}", addr);
+                skipped.push(addr.to_string());
+                continue;
+            }
+            None => {}
+        }
+
+        let parsed_addr = address_parse(addr, &mut resolver, ipv6_sample, rng);
+
+        if !parsed_addr.is_empty() {
+            let origin = TargetOrigin::Token(addr.to_string());
+            ips.extend(parsed_addr.into_iter().map(|ip| (ip, origin.clone())));
+        } else if ipv4_range_parse(addr).is_some() {
+            // Invalid range, already reported
+            skipped.push(addr.to_string());
+        } else {
+            // Check if we have a file to read addresses from
+            let file_path = Path::new(addr);
+            if !file_path.is_file() {
+                warn!("cannot resolve or parse {:?}", addr);
+                skipped.push(addr.to_string());
+                continue;
+            }
+
+            if let Ok(x) =
+                read_addresses_from_file(file_path, &mut resolver, ipv6_sample, rng, skipped)
+            {
+                ips.extend(x);
+            } else {
+                warn!("unknown target {:?}", addr);
+                skipped.push(addr.to_string());
+            }
+        }
+    }
+
+    resolver.save_cache();
+
+    ips.into_iter().unique_by(|(ip, _)| *ip).collect()
+}
+
+/// Targets token naming a file of addresses (see [target_file])
+enum TargetFile<'a> {
+    Existing(&'a Path),
+    Missing(&'a Path),
+}
+
+/// Whether the targets token `addr` looks like a path: it contains a `/` or
+/// ends with ".txt". Existing files win over the other interpretations, a
+/// path that does not exist is missing unless it is a CIDR (e.g.,
+/// "10.0.0.0/8").
+///
+/// Return `None` if `addr` does not look like a path.
+fn target_file(addr: &str) -> Option<TargetFile<'_>> {
+    if !addr.contains('/') && !addr.ends_with(".txt") {
+        return None;
+    }
+
+    let path = Path::new(addr);
+    if path.is_file() {
+        Some(TargetFile::Existing(path))
+    } else if IpCidr::from_str(addr).is_ok() {
+        None
+    } else {
+        Some(TargetFile::Missing(path))
+    }
+}
+
+fn address_parse(
+    addr: &str,
+    resolver: &mut NameResolver<'_>,
+    ipv6_sample: Option<usize>,
+    rng: &mut StdRng,
+) -> Vec<IpAddr> {
+    // IPv6 literals can be written in brackets, as in socket addresses
+    // (e.g., "[::1]")
+    let addr = addr
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(addr);
+
+    match ipv4_range_parse(addr) {
+        Some(Ok(range)) => return range.map(|ip| IpAddr::V4(Ipv4Addr::from(ip))).collect(),
+        Some(Err(e)) => {
+            warn!("{}", e);
+            return Vec::new();
+        }
+        None => {}
+    }
+
+    IpCidr::from_str(addr)
+        .map(|cidr| match (cidr, ipv6_sample) {
+            (IpCidr::V6(cidr), Some(n)) => ipv6_cidr_sample(&cidr, n, rng),
+            (cidr, _) => cidr.iter().collect(),
+        })
+        .unwrap_or_else(|_| resolver.resolve(addr))
+}
+
+/// Parse an IPv4 range, "A.B.C.D-E.F.G.H" or "A.B.C.D-H" where only the last
+/// octet varies, into the (inclusive) range of the addresses as integers
+///
+/// Return `None` if `addr` is not a range (e.g., a hostname with dashes) or
+/// an error if the range is invalid (e.g., reversed).
+fn ipv4_range_parse(addr: &str) -> Option<Result<RangeInclusive<u32>, String>> {
+    let (start, end) = addr.split_once('-')?;
+    let start: Ipv4Addr = start.parse().ok()?;
+
+    let end = match (end.parse::<Ipv4Addr>(), end.parse::<u8>()) {
+        (Ok(end), _) => end,
+        (_, Ok(last)) => {
+            let [a, b, c, _] = start.octets();
+            Ipv4Addr::new(a, b, c, last)
+        }
+        _ => return Some(Err(format!("invalid end of the IP range {:?}", addr))),
+    };
+
+    if start > end {
+        return Some(Err(format!(
+            "invalid IP range {:?}: {} is after {}",
+            addr, start, end
+        )));
+    }
+
+    Some(Ok(u32::from(start)..=u32::from(end)))
+}
+
+/// Validity of the resolutions of the system resolver, which does not
+/// report the records TTL
+const SYSTEM_RESOLVER_TTL: Duration = Duration::from_secs(300);
+
+/// DNS resolver created on first use and then shared by all the targets
+/// parses of a scanner: creating it is far more expensive than parsing
+/// addresses.
+struct LazyResolver {
+    /// Configured by the user (see [QScanner::set_resolver_config]), the
+    /// system resolver is not used
+    config: Option<(ResolverConfig, ResolverOpts)>,
+    /// `None` if the resolver cannot be created
+    resolver: OnceLock<Option<Resolver>>,
+}
+
+impl LazyResolver {
+    fn new(config: Option<(ResolverConfig, ResolverOpts)>) -> Self {
+        Self {
+            config,
+            resolver: OnceLock::new(),
+        }
+    }
+
+    fn custom(&self) -> bool {
+        self.config.is_some()
+    }
+
+    /// Return the resolver, creating it if this is the first call
+    fn get(&self) -> Option<&Resolver> {
+        self.resolver
+            .get_or_init(|| {
+                let (config, opts) = self
+                    .config
+                    .clone()
+                    .unwrap_or_else(|| (ResolverConfig::cloudflare_tls(), ResolverOpts::default()));
+                Resolver::new(config, opts)
+                    .map_err(|e| warn!("cannot create the DNS resolver: {}", e))
+                    .ok()
+            })
+            .as_ref()
+    }
+}
+
+impl Default for LazyResolver {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl fmt::Debug for LazyResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyResolver")
+            .field("custom", &self.custom())
+            .field("created", &self.resolver.get().is_some())
+            .finish()
+    }
+}
+
+impl Drop for LazyResolver {
+    fn drop(&mut self) {
+        // The resolver owns a runtime, which cannot be dropped from an
+        // asynchronous context
+        if let Some(Some(resolver)) = self.resolver.take() {
+            if tokio::runtime::Handle::try_current().is_ok() {
+                std::thread::spawn(move || drop(resolver));
+            }
+        }
+    }
+}
+
+/// Hostnames resolver, backed by the optional DNS cache file
+struct NameResolver<'a> {
+    alt_resolver: &'a LazyResolver,
+    cache: Option<DnsCache>,
+}
+
+impl<'a> NameResolver<'a> {
+    fn new(dns_cache_file: Option<&Path>, alt_resolver: &'a LazyResolver) -> Self {
+        Self {
+            alt_resolver,
+            cache: dns_cache_file.map(DnsCache::load),
+        }
+    }
+
+    /// Resolve `name` with the system resolver (first address only) or, if it
+    /// fails, with the alternative resolver. Only the alternative resolver is
+    /// used if it has been configured by the user. Cached resolutions are
+    /// used until they expire.
+    fn resolve(&mut self, name: &str) -> Vec<IpAddr> {
+        if let Some(ips) = self.cache.as_ref().and_then(|c| c.get(name)) {
+            return ips;
+        }
+
+        // (host, port) instead of "host:port": IPv6 literals would need brackets
+        let system = match self.alt_resolver.custom() {
+            true => None,
+            false => (name, 80)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut iter| iter.next()),
+        };
+        let (ips, ttl) = match system {
+            Some(sock) => (vec![sock.ip()], SYSTEM_RESOLVER_TTL),
+            None => match self.alt_resolver.get() {
+                Some(resolver) => domain_name_resolve_with_ttl(name, resolver),
+                None => (Vec::new(), SYSTEM_RESOLVER_TTL),
+            },
+        };
+
+        if let Some(cache) = self.cache.as_mut() {
+            if !ips.is_empty() {
+                cache.insert(name, ips.clone(), ttl);
+            }
+        }
+
+        ips
+    }
+
+    fn save_cache(&self) {
+        if let Some(Err(e)) = self.cache.as_ref().map(DnsCache::save) {
+            warn!("cannot write DNS cache: {}", e);
+        }
+    }
+}
+
+/// CIDRs with more host bits are kept as CIDRs and expanded lazily while
+/// scanning (see [split_lazy_cidrs])
+const LAZY_CIDR_HOST_BITS: u32 = 16;
+
+/// Targets addresses, their origins and the CIDRs expanded lazily
+type ParsedTargets = (
+    Vec<IpAddr>,
+    HashMap<IpAddr, TargetOrigin>,
+    Vec<(IpCidr, TargetOrigin)>,
+);
+
+fn cidr_host_bits(cidr: &IpCidr) -> u32 {
+    match cidr {
+        IpCidr::V4(c) => 32 - c.get_bits() as u32,
+        IpCidr::V6(c) => 128 - c.get_bits() as u32,
+    }
+}
+
+/// Whether `inner` is contained in (or equal to) `outer`
+fn cidr_contains(outer: &IpCidr, inner: &IpCidr) -> bool {
+    outer.contains(inner.first_as_ip_addr()) && cidr_host_bits(inner) <= cidr_host_bits(outer)
+}
+
+/// Intersection of two CIDRs: the smaller one if they are nested, `None`
+/// otherwise
+fn cidr_intersection(a: &IpCidr, b: &IpCidr) -> Option<IpCidr> {
+    if cidr_contains(a, b) {
+        Some(*b)
+    } else if cidr_contains(b, a) {
+        Some(*a)
+    } else {
+        None
+    }
+}
+
+/// Number of addresses of `cidr` (saturated to `usize::MAX`)
+fn cidr_size(cidr: &IpCidr) -> usize {
+    1usize
+        .checked_shl(cidr_host_bits(cidr))
+        .unwrap_or(usize::MAX)
+}
+
+/// Number of addresses of `cidr` (saturated to `u64::MAX`)
+fn cidr_size_u64(cidr: &IpCidr) -> u64 {
+    1u64.checked_shl(cidr_host_bits(cidr)).unwrap_or(u64::MAX)
+}
+
+/// `n` distinct random addresses of `cidr` (all if they are at most `n`)
+fn cidr_sample(cidr: &IpCidr, n: usize, rng: &mut StdRng) -> Vec<IpAddr> {
+    match cidr {
+        IpCidr::V6(c) => ipv6_cidr_sample(c, n, rng),
+        IpCidr::V4(c) => {
+            let size = cidr_size(cidr);
+            let first = c.first();
+            rand::seq::index::sample(rng, size, std::cmp::min(n, size))
+                .into_iter()
+                .map(|idx| IpAddr::V4(Ipv4Addr::from(first + idx as u32)))
+                .collect()
+        }
+    }
+}
+
+/// Split the CIDRs larger than `LAZY_CIDR_HOST_BITS` host bits out of
+/// `addresses`, so that they are not expanded in memory. IPv6 CIDRs are
+/// kept in `addresses` when they are sampled (`ipv6_sample`).
+fn split_lazy_cidrs(
+    addresses: &str,
+    ipv6_sample: Option<usize>,
+) -> (String, Vec<(IpCidr, TargetOrigin)>) {
+    let mut rest: Vec<&str> = Vec::new();
+    let mut cidrs: Vec<(IpCidr, TargetOrigin)> = Vec::new();
+    let addrs: String = addresses.chars().filter(|c| !c.is_whitespace()).collect();
+
+    for addr in addrs.split(',') {
+        match IpCidr::from_str(addr) {
+            Ok(cidr)
+                if cidr_host_bits(&cidr) > LAZY_CIDR_HOST_BITS
+                    && !(matches!(cidr, IpCidr::V6(_)) && ipv6_sample.is_some()) =>
+            {
+                if !cidrs.iter().any(|(c, _)| *c == cidr) {
+                    cidrs.push((cidr, TargetOrigin::Token(addr.to_string())));
+                }
+            }
+            _ => rest.push(addr),
+        }
+    }
+
+    (rest.join(","), cidrs)
+}
+
+/// All the addresses of `cidr` if they are at most `n`, otherwise `n`
+/// distinct addresses chosen at random
+fn ipv6_cidr_sample(cidr: &Ipv6Cidr, n: usize, rng: &mut StdRng) -> Vec<IpAddr> {
+    let host_bits = 128 - cidr.get_bits() as u32;
+
+    if host_bits < usize::BITS && (1usize << host_bits) <= n {
+        return IpCidr::V6(*cidr).iter().collect();
+    }
+
+    let host_mask = u128::MAX.checked_shr(cidr.get_bits() as u32).unwrap_or(0);
+    let mut sample: HashSet<u128> = HashSet::new();
+    while sample.len() < n {
+        sample.insert(cidr.first() | (rng.gen::<u128>() & host_mask));
+    }
+
+    sample
+        .into_iter()
+        .sorted()
+        .map(|ip| IpAddr::V6(Ipv6Addr::from(ip)))
+        .collect()
+}
+
+#[cfg(test)]
+fn domain_name_resolve_to_ip(source: &str, alt_resolver: &Resolver) -> Vec<IpAddr> {
+    domain_name_resolve_with_ttl(source, alt_resolver).0
+}
+
+/// Resolve `source`, return the addresses and how long they are valid
+fn domain_name_resolve_with_ttl(source: &str, alt_resolver: &Resolver) -> (Vec<IpAddr>, Duration) {
+    let mut ips: Vec<IpAddr> = Vec::new();
+    let mut ttl = SYSTEM_RESOLVER_TTL;
+
+    if let Ok(addrs) = source.to_socket_addrs() {
+        for ip in addrs {
+            ips.push(ip.ip());
+        }
+    } else if let Ok(addrs) = resolver_lookup_ip(alt_resolver, source) {
+        ttl = addrs
+            .valid_until()
+            .saturating_duration_since(Instant::now());
+        ips.extend(addrs.iter());
+    }
+
+    (ips, ttl)
+}
+
+/// The resolver blocks on its own runtime, which cannot be done from a thread
+/// driving another runtime (e.g., if the targets are set from async code): in
+/// that case, the lookup runs on a separate thread
+fn resolver_lookup_ip(resolver: &Resolver, name: &str) -> ResolveResult<LookupIp> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => std::thread::scope(|s| {
+            s.spawn(|| resolver.lookup_ip(name))
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e))
+        }),
+        Err(_) => resolver.lookup_ip(name),
+    }
+}
+
+// Read ips or fomain name from a file
+fn read_addresses_from_file(
+    addrs_file_path: &Path,
+    backup_resolver: &mut NameResolver<'_>,
+    ipv6_sample: Option<usize>,
+    rng: &mut StdRng,
+    skipped: &mut Vec<String>,
+) -> Result<Vec<(IpAddr, TargetOrigin)>, std::io::Error> {
+    let file = File::open(addrs_file_path)?;
+    let reader = BufReader::new(file);
+    let mut ips: Vec<(IpAddr, TargetOrigin)> = Vec::new();
+
+    for (idx, address_line) in reader.lines().enumerate() {
+        let line = format!("{}:{}", addrs_file_path.display(), idx + 1);
+        if let Ok(address) = address_line {
+            let origin = TargetOrigin::FileLine {
+                path: addrs_file_path.to_path_buf(),
+                line: idx + 1,
+                token: address.clone(),
+            };
+            let parsed = address_parse(&address, backup_resolver, ipv6_sample, rng);
+            if parsed.is_empty() && !address.trim().is_empty() {
+                warn!("{}: cannot resolve or parse {:?}", line, address);
+                skipped.push(format!("{}: {}", line, address));
+            }
+            ips.extend(parsed.into_iter().map(|ip| (ip, origin.clone())));
+        } else {
+            warn!("Line {} in file is not valid", idx);
+            skipped.push(line);
+        }
+    }
+
+    Ok(ips)
+}
+
+mod sockiter {
+    use super::TargetOrigin;
+    use cidr_utils::cidr::IpCidr;
+    use rand::Rng;
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    /// Sockets to scan: for each port, all the target addresses (the large
+    /// CIDRs are expanded on the fly), then the sockets of the hosts with
+    /// their own ports
+    pub struct SockIter<'a> {
+        ips: &'a [IpAddr],
+        cidrs: &'a [(IpCidr, TargetOrigin)],
+        ports: std::slice::Iter<'a, u16>,
+        port: Option<u16>,
+        hosts: Box<dyn Iterator<Item = IpAddr> + Send + 'a>,
+        host_ports: &'a HashMap<IpAddr, Vec<u16>>,
+        mapped: Box<dyn Iterator<Item = SocketAddr> + Send + 'a>,
+    }
+
+    fn hosts<'a>(
+        ips: &'a [IpAddr],
+        cidrs: &'a [(IpCidr, TargetOrigin)],
+    ) -> Box<dyn Iterator<Item = IpAddr> + Send + 'a> {
+        Box::new(
+            ips.iter()
+                .copied()
+                .chain(cidrs.iter().flat_map(|(cidr, _)| cidr.iter())),
+        )
+    }
+
+    /// Sockets of the hosts with their own ports
+    fn mapped<'a>(
+        ips: &'a [IpAddr],
+        cidrs: &'a [(IpCidr, TargetOrigin)],
+        host_ports: &'a HashMap<IpAddr, Vec<u16>>,
+    ) -> impl Iterator<Item = SocketAddr> + Send + 'a {
+        let mut cidrs_mapped: Vec<(&IpAddr, &Vec<u16>)> = host_ports
+            .iter()
+            .filter(|(ip, _)| {
+                cidrs.iter().any(|(cidr, _)| cidr.contains(**ip)) && !ips.contains(ip)
+            })
+            .collect();
+        cidrs_mapped.sort();
+
+        ips.iter()
+            .filter_map(move |ip| host_ports.get(ip).map(|p| (ip, p)))
+            .chain(cidrs_mapped)
+            .flat_map(|(ip, p)| p.iter().map(move |port| SocketAddr::new(*ip, *port)))
+    }
+
+    impl<'a> SockIter<'a> {
+        pub fn new(
+            ips: &'a [IpAddr],
+            cidrs: &'a [(IpCidr, TargetOrigin)],
+            ports: &'a [u16],
+            host_ports: &'a HashMap<IpAddr, Vec<u16>>,
+        ) -> Self {
+            Self {
+                ips,
+                cidrs,
+                ports: ports.iter(),
+                port: None,
+                hosts: Box::new(std::iter::empty()),
+                host_ports,
+                mapped: Box::new(mapped(ips, cidrs, host_ports)),
+            }
+        }
+    }
+
+    impl<'s> Iterator for SockIter<'s> {
+        type Item = SocketAddr;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                // Hosts with their own ports are skipped in the global
+                // product and emitted afterwards
+                if let Some(port) = self.port {
+                    for ip in self.hosts.by_ref() {
+                        if !self.host_ports.contains_key(&ip) {
+                            return Some(SocketAddr::new(ip, port));
+                        }
+                    }
+                }
+
+                match self.ports.next() {
+                    Some(port) => {
+                        self.port = Some(*port);
+                        self.hosts = hosts(self.ips, self.cidrs);
+                    }
+                    None => break,
+                }
+            }
+
+            self.mapped.next()
+        }
+    }
+
+    /// Random permutation of `0..n` computed on the fly: a full period linear
+    /// congruential generator modulo the smallest power of two not smaller
+    /// than `n`, skipping the values not smaller than `n`. The low bits of such
+    /// a generator have a short period, so every value goes through an
+    /// invertible xorshift-multiply mix before being emitted.
+    pub struct Permutation {
+        n: u128,
+        mask: u128,
+        shift: u32,
+        a: u128,
+        c: u128,
+        x: u128,
+        emitted: u128,
+    }
+
+    impl Permutation {
+        pub fn new(n: u128, rng: &mut impl Rng) -> Self {
+            let mask = n.checked_next_power_of_two().map_or(u128::MAX, |m| m - 1);
+
+            Self {
+                n,
+                mask,
+                shift: mask.count_ones() / 2 + 1,
+                // Full period (Hull-Dobell): c odd, a - 1 multiple of 4
+                a: (rng.gen::<u128>() & !3) | 1,
+                c: rng.gen::<u128>() | 1,
+                x: rng.gen::<u128>() & mask,
+                emitted: 0,
+            }
+        }
+
+        /// Bijection of `0..=mask`: the xorshifts move the high bits into the
+        /// low ones and the odd multiplier spreads the low bits upwards
+        fn mix(&self, x: u128) -> u128 {
+            let x = x ^ (x >> self.shift);
+            let x = x.wrapping_mul(0x9e37_79b9_7f4a_7c15_f39c_c060_5ced_c835) & self.mask;
+            x ^ (x >> self.shift)
+        }
+    }
+
+    impl Iterator for Permutation {
+        type Item = u128;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            while self.emitted < self.n {
+                self.x = self.a.wrapping_mul(self.x).wrapping_add(self.c) & self.mask;
+                let idx = self.mix(self.x);
+                if idx < self.n {
+                    self.emitted += 1;
+                    return Some(idx);
+                }
+            }
+
+            None
+        }
+    }
+
+    /// Same sockets as [SockIter], in random order
+    pub struct ShuffledSockIter<'a> {
+        ips: &'a [IpAddr],
+        cidrs: &'a [(IpCidr, TargetOrigin)],
+        ports: &'a [u16],
+        host_ports: &'a HashMap<IpAddr, Vec<u16>>,
+        /// Index of the first address of each large CIDR
+        offsets: Vec<u128>,
+        nhosts: u128,
+        mapped: Vec<SocketAddr>,
+        perm: Permutation,
+    }
+
+    impl<'a> ShuffledSockIter<'a> {
+        pub fn new(
+            ips: &'a [IpAddr],
+            cidrs: &'a [(IpCidr, TargetOrigin)],
+            ports: &'a [u16],
+            host_ports: &'a HashMap<IpAddr, Vec<u16>>,
+            rng: &mut impl Rng,
+        ) -> Self {
+            let mut offsets = Vec::with_capacity(cidrs.len());
+            let mut nhosts = ips.len() as u128;
+            for (cidr, _) in cidrs {
+                offsets.push(nhosts);
+                nhosts = nhosts.saturating_add(cidr_size(cidr));
+            }
+
+            let mapped: Vec<SocketAddr> = mapped(ips, cidrs, host_ports).collect();
+            let n = nhosts
+                .saturating_mul(ports.len() as u128)
+                .saturating_add(mapped.len() as u128);
+
+            Self {
+                ips,
+                cidrs,
+                ports,
+                host_ports,
+                offsets,
+                nhosts,
+                mapped,
+                perm: Permutation::new(n, rng),
+            }
+        }
+
+        /// `idx`-th target address
+        fn host(&self, idx: u128) -> IpAddr {
+            if idx < self.ips.len() as u128 {
+                return self.ips[idx as usize];
+            }
+
+            let i = self.offsets.partition_point(|o| *o <= idx) - 1;
+            let offset = idx - self.offsets[i];
+            match self.cidrs[i].0 {
+                IpCidr::V4(c) => IpAddr::V4(Ipv4Addr::from(c.first() + offset as u32)),
+                IpCidr::V6(c) => IpAddr::V6(Ipv6Addr::from(c.first() + offset)),
+            }
+        }
+
+        /// `idx`-th socket, `None` for the hosts with their own ports in the
+        /// global product
+        fn socket(&self, idx: u128) -> Option<SocketAddr> {
+            let product = self.nhosts.saturating_mul(self.ports.len() as u128);
+            if idx >= product {
+                return Some(self.mapped[(idx - product) as usize]);
+            }
+
+            let ip = self.host(idx % self.nhosts);
+            let port = self.ports[(idx / self.nhosts) as usize];
+            (!self.host_ports.contains_key(&ip)).then(|| SocketAddr::new(ip, port))
+        }
+    }
+
+    impl<'s> Iterator for ShuffledSockIter<'s> {
+        type Item = SocketAddr;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            while let Some(idx) = self.perm.next() {
+                if let Some(socket) = self.socket(idx) {
+                    return Some(socket);
+                }
+            }
+
+            None
+        }
+    }
+
+    /// Number of addresses of `cidr` (saturated to `u128::MAX`)
+    fn cidr_size(cidr: &IpCidr) -> u128 {
+        1u128
+            .checked_shl(super::cidr_host_bits(cidr))
+            .unwrap_or(u128::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+    use trust_dns_resolver::{
+        config::{ResolverConfig, ResolverOpts},
+        Resolver,
+    };
+
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn parse_empty_address() {
+        let res = super::addresses_parse("", &Default::default()).0;
+        assert_eq!(res, Vec::<IpAddr>::new());
+    }
+
+    #[test]
+    fn parse_commas_address() {
+        let res = super::addresses_parse(",,,,", &Default::default()).0;
+        assert_eq!(res, Vec::<IpAddr>::new());
+    }
+
+    #[test]
+    fn parse_simple_address() {
+        let res = super::addresses_parse("127.0.0.1", &Default::default()).0;
+        assert_eq!(res, vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn parse_repeated_address1() {
+        let res = super::addresses_parse("127.0.0.1,127.0.0.1", &Default::default()).0;
+        assert_eq!(res, vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn parse_repeated_address2() {
+        let res = super::addresses_parse("127.0.0.1,127.0.0.2,127.0.0.0/30", &Default::default()).0;
+        assert_eq!(
+            res,
+            vec![
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "127.0.0.2".parse::<IpAddr>().unwrap(),
+                "127.0.0.0".parse::<IpAddr>().unwrap(),
+                "127.0.0.3".parse::<IpAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_repeated_address3() {
+        let res =
+            super::addresses_parse("127.0.0.1,192.168.1.1,127.0.0.0/30", &Default::default()).0;
+        assert_eq!(
+            res,
+            vec![
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "192.168.1.1".parse::<IpAddr>().unwrap(),
+                "127.0.0.0".parse::<IpAddr>().unwrap(),
+                "127.0.0.2".parse::<IpAddr>().unwrap(),
+                "127.0.0.3".parse::<IpAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_multiple_addresses() {
+        let res = super::addresses_parse("127.0.0.1,127.0.0.2", &Default::default()).0;
+        assert_eq!(
+            res,
+            vec![
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "127.0.0.2".parse::<IpAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_cidr() {
+        let res = super::addresses_parse("127.0.0.10/31", &Default::default()).0;
+        assert_eq!(
+            res,
+            vec![
+                "127.0.0.10".parse::<IpAddr>().unwrap(),
+                "127.0.0.11".parse::<IpAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_ipv4_range() {
+        let res = super::addresses_parse("10.0.0.254-10.0.1.1", &Default::default()).0;
+        assert_eq!(
+            res,
+            vec![
+                "10.0.0.254".parse::<IpAddr>().unwrap(),
+                "10.0.0.255".parse::<IpAddr>().unwrap(),
+                "10.0.1.0".parse::<IpAddr>().unwrap(),
+                "10.0.1.1".parse::<IpAddr>().unwrap(),
+            ]
+        );
+
+        let res = super::addresses_parse("127.0.0.1,192.168.1.1-3", &Default::default()).0;
+        assert_eq!(
+            res,
+            vec![
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "192.168.1.1".parse::<IpAddr>().unwrap(),
+                "192.168.1.2".parse::<IpAddr>().unwrap(),
+                "192.168.1.3".parse::<IpAddr>().unwrap(),
+            ]
+        );
+
+        let res = super::addresses_parse("127.0.0.1-127.0.0.1", &Default::default()).0;
+        assert_eq!(res, vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+
+        // Reversed ranges and shorthands beyond the last octet
+        for range in [
+            "10.0.0.5-10.0.0.1",
+            "10.0.0.5-1",
+            "10.0.0.1-256",
+            "10.0.0.1-x",
+        ] {
+            assert!(matches!(super::ipv4_range_parse(range), Some(Err(_))));
+            assert!(super::addresses_parse(range, &Default::default())
+                .0
+                .is_empty());
+        }
+        // Not a range
+        assert!(super::ipv4_range_parse("my-host.example.com").is_none());
+        assert!(super::ipv4_range_parse("10.0.0.0/24").is_none());
+    }
+
+    #[test]
+    fn parse_cidr_and_addresses() {
+        let res =
+            super::addresses_parse("127.0.0.1,127.0.0.10/31, 127.0.0.2", &Default::default()).0;
+        assert_eq!(
+            res,
+            vec![
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "127.0.0.10".parse::<IpAddr>().unwrap(),
+                "127.0.0.11".parse::<IpAddr>().unwrap(),
+                "127.0.0.2".parse::<IpAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        let res = super::addresses_parse("2001:4860:4860::8888", &Default::default()).0;
+        assert_eq!(res, vec!["2001:4860:4860::8888".parse::<IpAddr>().unwrap()]);
+
+        let res = super::addresses_parse("::1/127,[fe80::1]", &Default::default()).0;
+        assert_eq!(
+            res,
+            vec![
+                "::".parse::<IpAddr>().unwrap(),
+                "::1".parse::<IpAddr>().unwrap(),
+                "fe80::1".parse::<IpAddr>().unwrap(),
+            ]
+        );
+
+        // Sockets keep the family of their address
+        let scanner = super::QScanner::new("127.0.0.1,::1", "80,443");
+        let sockets: Vec<String> = scanner.sock_iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            sockets,
+            vec!["127.0.0.1:80", "[::1]:80", "127.0.0.1:443", "[::1]:443"]
+        );
+    }
+
+    #[test]
+    fn parse_targets_files_first() {
+        let dir = std::env::temp_dir().join("qscan_parse_targets_files_first");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ips.txt");
+        std::fs::write(&path, "127.0.0.5\n127.0.0.6/31\n").unwrap();
+        let missing = dir.join("missing.txt");
+
+        let targets = format!(
+            "127.0.0.1,{}, 10.0.0.0/30,10.0.1.1-2,[::1],{}",
+            path.display(),
+            missing.display()
+        );
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        scanner.set_targets_addr(&targets);
+        let ips: std::collections::HashSet<IpAddr> =
+            scanner.get_tagets_ips().iter().copied().collect();
+        let expected: std::collections::HashSet<IpAddr> = [
+            "127.0.0.1",
+            "127.0.0.5",
+            "127.0.0.6",
+            "127.0.0.7",
+            "10.0.0.0",
+            "10.0.0.1",
+            "10.0.0.2",
+            "10.0.0.3",
+            "10.0.1.1",
+            "10.0.1.2",
+            "::1",
+        ]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+        assert_eq!(ips, expected);
+
+        assert_eq!(
+            scanner.try_set_targets_addr(&targets),
+            Err(super::QScanParseError::MissingFile(
+                missing.display().to_string()
+            ))
+        );
+        assert_eq!(scanner.get_tagets_ips().len(), expected.len());
+
+        let targets = format!("127.0.0.2,{},10.0.0.0/31", path.display());
+        assert_eq!(scanner.try_set_targets_addr(&targets), Ok(()));
+        assert_eq!(scanner.get_tagets_ips().len(), 6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn strict_resolution() {
+        let path = std::env::temp_dir().join("qscan_strict_resolution.txt");
+        std::fs::write(&path, "127.0.0.5\nbogus-line.qscan.invalid\n\n").unwrap();
+
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        let mut opts = super::ResolverOpts::default();
+        opts.timeout = Duration::from_millis(100);
+        opts.attempts = 0;
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+        scanner.set_resolver_config(
+            super::ResolverConfig::from_parts(
+                None,
+                vec![],
+                trust_dns_resolver::config::NameServerConfigGroup::from_ips_clear(
+                    &["127.0.0.1".parse().unwrap()],
+                    port,
+                    true,
+                ),
+            ),
+            opts,
+        );
+
+        let targets = format!(
+            "127.0.0.2,bogus.qscan.invalid,10.0.0.5-1,{}",
+            path.display()
+        );
+        // Not strict: the bad targets are skipped
+        assert_eq!(scanner.try_set_targets_addr(&targets), Ok(()));
+        assert_eq!(scanner.get_tagets_ips().len(), 2);
+
+        scanner.set_strict_resolution(true);
+        assert_eq!(scanner.try_set_targets("127.0.0.3", "22"), Ok(()));
+        let err = scanner.try_set_targets(&targets, "22").unwrap_err();
+        assert_eq!(
+            err,
+            super::QScanParseError::UnresolvedTargets(vec![
+                "bogus.qscan.invalid".to_string(),
+                "10.0.0.5-1".to_string(),
+                format!("{}:2: bogus-line.qscan.invalid", path.display()),
+            ])
+        );
+        assert!(err.to_string().contains("\"bogus.qscan.invalid\""));
+        // The old targets are kept
+        assert_eq!(
+            *scanner.get_tagets_ips(),
+            vec!["127.0.0.3".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(*scanner.get_tagets_ports(), vec![22]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parse_targets_origin() {
+        let path = std::env::temp_dir().join("qscan_parse_targets_origin.txt");
+        std::fs::write(&path, "127.0.0.5\n127.0.0.6/31\n").unwrap();
+        let (ips, origins) = super::addresses_parse(
+            &format!("127.0.0.1,127.0.0.2/31,{}", path.display()),
+            &Default::default(),
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ips.len(), 6);
+        assert_eq!(
+            origins.get(&"127.0.0.1".parse::<IpAddr>().unwrap()),
+            Some(&super::TargetOrigin::Token("127.0.0.1".to_string()))
+        );
+        assert_eq!(
+            origins.get(&"127.0.0.3".parse::<IpAddr>().unwrap()),
+            Some(&super::TargetOrigin::Token("127.0.0.2/31".to_string()))
+        );
+        assert_eq!(
+            origins.get(&"127.0.0.7".parse::<IpAddr>().unwrap()),
+            Some(&super::TargetOrigin::FileLine {
+                path: path.clone(),
+                line: 2,
+                token: "127.0.0.6/31".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn time_window_parse() {
+        use super::TimeWindow;
+
+        let window = TimeWindow::parse("22:00-06:30").unwrap();
+        assert_eq!(window, TimeWindow::new((22, 0), (6, 30)));
+        assert_eq!(window.to_string(), "22:00-06:30");
+        assert_eq!(
+            TimeWindow::parse(" 9:00 - 17:05 ").unwrap().to_string(),
+            "09:00-17:05"
+        );
+        assert!(TimeWindow::parse("24:00-06:00").is_none());
+        assert!(TimeWindow::parse("22:00-06:60").is_none());
+        assert!(TimeWindow::parse("22:00").is_none());
+    }
+
+    #[test]
+    fn time_window_try_new() {
+        use super::TimeWindow;
+
+        assert_eq!(
+            TimeWindow::try_new((22, 0), (6, 30)),
+            Some(TimeWindow::new((22, 0), (6, 30)))
+        );
+        assert!(TimeWindow::try_new((24, 0), (6, 0)).is_none());
+        assert!(TimeWindow::try_new((22, 0), (6, 60)).is_none());
+        assert!(std::panic::catch_unwind(|| TimeWindow::new((24, 0), (6, 0))).is_err());
+    }
+
+    #[test]
+    fn time_window_wait() {
+        use super::TimeWindow;
+
+        let day = TimeWindow::new((9, 0), (17, 30));
+        let night = TimeWindow::new((22, 0), (6, 0));
+
+        assert_eq!(super::time_window_wait(&[], 0), None);
+        assert_eq!(super::time_window_wait(&[day], 9 * 3600), None);
+        assert_eq!(super::time_window_wait(&[day], 8 * 3600), Some(3600));
+        assert_eq!(
+            super::time_window_wait(&[day], 17 * 3600 + 30 * 60),
+            Some(15 * 3600 + 30 * 60)
+        );
+        assert_eq!(super::time_window_wait(&[night], 23 * 3600), None);
+        assert_eq!(super::time_window_wait(&[night], 3600), None);
+        assert_eq!(
+            super::time_window_wait(&[day, night], 18 * 3600),
+            Some(4 * 3600)
+        );
+    }
+
+    #[test]
+    fn ipv6_cidr_sample() {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let small = cidr_utils::cidr::Ipv6Cidr::from_str("2001:db8::/126").unwrap();
+        assert_eq!(super::ipv6_cidr_sample(&small, 8, &mut rng).len(), 4);
+
+        let large = cidr_utils::cidr::Ipv6Cidr::from_str("2001:db8::/64").unwrap();
+        let res = super::ipv6_cidr_sample(&large, 8, &mut rng);
+        assert_eq!(res.len(), 8);
+        assert_eq!(
+            res.iter().collect::<std::collections::HashSet<_>>().len(),
+            8
+        );
+        for ip in res {
+            if let std::net::IpAddr::V6(ip) = ip {
+                assert!(large.contains(ip));
+            }
+        }
+
+        let all = cidr_utils::cidr::Ipv6Cidr::from_str("::/0").unwrap();
+        assert_eq!(super::ipv6_cidr_sample(&all, 3, &mut rng).len(), 3);
+    }
+
+    #[test]
+    fn parse_empty_port() {
+        let res = super::ports_parse("").unwrap();
+        assert_eq!(res, Vec::<u16>::new());
+    }
+
+    #[test]
+    fn parse_commas_port() {
+        let res = super::ports_parse(",,,").unwrap();
+        assert_eq!(res, Vec::<u16>::new());
+    }
+
+    #[test]
+    fn parse_single_port() {
+        let res = super::ports_parse("80").unwrap();
+        assert_eq!(res, vec![80]);
+    }
+
+    #[test]
+    fn parse_repeated_port1() {
+        let res = super::ports_parse("80,80").unwrap();
+        assert_eq!(res, vec![80]);
+    }
+
+    #[test]
+    fn parse_repeated_port2() {
+        let res = super::ports_parse("80,79-81").unwrap();
+        assert_eq!(res, vec![80, 79, 81]);
+    }
+
+    #[test]
+    fn parse_repeated_port3() {
+        let res = super::ports_parse("80,128,79-81").unwrap();
+        assert_eq!(res, vec![80, 128, 79, 81]);
+    }
+
+    #[test]
+    fn parse_multiple_ports() {
+        let res = super::ports_parse("80, 443,8080").unwrap();
+        assert_eq!(res, vec![80, 443, 8080]);
+    }
+
+    #[test]
+    fn parse_ports_range() {
+        let res = super::ports_parse("80-83").unwrap();
+        assert_eq!(res, vec![80, 81, 82, 83]);
+    }
+
+    #[test]
+    fn parse_ports_mixed() {
+        let res = super::ports_parse("21,80-83,443,8080-8081").unwrap();
+        assert_eq!(res, vec![21, 80, 81, 82, 83, 443, 8080, 8081]);
+    }
+
+    #[test]
+    fn parse_ports_malformed() {
+        use super::QScanParseError::*;
+
+        for (ports, err) in [
+            ("80,abc", InvalidPort("abc".to_string())),
+            ("80-abc", InvalidPort("abc".to_string())),
+            ("70000", PortOutOfRange("70000".to_string())),
+            ("1-99999", PortOutOfRange("99999".to_string())),
+            ("1-2-3", InvalidRange("1-2-3".to_string())),
+            ("80--", InvalidRange("80--".to_string())),
+            ("--80", InvalidRange("--80".to_string())),
+            ("100-80", ReversedRange("100-80".to_string())),
+        ] {
+            assert_eq!(super::ports_parse(ports), Err(err), "{}", ports);
+        }
+
+        // Empty items are still skipped
+        assert_eq!(super::ports_parse(",80,,443,").unwrap(), vec![80, 443]);
+        assert_eq!(
+            super::ports_parse_lenient("22,1-2-3,100-80,80"),
+            vec![22, 80]
+        );
+    }
+
+    #[test]
+    fn parse_ports_open_ended() {
+        let full: Vec<u16> = (1..=u16::MAX).collect();
+        assert_eq!(
+            super::ports_parse("1024-").unwrap(),
+            (1024..=u16::MAX).collect::<Vec<u16>>()
+        );
+        assert_eq!(
+            super::ports_parse("-80").unwrap(),
+            (1..=80).collect::<Vec<u16>>()
+        );
+        assert_eq!(super::ports_parse("-").unwrap(), full);
+        // Still deduplicated
+        assert_eq!(super::ports_parse("443,-,80").unwrap()[..2], [443, 1]);
+        assert_eq!(super::ports_parse("-3,2-").unwrap(), full);
+    }
+
+    #[test]
+    fn parse_ports_keywords() {
+        assert_eq!(super::ports_parse("all").unwrap().len(), 65535);
+        assert_eq!(
+            super::ports_parse("well-known").unwrap(),
+            (1..=1023).collect::<Vec<u16>>()
+        );
+        assert_eq!(
+            super::ports_parse("Reserved").unwrap(),
+            super::ports_parse("1-1023").unwrap()
+        );
+        assert_eq!(
+            super::ports_parse("DYNAMIC").unwrap(),
+            (49152..=65535).collect::<Vec<u16>>()
+        );
+
+        let ports = super::ports_parse("22,all").unwrap();
+        assert_eq!(ports.len(), 65535);
+        assert_eq!(ports[..3], [22, 1, 2]);
+    }
+
+    #[test]
+    fn try_new_ports() {
+        let mut scanner = super::QScanner::try_new("127.0.0.1", "22,80-81").unwrap();
+        assert_eq!(*scanner.get_tagets_ports(), vec![22, 80, 81]);
+        assert!(super::QScanner::try_new("127.0.0.1", "22,x").is_err());
+
+        assert!(scanner.try_set_targets_port("443,70000").is_err());
+        assert_eq!(*scanner.get_tagets_ports(), vec![22, 80, 81]);
+        scanner.try_set_targets_port("443").unwrap();
+        assert_eq!(*scanner.get_tagets_ports(), vec![443]);
+
+        // The infallible constructor skips the malformed items
+        let scanner = super::QScanner::new("127.0.0.1", "22,1-2-3,80");
+        assert_eq!(*scanner.get_tagets_ports(), vec![22, 80]);
+    }
+
+    #[test]
+    fn close_policy() {
+        use super::ClosePolicy;
+        use std::io::Read;
+
+        let rt = Runtime::new().unwrap();
+        for (policy, reset) in [
+            (ClosePolicy::GracefulFin, false),
+            (ClosePolicy::AbortiveRst, true),
+            (ClosePolicy::Drop, false),
+        ] {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let mut scanner = super::QScanner::new("127.0.0.1", &port.to_string());
+            scanner.set_close_policy(policy);
+            let res = rt.block_on(scanner.scan_tcp_connect());
+            if let super::QScanResult::TcpConnect(sa) = &res[0] {
+                assert_eq!(sa.state, super::QScanTcpConnectState::Open);
+            }
+
+            let (mut accepted, _) = listener.accept().unwrap();
+            match accepted.read(&mut [0u8; 1]) {
+                Ok(n) => assert!(!reset && n == 0),
+                Err(e) => assert!(reset && e.kind() == std::io::ErrorKind::ConnectionReset),
+            }
+        }
+    }
+
+    #[test]
+    fn cancel_scan() {
+        let mut scanner = super::QScanner::new("127.0.0.0/24", "1");
+        // One probe every ~14s: the scan would last one hour
+        scanner.set_scan_spread(Duration::from_secs(3600));
+        let cancel = scanner.cancel_handle();
+        let start = std::time::Instant::now();
+
+        let nres = Runtime::new().unwrap().block_on(async {
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                cancel.cancel();
+            });
+            scanner.scan_tcp_connect().await.len()
+        });
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(nres < 256);
+        assert_eq!(scanner.get_last_results().unwrap().len(), nres);
+
+        // A new scan is not affected by the previous cancel
+        scanner.set_targets("127.0.0.1", "1");
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+        assert_eq!(res.len(), 1);
+    }
+
+    #[test]
+    fn probe_audit_log() {
+        let path = std::env::temp_dir().join("qscan_test_probe_audit.log");
+        let _ = std::fs::remove_file(&path);
+        let rt = Runtime::new().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut scanner = super::QScanner::new("127.0.0.1", &format!("{},1", port));
+        scanner.set_probe_audit_log(path.clone()).unwrap();
+        rt.block_on(scanner.scan_tcp_connect());
+        scanner.set_timeout_ms(100);
+        scanner.set_udp_payload(b"hi");
+        rt.block_on(scanner.scan_udp());
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Vec<&str>> = log.lines().map(|l| l.split(' ').collect()).collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines.iter().all(|l| l.len() == 5));
+
+        let open = lines
+            .iter()
+            .find(|l| l[1] == "tcp" && l[3] == format!("127.0.0.1:{}", port))
+            .unwrap();
+        assert!(open[2].starts_with("127.0.0.1:"));
+        assert_eq!(open[4], "-");
+        let closed = lines
+            .iter()
+            .find(|l| l[1] == "tcp" && l[3] == "127.0.0.1:1")
+            .unwrap();
+        assert_eq!(closed[2], "-");
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|l| l[1] == "udp" && l[4] == "6869")
+                .count(),
+            2
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn top_ports() {
+        let mut scanner = super::QScanner::new("127.0.0.1", "22");
+        scanner.set_top_ports(5);
+        assert_eq!(*scanner.get_tagets_ports(), vec![80, 23, 443, 21, 22]);
+
+        scanner.set_top_ports(1000);
+        assert_eq!(scanner.get_tagets_ports().len(), 100);
+        assert!(scanner.get_tagets_ports().contains(&3306));
+    }
+
+    #[test]
+    fn parse_service_ports() {
+        let res = super::ports_parse("ssh,http,https").unwrap();
+        assert_eq!(res, vec![22, 80, 443]);
+    }
+
+    #[test]
+    fn parse_service_ports_mixed() {
+        let res = super::ports_parse_lenient("http,80,3306,nosuchservice,ssh-smtp");
+        assert_eq!(res, vec![80, 3306, 22, 23, 24, 25]);
+    }
+
+    #[test]
+    fn set_new_targets() {
+        let mut scanner = super::QScanner::new("", "");
+        scanner.set_targets("1.1.1.1", "80");
+        assert_eq!(
+            *scanner.get_tagets_ips(),
+            vec!["1.1.1.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(*scanner.get_tagets_ports(), vec![80]);
+    }
+
+    #[test]
+    fn add_new_targets() {
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        scanner.add_targets("127.0.0.0/30,192.168.1.1", "79-80,81");
+        assert_eq!(
+            *scanner.get_tagets_ips(),
+            vec![
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "127.0.0.0".parse::<IpAddr>().unwrap(),
+                "127.0.0.2".parse::<IpAddr>().unwrap(),
+                "127.0.0.3".parse::<IpAddr>().unwrap(),
+                "192.168.1.1".parse::<IpAddr>().unwrap(),
+            ]
+        );
+        assert_eq!(*scanner.get_tagets_ports(), vec![80, 79, 81]);
+    }
+
+    #[test]
+    fn set_vec_new_targets() {
+        let mut scanner = super::QScanner::new("", "");
+        let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))];
+        let target_ports = vec![80];
+        scanner.set_vec_targets(target_ips, target_ports);
+        assert_eq!(
+            *scanner.get_tagets_ips(),
+            vec!["127.0.0.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(*scanner.get_tagets_ports(), vec![80]);
+    }
+
+    #[test]
+    fn add_vec_new_targets() {
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        let target_ips = vec![
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        ];
+        let target_ports = vec![443, 80, 53];
+        scanner.add_vec_targets(target_ips, target_ports);
+        assert_eq!(
+            *scanner.get_tagets_ips(),
+            vec![
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "127.0.0.2".parse::<IpAddr>().unwrap(),
+            ]
+        );
+        assert_eq!(*scanner.get_tagets_ports(), vec![80, 443, 53]);
+    }
+
+    #[test]
+    fn add_many_targets() {
+        let ips: Vec<IpAddr> = (0..10_000u32)
+            .map(|i| IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i)))
+            .collect();
+
+        let mut scanner = super::QScanner::new("", "80");
+        let start = Instant::now();
+        for chunk in ips.chunks(10) {
+            // Every chunk also repeats the first addresses
+            scanner.add_vec_targets_addr(chunk.to_vec());
+            scanner.add_vec_targets_addr(ips[..10].to_vec());
+        }
+        scanner.add_targets_addr("10.0.0.0/30,10.0.39.15");
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(*scanner.get_tagets_ips(), ips);
+    }
+
+    #[test]
+    fn sockiter_no_host_ports() {
+        let ips = super::addresses_parse("127.0.0.1,127.0.0.2", &Default::default()).0;
+        let ports = vec![80, 443];
+        let host_ports = HashMap::new();
+        let socks: Vec<SocketAddr> =
+            super::sockiter::SockIter::new(&ips, &[], &ports, &host_ports).collect();
+        assert_eq!(
+            socks,
+            vec![
+                "127.0.0.1:80".parse::<SocketAddr>().unwrap(),
+                "127.0.0.2:80".parse::<SocketAddr>().unwrap(),
+                "127.0.0.1:443".parse::<SocketAddr>().unwrap(),
+                "127.0.0.2:443".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn sockiter_host_ports_override() {
+        let ips = super::addresses_parse("127.0.0.1,127.0.0.2,127.0.0.3", &Default::default()).0;
+        let ports = vec![80, 443];
+        let mut host_ports = HashMap::new();
+        host_ports.insert("127.0.0.2".parse::<IpAddr>().unwrap(), vec![5432, 3306]);
+        // Hosts that are not targets are ignored
+        host_ports.insert("10.0.0.1".parse::<IpAddr>().unwrap(), vec![22]);
+        let socks: Vec<SocketAddr> =
+            super::sockiter::SockIter::new(&ips, &[], &ports, &host_ports).collect();
+        assert_eq!(
+            socks,
+            vec![
+                "127.0.0.1:80".parse::<SocketAddr>().unwrap(),
+                "127.0.0.3:80".parse::<SocketAddr>().unwrap(),
+                "127.0.0.1:443".parse::<SocketAddr>().unwrap(),
+                "127.0.0.3:443".parse::<SocketAddr>().unwrap(),
+                "127.0.0.2:5432".parse::<SocketAddr>().unwrap(),
+                "127.0.0.2:3306".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn stratified_sample_proportional() {
+        let mut scanner = super::QScanner::new("10.0.0.0/24,10.1.0.0/26", "80");
+        scanner.set_stratified_sample(40);
+        scanner.set_seed(42);
+        let sampled = scanner.sampled_ips().unwrap();
+
+        let in_first = sampled
+            .iter()
+            .filter(|ip| ip.to_string().starts_with("10.0.0."))
+            .count();
+        let in_second = sampled
+            .iter()
+            .filter(|ip| ip.to_string().starts_with("10.1.0."))
+            .count();
+        assert_eq!(in_first, 32);
+        assert_eq!(in_second, 8);
+
+        // Same seed, same sample
+        assert_eq!(scanner.sampled_ips().unwrap(), sampled);
+        assert_eq!(scanner.sock_iter().count(), 40);
+    }
+
+    #[test]
+    fn stratified_allocation_remainders() {
+        let strata = vec![3; 3];
+        assert_eq!(super::stratified_allocation(&strata, 4), vec![2, 1, 1]);
+        assert_eq!(super::stratified_allocation(&strata, 100), vec![3, 3, 3]);
+    }
+
+    #[test]
+    fn socket_targets() {
+        let mut scanner = super::QScanner::new("8.8.8.8,1.1.1.1,10.0.0.0/24", "53,80,443");
+        let sockets: Vec<SocketAddr> = vec![
+            "8.8.8.8:53".parse().unwrap(),
+            "1.1.1.1:80".parse().unwrap(),
+            "8.8.8.8:53".parse().unwrap(),
+        ];
+        scanner.set_socket_targets(sockets);
+
+        assert_eq!(scanner.sockets_count(), 2);
+        let mut probed: Vec<SocketAddr> = scanner.sock_iter().collect();
+        probed.sort();
+        assert_eq!(
+            probed,
+            vec![
+                "1.1.1.1:80".parse::<SocketAddr>().unwrap(),
+                "8.8.8.8:53".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn local_only_skips_off_host() {
+        let mut scanner = super::QScanner::new("127.0.0.1,::1,192.0.2.1", "22,80");
+        assert_eq!(
+            scanner.set_local_only(true),
+            Err(vec!["192.0.2.1".parse::<IpAddr>().unwrap()])
+        );
+        assert!(scanner.sock_iter().all(|s| s.ip().is_loopback()));
+        assert_eq!(scanner.sock_iter().count(), 4);
+
+        assert_eq!(scanner.set_local_only(false), Ok(()));
+        assert_eq!(scanner.sock_iter().count(), 6);
+    }
+
+    #[test]
+    fn reset_config_keeps_targets() {
+        let mut scanner = super::QScanner::new("127.0.0.0/30", "80,443");
+        let defaults = scanner.export_scan_plan();
+        scanner.set_scan_type(crate::QScanType::Ping);
+        scanner.set_batch(10);
+        scanner.set_timeout_ms(250);
+        scanner.set_ntries(3);
+        scanner.set_ping_interval_ms(10);
+        scanner.set_local_only(true).unwrap();
+        scanner.set_exclude("127.0.0.1,127.0.0.2/31");
+        scanner.reset_config();
+
+        assert_eq!(scanner.export_scan_plan(), defaults);
+        assert!(!scanner.local_only);
+        assert_eq!(scanner.get_excluded_count(), 0);
+        assert_eq!(scanner.enumerate_targets().count(), 8);
+        assert_eq!(
+            scanner.origin_of("127.0.0.1".parse::<IpAddr>().unwrap()),
+            Some(&super::TargetOrigin::Token("127.0.0.0/30".to_string()))
+        );
+
+        // The resolver created by the previous parses is reused
+        scanner.set_resolver_config(fake_name_server(), super::ResolverOpts::default());
+        scanner.add_targets_addr("a.qscan.test");
+        let resolver = scanner.resolver.get().unwrap() as *const Resolver;
+        scanner.reset_config();
+        assert_eq!(scanner.resolver.get().unwrap() as *const Resolver, resolver);
+    }
+
+    #[test]
+    fn scan_plan_roundtrip() {
+        let mut planner = super::QScanner::new("127.0.0.0/30", "80,443");
+        planner.set_batch(10);
+        planner.set_timeout_ms(250);
+        planner.set_ntries(3);
+        let plan = planner.export_scan_plan();
+        assert_eq!(plan.ips.len(), 4);
+        assert_eq!(plan.ports, vec![80, 443]);
+        assert_eq!(plan.timeout_ms, 250);
+
+        let scanner = super::QScanner::from_scan_plan(plan.clone());
+        assert_eq!(scanner.export_scan_plan(), plan);
+    }
+
+    #[test]
+    fn collapse_to_cidrs() {
+        let collapse = |s: &str| {
+            let mut scanner = super::QScanner::new("127.0.0.1", "80");
+            scanner.set_exclude(s);
+            super::collapse_to_cidrs(&scanner.excluded_ips)
+        };
+
+        assert_eq!(collapse("10.0.0.1"), vec!["10.0.0.1"]);
+        assert_eq!(collapse("10.1.0.0/16"), vec!["10.1.0.0/16"]);
+        assert_eq!(
+            collapse("10.0.0.0/31,10.0.0.2"),
+            vec!["10.0.0.0/31", "10.0.0.2"]
+        );
+        assert_eq!(
+            collapse("10.0.0.1-6"),
+            vec!["10.0.0.1", "10.0.0.2/31", "10.0.0.4/31", "10.0.0.6"]
+        );
+        assert_eq!(
+            collapse("::1,::2,::3,255.255.255.254/31"),
+            vec!["255.255.255.254/31", "::1", "::2/127"]
+        );
+    }
+
+    #[test]
+    fn scan_plan_cidrs_excludes_timing() {
+        let mut planner = super::QScanner::new("127.0.0.1,127.0.0.2,10.0.0.0/8", "80");
+        planner.set_exclude("127.0.0.2,10.1.0.0/16,10.16.0.0/12");
+        planner.set_timeout_tiers(vec![Duration::from_millis(200), Duration::from_secs(2)]);
+        planner.set_timeout_backoff(1.5);
+        planner.set_rate(300);
+        planner.set_scan_spread(Duration::from_secs(3600));
+        planner.set_host_start_jitter(Duration::from_millis(500));
+        planner.set_allowed_time_window(vec![super::TimeWindow::new((22, 0), (6, 0))]);
+        let plan = planner.export_scan_plan();
+
+        // The /8 is not expanded
+        assert_eq!(plan.ips.len(), 2);
+        assert_eq!(plan.cidrs, vec!["10.0.0.0/8".to_string()]);
+        // Expanded and large excludes
+        assert_eq!(
+            plan.excludes,
+            vec!["10.1.0.0/16", "127.0.0.2", "10.16.0.0/12"]
+        );
+        assert_eq!(plan.timeout_tiers_ms, vec![200, 2000]);
+        assert_eq!(plan.rate, 300);
+        assert_eq!(plan.scan_spread_ms, Some(3_600_000));
+        assert_eq!(plan.host_start_jitter_ms, Some(500));
+
+        let scanner = super::QScanner::from_scan_plan(plan.clone());
+        assert_eq!(scanner.export_scan_plan(), plan);
+        assert_eq!(scanner.get_sockets_count(), planner.get_sockets_count());
+        assert_eq!(scanner.rate, planner.rate);
+        assert_eq!(scanner.scan_spread, planner.scan_spread);
+    }
+
+    #[test]
+    #[cfg(feature = "serialize")]
+    fn scan_plan_json_roundtrip() {
+        let mut planner = super::QScanner::new("127.0.0.1,10.0.0.0/8", "22,80");
+        let mut map = HashMap::new();
+        map.insert("127.0.0.1".parse::<IpAddr>().unwrap(), vec![443]);
+        planner.set_host_port_map(map);
+        planner.set_exclude("10.0.0.1");
+        planner.set_scan_type(crate::QScanType::Ping);
+        planner.set_timeout_tiers(vec![Duration::from_millis(100)]);
+        planner.set_timeout_backoff(2.0);
+        planner.set_rate(50);
+        planner.set_allowed_time_window(vec![super::TimeWindow::new((8, 30), (17, 0))]);
+        let json = planner.get_scan_plan_as_json_string().unwrap();
+
+        let scanner = super::QScanner::from_scan_plan_json(&json).unwrap();
+        assert_eq!(scanner.export_scan_plan(), planner.export_scan_plan());
+        assert_eq!(scanner.get_scan_plan_as_json_string().unwrap(), json);
+
+        assert!(super::QScanner::from_scan_plan_json("{}").is_err());
+        let bad = json.replace("\"PING\"", "\"NOPE\"");
+        assert!(super::QScanner::from_scan_plan_json(&bad).is_err());
+    }
+
+    #[test]
+    fn scan_plan_host_ports_roundtrip() {
+        let sockets = |scanner: &super::QScanner| -> std::collections::HashSet<SocketAddr> {
+            scanner.enumerate_targets().collect()
+        };
+
+        let mut planner = super::QScanner::new("127.0.0.1,127.0.0.2", "80");
+        let mut map = HashMap::new();
+        map.insert("127.0.0.2".parse::<IpAddr>().unwrap(), vec![22, 5432]);
+        planner.set_host_port_map(map.clone());
+        let plan = planner.export_scan_plan();
+        assert_eq!(plan.host_port_map, map);
+
+        let scanner = super::QScanner::from_scan_plan(plan.clone());
+        assert_eq!(scanner.export_scan_plan(), plan);
+        assert_eq!(sockets(&scanner), sockets(&planner));
+        assert_eq!(sockets(&scanner).len(), 3);
+
+        let targets: Vec<SocketAddr> = ["127.0.0.1:22", "127.0.0.1:443", "127.0.0.3:80"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        planner.set_socket_targets(targets.clone());
+        let plan = planner.export_scan_plan();
+        assert!(plan.ports.is_empty());
+
+        let scanner = super::QScanner::from_scan_plan(plan.clone());
+        assert_eq!(scanner.export_scan_plan(), plan);
+        assert_eq!(sockets(&scanner), targets.into_iter().collect());
+    }
+
+    #[test]
+    fn scan_rtt_localhost() {
+        let rt = Runtime::new().unwrap();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let scanner = super::QScanner::new("127.0.0.1", &port.to_string());
+        let res = rt.block_on(scanner.scan_rtt());
+
+        assert_eq!(res.len(), 1);
+        assert_eq!(
+            res[0].0,
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+        );
+        assert!(res[0].1.is_some());
+    }
+
+    #[test]
+    fn scan_rtt_cancel() {
+        let mut scanner = super::QScanner::new("127.0.0.0/24", "1");
+        // One probe every ~14s: the scan would last one hour
+        scanner.set_scan_spread(Duration::from_secs(3600));
+        let cancel = scanner.cancel_handle();
+        let start = std::time::Instant::now();
+
+        let res = Runtime::new().unwrap().block_on(async {
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                cancel.cancel();
+            });
+            scanner.scan_rtt().await
+        });
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(res.len() < 256);
+    }
+
+    #[test]
+    fn scan_tcp_connect_target_rewriter() {
+        let rt = Runtime::new().unwrap();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut scanner = super::QScanner::new("192.0.2.1", &port.to_string());
+        scanner.set_target_rewriter(|ip| {
+            if ip == IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)) {
+                IpAddr::V4(Ipv4Addr::LOCALHOST)
+            } else {
+                ip
+            }
+        });
+        let res = rt.block_on(scanner.scan_tcp_connect());
+
+        assert_eq!(res.len(), 1);
+        if let super::QScanResult::TcpConnect(sa) = &res[0] {
+            assert_eq!(
+                sa.target,
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+            );
+            assert_eq!(sa.state, super::QScanTcpConnectState::Open);
+        }
+    }
+
+    #[test]
+    fn scan_tcp_connect_blocking_connector() {
+        let mut scanner = super::QScanner::new("127.0.0.1", "80,81");
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let calls_cl = calls.clone();
+        scanner.set_ntries(2);
+        scanner.set_retry_on_refused(true);
+        scanner.set_blocking_connector(move |sock, _| {
+            calls_cl.lock().unwrap().push(sock);
+            if sock.port() == 80 {
+                Ok(())
+            } else {
+                Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+            }
+        });
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        assert_eq!(res.len(), 2);
+        for r in res {
+            if let super::QScanResult::TcpConnect(sa) = r {
+                if sa.target.port() == 80 {
+                    assert_eq!(sa.state, super::QScanTcpConnectState::Open);
+                } else {
+                    assert_eq!(sa.state, super::QScanTcpConnectState::Close);
+                }
+            }
+        }
+        // One call for the open port, two tries for the closed one
+        assert_eq!(calls.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "binary")]
+    fn binary_results_roundtrip() {
+        let rt = Runtime::new().unwrap();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut scanner = super::QScanner::new("127.0.0.1,::1", &format!("{},1", port));
+        rt.block_on(scanner.scan_tcp_connect());
+
+        let path = std::env::temp_dir().join("qscan_binary_results_roundtrip.bin");
+        scanner.write_last_results_binary(&path).unwrap();
+        let res = super::QScanner::read_results_binary(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let last = scanner.get_last_results().unwrap();
+        assert_eq!(res.len(), last.len());
+        for (r, l) in res.iter().zip(last.iter()) {
+            if let (super::QScanResult::TcpConnect(r), super::QScanResult::TcpConnect(l)) = (r, l) {
+                assert_eq!(r.target, l.target);
+                assert_eq!(r.state, l.state);
+                assert_eq!(r.rtt.map(|d| d.as_micros()), l.rtt.map(|d| d.as_micros()));
+            } else {
+                panic!("Unexpected result type");
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "protobuf")]
+    fn protobuf_results() {
+        use crate::proto::{self, ScanResult};
+        use prost::Message;
+
+        let rt = Runtime::new().unwrap();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut scanner = super::QScanner::new("127.0.0.1", &format!("{}", port));
+        rt.block_on(scanner.scan_tcp_connect());
+
+        let res = proto::Results::decode(scanner.get_last_results_as_protobuf().as_slice());
+        let res = res.unwrap();
+        assert_eq!(res.results.len(), 1);
+        match &res.results[0].result {
+            Some(ScanResult::TcpConnect(r)) => {
+                assert_eq!(r.ip, vec![127, 0, 0, 1]);
+                assert_eq!(r.port, port as u32);
+                assert_eq!(r.state(), proto::TcpConnectState::Open);
+                assert_eq!(r.via, "direct");
+                assert!(r.rtt_us.is_some());
+            }
+            _ => panic!("Unexpected result type"),
+        }
+
+        let scanner = super::QScanner::new("127.0.0.1", "80");
+        assert!(scanner.get_last_results_as_protobuf().is_empty());
+    }
+
+    #[test]
+    fn randomize_same_sockets() {
+        let mut map = HashMap::new();
+        map.insert(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)), vec![8080, 8443]);
+        map.insert(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), vec![22]);
+        let mut scanner = super::QScanner::new("127.0.0.1,127.0.0.2,10.0.0.0/28", "22,80,443");
+        scanner.set_host_port_map(map);
+        let sorted: Vec<SocketAddr> = scanner.sock_iter().collect();
+
+        scanner.set_randomize(true);
+        scanner.set_seed(42);
+        let shuffled: Vec<SocketAddr> = scanner.sock_iter().collect();
+        assert_ne!(shuffled, sorted);
+
+        // Same seed, same order
+        assert_eq!(scanner.sock_iter().collect::<Vec<SocketAddr>>(), shuffled);
+
+        let (mut a, mut b) = (shuffled, sorted);
+        a.sort();
+        b.sort();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn permutation() {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        for n in [0u128, 1, 2, 3, 17, 64, 1000] {
+            let mut perm: Vec<u128> = super::sockiter::Permutation::new(n, &mut rng).collect();
+            assert_eq!(perm.len() as u128, n);
+            perm.sort();
+            assert!(perm.into_iter().eq(0..n));
+        }
+
+        // A bare power of two LCG alternates the parity of its outputs
+        let perm: Vec<u128> = super::sockiter::Permutation::new(1024, &mut rng).collect();
+        assert!(perm.windows(2).any(|w| w[0] % 2 == w[1] % 2));
+        assert!(perm
+            .windows(4)
+            .any(|w| w[0] % 4 != w[2] % 4 && w[1] % 4 != w[3] % 4));
+    }
+
+    #[test]
+    fn timeout_backoff() {
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        let to = Duration::from_millis(500);
+        let tries = |s: &super::QScanner| (0..4).map(|n| s.try_timeout(to, n)).collect::<Vec<_>>();
+        assert_eq!(tries(&scanner), vec![to; 4]);
+
+        scanner.set_timeout_backoff(2.0);
+        assert_eq!(
+            tries(&scanner),
+            vec![
+                Duration::from_millis(500),
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(4)
+            ]
+        );
+
+        // Capped
+        scanner.set_timeout_backoff(100.0);
+        assert_eq!(scanner.try_timeout(to, 3), Duration::from_secs(60));
+        assert_eq!(
+            scanner.try_timeout(Duration::from_secs(90), 1),
+            Duration::from_secs(90)
+        );
+
+        // Shrinking timeouts are not allowed
+        scanner.set_timeout_backoff(0.5);
+        assert_eq!(tries(&scanner), vec![to; 4]);
+    }
+
+    #[test]
+    fn excludes_win() {
+        let target = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+        let mut scanner = super::QScanner::new("192.168.1.0/24,192.168.1.1", "80");
+        scanner.set_exclude("192.168.1.1");
+
+        assert_eq!(scanner.get_excluded_count(), 1);
+        assert!(!scanner.targets_iter().any(|ip| ip == target));
+        assert!(!scanner.sock_iter().any(|s| s.ip() == target));
+        assert_eq!(scanner.sock_iter().count(), 255);
+
+        // Excludes are kept when the targets change
+        scanner.set_targets_addr("192.168.1.1,192.168.1.2");
+        assert_eq!(scanner.get_excluded_count(), 1);
+        assert_eq!(scanner.targets_iter().collect::<Vec<IpAddr>>().len(), 1);
+    }
+
+    #[test]
+    fn exclude_from_cidr_and_file() {
+        use itertools::Itertools;
+
+        let path = std::env::temp_dir().join("qscan_exclude_from_cidr_and_file.txt");
+        std::fs::write(&path, "10.0.0.7\n10.0.0.8/30\n").unwrap();
+
+        let mut scanner = super::QScanner::new("10.0.0.0/27", "80,443");
+        scanner.set_exclude(&format!("10.0.0.1,10.0.0.254,{}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+
+        let ips: Vec<IpAddr> = scanner.sock_iter().map(|s| s.ip()).unique().collect();
+        assert_eq!(ips.len(), 32 - 6);
+        for ip in ["10.0.0.1", "10.0.0.7", "10.0.0.8", "10.0.0.11"] {
+            assert!(!ips.contains(&ip.parse().unwrap()));
+        }
+        assert!(ips.contains(&"10.0.0.12".parse().unwrap()));
+        assert_eq!(scanner.get_excluded_count(), 6);
+
+        // Excluded addresses within a large CIDR (not expanded in memory)
+        scanner.set_targets_addr("10.0.0.0/8");
+        assert_eq!(scanner.get_excluded_count(), 7);
+    }
+
+    #[test]
+    fn enumerate_targets() {
+        use itertools::Itertools;
+        use std::collections::HashSet;
+
+        let scanner = super::QScanner::new("127.0.0.1,10.0.0.0/30", "22,80,443");
+        let sockets: HashSet<SocketAddr> = scanner.enumerate_targets().collect();
+        let ips = ["127.0.0.1", "10.0.0.0", "10.0.0.1", "10.0.0.2", "10.0.0.3"];
+        let expected: HashSet<SocketAddr> = ips
+            .iter()
+            .cartesian_product([22, 80, 443])
+            .map(|(ip, port)| SocketAddr::new(ip.parse().unwrap(), port))
+            .collect();
+        assert_eq!(sockets, expected);
+        assert_eq!(scanner.get_sockets_count(), 15);
+
+        // Huge ranges are counted and enumerated lazily
+        let scanner = super::QScanner::new("10.0.0.0/8", "1-65535");
+        assert_eq!(scanner.get_sockets_count(), (1 << 24) * 65535);
+        assert_eq!(scanner.enumerate_targets().take(10).count(), 10);
+    }
+
+    #[test]
+    fn max_targets() {
+        let mut scanner = super::QScanner::new("10.1.0.0/16,10.2.0.1", "1000-1099");
+        assert_eq!(scanner.get_sockets_count(), (65536 + 1) * 100);
+        assert_eq!(scanner.check_max_targets().unwrap(), 6553700);
+
+        scanner.set_max_targets(6553700);
+        assert!(scanner.check_max_targets().is_ok());
+
+        scanner.set_max_targets(1_000_000);
+        let err = scanner.check_max_targets().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let rt = Runtime::new().unwrap();
+        let res = rt.block_on(scanner.scan_tcp_connect());
+        assert!(res.is_empty());
+        let err = rt.block_on(scanner.try_scan_tcp_connect()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(scanner.get_last_results().unwrap().is_empty());
+
+        // The ping scan counts the addresses only
+        scanner.set_scan_type(crate::QScanType::Ping);
+        assert_eq!(scanner.check_max_targets().unwrap(), 65537);
+        scanner.set_max_targets(65536);
+        assert!(scanner.check_max_targets().is_err());
+        assert!(rt.block_on(scanner.scan_ping()).is_empty());
+
+        scanner.set_scan_type(crate::QScanType::TcpConnect);
+        scanner.set_max_targets(1_000_000);
+        assert!(rt.block_on(scanner.scan_rtt()).is_empty());
+    }
+
+    #[test]
+    fn target_count() {
+        let scanner = super::QScanner::new("10.0.0.0/16", "1-1024");
+        assert_eq!(scanner.target_count(), 65536 * 1024);
+
+        // Beyond usize on 32 bits targets, and beyond u64
+        let scanner = super::QScanner::new("10.0.0.0/8,11.0.0.0/8", "1-65535");
+        assert_eq!(scanner.target_count(), 2 * (1 << 24) * 65535);
+        let scanner = super::QScanner::new("2001:db8::/32", "80,443");
+        assert_eq!(scanner.target_count(), u64::MAX);
+        assert_eq!(scanner.get_sockets_count(), usize::MAX);
+
+        let mut scanner = super::QScanner::new("127.0.0.1,127.0.0.2", "22,80,443");
+        let mut map = HashMap::new();
+        map.insert("127.0.0.2".parse::<IpAddr>().unwrap(), vec![8080]);
+        scanner.set_host_port_map(map);
+        assert_eq!(scanner.target_count(), 3 + 1);
+    }
+
+    #[test]
+    fn exclude_file() {
+        use itertools::Itertools;
+
+        let path = std::env::temp_dir().join("qscan_exclude_file.txt");
+        std::fs::write(
+            &path,
+            "# do not scan\n10.0.0.7\n\n10.0.0.8/30\n192.168.0.1\n172.16.0.0/12\n",
+        )
+        .unwrap();
+
+        let mut scanner = super::QScanner::new("10.0.0.0/28,172.16.0.1", "80");
+        scanner.set_exclude_file(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // 192.168.0.1 is not a target
+        assert_eq!(scanner.get_excluded_count(), 6);
+        let ips: Vec<IpAddr> = scanner.sock_iter().map(|s| s.ip()).unique().collect();
+        assert_eq!(ips.len(), 17 - 6);
+        for ip in ["10.0.0.7", "10.0.0.8", "10.0.0.11", "172.16.0.1"] {
+            assert!(!ips.contains(&ip.parse().unwrap()));
+        }
+        // The large CIDR is not expanded
+        assert_eq!(scanner.excluded_ips.len(), 6);
+
+        assert!(scanner.set_exclude_file(path).is_err());
+        assert_eq!(scanner.get_excluded_count(), 6);
+    }
+
+    #[test]
+    fn excluded_count_lazy_cidr() {
+        let mut scanner = super::QScanner::new("10.0.0.0/8", "80");
+        scanner.set_exclude("10.1.0.0/16,10.1.2.0/24,10.0.0.0/8,192.168.0.0/16");
+        assert_eq!(scanner.get_excluded_count(), 1 << 24);
+
+        scanner.set_exclude("10.1.0.0/16,10.1.2.0/24,10.2.0.1");
+        assert_eq!(scanner.get_excluded_count(), (1 << 16) + 1);
+    }
+
+    #[test]
+    fn timeout_tiers() {
+        let mut scanner = super::QScanner::new("127.0.0.1", "1,2,3");
+        // Port 1 answers within 200ms, port 2 refuses, port 3 never answers
+        scanner.set_blocking_connector(|socket, to| match socket.port() {
+            1 if to >= Duration::from_millis(200) => Ok(()),
+            2 => Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::TimedOut)),
+        });
+        scanner.set_timeout_ms(50);
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(scanner.scan_tcp_connect());
+        assert!(scanner.get_last_tier_stats().is_empty());
+        assert_eq!(scanner.get_last_stats().unwrap().open, 0);
+
+        let tiers = vec![Duration::from_millis(50), Duration::from_millis(300)];
+        scanner.set_timeout_tiers(tiers);
+        let res = rt.block_on(scanner.scan_tcp_connect());
+        assert_eq!(res.len(), 3);
+        let open: Vec<u16> = res
+            .iter()
+            .filter_map(|r| match r {
+                super::QScanResult::TcpConnect(sa)
+                    if sa.state == super::QScanTcpConnectState::Open =>
+                {
+                    Some(sa.target.port())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(open, vec![1]);
+
+        let stats = scanner.get_last_stats().unwrap();
+        assert_eq!((stats.open, stats.closed, stats.errors), (1, 2, 0));
+        let tiers = scanner.get_last_tier_stats();
+        assert_eq!(tiers.len(), 2);
+        assert_eq!(tiers[0].timeout, Duration::from_millis(50));
+        assert_eq!(
+            (
+                tiers[0].probed,
+                tiers[0].open,
+                tiers[0].closed,
+                tiers[0].timed_out
+            ),
+            (3, 0, 1, 2)
+        );
+        assert_eq!(
+            (
+                tiers[1].probed,
+                tiers[1].open,
+                tiers[1].closed,
+                tiers[1].timed_out
+            ),
+            (2, 1, 0, 1)
+        );
+    }
+
+    #[test]
+    fn scan_tcp_connect_total_retry_budget() {
+        let mut scanner = super::QScanner::new("127.0.0.1,127.0.0.2", "80,81");
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let calls_cl = calls.clone();
+        scanner.set_ntries(3);
+        scanner.set_retry_on_refused(true);
+        scanner.set_total_retry_budget(2);
+        scanner.set_blocking_connector(move |_, _| {
+            calls_cl.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        });
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(scanner.scan_tcp_connect());
+        // One attempt for each socket plus the budget
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 4 + 2);
+
+        // The budget is restored for the next scan
+        rt.block_on(scanner.scan_tcp_connect());
+        assert_eq!(
+            calls.load(std::sync::atomic::Ordering::Relaxed),
+            2 * (4 + 2)
+        );
+    }
+
+    #[test]
+    fn adaptive_timeout() {
+        let ms = Duration::from_millis;
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        scanner.set_timeout_ms(1000);
+        assert_eq!(scanner.adaptive_timeout(ms(1000)), ms(1000));
+
+        scanner.set_adaptive_timeout(true);
+        assert_eq!(scanner.adaptive_timeout(ms(1000)), ms(1000));
+
+        // Stubbed round trip times of 200ms
+        let mut prev = ms(1000);
+        for _ in 0..50 {
+            scanner.observe_rtt(ms(200));
+            let to = scanner.adaptive_timeout(ms(1000));
+            assert!(to <= prev, "{:?} > {:?}", to, prev);
+            prev = to;
+        }
+        assert!(prev >= ms(200) && prev < ms(250), "{:?}", prev);
+
+        // Never shorter than the minimum
+        for _ in 0..50 {
+            scanner.observe_rtt(ms(1));
+        }
+        assert_eq!(
+            scanner.adaptive_timeout(ms(1000)),
+            super::ADAPTIVE_TIMEOUT_MIN
+        );
+
+        // Never longer than the maximum
+        scanner.set_adaptive_timeout_max_ms(2000);
+        for _ in 0..50 {
+            scanner.observe_rtt(ms(5000));
+        }
+        assert_eq!(scanner.adaptive_timeout(ms(1000)), ms(2000));
+        scanner.reset_adaptive_timeout();
+        assert_eq!(scanner.adaptive_timeout(ms(5000)), ms(2000));
+
+        // The timeouts of the probes follow the refused connections
+        let mut scanner = super::QScanner::new("127.0.0.1", "1-20");
+        let timeouts = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let timeouts_cl = timeouts.clone();
+        scanner.set_batch(1);
+        scanner.set_adaptive_timeout(true);
+        scanner.set_blocking_connector(move |_, to| {
+            timeouts_cl.lock().unwrap().push(to);
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        });
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+        let timeouts = timeouts.lock().unwrap();
+        assert_eq!(timeouts.len(), 20);
+        assert_eq!(timeouts[0], ms(1000));
+        assert_eq!(timeouts[19], super::ADAPTIVE_TIMEOUT_MIN);
+    }
+
+    #[test]
+    fn closed_ports_as_lines() {
+        let rt = Runtime::new().unwrap();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut scanner = super::QScanner::new("127.0.0.1", &format!("{},1", port));
+        assert!(scanner.get_closed_ports_as_lines().is_empty());
+
+        rt.block_on(scanner.scan_tcp_connect());
+        assert_eq!(scanner.get_closed_ports_as_lines(), vec!["127.0.0.1:1"]);
+    }
+
+    #[test]
+    fn filtered_ports_as_lines() {
+        use super::{QScanTcpConnectResult, QScanTcpConnectState};
+
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        assert!(scanner.get_filtered_ports_as_lines().is_empty());
+
+        scanner.ingest_external_results(
+            [
+                ("10.0.0.1:22", QScanTcpConnectState::Open),
+                ("10.0.0.1:23", QScanTcpConnectState::Filtered),
+                ("10.0.0.1:24", QScanTcpConnectState::Close),
+                ("[::1]:25", QScanTcpConnectState::Filtered),
+            ]
+            .into_iter()
+            .map(|(t, s)| QScanTcpConnectResult::new(t.parse().unwrap(), s))
+            .collect(),
+        );
+        assert_eq!(
+            scanner.get_filtered_ports_as_lines(),
+            vec!["10.0.0.1:23", "[::1]:25"]
+        );
+        assert_eq!(scanner.get_closed_ports_as_lines(), vec!["10.0.0.1:24"]);
+    }
+
+    #[test]
+    fn open_and_closed_ports() {
+        use super::{QScanTcpConnectResult, QScanTcpConnectState};
+
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        assert_eq!(scanner.open_ports().count(), 0);
+        assert_eq!(scanner.closed_count(), 0);
+
+        let results = [
+            ("10.0.0.1:22", QScanTcpConnectState::Open),
+            ("10.0.0.1:23", QScanTcpConnectState::Close),
+            ("10.0.0.1:24", QScanTcpConnectState::Filtered),
+            ("10.0.0.2:80", QScanTcpConnectState::Open),
+            ("[::1]:443", QScanTcpConnectState::Close),
+        ];
+        scanner.ingest_external_results(
+            results
+                .into_iter()
+                .map(|(t, s)| QScanTcpConnectResult::new(t.parse().unwrap(), s))
+                .collect(),
+        );
+
+        let sockets = |addrs: &[&str]| -> Vec<SocketAddr> {
+            addrs.iter().map(|a| a.parse().unwrap()).collect()
+        };
+        assert_eq!(scanner.open_count(), 2);
+        assert_eq!(scanner.closed_count(), 2);
+        assert_eq!(
+            scanner.open_ports().copied().collect::<Vec<_>>(),
+            sockets(&["10.0.0.1:22", "10.0.0.2:80"])
+        );
+        assert_eq!(
+            scanner.closed_ports().copied().collect::<Vec<_>>(),
+            sockets(&["10.0.0.1:23", "[::1]:443"])
+        );
+    }
+
+    #[test]
+    fn results_by_host() {
+        use super::{QScanTcpConnectResult, QScanTcpConnectState};
+        use QScanTcpConnectState::{Close, Filtered, Open};
+
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        assert!(scanner.results_by_host().is_empty());
+
+        let results = [
+            ("10.0.0.2:443", Open),
+            ("10.0.0.1:8080", Close),
+            ("10.0.0.2:22", Filtered),
+            ("10.0.0.1:53", Open),
+            ("10.0.0.2:80", Close),
+            ("10.0.0.1:22", Open),
+        ];
+        scanner.ingest_external_results(
+            results
+                .into_iter()
+                .map(|(t, s)| QScanTcpConnectResult::new(t.parse().unwrap(), s))
+                .collect(),
+        );
+
+        let hosts = scanner.results_by_host();
+        let ip1: IpAddr = "10.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(hosts.keys().copied().collect::<Vec<_>>(), vec![ip1, ip2]);
+        assert_eq!(hosts[&ip1], vec![(22, Open), (53, Open), (8080, Close)]);
+        assert_eq!(hosts[&ip2], vec![(22, Filtered), (80, Close), (443, Open)]);
+    }
+
+    #[test]
+    fn last_results_as_greppable() {
+        use super::{QScanTcpConnectResult, QScanTcpConnectState};
+
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        assert!(scanner.get_last_results_as_greppable().is_empty());
+
+        let results = [
+            ("10.0.0.2:443", QScanTcpConnectState::Open),
+            ("10.0.0.1:8080", QScanTcpConnectState::Close),
+            ("10.0.0.2:22", QScanTcpConnectState::Open),
+            ("10.0.0.1:53", QScanTcpConnectState::Open),
+            ("10.0.0.3:80", QScanTcpConnectState::Close),
+            ("[::1]:8443", QScanTcpConnectState::Open),
+        ];
+        scanner.ingest_external_results(
+            results
+                .into_iter()
+                .map(|(t, s)| QScanTcpConnectResult::new(t.parse().unwrap(), s))
+                .collect(),
+        );
+
+        assert_eq!(
+            scanner.get_last_results_as_greppable(),
+            "Host: 10.0.0.1 Ports: 53/open\n\
+             Host: 10.0.0.2 Ports: 22/open,443/open\n\
+             Host: 10.0.0.3 Ports: \n\
+             Host: ::1 Ports: 8443/open\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serialize")]
+    fn last_results_as_grouped_json() {
+        use super::{QScanTcpConnectResult, QScanTcpConnectState};
+
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        let results = [
+            ("10.0.0.2:443", QScanTcpConnectState::Open),
+            ("10.0.0.1:80", QScanTcpConnectState::Close),
+            ("10.0.0.2:53", QScanTcpConnectState::Open),
+            ("10.0.0.1:22", QScanTcpConnectState::Open),
+            ("10.0.0.2:8080", QScanTcpConnectState::Filtered),
+            ("10.0.0.2:25", QScanTcpConnectState::OpenNoResponse),
+        ];
+        scanner.ingest_external_results(
+            results
+                .into_iter()
+                .map(|(t, s)| QScanTcpConnectResult::new(t.parse().unwrap(), s))
+                .collect(),
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&scanner.get_last_results_as_grouped_json_string().unwrap())
+                .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "10.0.0.1": { "open": [22], "closed": [80], "filtered": [] },
+                "10.0.0.2": {
+                    "open": [53, 443],
+                    "closed": [],
+                    "filtered": [8080],
+                    "open_no_response": [25],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn last_results_as_urls() {
+        use super::{QScanTcpConnectResult, QScanTcpConnectState};
 
-        for _ in 0..self.batch {
-            if let Some(ip) = ip_it.next() {
-                ftrs.push(self.scan_ip_ping(*ip, &client_v4, &client_v6));
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        assert!(scanner.get_last_results_as_urls(&[]).is_empty());
+
+        let results = [
+            ("127.0.0.1:80", QScanTcpConnectState::Open),
+            ("127.0.0.1:443", QScanTcpConnectState::Open),
+            ("127.0.0.1:8080", QScanTcpConnectState::Close),
+            ("[::1]:8443", QScanTcpConnectState::Open),
+        ];
+        scanner.ingest_external_results(
+            results
+                .into_iter()
+                .map(|(t, s)| QScanTcpConnectResult::new(t.parse().unwrap(), s))
+                .collect(),
+        );
+
+        assert_eq!(
+            scanner.get_last_results_as_urls(&[]),
+            vec![
+                "http://127.0.0.1:80/",
+                "https://127.0.0.1:443/",
+                "https://[::1]:8443/",
+            ]
+        );
+        assert_eq!(
+            scanner.get_last_results_as_urls(&[80]),
+            vec![
+                "https://127.0.0.1:80/",
+                "http://127.0.0.1:443/",
+                "http://[::1]:8443/",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_ports_against_policy() {
+        let mut scanner = super::QScanner::new("127.0.0.1,127.0.0.2", "22,80");
+        scanner.set_host_port_map(HashMap::from([(
+            "127.0.0.2".parse::<IpAddr>().unwrap(),
+            vec![443, 3389, 22],
+        )]));
+
+        assert_eq!(
+            scanner.validate_ports_against_policy(&[22, 443]),
+            Err(vec![80, 3389])
+        );
+        assert_eq!(
+            scanner.validate_ports_against_policy(&[22, 80, 443, 3389]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn next_host_group() {
+        let sockets: Vec<SocketAddr> = [
+            "127.0.0.1:1",
+            "127.0.0.1:2",
+            "127.0.0.1:3",
+            "127.0.0.2:1",
+            "127.0.0.1:4",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        let mut it = sockets.into_iter().peekable();
+
+        assert_eq!(super::next_host_group(&mut it, 2).len(), 2);
+        assert_eq!(super::next_host_group(&mut it, 2).len(), 1);
+        assert_eq!(super::next_host_group(&mut it, 2).len(), 1);
+        assert_eq!(super::next_host_group(&mut it, 2).len(), 1);
+        assert!(super::next_host_group(&mut it, 2).is_empty());
+    }
+
+    #[test]
+    fn dispatch_window() {
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        assert_eq!(scanner.dispatch_window(100, 1, 1_000_000), 100);
+
+        scanner.set_max_memory_mb(1);
+        let mb = 1024 * 1024;
+        assert_eq!(scanner.dispatch_window(100, 1, 0), 100);
+        assert_eq!(
+            scanner.dispatch_window(1000, 1, 0),
+            mb / super::IN_FLIGHT_SIZE_EST
+        );
+        assert_eq!(
+            scanner.dispatch_window(1000, 2, 0),
+            mb / super::IN_FLIGHT_SIZE_EST / 2
+        );
+        assert_eq!(
+            scanner.dispatch_window(1000, 1, mb / super::RESULT_SIZE_EST),
+            1
+        );
+
+        scanner.set_max_memory_mb(usize::MAX);
+        assert_eq!(scanner.dispatch_window(1000, usize::MAX, usize::MAX), 1);
+        assert_eq!(scanner.dispatch_window(1000, 1, 0), 1000);
+    }
+
+    #[test]
+    fn max_memory_spill() {
+        let path = std::env::temp_dir().join("qscan_test_max_memory_spill.txt");
+        let nports = 10000;
+        let mut scanner = super::QScanner::new("127.0.0.1", &format!("1-{}", nports));
+        scanner.set_blocking_connector(|sock, _| match sock.port() % 100 {
+            0 => Ok(()),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)),
+        });
+        scanner.set_max_memory_mb(1);
+        scanner.set_output_writer(Box::new(std::fs::File::create(&path).unwrap()));
+        let kept = Runtime::new()
+            .unwrap()
+            .block_on(scanner.scan_tcp_connect())
+            .len();
+
+        // The results never exceed the cap, the spilled ones are printed
+        assert!(kept < 1024 * 1024 / super::RESULT_SIZE_EST);
+        assert!(scanner.get_last_errors().len() <= kept);
+        let spilled = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(spilled.lines().count() > 0);
+        assert_eq!(spilled.lines().count() + kept, nports);
+        assert_eq!(
+            spilled.lines().filter(|l| l.ends_with(":OPEN")).count()
+                + scanner
+                    .tcp_sockets_in(super::QScanTcpConnectState::Open)
+                    .count(),
+            nports / 100
+        );
+        let stats = scanner.get_last_stats().unwrap();
+        assert_eq!(
+            (stats.open, stats.closed),
+            (nports / 100, nports - nports / 100)
+        );
+    }
+
+    #[test]
+    fn host_start_jitter() {
+        let mut scanner = super::QScanner::new("127.0.0.1,127.0.0.2,127.0.0.3", "80");
+        let ips = scanner.get_tagets_ips().clone();
+        scanner.set_seed(7);
+        scanner.reset_host_start();
+        assert!(scanner.host_start_of(ips[0]).is_none());
+
+        let max = Duration::from_millis(200);
+        scanner.set_host_start_jitter(max);
+        let before = std::time::Instant::now();
+        scanner.reset_host_start();
+        let first: Vec<Duration> = ips
+            .iter()
+            .map(|ip| scanner.host_start_of(*ip).unwrap() - before)
+            .collect();
+        assert_eq!(first.len(), 3);
+        assert!(first.iter().all(|d| *d <= max + Duration::from_millis(50)));
+
+        // Same seed, same jitters
+        let before = std::time::Instant::now();
+        scanner.reset_host_start();
+        for (ip, d) in ips.iter().zip(first) {
+            let d2 = scanner.host_start_of(*ip).unwrap() - before;
+            assert!(d2.as_millis().abs_diff(d.as_millis()) < 50);
+        }
+    }
+
+    #[test]
+    fn lazy_cidr_targets() {
+        let mut scanner = super::QScanner::new("10.0.0.0/8,127.0.0.1", "80,443");
+        // The /8 is not expanded when configured
+        assert_eq!(
+            scanner.get_tagets_ips(),
+            &vec!["127.0.0.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(
+            scanner.origin_of("10.1.2.3".parse().unwrap()),
+            Some(&super::TargetOrigin::Token("10.0.0.0/8".to_string()))
+        );
+
+        // ... but streamed when iterated
+        let sockets: Vec<SocketAddr> = scanner.sock_iter().take(3).collect();
+        assert_eq!(
+            sockets,
+            vec![
+                "127.0.0.1:80".parse().unwrap(),
+                "10.0.0.0:80".parse().unwrap(),
+                "10.0.0.1:80".parse().unwrap()
+            ]
+        );
+
+        // Sampling picks addresses without expanding the CIDR
+        scanner.set_seed(1);
+        scanner.set_stratified_sample(9);
+        let sampled = scanner.sampled_ips().unwrap();
+        assert_eq!(sampled.len(), 9);
+        // Proportionally, all from the /8
+        assert!(sampled.iter().all(|ip| scanner.origin_of(*ip)
+            == Some(&super::TargetOrigin::Token("10.0.0.0/8".to_string()))));
+
+        scanner.add_targets_addr("10.0.0.0/8,172.16.0.0/12");
+        assert_eq!(scanner.cidrs.len(), 2);
+        scanner.set_targets_addr("127.0.0.0/30");
+        assert!(scanner.cidrs.is_empty());
+        assert_eq!(scanner.get_tagets_ips().len(), 4);
+    }
+
+    #[test]
+    fn ingest_external_results() {
+        use super::{QScanResult, QScanTcpConnectResult, QScanTcpConnectState};
+
+        let rt = Runtime::new().unwrap();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let open: SocketAddr = listener.local_addr().unwrap();
+        let closed: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let external: SocketAddr = "192.0.2.1:22".parse().unwrap();
+        let mut scanner = super::QScanner::new("127.0.0.1", &format!("1,{}", open.port()));
+        rt.block_on(scanner.scan_tcp_connect());
+
+        scanner.ingest_external_results(vec![
+            QScanTcpConnectResult::new(open, QScanTcpConnectState::Close),
+            QScanTcpConnectResult::new(closed, QScanTcpConnectState::Open),
+            QScanTcpConnectResult::new(external, QScanTcpConnectState::Open),
+            QScanTcpConnectResult::new(external, QScanTcpConnectState::Close),
+        ]);
+
+        let is_open: HashMap<SocketAddr, bool> = scanner
+            .get_last_results()
+            .unwrap()
+            .iter()
+            .filter_map(|r| match r {
+                QScanResult::TcpConnect(r) => {
+                    Some((r.target, r.state == QScanTcpConnectState::Open))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(scanner.get_last_results().unwrap().len(), 3);
+        assert!(is_open[&open]);
+        assert!(is_open[&closed]);
+        assert!(is_open[&external]);
+    }
+
+    #[test]
+    fn closed_confidence() {
+        use std::io::ErrorKind;
+
+        assert_eq!(
+            super::closed_confidence(Some(ErrorKind::ConnectionRefused), 1),
+            0.95
+        );
+        assert!((super::closed_confidence(None, 1) - 0.3).abs() < 1e-6);
+        assert!(super::closed_confidence(None, 3) > super::closed_confidence(None, 2));
+        assert!(super::closed_confidence(None, 10) < 1.0);
+        assert_eq!(super::closed_confidence(Some(ErrorKind::Other), 2), 0.6);
+    }
+
+    #[test]
+    fn error_states() {
+        use super::QScanTcpConnectState::{Close, Filtered};
+        use std::io::ErrorKind;
+
+        for (error, state) in [
+            (Some(ErrorKind::ConnectionRefused), Close),
+            (Some(ErrorKind::ConnectionReset), Close),
+            (Some(ErrorKind::TimedOut), Filtered),
+            (Some(ErrorKind::HostUnreachable), Filtered),
+            (Some(ErrorKind::NetworkUnreachable), Filtered),
+            (Some(ErrorKind::PermissionDenied), Close),
+            (None, Filtered),
+        ] {
+            assert_eq!(super::error_state(error), state, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn scan_tcp_connect_reasons() {
+        use super::{QScanResult, QScanTcpConnectState};
+        use std::io::{Error, ErrorKind};
+
+        let mut scanner = super::QScanner::new("127.0.0.1", "1-3");
+        scanner.set_sort_results(true);
+        scanner.set_blocking_connector(|sock, _| {
+            Err(match sock.port() {
+                1 => Error::new(ErrorKind::ConnectionRefused, "refused"),
+                2 => Error::new(ErrorKind::TimedOut, "timed out"),
+                _ => Error::new(ErrorKind::HostUnreachable, "unreachable"),
+            })
+        });
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        let states: Vec<(QScanTcpConnectState, Option<&str>)> = res
+            .iter()
+            .map(|r| match r {
+                QScanResult::TcpConnect(r) => (r.state, r.reason.as_deref()),
+                _ => panic!("unexpected result"),
+            })
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                (QScanTcpConnectState::Close, Some("refused")),
+                (QScanTcpConnectState::Filtered, Some("timed out")),
+                (QScanTcpConnectState::Filtered, Some("unreachable")),
+            ]
+        );
+    }
+
+    #[test]
+    fn spawn_scan_cancel_on_drop() {
+        let rt = Runtime::new().unwrap();
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let calls_cl = calls.clone();
+        let mut scanner = super::QScanner::new("127.0.0.1", "1-100");
+        scanner.set_batch(1);
+        scanner.set_blocking_connector(move |_, _| {
+            calls_cl.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            std::thread::sleep(Duration::from_millis(20));
+            Ok(())
+        });
+
+        rt.block_on(async {
+            let handle = scanner.spawn_scan();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(handle);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+
+        // At most the probe in flight when dropped ends after the cancel
+        let after_cancel = calls.load(std::sync::atomic::Ordering::Relaxed);
+        assert!(after_cancel < 100);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            calls.load(std::sync::atomic::Ordering::Relaxed),
+            after_cancel
+        );
+
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        scanner.set_blocking_connector(|_, _| {
+            std::thread::sleep(Duration::from_millis(100));
+            Ok(())
+        });
+        let handle = rt.block_on(async { scanner.spawn_scan() });
+        handle.cancel();
+        let scanner = rt.block_on(handle.wait()).unwrap();
+        assert!(scanner.get_last_results().unwrap().is_empty());
+    }
+
+    #[test]
+    fn spawn_scan_cancel_keeps_results() {
+        let rt = Runtime::new().unwrap();
+        let mut scanner = super::QScanner::new("127.0.0.1", "1-100");
+        scanner.set_batch(1);
+        scanner.set_blocking_connector(|_, _| {
+            std::thread::sleep(Duration::from_millis(10));
+            Ok(())
+        });
+
+        let scanner = rt.block_on(async {
+            let handle = scanner.spawn_scan();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            handle.cancel();
+            handle.wait().await.unwrap()
+        });
+
+        let nres = scanner.get_last_results().unwrap().len();
+        assert!(nres > 0 && nres < 100, "{} results", nres);
+    }
+
+    #[test]
+    fn spawn_scan_cancel_ptr_lookups() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use trust_dns_resolver::config::NameServerConfigGroup;
+
+        // Name server that never answers
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = super::ResolverConfig::from_parts(
+            None,
+            vec![],
+            NameServerConfigGroup::from_ips_clear(
+                &["127.0.0.1".parse().unwrap()],
+                server.local_addr().unwrap().port(),
+                true,
+            ),
+        );
+
+        let rt = Runtime::new().unwrap();
+        let mut scanner = super::QScanner::new("192.0.2.1", "80");
+        scanner.set_timeout_ms(5000);
+        scanner.set_resolve_ptr(true);
+        scanner.set_resolver_config(config, super::ResolverOpts::default());
+        scanner.set_blocking_connector(|_, _| Ok(()));
+        let opened = std::sync::Arc::new(AtomicBool::new(false));
+        let o = opened.clone();
+        scanner.set_on_open(move |_| o.store(true, Ordering::Relaxed));
+
+        let (scanner, elapsed) = rt.block_on(async {
+            let handle = scanner.spawn_scan();
+            while !opened.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            // The scan is waiting for the PTR lookup
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let start = std::time::Instant::now();
+            handle.cancel();
+            (handle.wait().await.unwrap(), start.elapsed())
+        });
+
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+        match &scanner.get_last_results().unwrap()[..] {
+            [super::QScanResult::TcpConnect(r)] => assert!(r.hostname.is_none()),
+            res => panic!("unexpected results {:?}", res),
+        }
+        drop(server);
+    }
+
+    #[test]
+    fn scan_tcp_connect_spread() {
+        let times = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let times_cl = times.clone();
+        let mut scanner = super::QScanner::new("127.0.0.1", "1-4");
+        scanner.set_scan_spread(Duration::from_millis(400));
+        scanner.set_blocking_connector(move |_, _| {
+            times_cl.lock().unwrap().push(std::time::Instant::now());
+            Ok(())
+        });
+
+        let start = std::time::Instant::now();
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        let mut times = times.lock().unwrap().clone();
+        times.sort();
+        assert_eq!(times.len(), 4);
+        // Slots at 0, 100, 200 and 300 ms
+        for (i, t) in times.iter().enumerate() {
+            assert!(*t - start >= Duration::from_millis(100 * i as u64));
+        }
+        assert!(times[3] - start < Duration::from_millis(390));
+    }
+
+    #[test]
+    fn rtt_percentiles() {
+        let ms = Duration::from_millis;
+        let rtts: Vec<Duration> = (1..=100).map(ms).collect();
+        assert_eq!(super::percentile(&rtts, 50), ms(50));
+        assert_eq!(super::percentile(&rtts, 90), ms(90));
+        assert_eq!(super::percentile(&rtts, 99), ms(99));
+        assert_eq!(super::percentile(&[ms(7)], 50), ms(7));
+        assert_eq!(super::percentile(&[ms(1), ms(2), ms(3)], 99), ms(3));
+
+        let mut scanner = super::QScanner::new("127.0.0.1,127.0.0.2,127.0.0.3", "80,81");
+        scanner.set_blocking_connector(|sock, _| match sock.port() {
+            80 => {
+                if sock.ip() == Ipv4Addr::new(127, 0, 0, 3) {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Ok(())
+            }
+            _ => Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)),
+        });
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        let percentiles = scanner.get_rtt_percentiles();
+        assert_eq!(percentiles.len(), 1);
+        assert!(percentiles[&80].p50 < ms(50));
+        assert!(percentiles[&80].p99 >= ms(50));
+    }
+
+    #[test]
+    fn rst_injection_heuristic() {
+        use super::suspected_rst_injection;
+
+        let ms = Duration::from_millis;
+        let us = Duration::from_micros;
+        let fast: Vec<Duration> = (0..10).map(|i| us(300 + i * 10)).collect();
+        assert!(suspected_rst_injection(&[ms(40), ms(50)], &fast));
+        // Too few closed ports, or nothing to compare with
+        assert!(!suspected_rst_injection(&[ms(40), ms(50)], &fast[..7]));
+        assert!(!suspected_rst_injection(&[], &fast));
+        // As fast as the open ports: genuine RSTs
+        assert!(!suspected_rst_injection(&[us(400)], &fast));
+        let slow: Vec<Duration> = (0..10).map(|i| ms(40 + i)).collect();
+        assert!(!suspected_rst_injection(&[ms(40), ms(50)], &slow));
+        // Fast on average but not uniform
+        let spread: Vec<Duration> = (0..10).map(|i| ms(i % 2 * 20)).collect();
+        assert!(!suspected_rst_injection(&[ms(40), ms(50)], &spread));
+    }
+
+    #[test]
+    fn suspected_rst_injection() {
+        // 127.0.0.1: RSTs much faster than its open ports, 127.0.0.2: RSTs as
+        // slow as its open ports, 127.0.0.3: no open port to compare with
+        let mut scanner = super::QScanner::new("127.0.0.1,127.0.0.2,127.0.0.3", "1-12");
+        scanner.set_blocking_connector(|sock, _| {
+            let host = match sock.ip() {
+                IpAddr::V4(ip) => ip.octets()[3],
+                IpAddr::V6(_) => unreachable!(),
+            };
+            if host != 3 && sock.port() <= 2 {
+                std::thread::sleep(Duration::from_millis(50));
+                return Ok(());
+            }
+            if host == 2 {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        });
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        assert_eq!(
+            scanner.get_suspected_rst_injection(),
+            vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]
+        );
+        #[cfg(feature = "serialize")]
+        {
+            let json = scanner.get_last_results_as_grouped_json_string().unwrap();
+            let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(v["127.0.0.1"]["suspected_rst_injection"], true);
+            assert!(v["127.0.0.2"].get("suspected_rst_injection").is_none());
+            assert_eq!(v["127.0.0.1"]["closed"].as_array().unwrap().len(), 10);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn scan_tcp_connect_mss() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut scanner = super::QScanner::new("127.0.0.1", &port.to_string());
+        scanner.set_tcp_mss(536);
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        if let super::QScanResult::TcpConnect(sa) = &res[0] {
+            assert_eq!(sa.state, super::QScanTcpConnectState::Open);
+            assert!(sa.peer_mss.is_some_and(|mss| mss > 0 && mss <= 536));
+        } else {
+            panic!("Unexpected result type");
+        }
+
+        // The listener side sees the MSS advertised in the SYN
+        let (accepted, _) = listener.accept().unwrap();
+        let mss = socket2::SockRef::from(&accepted).mss().unwrap();
+        assert!(mss <= 536);
+    }
+
+    #[test]
+    fn scan_udp_localhost() {
+        use super::QScanUdpState;
+
+        let rt = Runtime::new().unwrap();
+        let echo = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ports = [&echo, &silent, &closed].map(|s| s.local_addr().unwrap().port());
+        drop(closed);
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while let Ok((n, peer)) = echo.recv_from(&mut buf) {
+                let _ = echo.send_to(&buf[..n], peer);
+            }
+        });
+
+        let mut scanner =
+            super::QScanner::new("127.0.0.1", &ports.map(|p| p.to_string()).join(","));
+        scanner.set_scan_type(crate::QScanType::UdpConnect);
+        scanner.set_timeout_ms(200);
+        scanner.set_udp_payload(b"qscan");
+        let res = rt.block_on(scanner.scan_udp());
+
+        assert_eq!(res.len(), 3);
+        for r in res {
+            if let super::QScanResult::Udp(ur) = r {
+                let expected = match ur.target.port() {
+                    p if p == ports[0] => QScanUdpState::Open,
+                    p if p == ports[1] => QScanUdpState::Filtered,
+                    _ => QScanUdpState::Closed,
+                };
+                assert_eq!(ur.state, expected);
             } else {
-                break;
+                panic!("Unexpected result type");
             }
         }
+        drop(silent);
+    }
 
-        while let Some(result) = ftrs.next().await {
-            if let Some(ip) = ip_it.next() {
-                ftrs.push(self.scan_ip_ping(*ip, &client_v4, &client_v6));
+    #[test]
+    fn dns_cache_file() {
+        let path = std::env::temp_dir().join("qscan_test_dns_cache_file");
+        let expiry = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        std::fs::write(&path, format!("qscan.invalid {} 127.0.0.9,::1\n", expiry)).unwrap();
+
+        let mut scanner = super::QScanner::new("", "80");
+        scanner.set_dns_cache_file(path.clone());
+        scanner.set_targets_addr("qscan.invalid,localhost");
+
+        let ips = scanner.get_tagets_ips();
+        assert!(ips.contains(&"127.0.0.9".parse::<IpAddr>().unwrap()));
+        assert!(ips.contains(&"::1".parse::<IpAddr>().unwrap()));
+        // localhost has been resolved and added to the cache
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("localhost "));
+        assert!(content.contains("qscan.invalid "));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connect_path() {
+        use super::ConnectPath;
+
+        assert_eq!(ConnectPath::Direct.to_string(), "direct");
+        assert_eq!(
+            ConnectPath::Socks5("127.0.0.1:1080".parse().unwrap()).to_string(),
+            "socks5://127.0.0.1:1080"
+        );
+
+        let mut scanner = super::QScanner::new("127.0.0.1", "80,81");
+        scanner.set_blocking_connector(|sock, _| match sock.port() {
+            80 => Ok(()),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)),
+        });
+        for r in Runtime::new().unwrap().block_on(scanner.scan_tcp_connect()) {
+            if let super::QScanResult::TcpConnect(sa) = r {
+                assert_eq!(
+                    sa.via,
+                    ConnectPath::Custom(super::BLOCKING_CONNECTOR_PATH.to_string())
+                );
             }
+        }
+    }
 
-            match result {
-                Ok(ip) => {
-                    match self.print_mode {
-                        QSPrintMode::RealTime => {
-                            println!("{}", ip);
-                        }
-                        QSPrintMode::RealTimeAll => {
-                            println!("{}:UP", ip);
+    #[test]
+    fn last_errors() {
+        let mut scanner = super::QScanner::new("127.0.0.1", "80,81");
+        scanner.set_blocking_connector(|sock, _| {
+            if sock.port() == 80 {
+                Ok(())
+            } else {
+                Err(std::io::Error::other("no route to the moon"))
+            }
+        });
+        assert!(scanner.get_last_errors().is_empty());
+
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+        assert_eq!(
+            scanner.get_last_errors(),
+            vec![(
+                "127.0.0.1:81".parse().unwrap(),
+                "no route to the moon".to_string()
+            )]
+        );
+
+        scanner.reset_last_results();
+        assert!(scanner.get_last_errors().is_empty());
+    }
+
+    #[test]
+    fn fd_exhaustion() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // Simulate a process that can open at most `limit` sockets
+        let scan = |limit: usize| {
+            let inflight = Arc::new(AtomicUsize::new(0));
+            let mut scanner = super::QScanner::new("127.0.0.1", "1-200");
+            scanner.set_batch(1000);
+            scanner.set_blocking_connector(move |_, _| {
+                if inflight.fetch_add(1, Ordering::SeqCst) >= limit {
+                    inflight.fetch_sub(1, Ordering::SeqCst);
+                    return Err(std::io::Error::other("Too many open files (os error 24)"));
+                }
+                std::thread::sleep(Duration::from_millis(20));
+                inflight.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            });
+            Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+            scanner
+        };
+
+        // The batch shrinks to the available descriptors, nothing is lost
+        let scanner = scan(16);
+        let stats = scanner.get_last_stats().unwrap();
+        assert!(stats.fd_exhausted);
+        assert_eq!((stats.open, stats.closed, stats.errors), (200, 0, 0));
+        assert_eq!(scanner.get_last_results().unwrap().len(), 200);
+
+        // No descriptor at all: the scan stops with partial results
+        let scanner = scan(0);
+        let stats = scanner.get_last_stats().unwrap();
+        assert!(stats.fd_exhausted);
+        assert_eq!((stats.open, stats.closed, stats.errors), (0, 0, 1));
+        assert_eq!(scanner.get_last_errors().len(), 1);
+    }
+
+    #[test]
+    fn max_fds() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let (inflight, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut scanner = super::QScanner::new("127.0.0.1", "1-200");
+        scanner.set_batch(5000);
+        scanner.set_max_fds(10);
+        let (i, p) = (inflight.clone(), peak.clone());
+        scanner.set_blocking_connector(move |_, _| {
+            p.fetch_max(i.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(5));
+            i.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        });
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        assert_eq!(scanner.get_last_results().unwrap().len(), 200);
+        assert!(peak.load(Ordering::SeqCst) <= 10);
+        assert!(super::fds_limit() > 0);
+    }
+
+    #[test]
+    fn scan_tcp_connect_service_detection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let rt = Runtime::new().unwrap();
+        let ssh = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let http = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let ssh_port = ssh.local_addr().unwrap().port();
+        let http_port = http.local_addr().unwrap().port();
+        rt.spawn(async move {
+            loop {
+                let (mut s, _) = ssh.accept().await.unwrap();
+                let _ = s.write_all(b"SSH-2.0-OpenSSH_8.9\r\n").await;
+            }
+        });
+        rt.spawn(async move {
+            loop {
+                let (mut s, _) = http.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    if let Ok(n) = s.read(&mut buf).await {
+                        if buf[..n].starts_with(b"GET ") {
+                            let _ = s.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await;
                         }
-                        _ => {}
                     }
+                });
+            }
+        });
 
-                    ip_res.push(QScanResult::Ping(QScanPingResult {
-                        target: ip,
-                        state: QScanPingState::Up,
-                    }));
+        let mut scanner =
+            super::QScanner::new("127.0.0.1", &format!("{},{},1", ssh_port, http_port));
+        scanner.set_service_detection(true);
+        let res = rt.block_on(scanner.scan_tcp_connect());
+
+        assert_eq!(res.len(), 3);
+        for r in res {
+            if let super::QScanResult::TcpConnect(sa) = r {
+                let expected = match sa.target.port() {
+                    p if p == ssh_port => Some("ssh".to_string()),
+                    p if p == http_port => Some("http".to_string()),
+                    _ => None,
+                };
+                assert_eq!(sa.detected_service, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn run_scheduled_fixed_rate() {
+        let rt = Runtime::new().unwrap();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut scanner = super::QScanner::new("127.0.0.1", &port.to_string());
+        let passes = std::cell::Cell::new(0);
+        let start = std::time::Instant::now();
+
+        rt.block_on(
+            scanner.run_scheduled(Duration::from_millis(50), Some(3), |res| {
+                assert_eq!(res.len(), 1);
+                passes.set(passes.get() + 1);
+            }),
+        );
+
+        assert_eq!(passes.get(), 3);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn scan_tcp_connect_mixed_families() {
+        let mut scanner = super::QScanner::new("8.8.8.8,2001:4860:4860::8888", "53");
+        scanner.set_timeout_ms(500);
+        let v6 = "2001:4860:4860::8888".parse::<IpAddr>().unwrap();
+        let v6_routable = super::is_routable(v6);
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        let mut v4_found = false;
+        let mut v6_found = false;
+        for r in res {
+            if let super::QScanResult::TcpConnect(sa) = r {
+                v4_found |= sa.target.ip() == IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+                v6_found |= sa.target.ip() == v6;
+            }
+        }
+
+        assert!(v4_found);
+        assert_eq!(v6_found, v6_routable);
+    }
+
+    #[test]
+    fn scan_tcp_connect_google_dns() {
+        let mut scanner = super::QScanner::new("8.8.8.8", "53,54,55-60");
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        for r in res {
+            if let super::QScanResult::TcpConnect(sa) = r {
+                if sa.state == super::QScanTcpConnectState::Open {
+                    assert_eq!(
+                        sa.target,
+                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53)
+                    );
                 }
-                Err(ip) => {
-                    if let QSPrintMode::RealTimeAll = self.print_mode {
-                        println!("{}:DOWN", ip);
-                    }
+            }
+        }
+    }
 
-                    ip_res.push(QScanResult::Ping(QScanPingResult {
-                        target: ip,
-                        state: QScanPingState::Down,
-                    }));
+    #[test]
+    fn resolve_localhost() {
+        let resolver =
+            Resolver::new(ResolverConfig::cloudflare_tls(), ResolverOpts::default()).unwrap();
+        let res = super::domain_name_resolve_to_ip("localhost", &resolver);
+        assert_eq!(res, vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]);
+    }
+
+    /// Fake name server: answers every A query with 10.1.2.3
+    pub(super) fn fake_name_server() -> super::ResolverConfig {
+        use trust_dns_resolver::config::NameServerConfigGroup;
+
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((n, peer)) = server.recv_from(&mut buf) {
+                // Question: name labels, then type and class
+                let mut end = 12;
+                while end < n && buf[end] != 0 {
+                    end += buf[end] as usize + 1;
                 }
+                end += 5;
+                let is_a = buf[end - 4..end - 2] == [0, 1];
+
+                let mut resp = buf[..2].to_vec();
+                resp.extend_from_slice(&[0x81, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+                resp.extend_from_slice(&buf[12..end]);
+                if is_a {
+                    resp.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    resp.extend_from_slice(&[10, 1, 2, 3]);
+                }
+                let _ = server.send_to(&resp, peer);
             }
-        }
+        });
 
-        drop(ftrs);
-        self.last_results = Some(ip_res);
-        self.last_results.as_ref().unwrap()
+        super::ResolverConfig::from_parts(
+            None,
+            vec![],
+            NameServerConfigGroup::from_ips_clear(&["127.0.0.1".parse().unwrap()], port, true),
+        )
     }
 
-    async fn scan_socket_tcp_connect(&self, socket: SocketAddr) -> Result<SocketAddr, QScanError> {
-        let tries = self.tries.get();
+    #[test]
+    fn resolver_config() {
+        let mut scanner = super::QScanner::new("", "80");
+        scanner.set_resolver_config(fake_name_server(), super::ResolverOpts::default());
+        scanner.set_targets_addr("scanme.qscan.test");
+        assert_eq!(
+            *scanner.get_tagets_ips(),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))]
+        );
+    }
 
-        for ntry in 0..tries {
-            match self.tcp_connect(socket).await {
-                Ok(Ok(mut x)) => {
-                    if x.shutdown().await.is_err() {
-                        return Err(QScanError {
-                            msg: "Shutdown error".to_string(),
-                            sock: socket,
-                        });
-                    } else {
-                        return Ok(socket);
-                    }
-                }
-                Ok(Err(e)) => {
-                    let mut err_str = e.to_string();
+    #[test]
+    fn resolver_reused() {
+        // Not created if there are no hostnames
+        let mut scanner = super::QScanner::new("127.0.0.1", "80");
+        assert!(scanner.resolver.resolver.get().is_none());
+
+        scanner.set_resolver_config(fake_name_server(), super::ResolverOpts::default());
+        scanner.add_targets_addr("a.qscan.test");
+        let resolver = scanner.resolver.get().unwrap() as *const Resolver;
+        scanner.add_targets_addr("b.qscan.test");
+        scanner.set_targets_addr("c.qscan.test");
+        assert_eq!(scanner.resolver.get().unwrap() as *const Resolver, resolver);
+    }
 
-                    if err_str.to_lowercase().contains("too many open files") {
-                        panic!("Too many open files, reduce batch size {}", self.batch);
-                    }
+    #[test]
+    #[ignore]
+    fn resolver_reuse_benchmark() {
+        let config = fake_name_server();
+        let path = std::env::temp_dir().join("qscan_resolver_reuse_benchmark.txt");
+        let names: Vec<String> = (0..1000).map(|i| format!("h{}.qscan.test", i)).collect();
+        std::fs::write(&path, names.join("\n")).unwrap();
+
+        // A new resolver for every line, as before the resolver was reused
+        let start = Instant::now();
+        for name in &names {
+            let resolver = super::LazyResolver::new(Some((config.clone(), Default::default())));
+            super::addresses_parse(name, &resolver);
+        }
+        let fresh = start.elapsed();
 
-                    if ntry == tries - 1 {
-                        err_str.push(' ');
-                        err_str.push_str(&socket.ip().to_string());
-                        return Err(QScanError {
-                            msg: err_str,
-                            sock: socket,
-                        });
-                    }
-                }
-                Err(e) => {
-                    let mut err_str = e.to_string();
+        let mut scanner = super::QScanner::new("", "80");
+        scanner.set_resolver_config(config, super::ResolverOpts::default());
+        let start = Instant::now();
+        scanner.set_targets_addr(path.to_str().unwrap());
+        let reused = start.elapsed();
+        std::fs::remove_file(&path).unwrap();
 
-                    if ntry == tries - 1 {
-                        err_str.push(' ');
-                        err_str.push_str(&socket.ip().to_string());
-                        return Err(QScanError {
-                            msg: err_str,
-                            sock: socket,
-                        });
-                    }
-                }
-            };
-        }
-        unreachable!();
+        // The fake name server resolves every name to the same address
+        assert_eq!(
+            *scanner.get_tagets_ips(),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))]
+        );
+        assert!(reused * 2 < fresh, "reused {:?}, fresh {:?}", reused, fresh);
     }
 
-    async fn scan_ip_ping(
-        &self,
-        ip: IpAddr,
-        client4: &surge_ping::Client,
-        client6: &surge_ping::Client,
-    ) -> Result<IpAddr, IpAddr> {
-        let mut client = client4;
+    #[test]
+    fn resolve_lhost() {
+        let resolver =
+            Resolver::new(ResolverConfig::cloudflare_tls(), ResolverOpts::default()).unwrap();
+        let res = super::domain_name_resolve_to_ip("www.google.com", &resolver);
+        assert!(!res.is_empty());
+    }
 
-        if ip.is_ipv6() {
-            client = client6;
-        }
+    #[test]
+    #[ignore]
+    fn scan_ping_google_dns() {
+        let mut scanner = super::QScanner::new("8.8.8.8", "");
+        scanner.set_scan_type(crate::QScanType::Ping);
+        scanner.set_ntries(5);
+        let res = Runtime::new().unwrap().block_on(scanner.scan_ping());
 
-        match self.ping(client, ip).await {
-            QScanPingState::Up => Ok(ip),
-            QScanPingState::Down => Err(ip),
+        for r in res {
+            if let super::QScanResult::Ping(pr) = r {
+                if pr.state == super::QScanPingState::Up {
+                    assert_eq!(pr.target, IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)));
+                }
+                assert!(pr.state != super::QScanPingState::Down);
+            }
         }
     }
 
-    async fn tcp_connect(&self, socket: SocketAddr) -> Result<io::Result<TcpStream>, Elapsed> {
-        // See https://stackoverflow.com/questions/30022084/how-do-i-set-connect-timeout-on-tcpstream
-        timeout(self.to, TcpStream::connect(socket)).await
-    }
+    #[test]
+    #[ignore]
+    fn resolve_ptr_google_dns() {
+        let mut scanner = super::QScanner::new("8.8.8.8", "53");
+        scanner.set_resolve_ptr(true);
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
 
-    async fn ping(&self, client: &surge_ping::Client, addr: IpAddr) -> QScanPingState {
-        let mut pinger = client
-            .pinger(addr, surge_ping::PingIdentifier(rand::random()))
-            .await;
-        pinger.timeout(self.to);
-        let mut interval = time::interval(self.ping_interval);
-        for idx in 0..self.tries.get() {
-            match pinger
-                .ping(surge_ping::PingSequence(idx as u16), &self.ping_payload)
-                .await
-            {
-                Ok((surge_ping::IcmpPacket::V4(_), _)) => {
-                    return QScanPingState::Up;
-                }
-                Ok((surge_ping::IcmpPacket::V6(_), _)) => {
-                    return QScanPingState::Up;
-                }
-                _ => {}
+        match &res[0] {
+            super::QScanResult::TcpConnect(sa) => {
+                assert_eq!(sa.hostname.as_deref(), Some("dns.google"))
             }
-            interval.tick().await;
+            _ => panic!("Unexpected result type"),
         }
-        QScanPingState::Down
     }
-}
 
-/// Parse ports strings, comma separated strings and ranges.
-/// E.g., "80", "80,443", "80,100-200,443"
-fn ports_parse(ports: &str) -> Vec<u16> {
-    let mut pv: Vec<u16> = Vec::new();
-    let ps: String = ports.chars().filter(|c| !c.is_whitespace()).collect();
+    #[test]
+    #[ignore]
+    fn scan_ping_localhost() {
+        let mut scanner = super::QScanner::new("127.0.0.0/24", "");
+        scanner.set_scan_type(crate::QScanType::Ping);
+        scanner.set_ntries(5);
+        scanner.set_ping_payload(&[0x41; 56]);
+        let res = Runtime::new().unwrap().block_on(scanner.scan_ping());
+        let mut up_ctr = 0;
 
-    for p in ps.split(',') {
-        if p.is_empty() {
-            continue;
+        for r in res {
+            if let super::QScanResult::Ping(pr) = r {
+                if pr.state == super::QScanPingState::Up {
+                    up_ctr += 1;
+                }
+            }
         }
 
-        let range = p
-            .split('-')
-            .map(str::parse)
-            .collect::<Result<Vec<u16>, std::num::ParseIntError>>()
-            .unwrap();
+        assert!(up_ctr > 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn scan_ping_unreachable() {
+        let mut scanner = super::QScanner::new("169.254.101.100,8.8.8.8", "");
+        scanner.set_scan_type(crate::QScanType::Ping);
+        scanner.set_ntries(3);
+        scanner.set_timeout_ms(1000);
+        scanner.set_ping_interval_ms(500);
+        let res = Runtime::new().unwrap().block_on(scanner.scan_ping());
 
-        match range.len() {
-            1 => pv.push(range[0]),
-            2 => pv.extend(range[0]..=range[1]),
-            _ => {
-                panic!("Invalid Range: {:?}", range);
+        for r in res {
+            if let super::QScanResult::Ping(pr) = r {
+                if pr.state == super::QScanPingState::Down {
+                    assert_eq!(pr.target, IpAddr::V4(Ipv4Addr::new(169, 254, 101, 100)));
+                }
+                if pr.state == super::QScanPingState::Up {
+                    assert_eq!(pr.target, IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)));
+                }
             }
         }
     }
 
-    pv.into_iter().unique().collect::<Vec<u16>>()
-}
-
-/// Parse IP addresses strings.
-/// E.g., "1.2.3.4", "1.2.3.4,8.8.8.8", 192.168.1.0/24"
-fn addresses_parse(addresses: &str) -> Vec<IpAddr> {
-    let mut ips: Vec<IpAddr> = Vec::new();
-    let alt_resolver =
-        Resolver::new(ResolverConfig::cloudflare_tls(), ResolverOpts::default()).unwrap();
+    #[test]
+    #[ignore]
+    fn scan_discover_and_connect_localhost() {
+        use futures::StreamExt;
 
-    let addrs: String = addresses.chars().filter(|c| !c.is_whitespace()).collect();
+        let rt = Runtime::new().unwrap();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let scanner = super::QScanner::new("127.0.0.1", &format!("{},1", port));
+        let res: Vec<super::QScanResult> =
+            rt.block_on(async { scanner.scan_discover_and_connect_stream().collect().await });
 
-    for addr in addrs.split(',') {
-        if addr.is_empty() {
-            continue;
+        assert_eq!(res.len(), 3);
+        let mut open_ctr = 0;
+        for r in res {
+            match r {
+                super::QScanResult::Ping(pr) => assert_eq!(pr.state, super::QScanPingState::Up),
+                super::QScanResult::TcpConnect(sa) => {
+                    if sa.state == super::QScanTcpConnectState::Open {
+                        assert_eq!(sa.target.port(), port);
+                        open_ctr += 1;
+                    }
+                }
+                super::QScanResult::Udp(_) => panic!("Unexpected result type"),
+            }
         }
+        assert_eq!(open_ctr, 1);
+    }
 
-        let parsed_addr = address_parse(addr, &alt_resolver);
-
-        if !parsed_addr.is_empty() {
-            ips.extend(parsed_addr);
-        } else {
-            // Check if we have a file to read addresses from
-            let file_path = Path::new(addr);
-            if !file_path.is_file() {
-                println!("Error: not a file {:?}", addr);
-                continue;
-            }
+    #[test]
+    #[ignore]
+    fn scan_ping_multiple() {
+        let mut scanner = super::QScanner::new("8.8.8.8,1.1.1.1,8.8.4.4,1.0.0.1", "");
+        scanner.set_scan_type(crate::QScanType::Ping);
+        scanner.set_ntries(5);
+        let res = Runtime::new().unwrap().block_on(scanner.scan_ping());
+        let mut up_ctr = 0;
 
-            if let Ok(x) = read_addresses_from_file(file_path, &alt_resolver) {
-                ips.extend(x);
-            } else {
-                println!("Error: unknown target {:?}", addr);
+        for r in res {
+            if let super::QScanResult::Ping(pr) = r {
+                if pr.target == IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))
+                    && pr.state == super::QScanPingState::Up
+                {
+                    up_ctr += 1;
+                }
+                if pr.target == IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1))
+                    && pr.state == super::QScanPingState::Up
+                {
+                    up_ctr += 1;
+                }
+                if pr.target == IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4))
+                    && pr.state == super::QScanPingState::Up
+                {
+                    up_ctr += 1;
+                }
+                if pr.target == IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1))
+                    && pr.state == super::QScanPingState::Up
+                {
+                    up_ctr += 1;
+                }
             }
         }
-    }
 
-    ips.into_iter().unique().collect::<Vec<IpAddr>>()
+        assert_eq!(up_ctr, 4);
+    }
 }
 
-fn address_parse(addr: &str, resolver: &Resolver) -> Vec<IpAddr> {
-    IpCidr::from_str(&addr)
-        .map(|cidr| cidr.iter().collect())
-        .ok()
-        .or_else(|| {
-            format!("{}:{}", &addr, 80)
-                .to_socket_addrs()
-                .ok()
-                .map(|mut iter| vec![iter.next().unwrap().ip()])
-        })
-        .unwrap_or_else(|| domain_name_resolve_to_ip(addr, resolver))
-}
+/// End-to-end tests of the real TCP connect path against local listeners
+#[cfg(test)]
+mod loopback_tests {
+    use super::{ClosePolicy, QSPrintMode, QScanResult, QScanTcpConnectState, QScanner};
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
 
-fn domain_name_resolve_to_ip(source: &str, alt_resolver: &Resolver) -> Vec<IpAddr> {
-    let mut ips: Vec<IpAddr> = Vec::new();
+    /// Listeners on random loopback ports, and loopback ports known to be
+    /// closed
+    struct Harness {
+        ip: IpAddr,
+        listeners: Vec<TcpListener>,
+        closed: Vec<u16>,
+    }
 
-    if let Ok(addrs) = source.to_socket_addrs() {
-        for ip in addrs {
-            ips.push(ip.ip());
+    impl Harness {
+        fn new(nopen: usize, nclosed: usize) -> Self {
+            Self::with_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), nopen, nclosed)
         }
-    } else if let Ok(addrs) = alt_resolver.lookup_ip(source) {
-        ips.extend(addrs.iter());
-    }
 
-    ips
-}
+        fn with_ip(ip: IpAddr, nopen: usize, nclosed: usize) -> Self {
+            let bind = || TcpListener::bind(SocketAddr::new(ip, 0)).unwrap();
+            let listeners = (0..nopen).map(|_| bind()).collect();
+            // Nobody listens on a port once its listener is dropped. All of them
+            // are bound before dropping any, so that the ports are distinct.
+            let closed: Vec<TcpListener> = (0..nclosed).map(|_| bind()).collect();
+            let closed = closed
+                .iter()
+                .map(|l| l.local_addr().unwrap().port())
+                .collect();
 
-// Read ips or fomain name from a file
-fn read_addresses_from_file(
-    addrs_file_path: &Path,
-    backup_resolver: &Resolver,
-) -> Result<Vec<IpAddr>, std::io::Error> {
-    let file = File::open(addrs_file_path)?;
-    let reader = BufReader::new(file);
-    let mut ips: Vec<IpAddr> = Vec::new();
+            Self {
+                ip,
+                listeners,
+                closed,
+            }
+        }
 
-    for (idx, address_line) in reader.lines().enumerate() {
-        if let Ok(address) = address_line {
-            ips.extend(address_parse(&address, backup_resolver));
-        } else {
-            println!("Error: Line {} in file is not valid", idx);
+        fn open(&self) -> Vec<u16> {
+            self.listeners
+                .iter()
+                .map(|l| l.local_addr().unwrap().port())
+                .collect()
         }
-    }
 
-    Ok(ips)
-}
+        fn ports(&self) -> String {
+            let ports: Vec<String> = self
+                .open()
+                .iter()
+                .chain(self.closed.iter())
+                .map(|p| p.to_string())
+                .collect();
+            ports.join(",")
+        }
 
-mod sockiter {
-    use itertools::{iproduct, Product};
-    use std::net::{IpAddr, SocketAddr};
+        fn scanner(&self) -> QScanner {
+            QScanner::new(&self.ip.to_string(), &self.ports())
+        }
 
-    pub struct SockIter<'a> {
-        prod: Product<Box<std::slice::Iter<'a, u16>>, Box<std::slice::Iter<'a, std::net::IpAddr>>>,
-    }
+        /// Handle each connection to the `idx`-th open port with `f`, on its
+        /// own thread
+        fn serve(&self, idx: usize, f: impl Fn(TcpStream) + Send + Sync + 'static) {
+            let listener = self.listeners[idx].try_clone().unwrap();
+            let f = Arc::new(f);
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let f = f.clone();
+                    std::thread::spawn(move || f(stream));
+                }
+            });
+        }
 
-    impl<'a> SockIter<'a> {
-        pub fn new(ips: &'a [IpAddr], ports: &'a [u16]) -> Self {
-            let ports = Box::new(ports.iter());
-            let ips = Box::new(ips.iter());
-            Self {
-                prod: iproduct!(ports, ips),
-            }
+        /// Run a TCP connect scan and check the exact open/closed result set
+        fn check(&self, scanner: &mut QScanner) {
+            self.check_results(Runtime::new().unwrap().block_on(scanner.scan_tcp_connect()));
         }
-    }
 
-    impl<'s> Iterator for SockIter<'s> {
-        type Item = SocketAddr;
+        /// Check the exact open/closed result set of a TCP connect scan
+        fn check_results(&self, res: &[QScanResult]) {
+            let mut states: HashMap<u16, bool> = HashMap::new();
+            for r in res {
+                match r {
+                    QScanResult::TcpConnect(sa) => {
+                        assert_eq!(sa.target.ip(), self.ip);
+                        let open = sa.state == QScanTcpConnectState::Open;
+                        assert!(states.insert(sa.target.port(), open).is_none());
+                    }
+                    _ => panic!("Unexpected result type"),
+                }
+            }
 
-        fn next(&mut self) -> Option<Self::Item> {
-            self.prod
-                .next()
-                .map(|(port, ip)| SocketAddr::new(*ip, *port))
+            let expected: HashMap<u16, bool> = self
+                .open()
+                .into_iter()
+                .map(|p| (p, true))
+                .chain(self.closed.iter().map(|p| (*p, false)))
+                .collect();
+            assert_eq!(states, expected);
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-    use trust_dns_resolver::{
-        config::{ResolverConfig, ResolverOpts},
-        Resolver,
-    };
+    #[test]
+    fn open_and_closed() {
+        let harness = Harness::new(8, 8);
+        harness.check(&mut harness.scanner());
+    }
 
-    use tokio::runtime::Runtime;
+    #[test]
+    fn ipv6_open_and_closed() {
+        let harness = Harness::with_ip(IpAddr::V6(Ipv6Addr::LOCALHOST), 4, 4);
+        harness.check(&mut harness.scanner());
+    }
 
     #[test]
-    fn parse_empty_address() {
-        let res = super::addresses_parse("");
-        assert_eq!(res, Vec::<IpAddr>::new());
+    fn rescan_open_only() {
+        let harness = Harness::new(3, 4);
+        let mut scanner = harness.scanner();
+        assert_eq!(scanner.rescan_open_only(), None);
+        harness.check(&mut scanner);
+
+        assert_eq!(scanner.rescan_open_only(), Some(3));
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+        let mut open: Vec<u16> = res
+            .iter()
+            .map(|r| match r {
+                QScanResult::TcpConnect(sa) => {
+                    assert_eq!(sa.state, QScanTcpConnectState::Open);
+                    sa.target.port()
+                }
+                _ => panic!("unexpected result"),
+            })
+            .collect();
+        open.sort_unstable();
+        let mut expected = harness.open();
+        expected.sort_unstable();
+        assert_eq!(open, expected);
     }
 
     #[test]
-    fn parse_commas_address() {
-        let res = super::addresses_parse(",,,,");
-        assert_eq!(res, Vec::<IpAddr>::new());
+    fn sort_results() {
+        let harness = Harness::new(4, 4);
+        let mut scanner = QScanner::new("::1,127.0.0.1", &harness.ports());
+        scanner.set_sort_results(true);
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        assert_eq!(res.len(), 16);
+        let keys: Vec<(IpAddr, u16)> = res.iter().map(|r| r.sort_key()).collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        assert!(keys[..8].iter().all(|(ip, _)| ip.is_ipv4()));
     }
 
     #[test]
-    fn parse_simple_address() {
-        let res = super::addresses_parse("127.0.0.1");
-        assert_eq!(res, vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+    #[ignore]
+    #[cfg(feature = "raw")]
+    fn scan_syn_localhost() {
+        // Needs root privileges or CAP_NET_RAW
+        let harness = Harness::new(4, 4);
+        let mut scanner = harness.scanner();
+        let path = std::env::temp_dir().join("qscan_test_scan_syn_localhost.log");
+        let _ = std::fs::remove_file(&path);
+        scanner.set_probe_audit_log(path.clone()).unwrap();
+        scanner.set_timeout_ms(500);
+        // Several batches
+        scanner.set_batch(3);
+        let res = Runtime::new()
+            .unwrap()
+            .block_on(scanner.scan_syn())
+            .unwrap();
+
+        let states: HashMap<u16, QScanTcpConnectState> = res
+            .iter()
+            .map(|r| match r {
+                QScanResult::TcpConnect(sa) => (sa.target.port(), sa.state),
+                _ => panic!("Unexpected result type"),
+            })
+            .collect();
+        let expected: HashMap<u16, QScanTcpConnectState> = harness
+            .open()
+            .into_iter()
+            .map(|p| (p, QScanTcpConnectState::Open))
+            .chain(
+                harness
+                    .closed
+                    .iter()
+                    .map(|p| (*p, QScanTcpConnectState::Close)),
+            )
+            .collect();
+        assert_eq!(states, expected);
+        // One SYN for each port, the open and closed ones reply
+        let audit = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(audit.lines().count(), 8);
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[test]
-    fn parse_repeated_address1() {
-        let res = super::addresses_parse("127.0.0.1,127.0.0.1");
-        assert_eq!(res, vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+    #[cfg(feature = "raw")]
+    fn scan_syn_error() {
+        let mut scanner = QScanner::new("127.0.0.1,127.0.0.2", "80");
+        scanner.set_max_targets(1);
+        let err = Runtime::new()
+            .unwrap()
+            .block_on(scanner.scan_syn())
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(scanner.get_last_results().unwrap().is_empty());
     }
 
     #[test]
-    fn parse_repeated_address2() {
-        let res = super::addresses_parse("127.0.0.1,127.0.0.2,127.0.0.0/30");
-        assert_eq!(
-            res,
-            vec![
-                "127.0.0.1".parse::<IpAddr>().unwrap(),
-                "127.0.0.2".parse::<IpAddr>().unwrap(),
-                "127.0.0.0".parse::<IpAddr>().unwrap(),
-                "127.0.0.3".parse::<IpAddr>().unwrap(),
-            ]
-        );
+    fn batch_refill() {
+        let harness = Harness::new(5, 5);
+        let mut scanner = harness.scanner();
+        scanner.set_batch(1);
+        harness.check(&mut scanner);
+        scanner.set_batch(3);
+        harness.check(&mut scanner);
     }
 
     #[test]
-    fn parse_repeated_address3() {
-        let res = super::addresses_parse("127.0.0.1,192.168.1.1,127.0.0.0/30");
-        assert_eq!(
-            res,
-            vec![
-                "127.0.0.1".parse::<IpAddr>().unwrap(),
-                "192.168.1.1".parse::<IpAddr>().unwrap(),
-                "127.0.0.0".parse::<IpAddr>().unwrap(),
-                "127.0.0.2".parse::<IpAddr>().unwrap(),
-                "127.0.0.3".parse::<IpAddr>().unwrap(),
-            ]
-        );
+    fn coalesced_probes() {
+        let harness = Harness::new(6, 5);
+        let mut scanner = harness.scanner();
+        scanner.set_host_probe_coalescing(4);
+        scanner.set_batch(4);
+        harness.check(&mut scanner);
     }
 
     #[test]
-    fn parse_multiple_addresses() {
-        let res = super::addresses_parse("127.0.0.1,127.0.0.2");
-        assert_eq!(
-            res,
-            vec![
-                "127.0.0.1".parse::<IpAddr>().unwrap(),
-                "127.0.0.2".parse::<IpAddr>().unwrap(),
-            ]
-        );
+    fn retries() {
+        let harness = Harness::new(3, 3);
+        let mut scanner = harness.scanner();
+        scanner.set_ntries(3);
+        harness.check(&mut scanner);
     }
 
     #[test]
-    fn parse_cidr() {
-        let res = super::addresses_parse("127.0.0.10/31");
-        assert_eq!(
-            res,
-            vec![
-                "127.0.0.10".parse::<IpAddr>().unwrap(),
-                "127.0.0.11".parse::<IpAddr>().unwrap(),
-            ]
-        );
+    fn close_policies() {
+        let harness = Harness::new(4, 4);
+        let mut scanner = harness.scanner();
+
+        for policy in [
+            ClosePolicy::GracefulFin,
+            ClosePolicy::AbortiveRst,
+            ClosePolicy::Drop,
+        ] {
+            scanner.set_close_policy(policy);
+            harness.check(&mut scanner);
+        }
     }
 
     #[test]
-    fn parse_cidr_and_addresses() {
-        let res = super::addresses_parse("127.0.0.1,127.0.0.10/31, 127.0.0.2");
-        assert_eq!(
-            res,
-            vec![
-                "127.0.0.1".parse::<IpAddr>().unwrap(),
-                "127.0.0.10".parse::<IpAddr>().unwrap(),
-                "127.0.0.11".parse::<IpAddr>().unwrap(),
-                "127.0.0.2".parse::<IpAddr>().unwrap(),
-            ]
-        );
+    fn linger() {
+        let harness = Harness::new(4, 4);
+        let mut scanner = harness.scanner();
+        for policy in [ClosePolicy::GracefulFin, ClosePolicy::Drop] {
+            scanner.set_close_policy(policy);
+            scanner.set_linger(Some(Duration::from_millis(100)));
+            harness.check(&mut scanner);
+        }
     }
 
     #[test]
-    fn parse_empty_port() {
-        let res = super::ports_parse("");
-        assert_eq!(res, Vec::<u16>::new());
+    fn reuse_addr() {
+        let harness = Harness::new(4, 4);
+        let mut scanner = harness.scanner();
+        scanner.set_reuse_addr(true);
+        harness.check(&mut scanner);
+        // Rescan right away, the connections of the first scan are in TIME_WAIT
+        harness.check(&mut scanner);
     }
 
     #[test]
-    fn parse_commas_port() {
-        let res = super::ports_parse(",,,");
-        assert_eq!(res, Vec::<u16>::new());
+    fn progress_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let harness = Harness::new(5, 250);
+        let mut scanner = harness.scanner();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let r = reports.clone();
+        scanner.set_progress_callback(move |completed, total| {
+            r.lock().unwrap().push((completed, total));
+        });
+        harness.check(&mut scanner);
+
+        // 255 probes: reported every 2 completions, and at the end
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 128);
+        assert!(reports.iter().all(|(_, total)| *total == 255));
+        assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(reports.first(), Some(&(2, 255)));
+        assert_eq!(reports.last(), Some(&(255, 255)));
     }
 
     #[test]
-    fn parse_single_port() {
-        let res = super::ports_parse("80");
-        assert_eq!(res, vec![80]);
+    fn progress_callback_exclude() {
+        use std::sync::Mutex;
+
+        // The excluded host is counted in the total until the scan ends
+        let mut scanner = QScanner::new("127.0.0.1,127.0.0.2", "1-50");
+        scanner.set_exclude("127.0.0.2");
+        scanner.set_blocking_connector(|_, _| Ok(()));
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let r = reports.clone();
+        scanner.set_progress_callback(move |completed, total| {
+            r.lock().unwrap().push((completed, total));
+        });
+        let etas = Arc::new(Mutex::new(Vec::new()));
+        let e = etas.clone();
+        scanner.set_progress_eta_callback(move |progress| e.lock().unwrap().push(*progress));
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 51);
+        assert_eq!(reports[49], (50, 100));
+        assert_eq!(reports.last(), Some(&(50, 50)));
+        let last = *etas.lock().unwrap().last().unwrap();
+        assert_eq!((last.completed, last.total), (50, 50));
+        assert_eq!(last.eta, Some(Duration::ZERO));
     }
 
     #[test]
-    fn parse_repeated_port1() {
-        let res = super::ports_parse("80,80");
-        assert_eq!(res, vec![80]);
+    fn progress_eta() {
+        use std::sync::Mutex;
+
+        // The probes slow down from 1ms to 10ms halfway through the scan
+        let mut scanner = QScanner::new("127.0.0.1", "1-100");
+        scanner.set_batch(1);
+        scanner.set_blocking_connector(|sock, _| {
+            let ms = if sock.port() <= 50 { 1 } else { 10 };
+            std::thread::sleep(Duration::from_millis(ms));
+            Ok(())
+        });
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let r = reports.clone();
+        scanner.set_progress_eta_callback(move |progress| r.lock().unwrap().push(*progress));
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 100);
+        assert!(reports.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+        assert!(reports.iter().skip(1).all(|p| p.eta.is_some()));
+        let last = reports.last().unwrap();
+        assert_eq!((last.completed, last.total), (100, 100));
+        assert_eq!(last.eta, Some(Duration::ZERO));
+
+        // 25 probes of 10ms left: the overall average rate would give less
+        // than 150ms, the rolling rate follows the slowdown
+        let eta = reports[74].eta.unwrap();
+        assert_eq!(reports[74].completed, 75);
+        assert!(eta > Duration::from_millis(150), "{:?}", eta);
+        assert!(eta < Duration::from_secs(5), "{:?}", eta);
     }
 
     #[test]
-    fn parse_repeated_port2() {
-        let res = super::ports_parse("80,79-81");
-        assert_eq!(res, vec![80, 79, 81]);
+    fn on_open_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let harness = Harness::new(6, 6);
+        let mut scanner = harness.scanner();
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let o = opened.clone();
+        scanner.set_on_open(move |socket| o.lock().unwrap().push(socket.port()));
+        harness.check(&mut scanner);
+
+        let mut opened = opened.lock().unwrap().clone();
+        opened.sort_unstable();
+        let mut expected = harness.open();
+        expected.sort_unstable();
+        assert_eq!(opened, expected);
     }
 
     #[test]
-    fn parse_repeated_port3() {
-        let res = super::ports_parse("80,128,79-81");
-        assert_eq!(res, vec![80, 128, 79, 81]);
+    #[cfg(feature = "xml")]
+    fn xml_results() {
+        let harness = Harness::new(3, 3);
+        let mut scanner = harness.scanner();
+        harness.check(&mut scanner);
+        let xml = scanner.get_last_results_as_xml_string().unwrap();
+
+        // Well formed: every element is closed, in order
+        let body = xml
+            .strip_prefix("<?xml version=\"1.0\" encoding=\"UTF-8\"?>")
+            .unwrap();
+        let mut open: Vec<&str> = Vec::new();
+        let mut ports: HashMap<u16, String> = HashMap::new();
+        let mut port = None;
+        for tag in body.split('<').skip(1) {
+            let tag = tag.split('>').next().unwrap();
+            let name = tag.split(' ').next().unwrap().trim_end_matches('/');
+            if let Some(name) = name.strip_prefix('/') {
+                assert_eq!(open.pop(), Some(name));
+            } else if !tag.ends_with('/') {
+                open.push(name);
+            }
+
+            let attr = |key: &str| {
+                let v = tag.split(&format!(" {}=\"", key)).nth(1)?;
+                v.split('"').next()
+            };
+            match name {
+                "port" => port = attr("portid").map(|p| p.parse::<u16>().unwrap()),
+                "state" => {
+                    let state = attr("state").unwrap().to_string();
+                    assert!(ports.insert(port.unwrap(), state).is_none());
+                }
+                _ => {}
+            }
+        }
+        assert!(open.is_empty());
+        assert!(xml.contains("<status state=\"up\"/>"));
+
+        let expected: HashMap<u16, String> = harness
+            .open()
+            .into_iter()
+            .map(|p| (p, "open".to_string()))
+            .chain(harness.closed.iter().map(|p| (*p, "closed".to_string())))
+            .collect();
+        assert_eq!(ports, expected);
+        assert_eq!(
+            super::xml_escape("a<b&\"c\">"),
+            "a&lt;b&amp;&quot;c&quot;&gt;"
+        );
     }
 
     #[test]
-    fn parse_multiple_ports() {
-        let res = super::ports_parse("80, 443,8080");
-        assert_eq!(res, vec![80, 443, 8080]);
+    fn output_writer() {
+        use std::sync::{Arc, Mutex};
+
+        /// Writer shared with the test
+        struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let harness = Harness::new(4, 4);
+        let mut scanner = harness.scanner();
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        scanner.set_print_mode(QSPrintMode::RealTime);
+        scanner.set_output_writer(Box::new(SharedBuf(buf.clone())));
+        harness.check(&mut scanner);
+
+        let out = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        let mut lines: Vec<&str> = out.lines().collect();
+        lines.sort_unstable();
+        let mut expected: Vec<String> = harness
+            .open()
+            .iter()
+            .map(|p| format!("127.0.0.1:{}", p))
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(lines, expected);
+
+        // Without a terminal, the live table prints the same lines
+        #[cfg(feature = "tui")]
+        {
+            let buf = Arc::new(Mutex::new(Vec::new()));
+            scanner.set_print_mode(QSPrintMode::LiveTable);
+            scanner.set_output_writer(Box::new(SharedBuf(buf.clone())));
+            harness.check(&mut scanner);
+
+            let out = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+            let mut lines: Vec<&str> = out.lines().collect();
+            lines.sort_unstable();
+            assert_eq!(lines, expected);
+        }
     }
 
     #[test]
-    fn parse_ports_range() {
-        let res = super::ports_parse("80-83");
-        assert_eq!(res, vec![80, 81, 82, 83]);
+    fn stats() {
+        let harness = Harness::new(3, 4);
+        let mut scanner = harness.scanner();
+        assert!(scanner.get_last_stats().is_none());
+        harness.check(&mut scanner);
+
+        let stats = scanner.get_last_stats().unwrap();
+        assert_eq!(stats.total, 7);
+        assert_eq!((stats.open, stats.closed, stats.errors), (3, 4, 0));
+        assert!(stats.elapsed > Duration::ZERO);
+        assert!(stats.end >= stats.start);
+        assert!(stats.sockets_per_sec() > 0.0);
+
+        #[cfg(feature = "serialize")]
+        {
+            let json = scanner.get_last_stats_as_json_string().unwrap();
+            assert!(json.contains("\"total\":7,\"open\":3,\"closed\":4,\"errors\":0"));
+        }
+
+        scanner.reset_last_results();
+        assert!(scanner.get_last_stats().is_none());
     }
 
     #[test]
-    fn parse_ports_mixed() {
-        let res = super::ports_parse("21,80-83,443,8080-8081");
-        assert_eq!(res, vec![21, 80, 81, 82, 83, 443, 8080, 8081]);
+    fn resolve_ptr() {
+        let harness = Harness::new(2, 2);
+        let mut scanner = harness.scanner();
+        scanner.set_timeout_ms(300);
+        scanner.set_resolve_ptr(true);
+        harness.check(&mut scanner);
+
+        // Only open ports are resolved, unresolved ones are left to None
+        for r in scanner.get_last_results().unwrap() {
+            if let QScanResult::TcpConnect(sa) = r {
+                if sa.state == QScanTcpConnectState::Close {
+                    assert!(sa.hostname.is_none());
+                }
+            }
+        }
     }
 
     #[test]
-    fn set_new_targets() {
-        let mut scanner = super::QScanner::new("", "");
-        scanner.set_targets("1.1.1.1", "80");
+    fn source_addr() {
+        let harness = Harness::new(1, 0);
+        let mut scanner = harness.scanner();
+        scanner.set_source_addr(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)));
+        let accept = std::thread::spawn(move || {
+            let (_, peer) = harness.listeners[0].accept().unwrap();
+            peer.ip()
+        });
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
         assert_eq!(
-            *scanner.get_tagets_ips(),
-            vec!["1.1.1.1".parse::<IpAddr>().unwrap()]
+            accept.join().unwrap(),
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))
         );
-        assert_eq!(*scanner.get_tagets_ports(), vec![80]);
+
+        // No route between the families
+        let mut scanner = QScanner::new("::1", "1");
+        scanner.set_source_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+        let errors = scanner.get_last_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0]
+            .1
+            .contains("source address 127.0.0.1 cannot reach [::1]:1"));
     }
 
     #[test]
-    fn add_new_targets() {
-        let mut scanner = super::QScanner::new("127.0.0.1", "80");
-        scanner.add_targets("127.0.0.0/30,192.168.1.1", "79-80,81");
-        assert_eq!(
-            *scanner.get_tagets_ips(),
-            vec![
-                "127.0.0.1".parse::<IpAddr>().unwrap(),
-                "127.0.0.0".parse::<IpAddr>().unwrap(),
-                "127.0.0.2".parse::<IpAddr>().unwrap(),
-                "127.0.0.3".parse::<IpAddr>().unwrap(),
-                "192.168.1.1".parse::<IpAddr>().unwrap(),
-            ]
-        );
-        assert_eq!(*scanner.get_tagets_ports(), vec![80, 79, 81]);
+    fn rate() {
+        let harness = Harness::new(2, 8);
+        let mut scanner = harness.scanner();
+        scanner.set_rate(50);
+
+        // 10 probes, the first one starts right away
+        let start = std::time::Instant::now();
+        harness.check(&mut scanner);
+        assert!(start.elapsed() >= Duration::from_millis(180));
+
+        // Retries of the closed ports do not take probe slots
+        scanner.set_ntries(3);
+        let start = std::time::Instant::now();
+        harness.check(&mut scanner);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(180));
+        assert!(elapsed < Duration::from_millis(500));
+
+        scanner.set_rate(0);
+        let start = std::time::Instant::now();
+        harness.check(&mut scanner);
+        assert!(start.elapsed() < Duration::from_millis(180));
     }
 
     #[test]
-    fn set_vec_new_targets() {
-        let mut scanner = super::QScanner::new("", "");
-        let target_ips = vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))];
-        let target_ports = vec![80];
-        scanner.set_vec_targets(target_ips, target_ports);
-        assert_eq!(
-            *scanner.get_tagets_ips(),
-            vec!["127.0.0.1".parse::<IpAddr>().unwrap()]
-        );
-        assert_eq!(*scanner.get_tagets_ports(), vec![80]);
+    fn open_despite_shutdown_error() {
+        let harness = Harness::new(1, 2);
+        // Reset the connection while the scanner waits for the banner: reading
+        // the banner fails, then so does the shutdown
+        harness.serve(0, |stream| {
+            // Let the connect complete first
+            std::thread::sleep(Duration::from_millis(100));
+            socket2::SockRef::from(&stream)
+                .set_linger(Some(Duration::from_secs(0)))
+                .unwrap();
+        });
+
+        let mut scanner = harness.scanner();
+        scanner.set_grab_banner(true);
+        harness.check(&mut scanner);
+
+        for r in scanner.get_last_results().unwrap() {
+            if let QScanResult::TcpConnect(sa) = r {
+                if sa.state == QScanTcpConnectState::Open {
+                    assert_eq!(sa.confidence, super::SHUTDOWN_ERROR_CONFIDENCE);
+                }
+            }
+        }
     }
 
     #[test]
-    fn add_vec_new_targets() {
-        let mut scanner = super::QScanner::new("127.0.0.1", "80");
-        let target_ips = vec![
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-        ];
-        let target_ports = vec![443, 80, 53];
-        scanner.add_vec_targets(target_ips, target_ports);
+    #[cfg(feature = "csv")]
+    fn csv_results() {
+        let harness = Harness::new(1, 1);
+        let mut scanner = harness.scanner();
         assert_eq!(
-            *scanner.get_tagets_ips(),
-            vec![
-                "127.0.0.1".parse::<IpAddr>().unwrap(),
-                "127.0.0.2".parse::<IpAddr>().unwrap(),
-            ]
+            scanner.get_last_results_as_csv_string().unwrap(),
+            "ip,port,state\n"
         );
-        assert_eq!(*scanner.get_tagets_ports(), vec![80, 443, 53]);
+
+        harness.check(&mut scanner);
+        let csv = scanner.get_last_results_as_csv_string().unwrap();
+        let mut lines: Vec<String> = csv.lines().map(String::from).collect();
+        lines[1..].sort_unstable();
+        let mut expected = vec![
+            "ip,port,state".to_string(),
+            format!("127.0.0.1,{},OPEN", harness.open()[0]),
+            format!("127.0.0.1,{},CLOSE", harness.closed[0]),
+        ];
+        expected[1..].sort_unstable();
+        assert_eq!(lines, expected);
     }
 
     #[test]
-    fn scan_tcp_connect_google_dns() {
-        let mut scanner = super::QScanner::new("8.8.8.8", "53,54,55-60");
-        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
-
-        for r in res {
-            if let super::QScanResult::TcpConnect(sa) = r {
-                if sa.state == super::QScanTcpConnectState::Open {
-                    assert_eq!(
-                        sa.target,
-                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53)
-                    );
+    fn grab_banner() {
+        let harness = Harness::new(2, 2);
+        // The second open port is a silent service: never accepted, the
+        // connection stays open
+        let ssh_port = harness.open()[0];
+        harness.serve(0, |mut s| {
+            let _ = s.write_all(b"SSH-2.0-OpenSSH_8.9\r\n");
+        });
+
+        let mut scanner = harness.scanner();
+        scanner.set_timeout_ms(200);
+        scanner.set_grab_banner(true);
+        scanner.set_service_detection(true);
+        harness.check(&mut scanner);
+
+        for r in scanner.get_last_results().unwrap() {
+            if let QScanResult::TcpConnect(sa) = r {
+                if sa.target.port() == ssh_port {
+                    assert_eq!(sa.banner.as_deref(), Some(&b"SSH-2.0-OpenSSH_8.9\r\n"[..]));
+                    assert_eq!(sa.detected_service.as_deref(), Some("ssh"));
+                } else {
+                    assert_eq!(sa.banner, None);
                 }
             }
         }
-    }
 
-    #[test]
-    fn resolve_localhost() {
-        let resolver =
-            Resolver::new(ResolverConfig::cloudflare_tls(), ResolverOpts::default()).unwrap();
-        let res = super::domain_name_resolve_to_ip("localhost", &resolver);
-        assert_eq!(res, vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]);
+        // Banners are truncated to the configured length
+        scanner.set_targets_port(&ssh_port.to_string());
+        scanner.set_banner_len(7);
+        match &Runtime::new().unwrap().block_on(scanner.scan_tcp_connect())[0] {
+            QScanResult::TcpConnect(sa) => assert_eq!(sa.banner.as_deref(), Some(&b"SSH-2.0"[..])),
+            _ => panic!("Unexpected result type"),
+        }
     }
 
     #[test]
-    fn resolve_lhost() {
-        let resolver =
-            Resolver::new(ResolverConfig::cloudflare_tls(), ResolverOpts::default()).unwrap();
-        let res = super::domain_name_resolve_to_ip("www.google.com", &resolver);
-        assert!(res.len() > 0);
+    fn require_banner_for_open() {
+        let harness = Harness::new(2, 1);
+        // The second open port is never served: connect only
+        harness.serve(0, |mut s| {
+            let _ = s.write_all(b"SSH-2.0-OpenSSH_8.9\r\n");
+        });
+        let open = harness.open();
+
+        let mut scanner = harness.scanner();
+        scanner.set_timeout_ms(200);
+        scanner.set_require_banner_for_open(true);
+        // No effect without banner grabbing
+        harness.check(&mut scanner);
+
+        scanner.set_grab_banner(true);
+        let res = Runtime::new().unwrap().block_on(scanner.scan_tcp_connect());
+        let states: HashMap<u16, QScanTcpConnectState> = res
+            .iter()
+            .map(|r| match r {
+                QScanResult::TcpConnect(sa) => (sa.target.port(), sa.state),
+                _ => panic!("Unexpected result type"),
+            })
+            .collect();
+        let expected = HashMap::from([
+            (open[0], QScanTcpConnectState::Open),
+            (open[1], QScanTcpConnectState::OpenNoResponse),
+            (harness.closed[0], QScanTcpConnectState::Close),
+        ]);
+        assert_eq!(states, expected);
     }
 
     #[test]
-    #[ignore]
-    fn scan_ping_google_dns() {
-        let mut scanner = super::QScanner::new("8.8.8.8", "");
-        scanner.set_scan_type(crate::QScanType::Ping);
-        scanner.set_ntries(5);
-        let res = Runtime::new().unwrap().block_on(scanner.scan_ping());
+    fn max_total_banner_bytes() {
+        let harness = Harness::new(5, 1);
+        for idx in 0..5 {
+            harness.serve(idx, |mut s| {
+                let _ = s.write_all(b"0123456789");
+            });
+        }
 
-        for r in res {
-            if let super::QScanResult::Ping(pr) = r {
-                if pr.state == super::QScanPingState::Up {
-                    assert_eq!(pr.target, IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)));
-                }
-                if pr.state == super::QScanPingState::Down {
-                    assert!(false);
-                }
-            }
+        let mut scanner = harness.scanner();
+        scanner.set_batch(1);
+        scanner.set_timeout_ms(200);
+        scanner.set_banner_len(10);
+        scanner.set_max_total_banner_bytes(25);
+        // The budget is restored at the beginning of each scan
+        for _ in 0..2 {
+            harness.check(&mut scanner);
+
+            let banners: Vec<usize> = scanner
+                .get_last_results()
+                .unwrap()
+                .iter()
+                .filter_map(|r| match r {
+                    QScanResult::TcpConnect(sa) => sa.banner.as_ref().map(Vec::len),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(banners.iter().sum::<usize>(), 25);
+            assert_eq!(banners.len(), 3);
         }
     }
 
     #[test]
-    #[ignore]
-    fn scan_ping_localhost() {
-        let mut scanner = super::QScanner::new("127.0.0.0/24", "");
-        scanner.set_scan_type(crate::QScanType::Ping);
-        scanner.set_ntries(5);
-        scanner.set_ping_payload(&[0x41; 56]);
-        let res = Runtime::new().unwrap().block_on(scanner.scan_ping());
-        let mut up_ctr = 0;
-
-        for r in res {
-            if let super::QScanResult::Ping(pr) = r {
-                if pr.state == super::QScanPingState::Up {
-                    up_ctr += 1;
+    fn http_probe() {
+        let harness = Harness::new(2, 1);
+        let http_port = harness.open()[0];
+        harness.serve(0, |mut s| {
+            let mut buf = [0u8; 1024];
+            if let Ok(n) = s.read(&mut buf) {
+                if buf[..n].starts_with(b"GET / HTTP/1.0\r\nHost: 127.0.0.1\r\n") {
+                    let _ = s.write_all(b"HTTP/1.1 301 Moved\r\nServer: qtest/1.0\r\n\r\n");
                 }
             }
+        });
+        harness.serve(1, |mut s| {
+            let _ = s.write_all(b"SSH-2.0-OpenSSH_8.9\r\n");
+        });
+
+        let mut scanner = harness.scanner();
+        scanner.set_timeout_ms(500);
+        scanner.set_http_probe(true);
+        harness.check(&mut scanner);
+
+        for r in scanner.get_last_results().unwrap() {
+            if let QScanResult::TcpConnect(sa) = r {
+                let expected = (sa.target.port() == http_port).then(|| super::QScanHttpInfo {
+                    status: 301,
+                    server: Some("qtest/1.0".to_string()),
+                });
+                assert_eq!(sa.http, expected);
+            }
         }
-
-        assert!(up_ctr > 0);
     }
 
     #[test]
-    #[ignore]
-    fn scan_ping_unreachable() {
-        let mut scanner = super::QScanner::new("169.254.101.100,8.8.8.8", "");
-        scanner.set_scan_type(crate::QScanType::Ping);
-        scanner.set_ntries(3);
-        scanner.set_timeout_ms(1000);
-        scanner.set_ping_interval_ms(500);
-        let res = Runtime::new().unwrap().block_on(scanner.scan_ping());
+    fn scan_from_async_context() {
+        let harness = Harness::new(1, 1);
+        let config = super::tests::fake_name_server();
+
+        Runtime::new().unwrap().block_on(async move {
+            let mut scanner = QScanner::new("", &harness.ports());
+            scanner.set_resolver_config(config, super::ResolverOpts::default());
+            scanner.set_targets_addr("scanme.qscan.test,127.0.0.1");
+            assert_eq!(scanner.get_tagets_ips().len(), 2);
+
+            scanner.set_targets_addr("127.0.0.1");
+            harness.check_results(scanner.scan_tcp_connect_blocking());
+            harness.check_results(scanner.scan_tcp_connect().await);
+        });
+    }
 
-        for r in res {
-            if let super::QScanResult::Ping(pr) = r {
-                if pr.state == super::QScanPingState::Down {
-                    assert_eq!(pr.target, IpAddr::V4(Ipv4Addr::new(169, 254, 101, 100)));
-                }
-                if pr.state == super::QScanPingState::Up {
-                    assert_eq!(pr.target, IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)));
+    #[test]
+    fn stream() {
+        let harness = Harness::new(1, 1);
+        let scanner = harness.scanner();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+
+        let res = Runtime::new().unwrap().block_on(async {
+            let collect = async {
+                let mut res = Vec::new();
+                while let Some(r) = rx.recv().await {
+                    res.push(r);
                 }
-            }
-        }
+                res
+            };
+            futures::join!(scanner.scan_tcp_connect_stream(tx), collect).1
+        });
+
+        harness.check_results(&res);
+        assert!(scanner.get_last_results().is_none());
     }
 
     #[test]
-    #[ignore]
-    fn scan_ping_multiple() {
-        let mut scanner = super::QScanner::new("8.8.8.8,1.1.1.1,8.8.4.4,1.0.0.1", "");
-        scanner.set_scan_type(crate::QScanType::Ping);
-        scanner.set_ntries(5);
-        let res = Runtime::new().unwrap().block_on(scanner.scan_ping());
-        let mut up_ctr = 0;
+    fn host_probe_coalescing() {
+        let harness = Harness::new(1, 20);
+        let mut scanner = harness.scanner();
+        scanner.set_batch(8);
+        scanner.set_host_probe_coalescing(16);
+        harness.check(&mut scanner);
+    }
 
-        for r in res {
-            if let super::QScanResult::Ping(pr) = r {
-                if pr.target == IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)) {
-                    if pr.state == super::QScanPingState::Up {
-                        up_ctr += 1;
-                    }
-                }
-                if pr.target == IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)) {
-                    if pr.state == super::QScanPingState::Up {
-                        up_ctr += 1;
-                    }
-                }
-                if pr.target == IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4)) {
-                    if pr.state == super::QScanPingState::Up {
-                        up_ctr += 1;
-                    }
-                }
-                if pr.target == IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)) {
-                    if pr.state == super::QScanPingState::Up {
-                        up_ctr += 1;
-                    }
+    #[test]
+    fn confidence() {
+        let harness = Harness::new(2, 2);
+        let mut scanner = harness.scanner();
+        harness.check(&mut scanner);
+
+        for r in scanner.get_last_results().unwrap() {
+            if let QScanResult::TcpConnect(sa) = r {
+                if sa.state == QScanTcpConnectState::Open {
+                    assert_eq!(sa.confidence, super::OPEN_CONFIDENCE);
+                } else {
+                    assert_eq!(sa.confidence, 0.95);
                 }
             }
         }
+    }
 
-        assert_eq!(up_ctr, 4);
+    #[test]
+    fn no_retry_on_refused() {
+        let path = std::env::temp_dir().join("qscan_test_no_retry_on_refused.log");
+        let _ = std::fs::remove_file(&path);
+        let harness = Harness::new(0, 1);
+        let mut scanner = harness.scanner();
+        scanner.set_ntries(3);
+        scanner.set_probe_audit_log(path.clone()).unwrap();
+        harness.check(&mut scanner);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        std::fs::remove_file(&path).unwrap();
+
+        let rt = Runtime::new().unwrap();
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let calls_cl = calls.clone();
+        scanner.set_blocking_connector(move |_, _| {
+            calls_cl.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        });
+        rt.block_on(scanner.scan_tcp_connect());
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        scanner.set_retry_on_refused(true);
+        rt.block_on(scanner.scan_tcp_connect());
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1 + 3);
     }
 }
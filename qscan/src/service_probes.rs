@@ -0,0 +1,208 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Small built-in service probes database.
+//!
+//! A service is first matched against the greeting it sends right after the
+//! connection (null probe). If nothing is received, the active probes are
+//! sent in order, each one on a new connection, until one of them matches.
+
+use std::net::IpAddr;
+
+/// Active probe: payload sent to the service and matcher for the response
+pub(crate) struct ServiceProbe {
+    pub payload: fn() -> Vec<u8>,
+    pub matches: fn(&[u8]) -> Option<&'static str>,
+}
+
+/// Maximum number of bytes read from a service response
+pub(crate) const RESPONSE_MAX_LEN: usize = 1024;
+
+/// Active probes, in the order they are tried
+pub(crate) const PROBES: &[ServiceProbe] = &[
+    ServiceProbe {
+        payload: http_payload,
+        matches: http_match,
+    },
+    ServiceProbe {
+        payload: tls_client_hello,
+        matches: tls_match,
+    },
+];
+
+/// Match the greeting sent by a service right after the connection
+pub(crate) fn greeting_match(resp: &[u8]) -> Option<&'static str> {
+    let text = String::from_utf8_lossy(resp).to_lowercase();
+
+    if resp.starts_with(b"SSH-") {
+        Some("ssh")
+    } else if resp.starts_with(b"220") && text.contains("ftp") {
+        Some("ftp")
+    } else if resp.starts_with(b"220") && text.contains("smtp") {
+        Some("smtp")
+    } else if resp.starts_with(b"+OK") {
+        Some("pop3")
+    } else if resp.starts_with(b"* OK") {
+        Some("imap")
+    } else if resp.starts_with(b"RFB ") {
+        Some("vnc")
+    } else if resp.len() > 5 && resp[4] == 0x0a && text.contains("mysql") {
+        Some("mysql")
+    } else {
+        None
+    }
+}
+
+fn http_payload() -> Vec<u8> {
+    b"GET / HTTP/1.0\r\n\r\n".to_vec()
+}
+
+fn http_match(resp: &[u8]) -> Option<&'static str> {
+    resp.starts_with(b"HTTP/").then_some("http")
+}
+
+/// Maximum number of bytes read from the headers of an HTTP response
+pub(crate) const HTTP_HEAD_MAX_LEN: usize = 8192;
+
+/// HTTP request for the root of `host`
+pub(crate) fn http_request(host: IpAddr) -> Vec<u8> {
+    let host = match host {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+
+    format!("GET / HTTP/1.0\r\nHost: {}\r\n\r\n", host).into_bytes()
+}
+
+/// Status code and `Server` header of an HTTP response, `None` if `resp`
+/// does not start with an HTTP status line
+pub(crate) fn http_parse(resp: &[u8]) -> Option<(u16, Option<String>)> {
+    let text = String::from_utf8_lossy(resp);
+    let mut lines = text.split("\r\n");
+
+    let mut status_line = lines.next()?.split(' ');
+    if !status_line.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    let status = status_line
+        .next()
+        .filter(|s| s.len() == 3)
+        .and_then(|s| s.parse().ok())?;
+
+    let server = lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("server"))
+        .map(|(_, value)| value.trim().to_string());
+
+    Some((status, server))
+}
+
+/// Minimal TLS 1.2 ClientHello
+fn tls_client_hello() -> Vec<u8> {
+    let ciphers: &[u16] = &[
+        0xc02f, 0xc030, 0xc02b, 0xc02c, 0x009c, 0x009d, 0x002f, 0x0035,
+    ];
+    let mut hello: Vec<u8> = vec![0x03, 0x03];
+    hello.extend_from_slice(&rand::random::<[u8; 32]>());
+    // Session ID
+    hello.push(0);
+    hello.extend_from_slice(&((ciphers.len() * 2) as u16).to_be_bytes());
+    for c in ciphers {
+        hello.extend_from_slice(&c.to_be_bytes());
+    }
+    // Compression methods: null
+    hello.extend_from_slice(&[0x01, 0x00]);
+
+    let mut handshake: Vec<u8> = vec![0x01];
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend(hello);
+
+    let mut record: Vec<u8> = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend(handshake);
+    record
+}
+
+fn tls_match(resp: &[u8]) -> Option<&'static str> {
+    // Handshake (ServerHello) or alert record
+    (resp.len() >= 2 && (resp[0] == 0x16 || resp[0] == 0x15) && resp[1] == 0x03).then_some("tls")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn match_greetings() {
+        assert_eq!(
+            super::greeting_match(b"SSH-2.0-OpenSSH_8.9\r\n"),
+            Some("ssh")
+        );
+        assert_eq!(
+            super::greeting_match(b"220 ProFTPD Server ready.\r\n"),
+            Some("ftp")
+        );
+        assert_eq!(
+            super::greeting_match(b"220 mx.example.com ESMTP Postfix\r\n"),
+            Some("smtp")
+        );
+        assert_eq!(super::greeting_match(b"+OK POP3 ready\r\n"), Some("pop3"));
+        assert_eq!(super::greeting_match(b"hello"), None);
+    }
+
+    #[test]
+    fn match_probes() {
+        assert_eq!(super::http_match(b"HTTP/1.1 200 OK\r\n"), Some("http"));
+        assert_eq!(super::http_match(b"SSH-2.0"), None);
+        assert_eq!(
+            super::tls_match(&[0x15, 0x03, 0x03, 0x00, 0x02]),
+            Some("tls")
+        );
+        assert_eq!(super::tls_match(b"HTTP/1.1"), None);
+    }
+
+    #[test]
+    fn http_responses() {
+        assert_eq!(
+            super::http_parse(b"HTTP/1.1 200 OK\r\nServer: nginx/1.18.0\r\n\r\n"),
+            Some((200, Some("nginx/1.18.0".to_string())))
+        );
+        assert_eq!(
+            super::http_parse(b"HTTP/1.0 301 Moved\r\nserver:gws\r\nLocation: /\r\n"),
+            Some((301, Some("gws".to_string())))
+        );
+        assert_eq!(
+            super::http_parse(b"HTTP/1.1 404\r\n\r\n"),
+            Some((404, None))
+        );
+        assert_eq!(super::http_parse(b"SSH-2.0-OpenSSH_8.9\r\n"), None);
+        assert_eq!(super::http_parse(b"HTTP/1.1 abc\r\n"), None);
+        assert_eq!(super::http_parse(b""), None);
+
+        assert_eq!(
+            super::http_request("::1".parse().unwrap()),
+            b"GET / HTTP/1.0\r\nHost: [::1]\r\n\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn tls_client_hello_lengths() {
+        let hello = super::tls_client_hello();
+        let record_len = u16::from_be_bytes([hello[3], hello[4]]) as usize;
+        assert_eq!(hello.len(), 5 + record_len);
+        let handshake_len = u32::from_be_bytes([0, hello[6], hello[7], hello[8]]) as usize;
+        assert_eq!(record_len, 4 + handshake_len);
+    }
+}
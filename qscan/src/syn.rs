@@ -0,0 +1,233 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Raw socket used by the SYN scan (`raw` feature).
+//!
+//! Only the TCP header of the probes is built here, the IPv4 header is added
+//! by the kernel. The replies are read from the same socket, which receives a
+//! copy of every incoming TCP segment (IPv4 header included). The handshake is
+//! never completed: the kernel answers a SYN/ACK with a RST since it does not
+//! know about the connection.
+
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+/// Length of the TCP header of a probe (no options)
+const TCP_HDR_LEN: usize = 20;
+
+/// Reply to a SYN probe
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct SynReply {
+    /// Address and port that sent the reply (the probed target)
+    pub from: SocketAddrV4,
+    /// Destination port of the reply (the source port of the probe)
+    pub dst_port: u16,
+    pub ack: u32,
+    pub flags: u8,
+}
+
+impl SynReply {
+    /// `Some(true)` if the port is open (SYN/ACK), `Some(false)` if it is
+    /// closed (RST), `None` for anything else
+    pub fn is_open(&self) -> Option<bool> {
+        if self.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK {
+            Some(true)
+        } else if self.flags & TCP_RST != 0 {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Raw IPv4 TCP socket. Creating it requires root or CAP_NET_RAW
+pub(crate) struct SynSocket {
+    socket: Socket,
+}
+
+impl SynSocket {
+    /// Create the raw socket. Reads wait at most `poll` for a segment
+    pub fn new(poll: Duration) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::TCP))?;
+        socket.set_read_timeout(Some(poll))?;
+        Ok(Self { socket })
+    }
+
+    /// Send a SYN from `src`:`src_port` to `dst` with sequence number `seq`
+    pub fn send_syn(
+        &self,
+        src: Ipv4Addr,
+        src_port: u16,
+        dst: SocketAddrV4,
+        seq: u32,
+    ) -> io::Result<()> {
+        let segment = build_syn(src, src_port, dst, seq);
+        let to = SocketAddrV4::new(*dst.ip(), 0);
+        self.socket.send_to(&segment, &SockAddr::from(to))?;
+        Ok(())
+    }
+
+    /// Read the next TCP segment, `None` if nothing has been received within
+    /// the poll interval or the segment cannot be parsed
+    pub fn recv(&self) -> Option<SynReply> {
+        let mut buf = [MaybeUninit::<u8>::uninit(); 1500];
+        let (len, _) = self.socket.recv_from(&mut buf).ok()?;
+        // SAFETY: the first `len` bytes have been initialized by recv_from
+        let pkt: Vec<u8> = buf[..len]
+            .iter()
+            .map(|b| unsafe { b.assume_init() })
+            .collect();
+        parse_reply(&pkt)
+    }
+}
+
+/// Local IPv4 address used to reach `dst`
+///
+/// A UDP socket is connected to `dst` (nothing is sent) and its local
+/// address is the one chosen by the routing table.
+pub(crate) fn local_ipv4(dst: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(SocketAddr::from((dst, 9)))?;
+    match socket.local_addr()? {
+        SocketAddr::V4(sa) => Ok(*sa.ip()),
+        SocketAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no local IPv4 address",
+        )),
+    }
+}
+
+/// TCP SYN segment, checksum included
+fn build_syn(src: Ipv4Addr, src_port: u16, dst: SocketAddrV4, seq: u32) -> Vec<u8> {
+    let mut seg = vec![0u8; TCP_HDR_LEN];
+    seg[0..2].copy_from_slice(&src_port.to_be_bytes());
+    seg[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    seg[4..8].copy_from_slice(&seq.to_be_bytes());
+    // Data offset (5 words), no options
+    seg[12] = ((TCP_HDR_LEN / 4) as u8) << 4;
+    seg[13] = TCP_SYN;
+    seg[14..16].copy_from_slice(&1024u16.to_be_bytes());
+
+    let csum = tcp_checksum(src, *dst.ip(), &seg);
+    seg[16..18].copy_from_slice(&csum.to_be_bytes());
+    seg
+}
+
+/// Checksum of the TCP segment `seg`, IPv4 pseudo header included
+fn tcp_checksum(src: Ipv4Addr, dst: Ipv4Addr, seg: &[u8]) -> u16 {
+    let mut data = Vec::with_capacity(12 + seg.len() + 1);
+    data.extend_from_slice(&src.octets());
+    data.extend_from_slice(&dst.octets());
+    data.extend_from_slice(&[0, 6]);
+    data.extend_from_slice(&(seg.len() as u16).to_be_bytes());
+    data.extend_from_slice(seg);
+    if data.len() % 2 == 1 {
+        data.push(0);
+    }
+
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Parse an IPv4 packet carrying a TCP segment
+fn parse_reply(pkt: &[u8]) -> Option<SynReply> {
+    if pkt.len() < 20 || pkt[0] >> 4 != 4 || pkt[9] != 6 {
+        return None;
+    }
+    let ihl = ((pkt[0] & 0x0f) as usize) * 4;
+    let tcp = pkt.get(ihl..)?;
+    if ihl < 20 || tcp.len() < TCP_HDR_LEN {
+        return None;
+    }
+
+    let src = Ipv4Addr::new(pkt[12], pkt[13], pkt[14], pkt[15]);
+    Some(SynReply {
+        from: SocketAddrV4::new(src, u16::from_be_bytes([tcp[0], tcp[1]])),
+        dst_port: u16::from_be_bytes([tcp[2], tcp[3]]),
+        ack: u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]),
+        flags: tcp[13],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    /// IPv4 header (no options) followed by `tcp`
+    fn ipv4_packet(src: Ipv4Addr, tcp: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0];
+        pkt.extend_from_slice(&src.octets());
+        pkt.extend_from_slice(&[127, 0, 0, 1]);
+        pkt.extend_from_slice(tcp);
+        pkt
+    }
+
+    #[test]
+    fn build_syn() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80);
+        let seg = super::build_syn(src, 40000, dst, 0x01020304);
+
+        assert_eq!(seg.len(), 20);
+        assert_eq!(&seg[0..4], &[0x9c, 0x40, 0, 80]);
+        assert_eq!(&seg[4..8], &[1, 2, 3, 4]);
+        assert_eq!(seg[12], 0x50);
+        assert_eq!(seg[13], 0x02);
+        // A segment with a valid checksum sums to zero
+        assert_eq!(super::tcp_checksum(src, *dst.ip(), &seg), 0);
+    }
+
+    #[test]
+    fn parse_replies() {
+        let src = Ipv4Addr::new(10, 0, 0, 2);
+        let mut tcp = super::build_syn(src, 80, SocketAddrV4::new(src, 40000), 7);
+        tcp[8..12].copy_from_slice(&0x01020305u32.to_be_bytes());
+
+        tcp[13] = 0x12;
+        let reply = super::parse_reply(&ipv4_packet(src, &tcp)).unwrap();
+        assert_eq!(reply.from, SocketAddrV4::new(src, 80));
+        assert_eq!(reply.dst_port, 40000);
+        assert_eq!(reply.ack, 0x01020305);
+        assert_eq!(reply.is_open(), Some(true));
+
+        tcp[13] = 0x14;
+        let reply = super::parse_reply(&ipv4_packet(src, &tcp)).unwrap();
+        assert_eq!(reply.is_open(), Some(false));
+
+        tcp[13] = 0x10;
+        let reply = super::parse_reply(&ipv4_packet(src, &tcp)).unwrap();
+        assert_eq!(reply.is_open(), None);
+
+        let mut udp = ipv4_packet(src, &tcp);
+        udp[9] = 17;
+        assert_eq!(super::parse_reply(&udp), None);
+        assert_eq!(super::parse_reply(&udp[..30]), None);
+    }
+}
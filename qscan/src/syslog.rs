@@ -0,0 +1,180 @@
+//
+// qscan
+// Copyright (C) 2022  0xor0ne
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+/// Local syslog socket
+#[cfg(unix)]
+const SYSLOG_LOCAL_PATH: &str = "/dev/log";
+
+/// Private enterprise number reserved for documentation (RFC 5612), used as
+/// SD-ID suffix for the structured data of the messages
+const SD_ID: &str = "qscan@32473";
+
+/// Severity of the emitted messages (Notice)
+const SEVERITY: u8 = 5;
+
+/// Syslog facility (RFC 5424)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QSSyslogFacility {
+    User = 1,
+    Daemon = 3,
+    Auth = 4,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Debug)]
+enum Sink {
+    Remote(UdpSocket, SocketAddr),
+    #[cfg(unix)]
+    Local(UnixDatagram),
+}
+
+/// Syslog output for scan findings
+#[derive(Debug)]
+pub(crate) struct QSSyslog {
+    facility: QSSyslogFacility,
+    sink: Sink,
+}
+
+impl QSSyslog {
+    /// Send messages to `server` (UDP) or, if `None`, to the local syslog
+    pub(crate) fn new(facility: QSSyslogFacility, server: Option<SocketAddr>) -> io::Result<Self> {
+        let sink = match server {
+            Some(server) => {
+                let local = match server {
+                    SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                    SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                };
+                Sink::Remote(UdpSocket::bind(local)?, server)
+            }
+            #[cfg(unix)]
+            None => {
+                let sock = UnixDatagram::unbound()?;
+                sock.connect(SYSLOG_LOCAL_PATH)?;
+                Sink::Local(sock)
+            }
+            #[cfg(not(unix))]
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "local syslog is not supported on this platform",
+                ))
+            }
+        };
+
+        Ok(Self { facility, sink })
+    }
+
+    /// Emit a message for a scan finding. `sd` are the structured data
+    /// parameters.
+    pub(crate) fn emit(&self, msgid: &str, sd: &[(&str, String)], msg: &str) -> io::Result<()> {
+        let m = format_message(self.facility, msgid, sd, msg);
+
+        match &self.sink {
+            Sink::Remote(sock, server) => sock.send_to(m.as_bytes(), server).map(|_| ()),
+            #[cfg(unix)]
+            Sink::Local(sock) => sock.send(m.as_bytes()).map(|_| ()),
+        }
+    }
+}
+
+/// Format a RFC 5424 message. Timestamp and hostname are left to the
+/// collector (NILVALUE).
+fn format_message(
+    facility: QSSyslogFacility,
+    msgid: &str,
+    sd: &[(&str, String)],
+    msg: &str,
+) -> String {
+    let pri = (facility as u8) * 8 + SEVERITY;
+    let params: String = sd
+        .iter()
+        .map(|(name, value)| format!(" {}=\"{}\"", name, escape_param(value)))
+        .collect();
+
+    format!(
+        "<{}>1 - - qscan {} {} [{}{}] {}",
+        pri,
+        std::process::id(),
+        msgid,
+        SD_ID,
+        params,
+        msg
+    )
+}
+
+/// Escape a structured data parameter value (RFC 5424, section 6.3.3)
+fn escape_param(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QSSyslog, QSSyslogFacility};
+    use std::net::UdpSocket;
+
+    #[test]
+    fn format_message() {
+        let m = super::format_message(
+            QSSyslogFacility::Local0,
+            "OPEN",
+            &[("ip", "127.0.0.1".to_string()), ("port", "22".to_string())],
+            "127.0.0.1:22 open",
+        );
+        assert_eq!(
+            m,
+            format!(
+                "<133>1 - - qscan {} OPEN [qscan@32473 ip=\"127.0.0.1\" port=\"22\"] 127.0.0.1:22 open",
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn escape_param() {
+        assert_eq!(super::escape_param("a\"b]c\\"), "a\\\"b\\]c\\\\");
+    }
+
+    #[test]
+    fn emit_remote() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let syslog = QSSyslog::new(
+            QSSyslogFacility::User,
+            Some(collector.local_addr().unwrap()),
+        )
+        .unwrap();
+        syslog.emit("OPEN", &[], "test").unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = collector.recv(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("<13>1 - - qscan "));
+    }
+}